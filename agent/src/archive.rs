@@ -0,0 +1,95 @@
+use crate::{
+	meta,
+	pipe,
+	store::Store,
+	temp
+};
+use std::{
+	fs::Metadata,
+	io::Result,
+	io::Write,
+	ops::Range,
+	os::unix::fs::PermissionsExt,
+	path::Path,
+	time::SystemTime
+};
+use zip::{
+	write::SimpleFileOptions,
+	write::StreamWriter,
+	CompressionMethod,
+	DateTime,
+	ZipWriter
+};
+
+/// An archive written as it is built, each entry followed by its size and checksum.
+type Archive<W> = ZipWriter<StreamWriter<W>>;
+
+/// Writes `path` to `to` as a zip archive, with entries named relative to `path`, or named after it if it is a file.
+/// Entries are stored as they are, since the archive is streamed as it is built and most large files are compressed
+/// already. The agent's own state and symbolic links are left out, so that an archive of a directory can't reach
+/// anything outside of it.
+pub fn zip(store: &Store, path: &Path, to: impl Write) -> Result<()> {
+	let mut zip = ZipWriter::new_stream(to);
+	let stat = path.symlink_metadata()?;
+
+	if stat.is_dir() {
+		add_dir(store, &mut zip, path, "")?;
+	} else {
+		let name = path.file_name().unwrap_or_default().to_string_lossy();
+		add_file(store, &mut zip, path, &stat, &name)?;
+	}
+
+	zip.finish()?.flush()
+}
+
+fn add_dir<W: Write>(store: &Store, zip: &mut Archive<W>, dir: &Path, prefix: &str) -> Result<()> {
+	let mut children = dir.read_dir()?.collect::<Result<Vec<_>>>()?;
+	children.sort_by_key(|child| child.file_name());
+
+	for child in children {
+		let path = child.path();
+
+		if path == meta(store.base()) || temp::is_temp(&child.file_name()) {
+			continue;
+		}
+
+		let stat = path.symlink_metadata()?;
+		let name = format!("{}{}", prefix, child.file_name().to_string_lossy());
+
+		if stat.is_dir() {
+			zip.add_directory(name.as_str(), options(&stat))?;
+			add_dir(store, zip, &path, &format!("{}/", name))?;
+		} else if stat.is_file() {
+			add_file(store, zip, &path, &stat, &name)?;
+		}
+	}
+
+	Ok(())
+}
+
+fn add_file<W: Write>(store: &Store, zip: &mut Archive<W>, path: &Path, stat: &Metadata, name: &str) -> Result<()> {
+	let (size, _) = store.head(path, stat, 0);
+
+	zip.start_file(name, options(stat).large_file(size >= u32::MAX as u64))?;
+	pipe(store.open(path)?, &mut *zip)
+}
+
+fn options(stat: &Metadata) -> SimpleFileOptions {
+	let options = SimpleFileOptions::default()
+		.compression_method(CompressionMethod::Stored)
+		.unix_permissions(stat.permissions().mode() & 0o777);
+
+	match stat.modified().ok().and_then(timestamp) {
+		Some(modified) => options.last_modified_time(modified),
+		None => options
+	}
+}
+
+/// Converts to the archive's timestamps, which hold a UTC date between 1980 and 2107 to the nearest two seconds.
+fn timestamp(time: SystemTime) -> Option<DateTime> {
+	let time = humantime::format_rfc3339_seconds(time).to_string();
+	let field = |range: Range<usize>| time.get(range)?.parse::<u16>().ok();
+
+	DateTime::from_date_and_time(field(0..4)?, field(5..7)? as u8, field(8..10)? as u8, field(11..13)? as u8,
+		field(14..16)? as u8, field(17..19)? as u8).ok()
+}
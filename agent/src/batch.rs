@@ -0,0 +1,323 @@
+use crate::{
+	create_meta,
+	meta,
+	names,
+	resolve,
+	rm,
+	tags,
+	transfer,
+	Attributes
+};
+use serde::{
+	Deserialize,
+	Serialize
+};
+use std::{
+	fs,
+	io::Error,
+	io::ErrorKind,
+	io::Result,
+	path::Path,
+	path::PathBuf
+};
+
+pub const STAGING: &str = "batch";
+
+/// A single step of a batch, as read from stdin. Paths are given as the user sees them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+	Mkdir {
+		path: PathBuf
+	},
+	Move {
+		path: PathBuf,
+		to: PathBuf
+	},
+	Copy {
+		path: PathBuf,
+		to: PathBuf
+	},
+	Rm {
+		path: PathBuf
+	},
+	WriteMetadata {
+		path: PathBuf,
+
+		#[serde(flatten)]
+		attributes: Attributes
+	},
+}
+
+impl Operation {
+	/// Confines the operation's paths to `base`.
+	fn resolve(self, base: &Path) -> Result<Self> {
+		Ok(match self {
+			Self::Mkdir { path } => Self::Mkdir { path: resolve(base, path)? },
+			Self::Move { path, to } => Self::Move { path: resolve(base, path)?, to: resolve(base, to)? },
+			Self::Copy { path, to } => Self::Copy { path: resolve(base, path)?, to: resolve(base, to)? },
+			Self::Rm { path } => Self::Rm { path: resolve(base, path)? },
+			Self::WriteMetadata { path, attributes } => Self::WriteMetadata { path: resolve(base, path)?, attributes },
+		})
+	}
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+	/// The operation passed validation, but nothing was run because another didn't.
+	Valid,
+	Invalid,
+	Done,
+	Failed,
+	/// The operation was run, then undone because a later one failed.
+	RolledBack,
+	/// The operation was run, but couldn't be undone.
+	RollbackFailed,
+	Skipped,
+}
+
+/// Reported for each operation once the batch has finished.
+#[derive(Serialize, Debug)]
+pub struct Outcome {
+	index: usize,
+	status: Status,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+}
+
+impl Outcome {
+	pub fn done(&self) -> bool {
+		matches!(self.status, Status::Done)
+	}
+}
+
+/// How to revert a completed operation.
+enum Undo {
+	Nothing,
+	Remove(PathBuf),
+	Rename {
+		from: PathBuf,
+		to: PathBuf
+	},
+	Restore {
+		path: PathBuf,
+		attributes: Attributes
+	},
+}
+
+impl Undo {
+	fn run(self, base: &Path) -> Result<()> {
+		match self {
+			Self::Nothing => Ok(()),
+			Self::Remove(path) => rm(path),
+			Self::Rename { from, to } => transfer::relocate(base, &from, &to),
+			Self::Restore { path, attributes } => attributes.apply(path).map(|_| ())
+		}
+	}
+}
+
+/// What an operation did to a path, as far as later operations are concerned.
+enum Change {
+	Created,
+	Removed,
+
+	/// The path now holds what was at another path.
+	Alias(PathBuf),
+}
+
+/// Tracks which paths the batch will have created or removed by the time each operation runs, so that operations can
+/// be validated against the state left by the ones before them.
+struct Plan<'a> {
+	changes: Vec<(PathBuf, Change)>,
+	names: &'a names::Policy,
+}
+
+impl Plan<'_> {
+	/// Whether `path` will exist after the first `upto` changes. The latest change to the path or one of its ancestors
+	/// decides, and the filesystem is consulted if there is none.
+	fn exists(&self, path: &Path, upto: usize) -> bool {
+		let latest = self.changes[..upto]
+			.iter()
+			.enumerate()
+			.rev()
+			.find(|(_, (changed, _))| path.starts_with(changed));
+
+		match latest {
+			Some((_, (changed, change))) if changed == path => !matches!(change, Change::Removed),
+			Some((_, (_, Change::Removed))) => false,
+			Some((index, (changed, Change::Alias(from)))) => self.exists(&from.join(path.strip_prefix(changed).unwrap_or(path)), index),
+			_ => path.symlink_metadata().is_ok()
+		}
+	}
+
+	fn check(&mut self, op: &Operation) -> Result<()> {
+		let missing = |path: &Path| Error::new(ErrorKind::NotFound, format!("{:?} does not exist", path));
+		let conflict = |path: &Path| Error::new(ErrorKind::AlreadyExists, format!("{:?} already exists", path));
+		let upto = self.changes.len();
+
+		match op {
+			Operation::Mkdir { path } => {
+				self.names.check_new(path)?;
+
+				let created = path.ancestors()
+					.take_while(|dir| !self.exists(dir, upto))
+					.map(|dir| (dir.to_path_buf(), Change::Created))
+					.collect::<Vec<_>>();
+
+				self.changes.extend(created.into_iter().rev());
+			},
+
+			Operation::Move { path, to } | Operation::Copy { path, to } => {
+				if !self.exists(path, upto) {
+					return Err(missing(path));
+				}
+
+				if self.exists(to, upto) {
+					return Err(conflict(to));
+				}
+
+				if to.starts_with(path) {
+					return Err(Error::new(ErrorKind::InvalidInput, "Cannot move or copy a directory into itself"));
+				}
+
+				self.names.check(to, matches!(op, Operation::Move { .. }).then_some(path.as_path()))?;
+
+				self.changes.push((to.clone(), Change::Alias(path.clone())));
+
+				if let Operation::Move { .. } = op {
+					self.changes.push((path.clone(), Change::Removed));
+				}
+			},
+
+			Operation::Rm { path } => {
+				if !self.exists(path, upto) {
+					return Err(missing(path));
+				}
+
+				self.changes.push((path.clone(), Change::Removed));
+			},
+
+			Operation::WriteMetadata { path, .. } => if !self.exists(path, upto) {
+				return Err(missing(path));
+			},
+		}
+
+		Ok(())
+	}
+}
+
+/// Confines every operation to `base` and validates it against the state left by those before it. If any fails, the
+/// outcome of each is returned instead.
+pub fn check(base: &Path, names: &names::Policy, ops: Vec<Operation>) -> std::result::Result<Vec<Operation>, Vec<Outcome>> {
+	let ops = ops.into_iter()
+		.map(|op| op.resolve(base))
+		.collect::<Vec<_>>();
+
+	let mut plan = Plan { changes: Vec::new(), names };
+	let checked = ops.iter()
+		.map(|op| op.as_ref().map_err(|err| Error::new(err.kind(), err.to_string())).and_then(|op| plan.check(op)))
+		.collect::<Vec<_>>();
+
+	if checked.iter().any(|check| check.is_err()) {
+		return Err(checked.into_iter()
+			.enumerate()
+			.map(|(index, check)| Outcome {
+				index,
+				status: if check.is_ok() { Status::Valid } else { Status::Invalid },
+				error: check.err().map(|err| err.to_string()),
+			})
+			.collect());
+	}
+
+	Ok(ops.into_iter().flatten().collect())
+}
+
+/// Validates every operation up front, then runs them in order. If one fails, those already run are undone in reverse
+/// order. Removed entries are staged in the user's meta directory until the whole batch succeeds, so that they too
+/// can be restored.
+pub fn run(base: &Path, names: &names::Policy, ops: Vec<Operation>) -> Result<Vec<Outcome>> {
+	let ops = match check(base, names, ops) {
+		Ok(ops) => ops,
+		Err(outcomes) => return Ok(outcomes)
+	};
+
+	let staging = meta(base).join(STAGING).join(std::process::id().to_string());
+	let mut outcomes: Vec<Outcome> = Vec::new();
+	let mut undo = Vec::new();
+
+	for (index, op) in ops.into_iter().enumerate() {
+		if outcomes.last().is_some_and(|outcome| !outcome.done()) {
+			outcomes.push(Outcome { index, status: Status::Skipped, error: None });
+			continue;
+		}
+
+		match execute(base, op, index, &staging) {
+			Ok(step) => {
+				undo.push((index, step));
+				outcomes.push(Outcome { index, status: Status::Done, error: None });
+			},
+			Err(err) => outcomes.push(Outcome { index, status: Status::Failed, error: Some(err.to_string()) })
+		}
+	}
+
+	if outcomes.iter().any(|outcome| matches!(outcome.status, Status::Failed)) {
+		for (index, step) in undo.into_iter().rev() {
+			outcomes[index] = match step.run(base) {
+				Ok(()) => Outcome { index, status: Status::RolledBack, error: None },
+				Err(err) => Outcome { index, status: Status::RollbackFailed, error: Some(err.to_string()) }
+			};
+		}
+	}
+
+	// Anything left staged after a failed rollback is all that remains of it, so is kept for manual recovery.
+	if staging.exists() && !outcomes.iter().any(|outcome| matches!(outcome.status, Status::RollbackFailed)) {
+		rm(&staging)?;
+		tags::forget(base, &staging)?;
+	}
+
+	Ok(outcomes)
+}
+
+fn execute(base: &Path, op: Operation, index: usize, staging: &Path) -> Result<Undo> {
+	match op {
+		Operation::Mkdir { path } => {
+			// Only the outermost directory which didn't exist yet needs removing again.
+			let created = path.ancestors()
+				.take_while(|dir| !dir.exists())
+				.last()
+				.map(Path::to_path_buf);
+
+			fs::create_dir_all(&path)?;
+			Ok(created.map_or(Undo::Nothing, Undo::Remove))
+		},
+
+		Operation::Move { path, to } => {
+			transfer::relocate(base, &path, &to)?;
+			Ok(Undo::Rename { from: to, to: path })
+		},
+
+		Operation::Copy { path, to } => {
+			if let Err(err) = transfer::duplicate(base, &path, &to) {
+				let _ = rm(&to);
+				return Err(err);
+			}
+
+			Ok(Undo::Remove(to))
+		},
+
+		Operation::Rm { path } => {
+			create_meta(staging)?;
+
+			let staged = staging.join(index.to_string());
+			transfer::relocate(base, &path, &staged)?;
+			Ok(Undo::Rename { from: staged, to: path })
+		},
+
+		Operation::WriteMetadata { path, attributes } => Ok(Undo::Restore {
+			attributes: attributes.apply(&path)?,
+			path,
+		}),
+	}
+}
@@ -0,0 +1,503 @@
+use crate::{
+	create_meta,
+	escape::EscapedReader,
+	escape::EscapeWriter,
+	Finish,
+	ReadSeek
+};
+use serde::{
+	Deserialize,
+	Serialize
+};
+use std::{
+	collections::BTreeMap,
+	fs,
+	io::Error,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	io::Seek,
+	io::SeekFrom,
+	io::Write,
+	path::Path,
+	path::PathBuf
+};
+
+/// Amount of data compressed into each independent frame. Seeking decompresses at most one frame.
+pub const FRAME_SIZE: usize = 256 * 1024;
+
+pub const LEVEL: i32 = 3;
+
+pub const POLICY: &str = "compression.json";
+
+/// A skippable frame marking files compressed by the agent, as opposed to `.zst` files belonging to the user. Being a
+/// skippable frame, the files remain readable by the regular `zstd` tool.
+const MARKER: [u8; 16] = [0x50, 0x2A, 0x4D, 0x18, 8, 0, 0, 0, b'J', b'C', b'Z', 1, 0, 0, 0, 0];
+
+/// Put in front of uncompressed data starting with the first bytes of [`MARKER`], which would otherwise be taken for a
+/// compressed file, or for escaped data itself. It differs from the marker in its version byte.
+const ESCAPE: [u8; 16] = [0x50, 0x2A, 0x4D, 0x18, 8, 0, 0, 0, b'J', b'C', b'Z', 0, 0, 0, 0, 0];
+
+/// What [`MARKER`] and [`ESCAPE`] have in common. Uncompressed data starting with it is escaped.
+const PREFIX_LEN: usize = 11;
+
+const SEEK_TABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+const FOOTER_LEN: usize = 9;
+
+/// Magic numbers of formats which are already compressed, and which gain nothing from another pass.
+const COMPRESSED_MAGIC: &[(usize, &[u8])] = &[
+	(0, &[0x1F, 0x8B]),
+	(0, &[0x28, 0xB5, 0x2F, 0xFD]),
+	(0, &[0xFD, b'7', b'z', b'X', b'Z', 0]),
+	(0, b"BZh"),
+	(0, &[0x04, 0x22, 0x4D, 0x18]),
+	(0, b"PK\x03\x04"),
+	(0, &[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]),
+	(0, b"Rar!"),
+	(0, &[0x89, b'P', b'N', b'G']),
+	(0, &[0xFF, 0xD8, 0xFF]),
+	(0, b"GIF8"),
+	(8, b"WEBP"),
+	(4, b"ftyp"),
+	(0, &[0x1A, 0x45, 0xDF, 0xA3]),
+	(0, b"OggS"),
+	(0, b"fLaC"),
+	(0, b"ID3"),
+	(0, b"JCE"),
+];
+
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+	"gz", "tgz", "zst", "xz", "bz2", "lz4", "br", "zip", "7z", "rar", "jar", "apk", "docx", "xlsx", "pptx", "odt", "jpg",
+	"jpeg", "png", "gif", "webp", "avif", "heic", "mp3", "mp4", "m4a", "mkv", "webm", "mov", "ogg", "opus", "flac",
+];
+
+/// Decides whether data starting with `head` is worth compressing. Known formats are recognised by their magic numbers
+/// or failing that their extension. Anything else is test-compressed, and skipped if it barely shrinks.
+pub fn compressible(path: impl AsRef<Path>, head: &[u8]) -> bool {
+	if COMPRESSED_MAGIC.iter().any(|(offset, magic)| head.get(*offset..*offset + magic.len()) == Some(*magic)) {
+		return false;
+	}
+
+	let extension = path.as_ref()
+		.extension()
+		.map(|ext| ext.to_string_lossy().to_lowercase());
+
+	if extension.is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.as_str())) {
+		return false;
+	}
+
+	match zstd::bulk::compress(head, 1) {
+		Ok(sample) => sample.len() * 100 < head.len() * 95,
+		Err(_) => false
+	}
+}
+
+/// Per-directory compression settings. The setting of the nearest ancestor applies, and compression is off unless
+/// enabled somewhere above a file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Policy {
+	paths: BTreeMap<PathBuf, bool>,
+}
+
+impl Policy {
+	pub fn load(meta: impl AsRef<Path>) -> Result<Self> {
+		match fs::read(meta.as_ref().join(POLICY)) {
+			Ok(policy) => Ok(serde_json::from_slice(&policy)?),
+			Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+			Err(err) => Err(err)
+		}
+	}
+
+	pub fn save(&self, meta: impl AsRef<Path>) -> Result<()> {
+		create_meta(&meta)?;
+
+		let path = meta.as_ref().join(POLICY);
+		let tmp = path.with_extension("tmp");
+
+		fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+		fs::rename(tmp, path)
+	}
+
+	/// Sets whether files below `path` are compressed, or defers to the parent directory if `None`.
+	pub fn set(&mut self, path: impl AsRef<Path>, enabled: Option<bool>) {
+		match enabled {
+			Some(enabled) => self.paths.insert(path.as_ref().to_path_buf(), enabled),
+			None => self.paths.remove(path.as_ref())
+		};
+	}
+
+	/// `path` is relative to the user's base, as shown in listings.
+	pub fn applies(&self, path: impl AsRef<Path>) -> bool {
+		path.as_ref()
+			.ancestors()
+			.find_map(|dir| self.paths.get(dir))
+			.copied()
+			.unwrap_or(false)
+	}
+}
+
+/// How a file's logical contents are kept, as recorded by the marker at its start.
+enum Layout {
+	Plain,
+	Escaped,
+	Compressed
+}
+
+impl Layout {
+	fn read(mut file: impl Read + Seek) -> Result<Self> {
+		let mut head = [0u8; MARKER.len()];
+
+		file.seek(SeekFrom::Start(0))?;
+		let layout = match file.read_exact(&mut head) {
+			Ok(()) if head == MARKER => Self::Compressed,
+			Ok(()) if head == ESCAPE => Self::Escaped,
+			Ok(()) => Self::Plain,
+			Err(err) if err.kind() == ErrorKind::UnexpectedEof => Self::Plain,
+			Err(err) => return Err(err)
+		};
+
+		file.seek(SeekFrom::Start(0))?;
+		Ok(layout)
+	}
+}
+
+/// Whether a file's logical contents are a compressed container.
+pub fn is_compressed(file: impl Read + Seek) -> Result<bool> {
+	Ok(matches!(Layout::read(file)?, Layout::Compressed))
+}
+
+/// Wraps `file` in a [`SeekableReader`] if it was written compressed.
+pub fn open(mut file: Box<dyn ReadSeek>) -> Result<Box<dyn ReadSeek>> {
+	match Layout::read(&mut file)? {
+		Layout::Compressed => Ok(Box::new(SeekableReader::new(file)?)),
+		Layout::Escaped => Ok(Box::new(EscapedReader::new(file, ESCAPE.len())?)),
+		Layout::Plain => Ok(file)
+	}
+}
+
+/// Wraps `to` so that what is written to it uncompressed is never taken for a compressed file.
+pub fn plain(to: Box<dyn Finish>) -> Box<dyn Finish> {
+	Box::new(EscapeWriter::new(to, &MARKER[..PREFIX_LEN], ESCAPE.to_vec()))
+}
+
+/// Writes data as a sequence of independent zstd frames followed by a seek table, following zstd's seekable format.
+pub struct SeekableWriter<W: Finish + ?Sized> {
+	inner: Box<W>,
+	buf: Vec<u8>,
+	frames: Vec<(u32, u32)>,
+}
+
+impl<W: Finish + ?Sized> SeekableWriter<W> {
+	pub fn new(mut inner: Box<W>) -> Result<Self> {
+		inner.write_all(&MARKER)?;
+
+		Ok(Self {
+			inner,
+			buf: Vec::with_capacity(FRAME_SIZE),
+			frames: vec![(MARKER.len() as u32, 0)],
+		})
+	}
+
+	fn frame(&mut self) -> Result<()> {
+		let frame = zstd::bulk::compress(&self.buf, LEVEL)?;
+
+		self.inner.write_all(&frame)?;
+		self.frames.push((frame.len() as u32, self.buf.len() as u32));
+		self.buf.clear();
+
+		Ok(())
+	}
+}
+
+impl<W: Finish + ?Sized> Write for SeekableWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		let len = buf.len().min(FRAME_SIZE - self.buf.len());
+		self.buf.extend_from_slice(&buf[..len]);
+
+		if self.buf.len() == FRAME_SIZE {
+			self.frame()?;
+		}
+
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl<W: Finish + ?Sized> Finish for SeekableWriter<W> {
+	fn finish(mut self: Box<Self>) -> Result<()> {
+		if !self.buf.is_empty() {
+			self.frame()?;
+		}
+
+		let mut table = Vec::with_capacity(8 + self.frames.len() * 8 + FOOTER_LEN);
+		table.extend_from_slice(&SEEK_TABLE_MAGIC.to_le_bytes());
+		table.extend_from_slice(&((self.frames.len() * 8 + FOOTER_LEN) as u32).to_le_bytes());
+
+		for (compressed, decompressed) in &self.frames {
+			table.extend_from_slice(&compressed.to_le_bytes());
+			table.extend_from_slice(&decompressed.to_le_bytes());
+		}
+
+		table.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+		table.push(0);
+		table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+
+		self.inner.write_all(&table)?;
+		self.inner.finish()
+	}
+}
+
+struct Frame {
+	offset: u64,
+	start: u64,
+	compressed: u32,
+	decompressed: u32,
+}
+
+/// Presents the decompressed contents of a file written by [`SeekableWriter`].
+pub struct SeekableReader<R: Read + Seek> {
+	inner: R,
+	frames: Vec<Frame>,
+	len: u64,
+	pos: u64,
+	cache: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> SeekableReader<R> {
+	pub fn new(mut inner: R) -> Result<Self> {
+		let corrupt = || Error::new(ErrorKind::InvalidData, "Corrupt seek table");
+
+		let len = inner.seek(SeekFrom::End(0))?;
+		let mut footer = [0u8; FOOTER_LEN];
+
+		if len < (MARKER.len() + 8 + FOOTER_LEN) as u64 {
+			return Err(corrupt());
+		}
+
+		inner.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+		inner.read_exact(&mut footer)?;
+
+		let count = u32::from_le_bytes(footer[..4].try_into().unwrap()) as usize;
+		let stride = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+
+		if u32::from_le_bytes(footer[5..].try_into().unwrap()) != SEEKABLE_MAGIC {
+			return Err(corrupt());
+		}
+
+		// The count comes from the file, so the table has to fit in it before anything is allocated for it. The frames
+		// end where the table's header starts.
+		let table_len = count.checked_mul(stride)
+			.filter(|table_len| (table_len + 8 + FOOTER_LEN) as u64 <= len)
+			.ok_or_else(corrupt)?;
+		let end = len - (table_len + 8 + FOOTER_LEN) as u64;
+
+		let mut table = vec![0u8; table_len];
+		inner.seek(SeekFrom::End(-((FOOTER_LEN + table.len()) as i64)))?;
+		inner.read_exact(&mut table)?;
+
+		let mut frames = Vec::with_capacity(count);
+		let (mut offset, mut start) = (0u64, 0u64);
+
+		for entry in table.chunks_exact(stride) {
+			let compressed = u32::from_le_bytes(entry[..4].try_into().unwrap());
+			let decompressed = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+
+			if offset + compressed as u64 > end || decompressed as usize > FRAME_SIZE {
+				return Err(corrupt());
+			}
+
+			frames.push(Frame { offset, start, compressed, decompressed });
+			offset += compressed as u64;
+			start += decompressed as u64;
+		}
+
+		Ok(Self {
+			inner,
+			frames,
+			len: start,
+			pos: 0,
+			cache: None,
+		})
+	}
+
+	fn load(&mut self, index: usize) -> Result<&[u8]> {
+		if self.cache.as_ref().is_none_or(|(cached, _)| *cached != index) {
+			let frame = &self.frames[index];
+			let mut compressed = vec![0u8; frame.compressed as usize];
+
+			self.inner.seek(SeekFrom::Start(frame.offset))?;
+			self.inner.read_exact(&mut compressed)?;
+
+			let decompressed = zstd::bulk::decompress(&compressed, frame.decompressed as usize)?;
+
+			if decompressed.len() != frame.decompressed as usize {
+				return Err(Error::new(ErrorKind::InvalidData, format!("Frame {} has the wrong size", index)));
+			}
+
+			self.cache = Some((index, decompressed));
+		}
+
+		Ok(self.cache.as_ref().map(|(_, frame)| frame.as_slice()).unwrap_or_default())
+	}
+}
+
+impl<R: Read + Seek> Read for SeekableReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		if self.pos >= self.len || buf.is_empty() {
+			return Ok(0);
+		}
+
+		// Frames without content (such as the marker) can never contain the position, so are never selected.
+		let index = self.frames.partition_point(|frame| frame.start + frame.decompressed as u64 <= self.pos);
+		let offset = (self.pos - self.frames[index].start) as usize;
+		let frame = self.load(index)?;
+
+		let len = buf.len().min(frame.len() - offset);
+		buf[..len].copy_from_slice(&frame[offset..offset + len]);
+		self.pos += len as u64;
+
+		Ok(len)
+	}
+}
+
+impl<R: Read + Seek> Seek for SeekableReader<R> {
+	fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+		let pos = match pos {
+			SeekFrom::Start(pos) => Some(pos),
+			SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+			SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+		};
+
+		self.pos = pos.ok_or(Error::new(ErrorKind::InvalidInput, "Seek before start of file"))?;
+		Ok(self.pos)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{
+		cell::RefCell,
+		io::Cursor,
+		rc::Rc
+	};
+
+	/// Collects what is written to it, for reading back once the writer has been finished.
+	#[derive(Clone, Default)]
+	struct Sink(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for Sink {
+		fn write(&mut self, buf: &[u8]) -> Result<usize> {
+			self.0.borrow_mut().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> Result<()> {
+			Ok(())
+		}
+	}
+
+	impl Finish for Sink {
+		fn finish(self: Box<Self>) -> Result<()> {
+			Ok(())
+		}
+	}
+
+	fn write(data: &[u8], compress: bool) -> Vec<u8> {
+		let sink = Sink::default();
+		let mut writer: Box<dyn Finish> = match compress {
+			true => Box::new(SeekableWriter::new(Box::new(sink.clone())).unwrap()),
+			false => plain(Box::new(sink.clone()))
+		};
+
+		writer.write_all(data).unwrap();
+		writer.finish().unwrap();
+		sink.0.take()
+	}
+
+	fn read(file: Vec<u8>) -> Result<Vec<u8>> {
+		let mut data = Vec::new();
+		open(Box::new(Cursor::new(file)))?.read_to_end(&mut data)?;
+		Ok(data)
+	}
+
+	fn data(len: usize) -> Vec<u8> {
+		(0..len).map(|i| (i % 251) as u8 ^ (i / 4096) as u8).collect()
+	}
+
+	#[test]
+	fn round_trip() {
+		for len in [0, 1, FRAME_SIZE - 1, FRAME_SIZE, FRAME_SIZE + 1, FRAME_SIZE * 3 + 7] {
+			let data = data(len);
+			let file = write(&data, true);
+
+			assert!(is_compressed(Cursor::new(&file)).unwrap(), "length {}", len);
+			assert_eq!(read(file).unwrap(), data, "length {}", len);
+		}
+	}
+
+	#[test]
+	fn truncation_fails() {
+		let data = data(FRAME_SIZE * 2 + 1);
+		let file = write(&data, true);
+
+		let mut short = file.clone();
+		short.pop();
+		assert!(read(short).is_err());
+
+		// Without its footer, the file no longer ends in a seek table.
+		assert!(read(file[..file.len() - FOOTER_LEN].to_vec()).is_err());
+	}
+
+	#[test]
+	fn seek_across_frames() {
+		let data = data(FRAME_SIZE * 2 + 10);
+		let mut reader = open(Box::new(Cursor::new(write(&data, true)))).unwrap();
+
+		for start in [0, FRAME_SIZE - 5, FRAME_SIZE, FRAME_SIZE * 2 - 1, data.len() - 3] {
+			let mut buf = vec![0u8; 20.min(data.len() - start)];
+			reader.seek(SeekFrom::Start(start as u64)).unwrap();
+			reader.read_exact(&mut buf).unwrap();
+			assert_eq!(buf, data[start..start + buf.len()], "offset {}", start);
+		}
+
+		assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), data.len() as u64 - 4);
+		assert_eq!(reader.seek(SeekFrom::Current(-(FRAME_SIZE as i64))).unwrap(), (data.len() - 4 - FRAME_SIZE) as u64);
+	}
+
+	#[test]
+	fn marker_is_escaped() {
+		let cases = [
+			MARKER.to_vec(),
+			ESCAPE.to_vec(),
+			MARKER[..PREFIX_LEN].to_vec(),
+			[&MARKER[..], &write(b"compressed", true)].concat(),
+			[&ESCAPE[..], b"escaped"].concat(),
+			MARKER[..PREFIX_LEN - 1].to_vec(),
+			data(100),
+		];
+
+		for data in cases {
+			let file = write(&data, false);
+
+			assert!(!is_compressed(Cursor::new(&file)).unwrap());
+			assert_eq!(read(file).unwrap(), data);
+		}
+	}
+
+	#[test]
+	fn oversized_seek_table() {
+		let mut file = MARKER.to_vec();
+		file.extend_from_slice(&SEEK_TABLE_MAGIC.to_le_bytes());
+		file.extend_from_slice(&0u32.to_le_bytes());
+		file.extend_from_slice(&u32::MAX.to_le_bytes());
+		file.push(0x80);
+		file.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+
+		assert_eq!(read(file).unwrap_err().kind(), ErrorKind::InvalidData);
+	}
+}
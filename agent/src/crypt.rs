@@ -0,0 +1,745 @@
+use argon2::Argon2;
+use base64::{
+	prelude::BASE64_STANDARD,
+	Engine
+};
+use chacha20poly1305::{
+	aead::Aead,
+	aead::KeyInit,
+	aead::OsRng,
+	aead::Payload,
+	aead::rand_core::RngCore,
+	ChaCha20Poly1305
+};
+use serde::{
+	Deserialize,
+	Serialize
+};
+use crate::{
+	create_meta,
+	escape::EscapedReader,
+	escape::EscapeWriter,
+	pipe,
+	Finish,
+	ReadSeek
+};
+use std::{
+	cell::OnceCell,
+	collections::HashMap,
+	fs,
+	fs::File,
+	fs::OpenOptions,
+	io::Error,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	io::Seek,
+	io::SeekFrom,
+	io::Write,
+	os::unix::fs::OpenOptionsExt,
+	path::Path,
+	path::PathBuf
+};
+
+/// Identifies a file written by [`EncryptWriter`]. The trailing byte is the format version.
+pub const MAGIC: [u8; 4] = *b"JCE\x01";
+
+/// Amount of plaintext sealed into each chunk. Range reads decrypt whole chunks, so this also bounds read amplification.
+pub const CHUNK_SIZE: u32 = 64 * 1024;
+
+const TAG_LEN: u64 = 16;
+const PREFIX_LEN: usize = 7;
+const HEADER_LEN: usize = MAGIC.len() + 4 + 4 + PREFIX_LEN;
+
+/// Key id of the header put in front of plaintext which happens to start with [`MAGIC`]. Data keys are numbered from 1.
+const PLAIN: u32 = 0;
+
+pub const KEYRING: &str = "keyring.json";
+
+pub type Key = [u8; 32];
+
+/// Credentials the agent was started with. These are collected before privileges are dropped, as the master key is
+/// typically only readable by the server.
+#[derive(Default)]
+pub struct Secrets {
+	pub master: Option<Key>,
+	pub new_master: Option<Key>,
+	pub passphrase: Option<String>,
+	pub new_passphrase: Option<String>,
+}
+
+impl Secrets {
+	pub fn load(master: Option<&Path>, new_master: Option<&Path>) -> Result<Self> {
+		Ok(Self {
+			master: master.map(read_key).transpose()?,
+			new_master: new_master.map(read_key).transpose()?,
+			passphrase: std::env::var("CLOUD_PASSPHRASE").ok(),
+			new_passphrase: std::env::var("CLOUD_NEW_PASSPHRASE").ok(),
+		})
+	}
+}
+
+/// Reads a 32-byte key, either raw or base64 encoded.
+pub fn read_key(path: impl AsRef<Path>) -> Result<Key> {
+	let raw = fs::read(path)?;
+
+	let key = match BASE64_STANDARD.decode(raw.trim_ascii()) {
+		Ok(decoded) => decoded,
+		Err(_) => raw
+	};
+
+	key.try_into()
+		.map_err(|_| Error::new(ErrorKind::InvalidData, "Master key must be 32 bytes"))
+}
+
+/// Describes how the data keys in a [`Keyring`] are wrapped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Wrapping {
+	Master,
+	Passphrase {
+		salt: String
+	}
+}
+
+impl Wrapping {
+	pub fn new(passphrase: bool) -> Self {
+		if passphrase {
+			let mut salt = [0u8; 16];
+			OsRng.fill_bytes(&mut salt);
+			Self::Passphrase { salt: BASE64_STANDARD.encode(salt) }
+		} else {
+			Self::Master
+		}
+	}
+
+	fn kek(&self, master: Option<&Key>, passphrase: Option<&str>) -> Result<Key> {
+		match self {
+			Self::Master => master
+				.copied()
+				.ok_or(Error::new(ErrorKind::PermissionDenied, "A master key is required to unlock this keyring")),
+
+			Self::Passphrase { salt } => {
+				let passphrase = passphrase
+					.ok_or(Error::new(ErrorKind::PermissionDenied, "A passphrase is required to unlock this keyring"))?;
+				let salt = BASE64_STANDARD.decode(salt)
+					.map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+				let mut kek = Key::default();
+				Argon2::default()
+					.hash_password_into(passphrase.as_bytes(), &salt, &mut kek)
+					.map_err(|err| Error::new(ErrorKind::InvalidInput, err.to_string()))?;
+
+				Ok(kek)
+			}
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WrappedKey {
+	id: u32,
+	nonce: String,
+	key: String,
+}
+
+impl WrappedKey {
+	fn wrap(id: u32, key: &Key, kek: &Key) -> Result<Self> {
+		let mut nonce = [0u8; 12];
+		OsRng.fill_bytes(&mut nonce);
+
+		let key = ChaCha20Poly1305::new(kek.into())
+			.encrypt(&nonce.into(), Payload { msg: key, aad: &id.to_be_bytes() })
+			.map_err(|_| Error::other("Failed to wrap data key"))?;
+
+		Ok(Self {
+			id,
+			nonce: BASE64_STANDARD.encode(nonce),
+			key: BASE64_STANDARD.encode(key),
+		})
+	}
+
+	fn unwrap(&self, kek: &Key) -> Result<Key> {
+		let nonce: [u8; 12] = BASE64_STANDARD.decode(&self.nonce)
+			.ok()
+			.and_then(|nonce| nonce.try_into().ok())
+			.ok_or(Error::new(ErrorKind::InvalidData, "Corrupt keyring nonce"))?;
+		let key = BASE64_STANDARD.decode(&self.key)
+			.map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+		ChaCha20Poly1305::new(kek.into())
+			.decrypt(&nonce.into(), Payload { msg: &key, aad: &self.id.to_be_bytes() })
+			.ok()
+			.and_then(|key| key.try_into().ok())
+			.ok_or(Error::new(ErrorKind::PermissionDenied, "Incorrect key or passphrase"))
+	}
+}
+
+/// A user's data keys, each wrapped by the same key-encryption key. Files record the id of the key they were written
+/// with, so old keys are kept around after a rotation until every file has been re-encrypted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Keyring {
+	wrapping: Wrapping,
+	active: u32,
+	keys: Vec<WrappedKey>,
+}
+
+impl Keyring {
+	pub fn path(meta: impl AsRef<Path>) -> PathBuf {
+		meta.as_ref().join(KEYRING)
+	}
+
+	pub fn load(meta: impl AsRef<Path>) -> Result<Option<Self>> {
+		match fs::read(Self::path(meta)) {
+			Ok(ring) => Ok(Some(serde_json::from_slice(&ring)?)),
+			Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+			Err(err) => Err(err)
+		}
+	}
+
+	pub fn save(&self, meta: impl AsRef<Path>) -> Result<()> {
+		create_meta(&meta)?;
+
+		let path = Self::path(&meta);
+		let tmp = path.with_extension("tmp");
+
+		OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(true)
+			.mode(0o600)
+			.open(&tmp)?
+			.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+		fs::rename(tmp, path)
+	}
+
+	/// Creates a keyring holding a single fresh data key.
+	pub fn create(wrapping: Wrapping, secrets: &Secrets) -> Result<Self> {
+		let kek = wrapping.kek(secrets.master.as_ref(), secrets.passphrase.as_deref())?;
+
+		Ok(Self {
+			keys: vec![WrappedKey::wrap(1, &generate(), &kek)?],
+			active: 1,
+			wrapping,
+		})
+	}
+
+	pub fn active(&self) -> u32 {
+		self.active
+	}
+
+	pub fn unlock(&self, secrets: &Secrets) -> Result<Keys> {
+		let kek = self.wrapping.kek(secrets.master.as_ref(), secrets.passphrase.as_deref())?;
+
+		Ok(Keys {
+			active: self.active,
+			keys: self.keys.iter()
+				.map(|key| Ok((key.id, key.unwrap(&kek)?)))
+				.collect::<Result<_>>()?,
+		})
+	}
+
+	/// Adds a new data key and makes it the one used for subsequent writes.
+	pub fn rotate(&mut self, secrets: &Secrets) -> Result<u32> {
+		let kek = self.wrapping.kek(secrets.master.as_ref(), secrets.passphrase.as_deref())?;
+		let id = self.keys.iter().map(|key| key.id).max().unwrap_or(0) + 1;
+
+		self.keys.push(WrappedKey::wrap(id, &generate(), &kek)?);
+		self.active = id;
+
+		Ok(id)
+	}
+
+	/// Re-wraps every data key under a new key-encryption key. File contents are unaffected.
+	pub fn rewrap(&mut self, wrapping: Wrapping, secrets: &Secrets) -> Result<()> {
+		let keys = self.unlock(secrets)?;
+		let kek = wrapping.kek(secrets.new_master.as_ref().or(secrets.master.as_ref()), secrets.new_passphrase.as_deref())?;
+
+		self.keys = keys.keys.iter()
+			.map(|(id, key)| WrappedKey::wrap(*id, key, &kek))
+			.collect::<Result<_>>()?;
+		self.keys.sort_by_key(|key| key.id);
+		self.wrapping = wrapping;
+
+		Ok(())
+	}
+
+	/// Drops every key other than the active one. Only safe once all files have been re-encrypted.
+	pub fn prune(&mut self) {
+		let active = self.active;
+		self.keys.retain(|key| key.id == active);
+	}
+}
+
+/// Unwrapped data keys, indexed by id.
+pub struct Keys {
+	active: u32,
+	keys: HashMap<u32, Key>,
+}
+
+impl Keys {
+	pub fn active(&self) -> Result<(u32, &Key)> {
+		self.keys.get(&self.active)
+			.map(|key| (self.active, key))
+			.ok_or(Error::new(ErrorKind::InvalidData, format!("Active data key {} is not in the keyring", self.active)))
+	}
+
+	pub fn get(&self, id: u32) -> Result<&Key> {
+		self.keys.get(&id)
+			.ok_or(Error::new(ErrorKind::NotFound, format!("Data key {} is not in the keyring", id)))
+	}
+}
+
+fn generate() -> Key {
+	let mut key = Key::default();
+	OsRng.fill_bytes(&mut key);
+	key
+}
+
+fn nonce(prefix: &[u8; PREFIX_LEN], counter: u64, last: bool) -> Result<[u8; 12]> {
+	let counter = u32::try_from(counter)
+		.map_err(|_| Error::new(ErrorKind::FileTooLarge, "Too many chunks for a single file"))?;
+
+	let mut nonce = [0u8; 12];
+	nonce[..PREFIX_LEN].copy_from_slice(prefix);
+	nonce[PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+	nonce[11] = last as u8;
+
+	Ok(nonce)
+}
+
+/// How a file's contents are kept, as recorded by the header at its start.
+pub enum Format {
+	/// Plaintext, without a header.
+	Plain,
+	/// Plaintext starting with [`MAGIC`], kept behind a plain header so that it isn't taken for an encrypted file.
+	Escaped,
+	Encrypted(Header),
+}
+
+impl Format {
+	/// Reads the header from the start of a file, leaving the file positioned just past it if there is one.
+	pub fn read(mut file: impl Read + Seek) -> Result<Self> {
+		let mut header = [0u8; HEADER_LEN];
+
+		file.seek(SeekFrom::Start(0))?;
+		match file.read_exact(&mut header) {
+			Ok(()) => (),
+			Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(Self::Plain),
+			Err(err) => return Err(err)
+		}
+
+		if header[..4] != MAGIC {
+			return Ok(Self::Plain);
+		}
+
+		let header = Header {
+			key: u32::from_be_bytes(header[4..8].try_into().unwrap()),
+			chunk: u32::from_be_bytes(header[8..12].try_into().unwrap()),
+			prefix: header[12..].try_into().unwrap(),
+		};
+
+		match header.key {
+			PLAIN => Ok(Self::Escaped),
+			_ => Ok(Self::Encrypted(header))
+		}
+	}
+}
+
+/// The fixed-size header at the start of every encrypted file. It is authenticated as associated data of each chunk.
+/// Plaintext starting with [`MAGIC`] gets one too, with a key id of [`PLAIN`], so the two can always be told apart.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+	pub key: u32,
+	chunk: u32,
+	prefix: [u8; PREFIX_LEN],
+}
+
+impl Header {
+	/// The header put in front of plaintext starting with [`MAGIC`].
+	fn plain() -> Self {
+		Self { key: PLAIN, chunk: 0, prefix: [0u8; PREFIX_LEN] }
+	}
+
+	fn to_bytes(self) -> [u8; HEADER_LEN] {
+		let mut header = [0u8; HEADER_LEN];
+		header[..4].copy_from_slice(&MAGIC);
+		header[4..8].copy_from_slice(&self.key.to_be_bytes());
+		header[8..12].copy_from_slice(&self.chunk.to_be_bytes());
+		header[12..].copy_from_slice(&self.prefix);
+		header
+	}
+
+	/// Reads the header from the start of a file, returning `None` if the file isn't encrypted.
+	pub fn read(file: impl Read + Seek) -> Result<Option<Self>> {
+		match Format::read(file)? {
+			Format::Encrypted(header) => Ok(Some(header)),
+			Format::Plain | Format::Escaped => Ok(None)
+		}
+	}
+}
+
+/// Seals everything written to it into authenticated chunks. [`EncryptWriter::close`] must be called to write the
+/// final chunk, without which the file won't decrypt.
+pub struct EncryptWriter<W: Write> {
+	inner: W,
+	cipher: ChaCha20Poly1305,
+	header: Header,
+	buf: Vec<u8>,
+	counter: u64,
+}
+
+impl<W: Write> EncryptWriter<W> {
+	pub fn new(mut inner: W, (id, key): (u32, &Key)) -> Result<Self> {
+		let mut prefix = [0u8; PREFIX_LEN];
+		OsRng.fill_bytes(&mut prefix);
+
+		let header = Header { key: id, chunk: CHUNK_SIZE, prefix };
+		inner.write_all(&header.to_bytes())?;
+
+		Ok(Self {
+			inner,
+			cipher: ChaCha20Poly1305::new(key.into()),
+			header,
+			buf: Vec::with_capacity(CHUNK_SIZE as usize * 2),
+			counter: 0,
+		})
+	}
+
+	fn seal(&mut self, len: usize, last: bool) -> Result<()> {
+		let nonce = nonce(&self.header.prefix, self.counter, last)?;
+		let chunk = self.cipher
+			.encrypt(&nonce.into(), Payload { msg: &self.buf[..len], aad: &self.header.to_bytes() })
+			.map_err(|_| Error::other("Failed to encrypt chunk"))?;
+
+		self.inner.write_all(&chunk)?;
+		self.buf.drain(..len);
+		self.counter += 1;
+
+		Ok(())
+	}
+
+	pub fn close(mut self) -> Result<W> {
+		self.seal(self.buf.len(), true)?;
+		self.inner.flush()?;
+		Ok(self.inner)
+	}
+}
+
+impl<W: Finish + ?Sized> Finish for EncryptWriter<Box<W>> {
+	fn finish(self: Box<Self>) -> Result<()> {
+		self.close()?.finish()
+	}
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		self.buf.extend_from_slice(buf);
+
+		// A chunk is only sealed once more data follows it, so that the last chunk can be marked as such.
+		while self.buf.len() > self.header.chunk as usize {
+			self.seal(self.header.chunk as usize, false)?;
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Presents the plaintext of an encrypted file. Seeking only decrypts the chunk containing the new position, which is
+/// what makes range reads cheap.
+pub struct DecryptReader<R: Read + Seek> {
+	inner: R,
+	cipher: ChaCha20Poly1305,
+	header: Header,
+	chunks: u64,
+	ciphertext: u64,
+	len: u64,
+	pos: u64,
+	cache: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> DecryptReader<R> {
+	pub fn new(mut inner: R, header: Header, keys: &Keys) -> Result<Self> {
+		let stride = header.chunk as u64 + TAG_LEN;
+		let ciphertext = inner.seek(SeekFrom::End(0))?
+			.checked_sub(HEADER_LEN as u64)
+			.ok_or(Error::new(ErrorKind::InvalidData, "Truncated encrypted file"))?;
+		let chunks = ciphertext.div_ceil(stride).max(1);
+
+		if header.chunk == 0 || ciphertext < chunks * TAG_LEN {
+			return Err(Error::new(ErrorKind::InvalidData, "Truncated encrypted file"));
+		}
+
+		let mut reader = Self {
+			cipher: ChaCha20Poly1305::new(keys.get(header.key)?.into()),
+			len: ciphertext - chunks * TAG_LEN,
+			inner,
+			header,
+			chunks,
+			ciphertext,
+			pos: 0,
+			cache: None,
+		};
+
+		// The length is worked out from the file's size, so the last chunk is checked up front. Otherwise a file cut
+		// right after a tag would read as complete, without its real last chunk ever being looked at.
+		reader.load(chunks - 1)?;
+		Ok(reader)
+	}
+
+	fn load(&mut self, index: u64) -> Result<&[u8]> {
+		if self.cache.as_ref().is_none_or(|(cached, _)| *cached != index) {
+			let stride = self.header.chunk as u64 + TAG_LEN;
+			let mut chunk = vec![0u8; stride.min(self.ciphertext - index * stride) as usize];
+
+			self.inner.seek(SeekFrom::Start(HEADER_LEN as u64 + index * stride))?;
+			self.inner.read_exact(&mut chunk)?;
+
+			let nonce = nonce(&self.header.prefix, index, index + 1 == self.chunks)?;
+			let plain = self.cipher
+				.decrypt(&nonce.into(), Payload { msg: &chunk, aad: &self.header.to_bytes() })
+				.map_err(|_| Error::new(ErrorKind::InvalidData, format!("Chunk {} failed authentication", index)))?;
+
+			self.cache = Some((index, plain));
+		}
+
+		Ok(self.cache.as_ref().map(|(_, plain)| plain.as_slice()).unwrap_or_default())
+	}
+}
+
+impl<R: Read + Seek> Read for DecryptReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		if self.pos >= self.len || buf.is_empty() {
+			return Ok(0);
+		}
+
+		let index = self.pos / self.header.chunk as u64;
+		let offset = (self.pos - index * self.header.chunk as u64) as usize;
+		let chunk = self.load(index)?;
+
+		let len = buf.len().min(chunk.len() - offset);
+		buf[..len].copy_from_slice(&chunk[offset..offset + len]);
+		self.pos += len as u64;
+
+		Ok(len)
+	}
+}
+
+impl<R: Read + Seek> Seek for DecryptReader<R> {
+	fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+		let pos = match pos {
+			SeekFrom::Start(pos) => Some(pos),
+			SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+			SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+		};
+
+		self.pos = pos.ok_or(Error::new(ErrorKind::InvalidInput, "Seek before start of file"))?;
+		Ok(self.pos)
+	}
+}
+
+/// A user's keyring together with the credentials to unlock it. Keys are only unwrapped once a request actually
+/// touches encrypted data, so plaintext files stay readable without a passphrase.
+pub struct Vault {
+	meta: PathBuf,
+	secrets: Secrets,
+	ring: Option<Keyring>,
+	keys: OnceCell<Keys>,
+}
+
+impl Vault {
+	pub fn new(meta: impl AsRef<Path>, secrets: Secrets) -> Result<Self> {
+		Ok(Self {
+			ring: Keyring::load(&meta)?,
+			meta: meta.as_ref().to_path_buf(),
+			secrets,
+			keys: OnceCell::new(),
+		})
+	}
+
+	pub fn secrets(&self) -> &Secrets {
+		&self.secrets
+	}
+
+	/// Whether new files are written encrypted.
+	pub fn enabled(&self) -> bool {
+		self.ring.is_some()
+	}
+
+	pub fn ring(&self) -> Result<&Keyring> {
+		self.ring.as_ref()
+			.ok_or(Error::new(ErrorKind::NotFound, "Encryption has not been set up for this user"))
+	}
+
+	pub fn keys(&self) -> Result<&Keys> {
+		if let Some(keys) = self.keys.get() {
+			return Ok(keys);
+		}
+
+		let keys = self.ring()?.unlock(&self.secrets)?;
+		Ok(self.keys.get_or_init(|| keys))
+	}
+
+	pub fn save(&mut self, ring: Keyring) -> Result<()> {
+		ring.save(&self.meta)?;
+		self.ring = Some(ring);
+		self.keys = OnceCell::new();
+		Ok(())
+	}
+
+	/// Opens a file for reading, decrypting it if it was written encrypted.
+	pub fn open(&self, path: impl AsRef<Path>) -> Result<Box<dyn ReadSeek>> {
+		let mut file = OpenOptions::new()
+			.read(true)
+			.open(path)?;
+
+		match Format::read(&mut file)? {
+			Format::Encrypted(header) => Ok(Box::new(DecryptReader::new(file, header, self.keys()?)?)),
+			Format::Escaped => Ok(Box::new(EscapedReader::new(file, HEADER_LEN)?)),
+			Format::Plain => {
+				file.seek(SeekFrom::Start(0))?;
+				Ok(Box::new(file))
+			}
+		}
+	}
+
+	/// Wraps `to` so that it encrypts under the active key if encryption is enabled.
+	pub fn writer(&self, to: Box<dyn Finish>) -> Result<Box<dyn Finish>> {
+		if self.enabled() {
+			Ok(Box::new(EncryptWriter::new(to, self.keys()?.active()?)?))
+		} else {
+			Ok(Box::new(EscapeWriter::new(to, &MAGIC, Header::plain().to_bytes().to_vec())))
+		}
+	}
+
+	/// Writes everything from `from` into `to`, encrypting it under the active key if encryption is enabled.
+	pub fn write(&self, from: impl Read, to: File) -> Result<()> {
+		let mut writer = self.writer(Box::new(to))?;
+		pipe(from, &mut writer)?;
+		writer.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	fn keys() -> Keys {
+		Keys { active: 1, keys: HashMap::from([(1, generate())]) }
+	}
+
+	fn encrypt(keys: &Keys, plain: &[u8]) -> Vec<u8> {
+		let mut writer = EncryptWriter::new(Vec::new(), keys.active().unwrap()).unwrap();
+		writer.write_all(plain).unwrap();
+		writer.close().unwrap()
+	}
+
+	fn decrypt(keys: &Keys, sealed: Vec<u8>) -> Result<Vec<u8>> {
+		let mut file = Cursor::new(sealed);
+		let Format::Encrypted(header) = Format::read(&mut file)? else {
+			panic!("Not encrypted");
+		};
+
+		let mut plain = Vec::new();
+		DecryptReader::new(file, header, keys)?.read_to_end(&mut plain)?;
+		Ok(plain)
+	}
+
+	fn data(len: usize) -> Vec<u8> {
+		(0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+	}
+
+	#[test]
+	fn round_trip() {
+		let keys = keys();
+
+		for len in [0, 1, CHUNK_SIZE as usize - 1, CHUNK_SIZE as usize, CHUNK_SIZE as usize + 1, CHUNK_SIZE as usize * 3] {
+			let plain = data(len);
+			assert_eq!(decrypt(&keys, encrypt(&keys, &plain)).unwrap(), plain, "length {}", len);
+		}
+	}
+
+	#[test]
+	fn truncation_fails() {
+		let keys = keys();
+		let stride = (CHUNK_SIZE as u64 + TAG_LEN) as usize;
+
+		for len in [0, CHUNK_SIZE as usize, CHUNK_SIZE as usize + 1, CHUNK_SIZE as usize * 2] {
+			let sealed = encrypt(&keys, &data(len));
+
+			let mut short = sealed.clone();
+			short.pop();
+			assert!(decrypt(&keys, short).is_err(), "truncated, length {}", len);
+
+			// Dropping the whole last chunk leaves a file which ends on a chunk not marked as the last.
+			let chunks = (sealed.len() - HEADER_LEN).div_ceil(stride);
+
+			if chunks > 1 {
+				let dropped = sealed[..HEADER_LEN + (chunks - 1) * stride].to_vec();
+				assert!(decrypt(&keys, dropped).is_err(), "dropped last chunk, length {}", len);
+			}
+		}
+	}
+
+	#[test]
+	fn tampering_fails() {
+		let keys = keys();
+		let mut sealed = encrypt(&keys, &data(100));
+		sealed[HEADER_LEN + 10] ^= 1;
+
+		assert_eq!(decrypt(&keys, sealed).unwrap_err().kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn seek_across_chunks() {
+		let keys = keys();
+		let plain = data(CHUNK_SIZE as usize * 2 + 10);
+		let mut file = Cursor::new(encrypt(&keys, &plain));
+		let Format::Encrypted(header) = Format::read(&mut file).unwrap() else {
+			panic!("Not encrypted");
+		};
+		let mut reader = DecryptReader::new(file, header, &keys).unwrap();
+
+		for start in [0, CHUNK_SIZE as usize - 5, CHUNK_SIZE as usize, CHUNK_SIZE as usize * 2 - 1, plain.len() - 3] {
+			let mut buf = vec![0u8; 20.min(plain.len() - start)];
+			reader.seek(SeekFrom::Start(start as u64)).unwrap();
+			reader.read_exact(&mut buf).unwrap();
+			assert_eq!(buf, plain[start..start + buf.len()], "offset {}", start);
+		}
+
+		assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), plain.len() as u64 - 1);
+	}
+
+	#[test]
+	fn magic_is_escaped() {
+		for plain in [MAGIC.to_vec(), [&MAGIC[..], b"more"].concat(), [&MAGIC[..], &Header::plain().to_bytes()].concat(), MAGIC[..3].to_vec()] {
+			let mut writer = EscapeWriter::new(Vec::new(), &MAGIC, Header::plain().to_bytes().to_vec());
+			writer.write_all(&plain).unwrap();
+			let mut file = Cursor::new(writer.close().unwrap());
+
+			let mut read = Vec::new();
+			match Format::read(&mut file).unwrap() {
+				Format::Escaped => EscapedReader::new(file, HEADER_LEN).unwrap().read_to_end(&mut read).unwrap(),
+				Format::Plain => {
+					assert!(!plain.starts_with(&MAGIC));
+					file.seek(SeekFrom::Start(0)).unwrap();
+					file.read_to_end(&mut read).unwrap()
+				},
+				Format::Encrypted(_) => panic!("Plaintext read as encrypted")
+			};
+
+			assert_eq!(read, plain);
+		}
+	}
+
+	#[test]
+	fn missing_active_key() {
+		let keys = Keys { active: 2, keys: HashMap::from([(1, generate())]) };
+		assert_eq!(keys.active().unwrap_err().kind(), ErrorKind::InvalidData);
+	}
+}
@@ -0,0 +1,586 @@
+use crate::{
+	format::Output,
+	store::Store,
+	tags,
+	temp,
+	transfer::Hashing,
+	ReadSeek
+};
+use base64::{
+	prelude::BASE64_STANDARD,
+	Engine
+};
+use serde::{
+	Deserialize,
+	Serialize
+};
+use sha2::{
+	Digest,
+	Sha256
+};
+use std::{
+	collections::HashMap,
+	fs,
+	io::Error,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	io::Seek,
+	io::SeekFrom,
+	path::Path
+};
+
+/// Literal data is sent in pieces of at most this many bytes.
+const CHUNK: usize = 64 * 1024;
+
+/// Bytes of each block's SHA-256 which are kept in signatures.
+const STRONG_LEN: usize = 16;
+
+/// Block sizes which can be asked for. Smaller blocks make signatures larger than the files they describe, and larger
+/// ones are read into memory whole.
+pub const MIN_BLOCK_SIZE: u64 = 512;
+pub const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Records describing the blocks of a file, as printed by `file::signature`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Signature {
+	/// The file the blocks which follow were taken from.
+	File {
+		size: u64,
+		block_size: usize
+	},
+
+	/// One block, in order. The last one may be shorter than the others.
+	Block {
+		weak: u32,
+		strong: String
+	},
+}
+
+/// Records describing how to rebuild a file from another, as printed by `file::delta`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Delta {
+	/// Bytes taken from the file the signature was made of.
+	Copy {
+		offset: u64,
+		length: u64
+	},
+
+	/// New bytes, base64 encoded.
+	Data {
+		data: String
+	},
+
+	/// The size and SHA-256 of the rebuilt file, so that it can be checked before it replaces the old one.
+	End {
+		size: u64,
+		sha256: String
+	},
+}
+
+/// The block size rsync would pick: about the square root of the file's size.
+pub fn block_size(size: u64) -> usize {
+	((size as f64).sqrt() as usize).next_multiple_of(8).clamp(700, 128 * 1024)
+}
+
+/// The rsync rolling checksum, which can be moved along a file a byte at a time.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+	a: u32,
+	b: u32,
+	len: u32,
+}
+
+impl Rolling {
+	fn new(block: &[u8]) -> Self {
+		let (a, b) = block.iter()
+			.enumerate()
+			.fold((0u32, 0u32), |(a, b), (i, &byte)| (a.wrapping_add(byte as u32), b.wrapping_add((block.len() - i) as u32 * byte as u32)));
+
+		Self { a: a & 0xffff, b: b & 0xffff, len: block.len() as u32 }
+	}
+
+	/// Moves the window along by one byte, dropping `out` from its start and adding `into` at its end.
+	fn roll(&mut self, out: u8, into: u8) {
+		self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32) & 0xffff;
+		self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a) & 0xffff;
+	}
+
+	fn digest(&self) -> u32 {
+		self.a | (self.b << 16)
+	}
+}
+
+fn strong(block: &[u8]) -> String {
+	Sha256::digest(block)[..STRONG_LEN]
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+/// Prints the signature of the file at `path`.
+pub fn signature(store: &Store, path: &Path, block_size: Option<usize>, output: &mut Output) -> Result<()> {
+	let mut file = store.open(path)?;
+	let size = file.seek(SeekFrom::End(0))?;
+	file.seek(SeekFrom::Start(0))?;
+
+	let block_size = block_size.unwrap_or(self::block_size(size)).max(1);
+	output.write(&Signature::File { size, block_size })?;
+
+	let mut block = Vec::with_capacity(block_size);
+
+	loop {
+		block.clear();
+
+		if file.by_ref().take(block_size as u64).read_to_end(&mut block)? == 0 {
+			return Ok(());
+		}
+
+		output.write(&Signature::Block { weak: Rolling::new(&block).digest(), strong: strong(&block) })?;
+	}
+}
+
+/// The blocks of a signature, looked up by their weak checksum.
+struct Blocks {
+	size: u64,
+	block_size: usize,
+	by_weak: HashMap<u32, Vec<(u64, String)>>,
+}
+
+impl Blocks {
+	fn read(mut signature: impl Iterator<Item = Result<Signature>>) -> Result<Self> {
+		let (size, block_size) = match signature.next().transpose()? {
+			Some(Signature::File { size, block_size }) if block_size > 0 => (size, block_size),
+			_ => return Err(Error::new(ErrorKind::InvalidData, "A signature starts with a `file` record"))
+		};
+
+		let mut by_weak = HashMap::<u32, Vec<(u64, String)>>::new();
+		let mut count = 0;
+
+		for record in signature {
+			match record? {
+				Signature::Block { weak, strong } => by_weak.entry(weak).or_default().push((count, strong)),
+				Signature::File { .. } => return Err(Error::new(ErrorKind::InvalidData, "A signature describes only one file"))
+			}
+
+			count += 1;
+		}
+
+		if count != size.div_ceil(block_size as u64) {
+			return Err(Error::new(ErrorKind::InvalidData, "The signature doesn't cover the whole file"));
+		}
+
+		Ok(Self { size, block_size, by_weak })
+	}
+
+	fn len(&self, index: u64) -> u64 {
+		(self.size - index * self.block_size as u64).min(self.block_size as u64)
+	}
+
+	/// The offset of a block with the same contents as `window`, if there is one.
+	fn find(&self, weak: u32, window: &[u8]) -> Option<u64> {
+		let candidates = self.by_weak.get(&weak)?;
+		let strong = strong(window);
+
+		candidates.iter()
+			.find(|(index, candidate)| self.len(*index) == window.len() as u64 && *candidate == strong)
+			.map(|(index, _)| index * self.block_size as u64)
+	}
+}
+
+/// Collects delta records, merging adjacent copies and batching literal bytes.
+struct Emitter<'a> {
+	output: &'a mut Output,
+	copy: Option<(u64, u64)>,
+	data: Vec<u8>,
+}
+
+impl Emitter<'_> {
+	fn copy(&mut self, offset: u64, length: u64) -> Result<()> {
+		self.flush_data()?;
+
+		match self.copy {
+			Some((start, ref mut len)) if start + *len == offset => *len += length,
+			_ => {
+				self.flush_copy()?;
+				self.copy = Some((offset, length));
+			}
+		}
+
+		Ok(())
+	}
+
+	fn data(&mut self, bytes: &[u8]) -> Result<()> {
+		self.flush_copy()?;
+		self.data.extend_from_slice(bytes);
+
+		if self.data.len() >= CHUNK {
+			self.flush_data()?;
+		}
+
+		Ok(())
+	}
+
+	fn flush_copy(&mut self) -> Result<()> {
+		match self.copy.take() {
+			Some((offset, length)) => self.output.write(&Delta::Copy { offset, length }),
+			None => Ok(())
+		}
+	}
+
+	fn flush_data(&mut self) -> Result<()> {
+		if self.data.is_empty() {
+			return Ok(());
+		}
+
+		let data = BASE64_STANDARD.encode(&self.data);
+		self.data.clear();
+		self.output.write(&Delta::Data { data })
+	}
+}
+
+/// Prints the delta which turns the file a signature was made of into the file at `path`.
+pub fn delta(store: &Store, path: &Path, signature: impl Iterator<Item = Result<Signature>>, output: &mut Output) -> Result<()> {
+	let blocks = Blocks::read(signature)?;
+	let block_size = blocks.block_size;
+	let tail = blocks.len(blocks.size.div_ceil(block_size as u64).saturating_sub(1)) as usize;
+
+	let mut file = Hashing::new(store.open(path)?);
+	let mut emitter = Emitter { output, copy: None, data: Vec::new() };
+	let mut buf = Vec::new();
+	let mut pos = 0;
+	let mut eof = false;
+	let mut rolling: Option<Rolling> = None;
+
+	loop {
+		// The window and the byte after it are kept in the buffer, so that the checksum can be rolled.
+		if buf.len() - pos <= block_size && !eof {
+			buf.drain(..pos);
+			pos = 0;
+
+			let want = (block_size + 1).max(CHUNK) as u64;
+			eof = file.by_ref().take(want).read_to_end(&mut buf)? < want as usize;
+			continue;
+		}
+
+		let window = &buf[pos..buf.len().min(pos + block_size)];
+
+		if window.is_empty() {
+			break;
+		}
+
+		// Only the last block can be shorter than the rest, so a short window at the end can only match that.
+		if window.len() < block_size && window.len() != tail {
+			emitter.data(window)?;
+			break;
+		}
+
+		let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+
+		if let Some(offset) = blocks.find(weak, window) {
+			emitter.copy(offset, window.len() as u64)?;
+			pos += window.len();
+			rolling = None;
+			continue;
+		}
+
+		if window.len() < block_size {
+			emitter.data(window)?;
+			break;
+		}
+
+		match (rolling.as_mut(), buf.get(pos + block_size)) {
+			(Some(rolling), Some(&into)) => rolling.roll(buf[pos], into),
+			_ => rolling = None
+		}
+
+		emitter.data(&buf[pos..pos + 1])?;
+		pos += 1;
+	}
+
+	emitter.flush_copy()?;
+	emitter.flush_data()?;
+
+	let (sha256, size) = file.finish();
+	emitter.output.write(&Delta::End { size, sha256 })
+}
+
+/// Produces the bytes a delta describes, hashing them as they are read.
+struct Rebuild<I> {
+	basis: Option<Box<dyn ReadSeek>>,
+	delta: I,
+	chunk: Vec<u8>,
+	pos: usize,
+
+	/// Bytes left to take from the basis for the current copy.
+	copying: u64,
+	hasher: Sha256,
+	len: u64,
+	end: Option<(u64, String)>,
+}
+
+impl<I: Iterator<Item = Result<Delta>>> Rebuild<I> {
+	/// Fails unless the whole delta was read and the bytes it produced match its `end` record.
+	fn verify(self) -> Result<()> {
+		let Some((size, sha256)) = self.end else {
+			return Err(Error::new(ErrorKind::UnexpectedEof, "The delta ended without an `end` record"));
+		};
+
+		let actual = self.hasher.finalize()
+			.iter()
+			.map(|byte| format!("{:02x}", byte))
+			.collect::<String>();
+
+		if size != self.len || sha256 != actual {
+			return Err(Error::new(ErrorKind::InvalidData, "The rebuilt file doesn't match the delta's checksum"));
+		}
+
+		Ok(())
+	}
+
+	/// Refills the chunk with the next bytes. Returns false once the delta has ended.
+	fn next(&mut self) -> Result<bool> {
+		self.chunk.clear();
+		self.pos = 0;
+
+		if self.copying > 0 {
+			let basis = self.basis.as_mut().ok_or_else(|| Error::new(ErrorKind::InvalidData, "The delta copies from a file which doesn't exist"))?;
+
+			if basis.by_ref().take(self.copying.min(CHUNK as u64)).read_to_end(&mut self.chunk)? == 0 {
+				return Err(Error::new(ErrorKind::InvalidData, "The delta copies beyond the end of the file"));
+			}
+
+			self.copying -= self.chunk.len() as u64;
+			return Ok(true);
+		}
+
+		if self.end.is_some() {
+			return Ok(false);
+		}
+
+		match self.delta.next().transpose()? {
+			Some(Delta::Copy { offset, length }) => {
+				if let Some(basis) = self.basis.as_mut() {
+					basis.seek(SeekFrom::Start(offset))?;
+				}
+
+				self.copying = length;
+			},
+			Some(Delta::Data { data }) => self.chunk = BASE64_STANDARD.decode(data).map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+			Some(Delta::End { size, sha256 }) => self.end = Some((size, sha256)),
+			None => return Ok(false)
+		}
+
+		Ok(true)
+	}
+}
+
+impl<I: Iterator<Item = Result<Delta>>> Read for Rebuild<I> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		while self.pos == self.chunk.len() {
+			if !self.next()? {
+				return Ok(0);
+			}
+		}
+
+		let len = buf.len().min(self.chunk.len() - self.pos);
+		buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+		self.pos += len;
+
+		self.hasher.update(&buf[..len]);
+		self.len += len as u64;
+
+		Ok(len)
+	}
+}
+
+/// Rebuilds the file at `path` from a delta against its current contents. The new file replaces the old one only once
+/// it is complete and matches the checksum the delta ends with. Missing files are created from deltas with no copies.
+pub fn patch(store: &Store, path: &Path, delta: impl Iterator<Item = Result<Delta>>) -> Result<()> {
+	let basis = match store.open(path) {
+		Ok(basis) => Some(basis),
+		Err(err) if err.kind() == ErrorKind::NotFound => None,
+		Err(err) => return Err(err)
+	};
+
+	// Files stay compressed or uncompressed as they were. New ones follow the policy.
+	let compress = match basis {
+		Some(_) => store.compressed(path)?,
+		None => store.policy.applies(store.relative(path))
+	};
+
+	let (tmp, file) = temp::file(path, "patch")?;
+
+	if let Ok(stat) = path.metadata() {
+		file.set_permissions(stat.permissions())?;
+	}
+
+	let mut rebuild = Rebuild { basis, delta, chunk: Vec::new(), pos: 0, copying: 0, hasher: Sha256::new(), len: 0, end: None };
+
+	match store.encode(&mut rebuild, file, compress).and_then(|_| rebuild.verify()).and_then(|_| tags::carry(path, &tmp)) {
+		Ok(()) => fs::rename(&tmp, path),
+		Err(err) => {
+			let _ = fs::remove_file(&tmp);
+			Err(err)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		crypt::Secrets,
+		crypt::Vault,
+		format,
+		format::Format,
+		meta
+	};
+	use std::{
+		cell::RefCell,
+		io::Cursor,
+		io::Write,
+		path::PathBuf,
+		rc::Rc
+	};
+
+	#[derive(Clone, Default)]
+	struct Sink(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for Sink {
+		fn write(&mut self, buf: &[u8]) -> Result<usize> {
+			self.0.borrow_mut().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> Result<()> {
+			Ok(())
+		}
+	}
+
+	/// A store in a directory of its own, removed again once the test is done with it.
+	struct Base(PathBuf, Store);
+
+	impl Base {
+		fn new(name: &str) -> Self {
+			let path = std::env::temp_dir().join(format!("agent-delta-{}-{}", name, std::process::id()));
+			let _ = fs::remove_dir_all(&path);
+			fs::create_dir_all(&path).unwrap();
+
+			let store = Store::new(&path, Vault::new(meta(&path), Secrets::default()).unwrap()).unwrap();
+			Self(path, store)
+		}
+	}
+
+	impl Drop for Base {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	fn data(len: usize, seed: u32) -> Vec<u8> {
+		let mut state = seed | 1;
+
+		(0..len).map(|_| {
+			state ^= state << 13;
+			state ^= state >> 17;
+			state ^= state << 5;
+			state as u8
+		}).collect()
+	}
+
+	/// Records printed by `print`, read back the way a client would.
+	fn records<T: serde::de::DeserializeOwned>(print: impl FnOnce(&mut Output) -> Result<()>) -> Vec<T> {
+		let sink = Sink::default();
+		print(&mut Output::to(Format::Json, Box::new(sink.clone())).unwrap()).unwrap();
+		format::records(Cursor::new(sink.0.take())).collect::<Result<_>>().unwrap()
+	}
+
+	/// Turns `old` into `new` the way a client would: with a signature of the old file and a delta of the new one.
+	fn sync(base: &Base, old: Option<&[u8]>, new: &[u8], block_size: Option<usize>) -> Vec<Delta> {
+		let (from, to) = (base.0.join("old"), base.0.join("new"));
+
+		match old {
+			Some(old) => fs::write(&from, old).unwrap(),
+			None => fs::write(&from, b"").unwrap()
+		}
+
+		fs::write(&to, new).unwrap();
+
+		let signature = records::<Signature>(|output| signature(&base.1, &from, block_size, output));
+		let delta = records::<Delta>(|output| delta(&base.1, &to, signature.into_iter().map(Ok), output));
+
+		if old.is_none() {
+			fs::remove_file(&from).unwrap();
+		}
+
+		patch(&base.1, &from, delta.clone().into_iter().map(Ok)).unwrap();
+		assert_eq!(fs::read(&from).unwrap(), new);
+
+		delta
+	}
+
+	#[test]
+	fn rolled_checksum_matches() {
+		let data = data(4096, 7);
+
+		for len in [1, 16, 700] {
+			let mut rolling = Rolling::new(&data[..len]);
+
+			for start in 1..=data.len() - len {
+				rolling.roll(data[start - 1], data[start + len - 1]);
+				assert_eq!(rolling.digest(), Rolling::new(&data[start..start + len]).digest(), "offset {}, length {}", start, len);
+			}
+		}
+	}
+
+	#[test]
+	fn delta_patch_identity() {
+		let base = Base::new("identity");
+		let old = data(10_000, 1);
+		let edit = data(300, 2);
+
+		let cases = [
+			old.clone(),
+			Vec::new(),
+			old[..5_000].to_vec(),
+			old[1..].to_vec(),
+			[&old[..], &edit[..]].concat(),
+			[&edit[..], &old[..]].concat(),
+			[&old[..3_000], &edit[..], &old[3_000..]].concat(),
+			[&old[..3_000], &old[4_000..]].concat(),
+			data(10_000, 3),
+		];
+
+		for block_size in [Some(1), Some(64), Some(1000), None] {
+			for new in &cases {
+				sync(&base, Some(&old), new, block_size);
+			}
+
+			sync(&base, Some(b""), &old, block_size);
+			sync(&base, None, &old, block_size);
+		}
+	}
+
+	#[test]
+	fn unchanged_file_is_copied() {
+		let base = Base::new("unchanged");
+		let old = data(10_000, 1);
+
+		let delta = sync(&base, Some(&old), &old, Some(64));
+		assert!(delta.iter().all(|record| !matches!(record, Delta::Data { .. })));
+	}
+
+	#[test]
+	fn wrong_checksum_fails() {
+		let base = Base::new("checksum");
+		let path = base.0.join("file");
+		fs::write(&path, b"old").unwrap();
+
+		let delta = [Delta::Data { data: BASE64_STANDARD.encode(b"new") }, Delta::End { size: 3, sha256: strong(b"new") }];
+		assert_eq!(patch(&base.1, &path, delta.into_iter().map(Ok)).unwrap_err().kind(), ErrorKind::InvalidData);
+		assert_eq!(fs::read(&path).unwrap(), b"old");
+	}
+}
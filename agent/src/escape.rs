@@ -0,0 +1,109 @@
+use crate::Finish;
+use std::{
+	io::Error,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	io::Seek,
+	io::SeekFrom,
+	io::Write
+};
+
+/// Writes data through as-is, unless it starts with `magic`, in which case `escape` goes in front of it. This keeps data
+/// which happens to look like one of the agent's own formats from being read back as such.
+pub struct EscapeWriter<W: Write> {
+	inner: W,
+	magic: &'static [u8],
+	escape: Vec<u8>,
+	head: Option<Vec<u8>>,
+}
+
+impl<W: Write> EscapeWriter<W> {
+	pub fn new(inner: W, magic: &'static [u8], escape: Vec<u8>) -> Self {
+		Self {
+			inner,
+			magic,
+			escape,
+			head: Some(Vec::with_capacity(magic.len())),
+		}
+	}
+
+	/// Writes out the start of the data once enough of it is known to tell whether it needs escaping.
+	fn start(&mut self) -> Result<()> {
+		let Some(head) = self.head.take() else {
+			return Ok(());
+		};
+
+		if head.starts_with(self.magic) {
+			self.inner.write_all(&self.escape)?;
+		}
+
+		self.inner.write_all(&head)
+	}
+
+	pub fn close(mut self) -> Result<W> {
+		self.start()?;
+		self.inner.flush()?;
+		Ok(self.inner)
+	}
+}
+
+impl<W: Finish + ?Sized> Finish for EscapeWriter<Box<W>> {
+	fn finish(self: Box<Self>) -> Result<()> {
+		self.close()?.finish()
+	}
+}
+
+impl<W: Write> Write for EscapeWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		match &mut self.head {
+			Some(head) => {
+				head.extend_from_slice(buf);
+
+				if head.len() >= self.magic.len() {
+					self.start()?;
+				}
+			},
+			None => self.inner.write_all(buf)?
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Presents what follows the escape in front of data written by [`EscapeWriter`], hiding it from reads and seeks.
+pub struct EscapedReader<R: Read + Seek> {
+	inner: R,
+	skip: u64,
+}
+
+impl<R: Read + Seek> EscapedReader<R> {
+	pub fn new(mut inner: R, skip: usize) -> Result<Self> {
+		inner.seek(SeekFrom::Start(skip as u64))?;
+		Ok(Self { inner, skip: skip as u64 })
+	}
+}
+
+impl<R: Read + Seek> Read for EscapedReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		self.inner.read(buf)
+	}
+}
+
+impl<R: Read + Seek> Seek for EscapedReader<R> {
+	fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+		let pos = match pos {
+			SeekFrom::Start(pos) => Some(pos),
+			SeekFrom::End(delta) => (self.inner.seek(SeekFrom::End(0))? - self.skip).checked_add_signed(delta),
+			SeekFrom::Current(delta) => (self.inner.stream_position()? - self.skip).checked_add_signed(delta),
+		};
+
+		let pos = pos.ok_or(Error::new(ErrorKind::InvalidInput, "Seek before start of file"))?;
+		self.inner.seek(SeekFrom::Start(self.skip + pos))?;
+		Ok(pos)
+	}
+}
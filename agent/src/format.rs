@@ -0,0 +1,153 @@
+use clap::ValueEnum;
+use serde::{
+	de::DeserializeOwned,
+	Deserialize,
+	Serialize
+};
+use std::{
+	io,
+	io::Error,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	io::Write
+};
+
+/// Version of the records the agent prints. Bumped whenever a record changes in a way older clients can't read.
+///
+/// 1. Timestamps as `{secs_since_epoch, nanos_since_epoch}`, no header.
+/// 2. Timestamps as RFC 3339 strings, preceded by a header.
+/// 3. Directories in listings as `{"Dir": {"path", "tags"}}`, and files with `tags`.
+pub const SCHEMA: u32 = 3;
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+	/// One JSON document per line
+	#[default]
+	Json,
+
+	/// One RON value per line
+	Ron,
+
+	/// A CBOR sequence, one data item per record
+	Cbor
+}
+
+/// The first record of any structured output, so that clients can tell whether they understand what follows.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Header {
+	pub schema: u32,
+
+	#[serde(default)]
+	pub format: Format,
+}
+
+/// Writes records to stdout in the selected format.
+pub struct Output {
+	format: Format,
+	stdout: Box<dyn Write>,
+}
+
+impl Output {
+	/// Starts the output by writing the header.
+	pub fn new(format: Format) -> Result<Self> {
+		Self::to(format, Box::new(io::stdout()))
+	}
+
+	/// The same, writing somewhere other than stdout.
+	pub fn to(format: Format, stdout: Box<dyn Write>) -> Result<Self> {
+		let mut output = Self { format, stdout };
+		output.write(&Header { schema: SCHEMA, format })?;
+
+		Ok(output)
+	}
+
+	pub fn write(&mut self, record: &impl Serialize) -> Result<()> {
+		match self.format {
+			Format::Json => writeln!(self.stdout, "{}", serde_json::to_string(record)?)?,
+			Format::Ron => writeln!(self.stdout, "{}", ron::to_string(record).map_err(|err| Error::new(ErrorKind::InvalidData, err))?)?,
+			Format::Cbor => ciborium::into_writer(record, &mut self.stdout).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?,
+		};
+
+		// Records are streamed, so each is sent on as soon as it is complete.
+		self.stdout.flush()
+	}
+}
+
+/// Reads records such as those written by [`Output`] in JSON, checking the header if there is one.
+pub fn records<T: DeserializeOwned>(reader: impl Read) -> impl Iterator<Item = Result<T>> {
+	let mut first = true;
+
+	serde_json::Deserializer::from_reader(io::BufReader::new(reader))
+		.into_iter::<serde_json::Value>()
+		.filter_map(move |record| {
+			let record = match record {
+				Ok(record) => record,
+				Err(err) => return Some(Err(err.into()))
+			};
+
+			if std::mem::take(&mut first) && record.get("schema").is_some() {
+				return match serde_json::from_value::<Header>(record) {
+					Ok(header) if header.schema == SCHEMA => None,
+					Ok(header) => Some(Err(Error::new(ErrorKind::InvalidData, format!("Records are in schema {}, but only schema {} is understood", header.schema, SCHEMA)))),
+					Err(err) => Some(Err(err.into()))
+				};
+			}
+
+			Some(serde_json::from_value(record).map_err(Error::from))
+		})
+}
+
+/// Serialises `SystemTime` as an RFC 3339 timestamp in UTC. Timestamps in the form of schema 1 are still accepted.
+pub mod timestamp {
+	use serde::{
+		Deserialize,
+		Deserializer,
+		Serializer,
+		de::Error
+	};
+	use std::time::SystemTime;
+
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Timestamp {
+		Rfc3339(String),
+		Legacy(SystemTime)
+	}
+
+	pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(&humantime::format_rfc3339_nanos(*time))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+		match Timestamp::deserialize(deserializer)? {
+			Timestamp::Rfc3339(time) => humantime::parse_rfc3339_weak(&time).map_err(D::Error::custom),
+			Timestamp::Legacy(time) => Ok(time)
+		}
+	}
+
+	/// The same, for optional fields.
+	pub mod option {
+		use serde::{
+			Deserialize,
+			Deserializer,
+			Serializer
+		};
+		use std::time::SystemTime;
+
+		pub fn serialize<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+			match time {
+				Some(time) => super::serialize(time, serializer),
+				None => serializer.serialize_none()
+			}
+		}
+
+		pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error> {
+			#[derive(Deserialize)]
+			struct Wrapped(#[serde(with = "super")] SystemTime);
+
+			Ok(Option::<Wrapped>::deserialize(deserializer)?.map(|Wrapped(time)| time))
+		}
+	}
+}
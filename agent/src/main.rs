@@ -1,25 +1,62 @@
+mod archive;
+mod batch;
+mod compress;
+mod crypt;
+mod delta;
+mod escape;
+mod format;
+mod manifest;
+mod mime;
+mod names;
+mod plan;
+mod snapshot;
+mod statfs;
+mod store;
+mod tags;
+mod temp;
+mod thumbnail;
+mod transfer;
+mod upload;
+
+use crate::{
+	crypt::Keyring,
+	crypt::Secrets,
+	crypt::Vault,
+	crypt::Wrapping,
+	format::Format,
+	format::Output,
+	snapshot::Snapshot,
+	store::Store
+};
 use clap::{
 	arg,
+	builder::RangedU64ValueParser,
 	Parser,
-	Subcommand
+	Subcommand,
+	ValueEnum
 };
 use serde::{
 	Deserialize,
 	Serialize
 };
 use std::{
+	collections::BTreeSet,
 	path::Path,
 	path::Component,
-	os::unix::fs::MetadataExt,
 	iter,
 	io::Write,
 	io::Result,
 	io::Read,
+	io::Seek,
+	io::SeekFrom,
 	io::ErrorKind,
 	io::Error,
 	io,
+	os::unix::fs::MetadataExt,
 	fs::OpenOptions,
 	fs::Metadata,
+	os::unix::fs::DirBuilderExt,
+	os::unix::fs::PermissionsExt,
 	fs,
 	path::PathBuf,
 	time::SystemTime
@@ -34,6 +71,26 @@ pub struct Args {
 	#[arg(long, default_value = "/")]
 	base: PathBuf,
 
+	/// Key used to unwrap the user's data keys. Read before privileges are dropped.
+	#[arg(long)]
+	master_key: Option<PathBuf>,
+
+	/// Replacement master key for `crypt::rewrap`.
+	#[arg(long)]
+	new_master_key: Option<PathBuf>,
+
+	/// Permit symbolic links with absolute targets. Targets must still lie within the base.
+	#[arg(long)]
+	allow_absolute_links: bool,
+
+	/// Print the filesystem changes the action would make, without making them
+	#[arg(long)]
+	dry_run: bool,
+
+	/// Format of the records printed by actions which describe files. File contents are always written as they are.
+	#[arg(long, value_enum, default_value_t)]
+	format: Format,
+
 	#[command(subcommand)]
 	action: Action,
 }
@@ -42,7 +99,13 @@ pub struct Args {
 pub enum Action {
 	#[clap(name = "file::read")]
 	FileRead {
-		path: PathBuf
+		path: PathBuf,
+
+		#[clap(long)]
+		offset: Option<u64>,
+
+		#[clap(long)]
+		length: Option<u64>
 	},
 
 	#[clap(name = "file::write")]
@@ -53,6 +116,16 @@ pub enum Action {
 
 	#[clap(name = "file::mkdir")]
 	Mkdir {
+		path: PathBuf,
+
+		/// Replace whatever is at `path` with an empty directory, which is only moved there once it has been made
+		#[clap(long)]
+		replace: bool
+	},
+
+	/// Writes `path` to stdout as a zip archive
+	#[clap(name = "file::zip")]
+	Zip {
 		path: PathBuf
 	},
 
@@ -69,54 +142,449 @@ pub enum Action {
 		path: PathBuf
 	},
 
+	/// Removes what agents which were killed part way through writing left staged beside `path`
+	#[clap(name = "file::sweep")]
+	Sweep {
+		path: PathBuf
+	},
+
+	/// Moves `path` to `to`. Moves between filesystems are journaled, and repeating the command resumes one which was
+	/// interrupted.
 	#[clap(name = "file::rename")]
 	Move {
 		path: PathBuf,
-		to: PathBuf
+		to: PathBuf,
+
+		/// Abandon an interrupted move between filesystems instead of resuming it
+		#[clap(long)]
+		rollback: bool,
+
+		/// Replace whatever is at `to`, which is left as it was should the move fail
+		#[clap(long)]
+		replace: bool
 	},
 
+	/// Copies `path` to `to`, journaled between filesystems like `file::rename`
 	#[clap(name = "file::copy")]
 	Copy {
 		path: PathBuf,
-		to: PathBuf
+		to: PathBuf,
+
+		/// Abandon an interrupted copy between filesystems instead of resuming it
+		#[clap(long)]
+		rollback: bool,
+
+		/// Replace whatever is at `to`, which is left as it was should the copy fail
+		#[clap(long)]
+		replace: bool
+	},
+
+	/// Prints the rsync block signature of a file, against which a delta can be computed
+	#[clap(name = "file::signature")]
+	Signature {
+		path: PathBuf,
+
+		/// Defaults to about the square root of the file's size. Between 512 bytes and 1 MiB
+		#[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(delta::MIN_BLOCK_SIZE..=delta::MAX_BLOCK_SIZE))]
+		block_size: Option<usize>
+	},
+
+	/// Reads the signature of another copy of a file from stdin, and prints the delta which turns it into this one
+	#[clap(name = "file::delta")]
+	Delta {
+		path: PathBuf
+	},
+
+	/// Reads a delta against a file from stdin, and replaces the file with the result once it is complete and checked
+	#[clap(name = "file::patch")]
+	Patch {
+		path: PathBuf
+	},
+
+	/// Prints a Merkle tree of the directories below `path`, or with `--since`, only those which changed since the tree
+	/// with that root hash
+	#[clap(name = "file::manifest")]
+	Manifest {
+		path: PathBuf,
+
+		#[clap(long)]
+		since: Option<String>
+	},
+
+	/// Adds tags to a file or directory, printing the tags it ends up with
+	#[clap(name = "file::tag::add")]
+	TagAdd {
+		path: PathBuf,
+
+		#[clap(required = true)]
+		tags: Vec<String>
+	},
+
+	/// Removes tags from a file or directory, printing the tags it ends up with
+	#[clap(name = "file::tag::remove")]
+	TagRemove {
+		path: PathBuf,
+
+		#[clap(required = true)]
+		tags: Vec<String>
+	},
+
+	/// Prints the tags of a file or directory
+	#[clap(name = "file::tag::list")]
+	TagList {
+		path: PathBuf
+	},
+
+	/// Lists the files and directories below `path` with all of the given tags, like `file::lsdir`
+	#[clap(name = "file::tag::find")]
+	TagFind {
+		path: PathBuf,
+		tags: Vec<String>,
+
+		/// Match entries with any of the tags, rather than all of them
+		#[clap(long)]
+		any: bool,
+
+		/// Leave out entries with this tag
+		#[clap(long)]
+		exclude: Vec<String>
+	},
+
+	/// Snapshots the user's whole tree. Files unchanged since the latest snapshot are shared with it.
+	#[clap(name = "file::snapshot::create")]
+	SnapshotCreate {
+		#[clap(long)]
+		label: Option<String>,
+
+		/// Delete the oldest snapshots beyond this many once the new one is complete
+		#[clap(long)]
+		keep: Option<usize>
 	},
 
+	/// Lists the user's snapshots, oldest first
+	#[clap(name = "file::snapshot::list")]
+	SnapshotList,
+
+	/// Lists a directory in a snapshot like `file::lsdir`, or prints a file like `file::read`
+	#[clap(name = "file::snapshot::browse")]
+	SnapshotBrowse {
+		id: String,
+		path: PathBuf,
+
+		#[clap(long = "depth")]
+		max_depth: Option<u32>,
+
+		#[clap(long)]
+		offset: Option<u64>,
+
+		#[clap(long)]
+		length: Option<u64>
+	},
+
+	/// Replaces `path` with its copy in a snapshot, or with `--to`, restores it elsewhere
+	#[clap(name = "file::snapshot::restore")]
+	SnapshotRestore {
+		id: String,
+		path: PathBuf,
+
+		#[clap(long)]
+		to: Option<PathBuf>
+	},
+
+	#[clap(name = "file::snapshot::delete")]
+	SnapshotDelete {
+		id: String
+	},
+
+	/// Lists moves and copies between filesystems which were interrupted
+	#[clap(name = "file::transfers")]
+	Transfers,
+
+	/// Reports the size, free space and type of the filesystem holding the user's files, and what their quota leaves
+	#[clap(name = "file::statfs")]
+	Statfs,
+
 	#[clap(name = "file::metadata")]
 	Meta {
-		path: PathBuf
+		path: PathBuf,
+
+		/// Describe what a symbolic link points to rather than the link itself
+		#[clap(long)]
+		follow: bool
+	},
+
+	/// Creates a symbolic link at `path`. Targets are relative to the link unless absolute links are permitted.
+	#[clap(name = "file::symlink")]
+	Symlink {
+		path: PathBuf,
+		target: PathBuf
 	},
 
+	/// Creates a hard link at `path` to the existing file `target`
+	#[clap(name = "file::hardlink")]
+	Hardlink {
+		path: PathBuf,
+		target: PathBuf
+	},
+
+	/// Sets a path's timestamps and permissions from an `Attributes` object read from stdin
 	#[clap(name = "file::write_metadata")]
 	WriteMeta {
 		path: PathBuf,
+	},
+
+	/// Runs a JSON array of operations read from stdin as a unit, undoing those already run if one fails
+	#[clap(name = "file::batch")]
+	Batch,
+
+	/// Writes a JPEG (or WebP, for images with transparency) thumbnail of an image to stdout
+	#[clap(name = "file::thumbnail")]
+	Thumbnail {
+		path: PathBuf,
+
+		#[clap(long, default_value = "256")]
+		size: thumbnail::Size
+	},
+
+	/// Enables encryption for the user. Keys are wrapped by the master key, or by `CLOUD_PASSPHRASE` if `--passphrase`
+	#[clap(name = "crypt::init")]
+	CryptInit {
+		#[clap(long)]
+		passphrase: bool
+	},
+
+	/// Generates a new data key for subsequent writes. Existing files remain readable under their old key.
+	#[clap(name = "crypt::rotate")]
+	CryptRotate,
+
+	/// Re-wraps the data keys under `--new-master-key`, or under `CLOUD_NEW_PASSPHRASE` if `--passphrase`
+	#[clap(name = "crypt::rewrap")]
+	CryptRewrap {
+		#[clap(long)]
+		passphrase: bool
+	},
+
+	/// Rewrites every file below `path` which isn't encrypted under the active key.
+	#[clap(name = "crypt::reencrypt")]
+	CryptReencrypt {
+		path: PathBuf,
+
+		/// Drop retired keys afterwards. Only permitted on the root directory.
+		#[clap(long)]
+		prune: bool
+	},
+
+	/// Sets whether files written below `path` are compressed
+	#[clap(name = "compress::policy")]
+	CompressPolicy {
+		path: PathBuf,
+		setting: Setting
+	},
+
+	/// Compresses (or with `--decompress`, decompresses) every file below `path` in place
+	#[clap(name = "compress::tree")]
+	CompressTree {
+		path: PathBuf,
+
+		#[clap(long)]
+		decompress: bool
+	},
+
+	/// Reports the rules a new name for `path` would break, and a name which wouldn't
+	#[clap(name = "names::check")]
+	NamesCheck {
+		path: PathBuf
+	},
+
+	/// Prints the user's filename policy, or with `--set`, replaces it with one read from stdin
+	#[clap(name = "names::policy")]
+	NamesPolicy {
+		#[clap(long)]
+		set: bool
+	},
+
+	/// Stores stdin as part `number` of the staged upload `id`, replacing any part stored under that number
+	#[clap(name = "upload::part")]
+	UploadPart {
+		id: String,
+		number: u32
+	},
+
+	/// Lists the parts of the staged upload `id` which have arrived in full
+	#[clap(name = "upload::list")]
+	UploadList {
+		id: String
+	},
+
+	/// Joins the given parts of the staged upload `id`, in order, into `path`, replacing it atomically
+	#[clap(name = "upload::commit")]
+	UploadCommit {
+		id: String,
+		path: PathBuf,
+		parts: Vec<u32>
+	},
+
+	/// Discards the staged upload `id`
+	#[clap(name = "upload::abort")]
+	UploadAbort {
+		id: String
 	}
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum Setting {
+	On,
+	Off,
+	Inherit
+}
+
 impl Action {
-	pub fn set_base(mut self, base: impl AsRef<Path>) -> Self {
+	pub fn set_base(mut self, base: impl AsRef<Path>) -> Result<Self> {
 		match self {
 			Action::FileRead { ref mut path, .. } |
 			Action::FileWrite { ref mut path, .. } |
 			Action::Mkdir { ref mut path, .. } |
 			Action::Lsdir { ref mut path, .. } |
+			Action::Zip { ref mut path } |
 			Action::Remove { ref mut path, .. } |
-			Action::Move { ref mut path, .. } |
-			Action::Copy { ref mut path, .. } |
+			Action::Sweep { ref mut path } |
 			Action::Meta { ref mut path, .. } |
-			Action::WriteMeta { ref mut path, .. } => {
-				*path = base.as_ref().join(path.flatten()
-					.components()
-					.map(|i| if i == Component::RootDir { Component::CurDir } else { i })
-					.collect::<PathBuf>())
-					.flatten()
+			Action::WriteMeta { ref mut path, .. } |
+			Action::Symlink { ref mut path, .. } |
+			Action::NamesCheck { ref mut path } |
+			Action::Signature { ref mut path, .. } |
+			Action::Delta { ref mut path } |
+			Action::Manifest { ref mut path, .. } |
+			Action::TagAdd { ref mut path, .. } |
+			Action::TagRemove { ref mut path, .. } |
+			Action::TagList { ref mut path } |
+			Action::TagFind { ref mut path, .. } |
+			Action::Patch { ref mut path } |
+			Action::Thumbnail { ref mut path, .. } |
+			Action::CryptReencrypt { ref mut path, .. } |
+			Action::CompressPolicy { ref mut path, .. } |
+			Action::CompressTree { ref mut path, .. } |
+			Action::UploadCommit { ref mut path, .. } => *path = resolve(&base, &path)?,
+
+			Action::Move { ref mut path, ref mut to, .. } |
+			Action::Copy { ref mut path, ref mut to, .. } |
+			Action::Hardlink { ref mut path, target: ref mut to } => {
+				*path = resolve(&base, &path)?;
+				*to = resolve(&base, &to)?;
+			},
+
+			Action::SnapshotRestore { ref mut path, ref mut to, .. } => {
+				*path = resolve(&base, &path)?;
+				*to = to.as_ref().map(|to| resolve(&base, to)).transpose()?;
 			},
+
+			// Resolved against the snapshot once it is found.
+			Action::SnapshotBrowse { .. } => (),
+
+			// Each operation is resolved as it is read.
+			Action::Batch |
+			Action::Transfers |
+			Action::Statfs |
+			Action::SnapshotCreate { .. } |
+			Action::SnapshotList |
+			Action::SnapshotDelete { .. } |
+			Action::NamesPolicy { .. } |
+			Action::CryptInit { .. } |
+			Action::CryptRotate |
+			Action::CryptRewrap { .. } |
+			Action::UploadPart { .. } |
+			Action::UploadList { .. } |
+			Action::UploadAbort { .. } => (),
 		}
 
-		self
+		Ok(self)
+	}
+
+	/// Actions which leave the user's files as they are. These run as usual under `--dry-run`.
+	pub fn is_read_only(&self) -> bool {
+		matches!(self, Action::FileRead { .. } | Action::Lsdir { .. } | Action::Zip { .. } | Action::Meta { .. } | Action::Thumbnail { .. } | Action::Transfers | Action::Statfs
+			| Action::NamesCheck { .. } | Action::NamesPolicy { set: false } | Action::SnapshotList | Action::SnapshotBrowse { .. }
+			| Action::Signature { .. } | Action::Delta { .. } | Action::Manifest { .. }
+			| Action::TagList { .. } | Action::TagFind { .. } | Action::UploadList { .. })
+	}
+
+	/// The paths the action touches, once resolved.
+	pub fn paths(&self) -> Vec<&Path> {
+		match self {
+			Action::FileRead { path, .. } |
+			Action::FileWrite { path, .. } |
+			Action::Mkdir { path, .. } |
+			Action::Lsdir { path, .. } |
+			Action::Zip { path } |
+			Action::Remove { path, .. } |
+			Action::Sweep { path } |
+			Action::Meta { path, .. } |
+			Action::WriteMeta { path, .. } |
+			Action::Symlink { path, .. } |
+			Action::NamesCheck { path } |
+			Action::Signature { path, .. } |
+			Action::Delta { path } |
+			Action::Manifest { path, .. } |
+			Action::TagAdd { path, .. } |
+			Action::TagRemove { path, .. } |
+			Action::TagList { path } |
+			Action::TagFind { path, .. } |
+			Action::Patch { path } |
+			Action::Thumbnail { path, .. } |
+			Action::CryptReencrypt { path, .. } |
+			Action::CompressPolicy { path, .. } |
+			Action::CompressTree { path, .. } |
+			Action::UploadCommit { path, .. } => vec![path],
+
+			Action::Move { path, to, .. } |
+			Action::Copy { path, to, .. } |
+			Action::Hardlink { path, target: to } => vec![path, to],
+
+			Action::SnapshotRestore { path, to, .. } => iter::once(path.as_path()).chain(to.as_deref()).collect(),
+
+			Action::Batch |
+			Action::Transfers |
+			Action::Statfs |
+			Action::SnapshotCreate { .. } |
+			Action::SnapshotList |
+			Action::SnapshotBrowse { .. } |
+			Action::SnapshotDelete { .. } |
+			Action::NamesPolicy { .. } |
+			Action::CryptInit { .. } |
+			Action::CryptRotate |
+			Action::CryptRewrap { .. } |
+			Action::UploadPart { .. } |
+			Action::UploadList { .. } |
+			Action::UploadAbort { .. } => vec![],
+		}
 	}
 
-	pub fn print(self) -> Self {
-		eprintln!("Invoked Agent: {:?}", &self);
+	/// Checks names the action would create against the user's filename policy. Existing names are left alone.
+	pub fn check_names(&self, names: &names::Policy) -> Result<()> {
+		let new = |path: &Path| path.symlink_metadata().is_err();
+
+		match self {
+			Action::FileWrite { path, create: Some(true) } |
+			Action::Patch { path } |
+			Action::UploadCommit { path, .. } if new(path) => names.check(path, None),
+			Action::Mkdir { path, .. } => names.check_new(path),
+			Action::Move { path, to, rollback: false, .. } if new(to) => names.check(to, Some(path)),
+			Action::Copy { to, rollback: false, .. } |
+			Action::Symlink { path: to, .. } |
+			Action::Hardlink { path: to, .. } |
+			Action::SnapshotRestore { to: Some(to), .. } if new(to) => names.check(to, None),
+			_ => Ok(())
+		}
+	}
+
+	/// Reports the resolved paths on stderr as a line of JSON, for the server's audit log.
+	pub fn print(self, base: impl AsRef<Path>) -> Self {
+		let paths = self.paths()
+			.into_iter()
+			.map(|path| Path::new("/").join(path.strip_prefix(&base).unwrap_or(path)))
+			.collect::<Vec<_>>();
+
+		eprintln!("{}", serde_json::json!({ "paths": paths }));
 		self
 	}
 }
@@ -144,6 +612,106 @@ impl<P: AsRef<Path>> Flatten for P {
 	}
 }
 
+/// Maps a path given by the user onto the filesystem, confining it to `base`.
+pub fn resolve(base: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<PathBuf> {
+	let path = base.as_ref().join(path.flatten()
+		.components()
+		.map(|i| if i == Component::RootDir { Component::CurDir } else { i })
+		.collect::<PathBuf>())
+		.flatten();
+
+	if path.starts_with(meta(&base)) {
+		return Err(Error::new(ErrorKind::PermissionDenied, format!("`{}` is reserved", META_DIR)));
+	}
+
+	confine(base.as_ref(), &path)?;
+	Ok(path)
+}
+
+/// Most symbolic links followed in resolving one path, as with the kernel's own limit.
+const MAX_LINKS: u32 = 40;
+
+/// The path `path` addresses once every symbolic link in it is followed, the way the kernel would. Components which
+/// don't exist yet are taken as they are, so that paths about to be created can be checked too.
+fn real(path: &Path, links: &mut u32) -> Result<PathBuf> {
+	let mut real = PathBuf::from("/");
+
+	for component in path.components() {
+		match component {
+			Component::Prefix(_) | Component::RootDir => real = PathBuf::from("/"),
+			Component::CurDir => (),
+			Component::ParentDir => {
+				real.pop();
+			},
+			Component::Normal(name) => {
+				let next = real.join(name);
+
+				match next.symlink_metadata() {
+					Ok(stat) if stat.is_symlink() => {
+						*links += 1;
+
+						if *links > MAX_LINKS {
+							return Err(Error::new(ErrorKind::InvalidInput, "Too many levels of symbolic links"));
+						}
+
+						real = self::real(&real.join(next.read_link()?), links)?;
+					},
+					_ => real = next
+				}
+			}
+		}
+	}
+
+	Ok(real)
+}
+
+/// Fails unless `path`, with every symbolic link in it followed, lies within `base` and outside its meta directory. A
+/// link can point anywhere once what it points through is swapped for another link, so only the real path tells.
+fn confine(base: &Path, path: &Path) -> Result<()> {
+	let base = real(base, &mut 0)?;
+	let path = real(path, &mut 0)?;
+
+	if !path.starts_with(&base) {
+		return Err(Error::new(ErrorKind::PermissionDenied, "The path leads outside of the user's storage"));
+	}
+
+	if path.starts_with(meta(&base)) {
+		return Err(Error::new(ErrorKind::PermissionDenied, format!("`{}` is reserved", META_DIR)));
+	}
+
+	Ok(())
+}
+
+/// Directory below each user's base holding agent-managed state. It is hidden from listings and can't be addressed.
+pub const META_DIR: &str = ".cloud";
+
+pub fn meta(base: impl AsRef<Path>) -> PathBuf {
+	base.as_ref().join(META_DIR)
+}
+
+/// Creates the directory returned by [`meta`], which is kept private to the user.
+pub fn create_meta(meta: impl AsRef<Path>) -> Result<()> {
+	fs::DirBuilder::new()
+		.recursive(true)
+		.mode(0o700)
+		.create(meta)
+}
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// A writer which needs to be told when no more data follows, such as to write a trailer.
+pub trait Finish: Write {
+	fn finish(self: Box<Self>) -> Result<()>;
+}
+
+impl Finish for fs::File {
+	fn finish(self: Box<Self>) -> Result<()> {
+		self.sync_all()
+	}
+}
+
 pub fn seteuid(uid: u32) -> Result<()> {
 	match unsafe { libc::seteuid(uid) } {
 		0 => Ok(()),
@@ -169,101 +737,425 @@ pub fn pipe(mut from: impl Read, mut to: impl Write) -> Result<()> {
 
 fn main() -> Result<()> {
 	let args = Args::parse();
+	let secrets = Secrets::load(args.master_key.as_deref(), args.new_master_key.as_deref())?;
 
 	seteuid(args.uid)?;
 
-	match args.action.set_base(&args.base).print() {
-		Action::FileRead { path } => pipe(
-		OpenOptions::new()
-				.read(true)
-				.open(path)?,
-			io::stdout()
-		)?,
+	let mut store = Store::new(&args.base, Vault::new(meta(&args.base), secrets)?)?;
+
+	let action = args.action.set_base(&args.base)?.print(&args.base);
 
-		Action::FileWrite { path, create } => pipe(
-			io::stdin(),
-			OpenOptions::new()
-				.write(true)
-				.create(create.unwrap_or(false))
-				.open(path)?
-		)?,
+	if !args.dry_run {
+		action.check_names(&store.names)?;
+	}
 
-		Action::Mkdir { path } => fs::create_dir_all(path)?,
+	if args.dry_run && !action.is_read_only() {
+		if !plan::dry_run(&store, action, args.allow_absolute_links, args.format)? {
+			exit(1);
+		}
 
-		Action::Lsdir { path, max_depth } => {
-			fn walk(path: impl AsRef<Path>, max_depth: u32) -> Result<Box<dyn Iterator<Item = DirEntry>>> {
-				if max_depth <= 0 {
-					return Ok(Box::new(iter::empty()));
-				}
+		return Ok(());
+	}
+
+	match action {
+		Action::FileRead { path, offset, length } => {
+			let mut file = store.open(path)?;
+			file.seek(SeekFrom::Start(offset.unwrap_or(0)))?;
+			pipe(file.take(length.unwrap_or(u64::MAX)), io::stdout())?
+		},
+
+		Action::FileWrite { path, create } => store.write(path, io::stdin(), create.unwrap_or(false))?,
+
+		Action::Mkdir { path, replace: false } => fs::create_dir_all(path)?,
+		Action::Mkdir { path, replace: true } => {
+			let staged = temp::name(&path, "replacing");
+			fs::create_dir(&staged)?;
 
-				let _ = path.as_ref().metadata()?;
-
-				Ok(Box::new(fs::read_dir(path)?
-					.filter_map(move |dir| dir.ok())
-					.filter_map(move |entry| Some(match entry.metadata().ok()? {
-						meta if meta.is_dir() => Box::new(iter::once(DirEntry::dir(entry.path()).ok()?)
-							.chain(walk(entry.path(), max_depth - 1).ok()?))
-							as Box<dyn Iterator<Item = DirEntry>>,
-						meta if meta.is_file() => Box::new(iter::once(DirEntry::file(entry.path(), meta).ok()?))
-							as Box<dyn Iterator<Item = DirEntry>>,
-						_ => return None
-					}))
-					.flatten()))
+			if let Err(err) = transfer::swap(&args.base, &staged, &path) {
+				let _ = fs::remove_dir(&staged);
+				Err(err)?;
 			}
+		},
 
+		Action::Lsdir { path, max_depth } => {
 			if path.exists() {
-				for dir in walk(path, max_depth.unwrap_or(u32::MAX))? {
-					println!("{}", serde_json::to_string(&dir.relative_to(&args.base)?)?);
+				let mut output = Output::new(args.format)?;
+
+				for dir in walk(&store, &tags::Reader::new(&args.base), path, max_depth.unwrap_or(u32::MAX))? {
+					output.write(&dir.relative_to(&args.base)?)?;
 				}
 			} else {
 				exit(libc::ENOENT);
 			}
 		},
 
-		Action::Remove { path } => rm(path)?,
-		Action::Copy { path, to } => copy(path, to)?,
+		Action::Zip { path } => archive::zip(&store, &path, io::BufWriter::with_capacity(compress::FRAME_SIZE, io::stdout().lock()))?,
 
-		Action::Move { path, to } => match fs::rename(&path, &to) {
-			Ok(()) => (),
-			Err(err) if err.kind() == ErrorKind::CrossesDevices => {
-				copy(&path, to)?;
-				rm(path)?;
-			},
-			Err(e) => Err(e)?
+		Action::Remove { path } => {
+			rm(&path)?;
+			tags::forget(&args.base, &path)?;
+		},
+		Action::Sweep { path } => temp::sweep(&path)?,
+		Action::Copy { path, to, rollback: false, replace: false } => transfer::duplicate(&args.base, &path, &to)?,
+		Action::Copy { path, to, rollback: false, replace: true } => transfer::replace(&args.base, transfer::Mode::Copy, &path, &to)?,
+		Action::Copy { path, to, rollback: true, .. } => transfer::rollback(&args.base, transfer::Mode::Copy, &path, &to)?,
+
+		Action::Move { path, to, rollback: false, replace: false } => transfer::relocate(&args.base, &path, &to)?,
+		Action::Move { path, to, rollback: false, replace: true } => transfer::replace(&args.base, transfer::Mode::Move, &path, &to)?,
+		Action::Move { path, to, rollback: true, .. } => transfer::rollback(&args.base, transfer::Mode::Move, &path, &to)?,
+
+		Action::NamesCheck { path } => Output::new(args.format)?.write(&store.names.report(&path, None)?)?,
+
+		Action::NamesPolicy { set: false } => Output::new(args.format)?.write(&store.names)?,
+		Action::NamesPolicy { set: true } => serde_json::from_reader::<_, names::Policy>(io::stdin())?.save(meta(&args.base))?,
+
+		Action::Signature { path, block_size } => delta::signature(&store, &path, block_size, &mut Output::new(args.format)?)?,
+		Action::Delta { path } => delta::delta(&store, &path, format::records(io::stdin()), &mut Output::new(args.format)?)?,
+		Action::Patch { path } => delta::patch(&store, &path, format::records(io::stdin()))?,
+
+		Action::Manifest { path, since } => manifest::manifest(&store, &path, since.as_deref(), &mut Output::new(args.format)?)?,
+
+		Action::TagAdd { path, tags: added } => {
+			let mut tags = tags::get(&args.base, &path)?;
+
+			for tag in added {
+				tags.insert(tags::validate(&tag)?);
+			}
+
+			tags::set(&args.base, &path, &tags)?;
+			Output::new(args.format)?.write(&tags)?;
+		},
+
+		Action::TagRemove { path, tags: removed } => {
+			let mut tags = tags::get(&args.base, &path)?;
+
+			for tag in removed {
+				tags.remove(tag.trim());
+			}
+
+			tags::set(&args.base, &path, &tags)?;
+			Output::new(args.format)?.write(&tags)?;
+		},
+
+		Action::TagList { path } => Output::new(args.format)?.write(&tags::get(&args.base, &path)?)?,
+
+		Action::TagFind { path, tags, any, exclude } => {
+			let tags = tags.iter().map(|tag| tags::validate(tag)).collect::<Result<Vec<_>>>()?;
+			let mut output = Output::new(args.format)?;
+
+			for entry in walk(&store, &tags::Reader::new(&args.base), path, u32::MAX)? {
+				let Some(has) = entry.tags() else {
+					continue;
+				};
+
+				let matches = match any {
+					true => tags.iter().any(|tag| has.contains(tag)),
+					false => tags.iter().all(|tag| has.contains(tag))
+				};
+
+				if matches && !has.is_empty() && !exclude.iter().any(|tag| has.contains(tag.trim())) {
+					output.write(&entry.relative_to(&args.base)?)?;
+				}
+			}
+		},
+
+		Action::SnapshotCreate { label, keep } => {
+			let snapshot = Snapshot::create(&args.base, label)?;
+
+			if let Some(keep) = keep {
+				let snapshots = Snapshot::all(&args.base)?;
+
+				for old in snapshots.iter().take(snapshots.len().saturating_sub(keep.max(1))) {
+					old.clone().delete(&args.base)?;
+				}
+			}
+
+			Output::new(args.format)?.write(&snapshot)?;
+		},
+
+		Action::SnapshotList => {
+			let mut output = Output::new(args.format)?;
+
+			for snapshot in Snapshot::all(&args.base)? {
+				output.write(&snapshot)?;
+			}
+		},
+
+		Action::SnapshotBrowse { id, path, max_depth, offset, length } => {
+			let tree = Snapshot::load(&args.base, &id)?.tree(&args.base);
+			let path = resolve(&tree, path)?;
+
+			if path.metadata()?.is_dir() {
+				let mut output = Output::new(args.format)?;
+
+				for dir in walk(&store, &tags::Reader::new(&args.base), path, max_depth.unwrap_or(u32::MAX))? {
+					output.write(&dir.relative_to(&tree)?)?;
+				}
+			} else {
+				let mut file = store.open(path)?;
+				file.seek(SeekFrom::Start(offset.unwrap_or(0)))?;
+				pipe(file.take(length.unwrap_or(u64::MAX)), io::stdout())?
+			}
+		},
+
+		Action::SnapshotRestore { id, path, to } => {
+			let to = to.unwrap_or_else(|| path.clone());
+			Snapshot::load(&args.base, &id)?.restore(&args.base, &path, &to)?
+		},
+
+		Action::SnapshotDelete { id } => Snapshot::load(&args.base, &id)?.delete(&args.base)?,
+
+		Action::Transfers => {
+			let mut output = Output::new(args.format)?;
+
+			for journal in transfer::Journal::all(&args.base)? {
+				output.write(&journal.progress(&store))?;
+			}
+		},
+
+		Action::Statfs => {
+			let statfs = statfs::statfs(&args.base, args.uid)?;
+			Output::new(args.format)?.write(&statfs)?;
+		},
+
+		Action::Meta { path, follow } => {
+			let entry = DirEntry::stat(&store, path, follow)?.relative_to(&args.base)?;
+			Output::new(args.format)?.write(&entry)?;
+		},
+
+		Action::Symlink { path, target } => std::os::unix::fs::symlink(link_target(&args.base, &path, target, args.allow_absolute_links)?, path)?,
+
+		Action::Hardlink { path, target } => match target.symlink_metadata()? {
+			stat if stat.is_file() => fs::hard_link(target, path)?,
+			_ => Err(Error::new(ErrorKind::InvalidInput, "Hard links can only point to files"))?
+		},
+
+		Action::WriteMeta { path } => {
+			let previous = serde_json::from_reader::<_, Attributes>(io::stdin())?.apply(path)?;
+			Output::new(args.format)?.write(&previous)?;
+		},
+
+		Action::Batch => {
+			let outcomes = batch::run(&args.base, &store.names, serde_json::from_reader(io::stdin())?)?;
+			let mut output = Output::new(args.format)?;
+
+			for outcome in outcomes.iter() {
+				output.write(outcome)?;
+			}
+
+			if !outcomes.iter().all(batch::Outcome::done) {
+				exit(1);
+			}
+		},
+
+		Action::Thumbnail { path, size } => pipe(thumbnail::thumbnail(&store, path, size)?, io::stdout())?,
+
+		Action::CryptInit { passphrase } => match Keyring::load(meta(&args.base))? {
+			Some(_) => Err(Error::new(ErrorKind::AlreadyExists, "Encryption is already enabled"))?,
+			None => {
+				let ring = Keyring::create(Wrapping::new(passphrase), store.vault.secrets())?;
+				store.vault.save(ring)?;
+			}
+		},
+
+		Action::CryptRotate => {
+			let mut ring = store.vault.ring()?.clone();
+			ring.rotate(store.vault.secrets())?;
+			store.vault.save(ring)?;
+		},
+
+		Action::CryptRewrap { passphrase } => {
+			let mut ring = store.vault.ring()?.clone();
+			ring.rewrap(Wrapping::new(passphrase), store.vault.secrets())?;
+			store.vault.save(ring)?;
+		},
+
+		Action::CryptReencrypt { path, prune } => {
+			if prune && path != args.base {
+				Err(Error::new(ErrorKind::InvalidInput, "Keys can only be pruned after re-encrypting the root directory"))?;
+			}
+
+			let (active, _) = store.vault.keys()?.active()?;
+			let mut output = Output::new(args.format)?;
+
+			visit(&store, &path, &mut |path, _| {
+				if crypt::Header::read(fs::File::open(path)?)?.is_some_and(|header| header.key == active) {
+					return Ok(());
+				}
+
+				// Only the encryption layer is replaced; compressed files stay compressed.
+				store.replace(path, |file| store.vault.write(store.vault.open(path)?, file))?;
+				output.write(&DirEntry::stat(&store, path, false)?.relative_to(&args.base)?)?;
+
+				Ok(())
+			})?;
+
+			if path == args.base {
+				upload::reencrypt(&store, active)?;
+			}
+
+			// Snapshots are only ever read, so rather than being re-encrypted, they have to be deleted before the keys
+			// they need are.
+			if prune {
+				Snapshot::with_retired(&args.base, active, |retired| match retired.as_slice() {
+					[] => {
+						let mut ring = store.vault.ring()?.clone();
+						ring.prune();
+						store.vault.save(ring)
+					},
+					retired => Err(Error::new(ErrorKind::InvalidInput,
+						format!("Snapshots {} still need retired keys; delete them before pruning", retired.join(", "))))
+				})?;
+			}
 		},
 
-		Action::Meta { .. } => todo!(),
-		Action::WriteMeta { .. } => todo!(),
+		Action::CompressPolicy { path, setting } => {
+			store.policy.set(store.relative(&path), match setting {
+				Setting::On => Some(true),
+				Setting::Off => Some(false),
+				Setting::Inherit => None
+			});
+			store.policy.save(meta(&args.base))?;
+		},
+
+		Action::CompressTree { path, decompress } => {
+			let mut output = Output::new(args.format)?;
+
+			visit(&store, &path, &mut |path, _| {
+				if store.compressed(path)? != decompress {
+					return Ok(());
+				}
+
+				if !decompress {
+					let mut head = Vec::new();
+					store.open(path)?.take(compress::FRAME_SIZE as u64).read_to_end(&mut head)?;
+
+					if !compress::compressible(path, &head) {
+						return Ok(());
+					}
+				}
+
+				store.replace(path, |file| store.encode(store.open(path)?, file, !decompress))?;
+				output.write(&DirEntry::stat(&store, path, false)?.relative_to(&args.base)?)?;
+
+				Ok(())
+			})?;
+		},
+
+		Action::UploadPart { id, number } => upload::part(&store, &id, number, io::stdin())?,
+
+		Action::UploadList { id } => {
+			let mut output = Output::new(args.format)?;
+
+			for part in upload::parts(&store, &id)? {
+				output.write(&part)?;
+			}
+		},
+
+		Action::UploadCommit { id, path, parts } => upload::commit(&store, &id, &path, &parts)?,
+		Action::UploadAbort { id } => upload::abort(&args.base, &id)?,
 	};
 
 	Ok(())
 }
 
-pub fn copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
-	if from.as_ref().is_dir() {
+/// Lists the tree below `path` as it is printed by `file::lsdir`, directories before their contents, looking up tags
+/// with `tags`.
+fn walk<'a>(store: &'a Store, tags: &'a tags::Reader, path: impl AsRef<Path>, max_depth: u32) -> Result<Box<dyn Iterator<Item = DirEntry> + 'a>> {
+	if max_depth == 0 {
+		return Ok(Box::new(iter::empty()));
+	}
+
+	let _ = path.as_ref().metadata()?;
+
+	Ok(Box::new(fs::read_dir(path)?
+		.filter_map(move |dir| dir.ok())
+		.filter(move |entry| entry.path() != meta(store.base()) && !temp::is_temp(&entry.file_name()))
+		.filter_map(move |entry| Some(match entry.metadata().ok()? {
+			meta if meta.is_dir() => Box::new(iter::once(DirEntry::dir(tags, entry.path()).ok()?)
+				.chain(walk(store, tags, entry.path(), max_depth - 1).ok()?))
+				as Box<dyn Iterator<Item = DirEntry>>,
+			meta if meta.is_file() => Box::new(iter::once(DirEntry::file(store, tags, entry.path(), meta).ok()?))
+				as Box<dyn Iterator<Item = DirEntry>>,
+			meta if meta.is_symlink() => Box::new(iter::once(DirEntry::link(entry.path()).ok()?))
+				as Box<dyn Iterator<Item = DirEntry>>,
+			_ => return None
+		}))
+		.flatten()))
+}
+
+/// Copies a file, directory or symbolic link, along with the tags of files and directories.
+pub fn copy(base: &Path, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+	if from.as_ref().is_symlink() {
+		std::os::unix::fs::symlink(from.as_ref().read_link()?, &to)?;
+		Ok(())
+	} else if from.as_ref().is_dir() {
+		fs::create_dir(&to)?;
+
 		for child in from.as_ref().read_dir()? {
 			let child = child?;
 			let to = to.as_ref().join(child.file_name());
-			copy(child.path(), to)?;
+			copy(base, child.path(), to)?;
 		}
 
-		Ok(())
-	} else if from.as_ref().is_symlink() {
-		std::os::unix::fs::symlink(from.as_ref().read_link()?, &to)?;
-		Ok(())
+		tags::copy(base, from.as_ref(), to.as_ref())
 	} else if from.as_ref().is_file() {
 		pipe(OpenOptions::new()
 			.read(true)
 			.open(from.as_ref())?,  OpenOptions::new()
 			.write(true)
 			.create(true)
+			.truncate(true)
 			.open(to.as_ref())?)?;
-		Ok(())
+		tags::copy(base, from.as_ref(), to.as_ref())
 	} else {
 		Err(Error::from(ErrorKind::InvalidInput))
 	}
 }
 
+/// Calls `f` for every regular file below `path`, skipping the agent's own state and what it is staging. Used by tools
+/// which rewrite files in place, where each file is replaced atomically so that an interrupted run can simply be restarted.
+pub fn visit(store: &Store, path: impl AsRef<Path>, f: &mut impl FnMut(&Path, &Metadata) -> Result<()>) -> Result<()> {
+	let path = path.as_ref();
+
+	if path == meta(store.base()) || path.file_name().is_some_and(temp::is_temp) {
+		return Ok(());
+	}
+
+	let stat = path.symlink_metadata()?;
+
+	if stat.is_dir() {
+		for child in path.read_dir()? {
+			visit(store, child?.path(), f)?;
+		}
+
+		Ok(())
+	} else if stat.is_file() {
+		f(path, &stat)
+	} else {
+		Ok(())
+	}
+}
+
+/// Checks that a symbolic link at `path` would point within the user's storage, returning the target to write.
+/// Relative targets are followed from the directory the link is placed in, through any links on the way.
+pub fn link_target(base: &Path, path: &Path, target: PathBuf, allow_absolute: bool) -> Result<PathBuf> {
+	if target.is_absolute() {
+		if !allow_absolute {
+			Err(Error::new(ErrorKind::PermissionDenied, "Only relative link targets are permitted"))?;
+		}
+
+		return resolve(base, &target);
+	}
+
+	confine(base, &path.parent().unwrap_or(base).join(&target)).map_err(|err| match err.kind() {
+		ErrorKind::PermissionDenied => Error::new(ErrorKind::PermissionDenied, "Link target lies outside of the user's storage"),
+		_ => err
+	})?;
+
+	Ok(target)
+}
+
 pub fn rm(from: impl AsRef<Path>) -> Result<()> {
 	match from.as_ref() {
 		path if path.is_dir() => fs::remove_dir_all(path),
@@ -271,39 +1163,141 @@ pub fn rm(from: impl AsRef<Path>) -> Result<()> {
 	}
 }
 
+/// The parts of a path's metadata which users may change. Omitted fields are left as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Attributes {
+	#[serde(default, skip_serializing_if = "Option::is_none", with = "format::timestamp::option")]
+	modified: Option<SystemTime>,
+
+	#[serde(default, skip_serializing_if = "Option::is_none", with = "format::timestamp::option")]
+	accessed: Option<SystemTime>,
+
+	/// Permission bits. Anything beyond `0o777` is ignored.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	mode: Option<u32>,
+}
+
+impl Attributes {
+	/// Applies the attributes to `path`, returning those which were replaced so that they can be restored.
+	pub fn apply(&self, path: impl AsRef<Path>) -> Result<Self> {
+		let stat = path.as_ref().metadata()?;
+		let previous = Self {
+			modified: self.modified.map(|_| stat.modified()).transpose()?,
+			accessed: self.accessed.map(|_| stat.accessed()).transpose()?,
+			mode: self.mode.map(|_| stat.mode() & 0o777),
+		};
+
+		if let Some(mode) = self.mode {
+			fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777))?;
+		}
+
+		let mut times = fs::FileTimes::new();
+
+		if let Some(modified) = self.modified {
+			times = times.set_modified(modified);
+		}
+
+		if let Some(accessed) = self.accessed {
+			times = times.set_accessed(accessed);
+		}
+
+		if self.modified.is_some() || self.accessed.is_some() {
+			fs::File::open(&path)?.set_times(times)?;
+		}
+
+		Ok(previous)
+	}
+}
+
 #[derive(Serialize, Deserialize)]
 enum DirEntry {
-	Dir(PathBuf),
+	Dir {
+		path: PathBuf,
+		#[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+		tags: BTreeSet<String>,
+	},
 	File {
 		path: PathBuf,
 		size: usize,
+		mime: String,
+		#[serde(with = "format::timestamp")]
 		modified: SystemTime,
+		#[serde(with = "format::timestamp")]
 		created: SystemTime,
+		thumbnail: bool,
+		links: u64,
+		#[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+		tags: BTreeSet<String>,
+	},
+	Link {
+		path: PathBuf,
+		target: PathBuf,
 	},
 }
 
 impl DirEntry {
-	pub fn file(dir: impl AsRef<Path>, metadata: Metadata) -> Result<Self> {
+	/// Files are reported by their logical size, which differs from their size on disk if compressed or encrypted.
+	pub fn file(store: &Store, tags: &tags::Reader, dir: impl AsRef<Path>, metadata: Metadata) -> Result<Self> {
+		let (size, head) = store.head(&dir, &metadata, mime::SNIFF_LEN);
+
 		Ok(Self::File {
 			path: dir.as_ref().to_path_buf(),
 
-			size: metadata.size() as usize,
+			size: size as usize,
+			mime: mime::detect(&dir, &head),
 			modified: metadata.modified()?,
 			created: metadata.created()?,
+			thumbnail: thumbnail::supported(&dir, &head),
+			links: metadata.nlink(),
+			tags: tags.get(dir.as_ref()).unwrap_or_default(),
+		})
+	}
+
+	pub fn dir(tags: &tags::Reader, dir: impl AsRef<Path>) -> Result<Self> {
+		Ok(Self::Dir {
+			path: dir.as_ref().to_path_buf(),
+			tags: tags.get(dir.as_ref()).unwrap_or_default(),
 		})
 	}
 
-	pub fn dir(dir: impl AsRef<Path>) -> Result<Self> {
-		Ok(Self::Dir(dir.as_ref().to_path_buf()))
+	fn tags(&self) -> Option<&BTreeSet<String>> {
+		match self {
+			Self::Dir { tags, .. } | Self::File { tags, .. } => Some(tags),
+			Self::Link { .. } => None
+		}
+	}
+
+	pub fn link(path: impl AsRef<Path>) -> Result<Self> {
+		Ok(Self::Link {
+			target: path.as_ref().read_link()?,
+			path: path.as_ref().to_path_buf(),
+		})
+	}
+
+	/// Describes whatever is at `path`, reporting files by their logical size. Symbolic links are described as such
+	/// unless `follow` is set.
+	pub fn stat(store: &Store, path: impl AsRef<Path>, follow: bool) -> Result<Self> {
+		let tags = tags::Reader::new(store.base());
+
+		match if follow { path.as_ref().metadata()? } else { path.as_ref().symlink_metadata()? } {
+			stat if stat.is_dir() => Self::dir(&tags, path),
+			stat if stat.is_symlink() => Self::link(path),
+			stat => Self::file(store, &tags, &path, stat)
+		}
 	}
 
 	pub fn relative_to(mut self, base: impl AsRef<Path>) -> Result<Self> {
 		match self {
-			Self::File { ref mut path, .. } | Self::Dir(ref mut path) => *path = PathBuf::from("/").join(path.strip_prefix(&base)
+			Self::File { ref mut path, .. } | Self::Dir { ref mut path, .. } | Self::Link { ref mut path, .. } => *path = PathBuf::from("/").join(path.strip_prefix(&base)
 				.map_err(|err| Error::new(ErrorKind::InvalidInput, err))
 				?.to_path_buf())
 		}
 
+		// Absolute targets are shown the way the user would address them.
+		if let Self::Link { ref mut target, .. } = self && let Ok(relative) = target.strip_prefix(&base) {
+			*target = PathBuf::from("/").join(relative);
+		}
+
 		Ok(self)
 	}
 }
\ No newline at end of file
@@ -0,0 +1,297 @@
+use crate::{
+	create_meta,
+	format,
+	format::Output,
+	meta,
+	store::Store,
+	temp,
+	transfer::Hashing
+};
+use serde::{
+	Deserialize,
+	Serialize
+};
+use sha2::{
+	Digest,
+	Sha256
+};
+use std::{
+	collections::BTreeMap,
+	collections::HashMap,
+	fs,
+	io::ErrorKind,
+	io::Result,
+	os::unix::ffi::OsStrExt,
+	os::unix::fs::MetadataExt,
+	path::Path,
+	path::PathBuf,
+	time::SystemTime
+};
+
+/// Content hashes of files, kept so that unchanged files needn't be read again.
+pub const HASHES: &str = "hashes.json";
+
+/// Directory below the meta directory holding recently computed trees, named after their root hash.
+pub const MANIFESTS: &str = "manifests";
+
+/// How many trees are kept to compare against.
+const KEEP: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+	File,
+	Dir,
+	Symlink
+}
+
+/// A child of a directory, as it goes into the directory's hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+	pub name: String,
+	pub kind: Kind,
+
+	/// The logical size of files, and the length of symbolic links' targets. Always 0 for directories.
+	pub size: u64,
+
+	#[serde(with = "format::timestamp")]
+	pub modified: SystemTime,
+
+	/// The SHA-256 of a file's contents or a link's target, or the hash of a directory.
+	pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Node {
+	pub hash: String,
+	pub entries: Vec<Entry>,
+}
+
+/// Every directory of a tree, by its path below the base.
+type Tree = BTreeMap<PathBuf, Node>;
+
+/// Records printed by `file::manifest`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record<'a> {
+	/// Starts the manifest. `full` is set when every directory follows, rather than only those which changed.
+	Root {
+		hash: &'a str,
+		full: bool
+	},
+
+	Dir {
+		path: &'a Path,
+		hash: &'a str,
+		entries: &'a [Entry]
+	},
+}
+
+/// A file's content hash, along with what identifies the version of the file it was computed for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Cached {
+	ino: u64,
+	stored: u64,
+	mtime: i64,
+	mtime_nsec: i64,
+	ctime: i64,
+	ctime_nsec: i64,
+	size: u64,
+	hash: String,
+}
+
+struct Builder<'a> {
+	store: &'a Store,
+	previous: HashMap<PathBuf, Cached>,
+	hashes: HashMap<PathBuf, Cached>,
+	tree: Tree,
+}
+
+impl Builder<'_> {
+	/// The logical size and content hash of a file, read again only if it changed since it was last hashed.
+	fn file(&mut self, path: &Path, stat: &fs::Metadata) -> Result<(u64, String)> {
+		let relative = self.store.relative(path);
+		let identity = |size, hash| Cached {
+			ino: stat.ino(),
+			stored: stat.size(),
+			mtime: stat.mtime(),
+			mtime_nsec: stat.mtime_nsec(),
+			ctime: stat.ctime(),
+			ctime_nsec: stat.ctime_nsec(),
+			size,
+			hash,
+		};
+
+		let cached = match self.previous.remove(&relative) {
+			Some(cached) if cached == identity(cached.size, cached.hash.clone()) => cached,
+			_ => {
+				let mut file = Hashing::new(self.store.open(path)?);
+				std::io::copy(&mut file, &mut std::io::sink())?;
+
+				let (hash, size) = file.finish();
+				identity(size, hash)
+			}
+		};
+
+		let result = (cached.size, cached.hash.clone());
+		self.hashes.insert(relative, cached);
+
+		Ok(result)
+	}
+
+	fn dir(&mut self, path: &Path) -> Result<String> {
+		let mut entries = Vec::new();
+
+		for child in path.read_dir()? {
+			let child = child?;
+			let path = child.path();
+
+			if path == meta(self.store.base()) || temp::is_temp(&child.file_name()) {
+				continue;
+			}
+
+			let stat = path.symlink_metadata()?;
+			let name = child.file_name().to_string_lossy().into_owned();
+
+			let (kind, size, hash) = if stat.is_dir() {
+				(Kind::Dir, 0, self.dir(&path)?)
+			} else if stat.is_symlink() {
+				let target = path.read_link()?;
+				(Kind::Symlink, target.as_os_str().len() as u64, hex(Sha256::digest(target.as_os_str().as_bytes())))
+			} else if stat.is_file() {
+				let (size, hash) = self.file(&path, &stat)?;
+				(Kind::File, size, hash)
+			} else {
+				continue;
+			};
+
+			entries.push(Entry { name, kind, size, modified: stat.modified()?, hash });
+		}
+
+		entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+		let hash = hash(&entries);
+		self.tree.insert(self.store.relative(path), Node { hash: hash.clone(), entries });
+
+		Ok(hash)
+	}
+}
+
+fn hex(bytes: impl AsRef<[u8]>) -> String {
+	bytes.as_ref()
+		.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+/// Hashes a directory's entries. Directories' own modification times are left out: they change whenever their
+/// contents do, which their hash already reflects.
+fn hash(entries: &[Entry]) -> String {
+	let mut hasher = Sha256::new();
+
+	for entry in entries {
+		let modified = match entry.kind {
+			Kind::Dir => 0,
+			_ => entry.modified.duration_since(SystemTime::UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or(0)
+		};
+
+		hasher.update(serde_json::to_vec(&entry.kind).unwrap_or_default());
+		hasher.update(entry.name.as_bytes());
+		hasher.update([0]);
+		hasher.update(entry.size.to_be_bytes());
+		hasher.update(modified.to_be_bytes());
+		hasher.update(entry.hash.as_bytes());
+	}
+
+	hex(hasher.finalize())
+}
+
+fn load<T: serde::de::DeserializeOwned + Default>(path: &Path) -> Result<T> {
+	match fs::read(path) {
+		Ok(contents) => Ok(serde_json::from_slice(&contents).unwrap_or_default()),
+		Err(err) if err.kind() == ErrorKind::NotFound => Ok(T::default()),
+		Err(err) => Err(err)
+	}
+}
+
+fn save(path: &Path, value: &impl Serialize) -> Result<()> {
+	let tmp = path.with_extension("tmp");
+	fs::write(&tmp, serde_json::to_vec(value)?)?;
+	fs::rename(tmp, path)
+}
+
+/// Computes the tree below `path` and prints the directories whose hashes differ from those in the tree with root
+/// hash `since`. Without `since`, or if that tree is no longer kept, every directory is printed.
+pub fn manifest(store: &Store, path: &Path, since: Option<&str>, output: &mut Output) -> Result<()> {
+	let meta = meta(store.base());
+	let manifests = meta.join(MANIFESTS);
+	create_meta(&manifests)?;
+
+	let mut builder = Builder {
+		store,
+		previous: load(&meta.join(HASHES))?,
+		hashes: HashMap::new(),
+		tree: Tree::new(),
+	};
+
+	let root = builder.dir(path)?;
+	let relative = store.relative(path);
+
+	// Files outside the tree which was walked keep their cached hashes. Those inside which are gone are forgotten.
+	let mut hashes = builder.previous;
+	hashes.retain(|file, _| !file.starts_with(&relative));
+	hashes.extend(builder.hashes);
+	save(&meta.join(HASHES), &hashes)?;
+
+	let previous: Option<Tree> = match since.filter(|since| since.len() == 64 && since.bytes().all(|c| c.is_ascii_hexdigit())) {
+		Some(since) => match fs::read(manifests.join(since).with_extension("json")) {
+			Ok(previous) => serde_json::from_slice(&previous).ok(),
+			Err(err) if err.kind() == ErrorKind::NotFound => None,
+			Err(err) => return Err(err)
+		},
+		None => None
+	};
+
+	save(&manifests.join(&root).with_extension("json"), &builder.tree)?;
+	prune(&manifests)?;
+
+	output.write(&Record::Root { hash: &root, full: previous.is_none() })?;
+
+	let mut pending = vec![relative];
+
+	while let Some(dir) = pending.pop() {
+		let Some(node) = builder.tree.get(&dir) else {
+			continue;
+		};
+
+		if previous.as_ref().and_then(|previous| previous.get(&dir)).is_some_and(|old| old.hash == node.hash) {
+			continue;
+		}
+
+		output.write(&Record::Dir { path: &dir, hash: &node.hash, entries: &node.entries })?;
+
+		pending.extend(node.entries
+			.iter()
+			.rev()
+			.filter(|entry| entry.kind == Kind::Dir)
+			.map(|entry| dir.join(&entry.name)));
+	}
+
+	Ok(())
+}
+
+/// Deletes all but the most recently computed trees.
+fn prune(manifests: &Path) -> Result<()> {
+	let mut trees = manifests.read_dir()?
+		.filter_map(|entry| entry.ok())
+		.filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+		.collect::<Vec<_>>();
+
+	trees.sort();
+
+	for (_, tree) in trees.iter().rev().skip(KEEP) {
+		fs::remove_file(tree)?;
+	}
+
+	Ok(())
+}
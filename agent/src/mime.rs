@@ -0,0 +1,36 @@
+use std::path::Path;
+
+/// Number of leading bytes inspected to determine a file's type.
+pub const SNIFF_LEN: usize = 8192;
+
+pub const DEFAULT: &str = "application/octet-stream";
+
+/// Container formats which many more specific formats are built on. A file's extension is more telling than these.
+const CONTAINERS: &[&str] = &["application/zip", "application/x-ole-storage"];
+
+/// Determines the MIME type of a file from its first bytes, falling back to its extension. Unrecognised data is
+/// reported as text if it is valid UTF-8.
+pub fn detect(path: impl AsRef<Path>, head: &[u8]) -> String {
+	let by_extension = mime_guess::from_path(path).first();
+
+	match infer::get(head) {
+		Some(kind) if !CONTAINERS.contains(&kind.mime_type()) || by_extension.is_none() => kind.mime_type().to_owned(),
+		_ => match by_extension {
+			Some(mime) => mime.essence_str().to_owned(),
+			None if is_text(head) => "text/plain".to_owned(),
+			None => DEFAULT.to_owned()
+		}
+	}
+}
+
+fn is_text(head: &[u8]) -> bool {
+	let text = match std::str::from_utf8(head) {
+		Ok(text) => text,
+
+		// The sniffed bytes may end part-way through a character, which doesn't make the data any less text.
+		Err(err) if err.error_len().is_none() => std::str::from_utf8(&head[..err.valid_up_to()]).unwrap_or_default(),
+		Err(_) => return false
+	};
+
+	!text.is_empty() && !text.contains('\0')
+}
@@ -0,0 +1,335 @@
+use crate::create_meta;
+use serde::{
+	Deserialize,
+	Serialize
+};
+use std::{
+	collections::HashMap,
+	ffi::OsStr,
+	fs,
+	io::Error,
+	io::ErrorKind,
+	io::Result,
+	path::Path
+};
+use unicode_normalization::{
+	is_nfc,
+	is_nfd,
+	UnicodeNormalization
+};
+
+pub const POLICY: &str = "filenames.json";
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED: &[&str] = &[
+	"CON", "PRN", "AUX", "NUL",
+	"COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+	"LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// How many names are tried before giving up on suggesting one.
+const MAX_ATTEMPTS: u32 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Form {
+	Nfc,
+	Nfd
+}
+
+/// Rules new names must follow, so that they survive being synced to other systems. Every rule is opt-in, so that by
+/// default any name the filesystem takes is accepted, as it always was.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Policy {
+	/// The Unicode normalisation form names must be in. `null` accepts any. Names differing only in normalisation
+	/// collide unless this is `null`.
+	pub normalization: Option<Form>,
+
+	/// Characters names may not contain, besides `/`.
+	pub forbidden: String,
+
+	/// Reject names containing control characters.
+	pub control: bool,
+
+	/// Reject names Windows reserves, and names ending in a dot or space.
+	pub reserved: bool,
+
+	/// Maximum length of a name in bytes.
+	pub max_length: usize,
+
+	/// Treat names differing only in case as the same name. Names differing only in normalisation always are.
+	pub case_insensitive: bool,
+}
+
+impl Default for Policy {
+	fn default() -> Self {
+		Self {
+			normalization: None,
+			forbidden: String::new(),
+			control: false,
+			reserved: false,
+			max_length: 255,
+			case_insensitive: false,
+		}
+	}
+}
+
+/// A way in which a name breaks the policy.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Violation {
+	Empty,
+	Normalization {
+		form: Form
+	},
+	Forbidden {
+		character: char
+	},
+	Reserved,
+	TrailingDotOrSpace,
+	TooLong {
+		max_length: usize
+	},
+	Collision {
+		with: String
+	},
+}
+
+impl std::fmt::Display for Violation {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Empty => write!(f, "names can't be empty"),
+			Self::Normalization { form: Form::Nfc } => write!(f, "names must be in Unicode NFC form"),
+			Self::Normalization { form: Form::Nfd } => write!(f, "names must be in Unicode NFD form"),
+			Self::Forbidden { character } => write!(f, "{:?} isn't allowed in names", character),
+			Self::Reserved => write!(f, "the name is reserved on Windows"),
+			Self::TrailingDotOrSpace => write!(f, "names can't end in a dot or space"),
+			Self::TooLong { max_length } => write!(f, "names can't be longer than {} bytes", max_length),
+			Self::Collision { with } => write!(f, "it can't be told apart from the existing {:?}", with)
+		}
+	}
+}
+
+/// The outcome of checking a name, as printed by `names::check`.
+#[derive(Serialize, Debug)]
+pub struct Report {
+	pub name: String,
+	pub violations: Vec<Violation>,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub suggestion: Option<String>,
+}
+
+impl Policy {
+	pub fn load(meta: impl AsRef<Path>) -> Result<Self> {
+		match fs::read(meta.as_ref().join(POLICY)) {
+			Ok(policy) => Ok(serde_json::from_slice(&policy)?),
+			Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+			Err(err) => Err(err)
+		}
+	}
+
+	pub fn save(&self, meta: impl AsRef<Path>) -> Result<()> {
+		create_meta(&meta)?;
+
+		let path = meta.as_ref().join(POLICY);
+		let tmp = path.with_extension("tmp");
+
+		fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+		fs::rename(tmp, path)
+	}
+
+	fn forbidden(&self, character: char) -> bool {
+		character == '/' || character == '\0' || (self.control && character.is_control()) || self.forbidden.contains(character)
+	}
+
+	fn reserved(name: &str) -> bool {
+		let stem = name.split('.').next().unwrap_or(name).trim_end();
+		RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem))
+	}
+
+	/// Names which would be confused with each other map to the same key.
+	fn key(&self, name: &str) -> String {
+		let name = match self.normalization {
+			Some(_) => name.nfc().collect::<String>(),
+			None => name.to_owned()
+		};
+
+		match self.case_insensitive {
+			true => name.to_lowercase(),
+			false => name
+		}
+	}
+
+	/// The rules `name` breaks, not counting collisions.
+	fn violations(&self, name: &str) -> Vec<Violation> {
+		let mut violations = Vec::new();
+
+		if name.is_empty() {
+			violations.push(Violation::Empty);
+		}
+
+		match self.normalization {
+			Some(Form::Nfc) if !is_nfc(name) => violations.push(Violation::Normalization { form: Form::Nfc }),
+			Some(Form::Nfd) if !is_nfd(name) => violations.push(Violation::Normalization { form: Form::Nfd }),
+			_ => ()
+		}
+
+		if let Some(character) = name.chars().find(|character| self.forbidden(*character)) {
+			violations.push(Violation::Forbidden { character });
+		}
+
+		if self.reserved && Self::reserved(name) {
+			violations.push(Violation::Reserved);
+		}
+
+		if self.reserved && name.ends_with(['.', ' ']) && name != "." && name != ".." {
+			violations.push(Violation::TrailingDotOrSpace);
+		}
+
+		if name.len() > self.max_length {
+			violations.push(Violation::TooLong { max_length: self.max_length });
+		}
+
+		violations
+	}
+
+	/// The entries in `dir` other than `except`, by their key.
+	fn existing(&self, dir: &Path, except: Option<&Path>) -> Result<HashMap<String, Vec<String>>> {
+		let mut existing = HashMap::<String, Vec<String>>::new();
+
+		if !dir.is_dir() {
+			return Ok(existing);
+		}
+
+		for entry in dir.read_dir()? {
+			let entry = entry?;
+
+			if except.is_some_and(|except| entry.path() == except) {
+				continue;
+			}
+
+			let name = entry.file_name().to_string_lossy().into_owned();
+			existing.entry(self.key(&name)).or_default().push(name);
+		}
+
+		Ok(existing)
+	}
+
+	/// An existing entry which `name` would be confused with.
+	fn collision(&self, existing: &HashMap<String, Vec<String>>, name: &str) -> Option<String> {
+		existing.get(&self.key(name))?
+			.iter()
+			.find(|existing| *existing != name)
+			.cloned()
+	}
+
+	/// Checks a name about to be given to `path`. `except` is the entry being renamed, which may differ from the new
+	/// name only in case or normalisation.
+	pub fn report(&self, path: &Path, except: Option<&Path>) -> Result<Report> {
+		let name = path.file_name().map(OsStr::to_string_lossy).unwrap_or_default().into_owned();
+		let dir = path.parent().unwrap_or(Path::new("/"));
+		let existing = self.existing(dir, except)?;
+		let mut violations = self.violations(&name);
+
+		if let Some(with) = self.collision(&existing, &name) {
+			violations.push(Violation::Collision { with });
+		}
+
+		let suggestion = match violations.is_empty() {
+			true => None,
+			false => Some(self.suggest(&existing, &name)?)
+		};
+
+		Ok(Report { name, violations, suggestion })
+	}
+
+	/// Fails with every rule the new name of `path` breaks, and a name which wouldn't.
+	pub fn check(&self, path: &Path, except: Option<&Path>) -> Result<()> {
+		let report = self.report(path, except)?;
+
+		if report.violations.is_empty() {
+			return Ok(());
+		}
+
+		let reasons = report.violations
+			.iter()
+			.map(Violation::to_string)
+			.collect::<Vec<_>>()
+			.join("; ");
+
+		Err(Error::new(ErrorKind::InvalidInput, format!("Invalid name {:?}: {}. Try {:?} instead.", report.name, reasons, report.suggestion.unwrap_or_default())))
+	}
+
+	/// Checks every directory `path` would create which doesn't exist yet.
+	pub fn check_new(&self, path: &Path) -> Result<()> {
+		for dir in path.ancestors().take_while(|dir| dir.symlink_metadata().is_err()) {
+			self.check(dir, None)?;
+		}
+
+		Ok(())
+	}
+
+	/// A name close to `name` which breaks none of the rules and is neither taken by nor confused with any of `existing`.
+	fn suggest(&self, existing: &HashMap<String, Vec<String>>, name: &str) -> Result<String> {
+		let name = match self.normalization {
+			Some(Form::Nfc) => name.nfc().collect::<String>(),
+			Some(Form::Nfd) => name.nfd().collect::<String>(),
+			None => name.to_owned()
+		};
+
+		let mut name = name.chars()
+			.map(|character| if self.forbidden(character) { '_' } else { character })
+			.collect::<String>();
+
+		if self.reserved {
+			name = name.trim_end_matches(['.', ' ']).to_owned();
+		}
+
+		if name.is_empty() {
+			name = "_".to_owned();
+		}
+
+		let (stem, ext) = match name.rfind('.') {
+			Some(dot) if dot > 0 => (name[..dot].to_owned(), name[dot..].to_owned()),
+			_ => (name.clone(), String::new())
+		};
+
+		let stem = match self.reserved && Self::reserved(&name) {
+			true => format!("{}_", stem),
+			false => stem
+		};
+
+		for attempt in 1..=MAX_ATTEMPTS {
+			let counter = match attempt {
+				1 => String::new(),
+				n => format!(" ({})", n)
+			};
+
+			// The counter is kept whole at the expense of the extension, or every attempt would be the same name.
+			let room = self.max_length.saturating_sub(counter.len());
+			let ext = truncate(&ext, room.saturating_sub(1));
+
+			let candidate = format!("{}{}{}", truncate(&stem, room.saturating_sub(ext.len()).max(1)), counter, ext);
+			let candidate = truncate(&candidate, self.max_length).to_owned();
+
+			if !existing.contains_key(&self.key(&candidate)) {
+				return Ok(candidate);
+			}
+		}
+
+		Err(Error::new(ErrorKind::AlreadyExists, format!("No name like {:?} is free after {} attempts", name, MAX_ATTEMPTS)))
+	}
+}
+
+/// The longest prefix of `name` no longer than `len` bytes which ends on a character boundary.
+pub fn truncate(name: &str, len: usize) -> &str {
+	let mut end = len.min(name.len());
+
+	while !name.is_char_boundary(end) {
+		end -= 1;
+	}
+
+	&name[..end]
+}
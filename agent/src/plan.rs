@@ -0,0 +1,370 @@
+use crate::{
+	batch,
+	batch::Operation,
+	format::Format,
+	format::Output,
+	link_target,
+	store::Store,
+	Action,
+	Attributes
+};
+use serde::Serialize;
+use std::{
+	ffi::CString,
+	fs::Metadata,
+	io,
+	io::Error,
+	io::ErrorKind,
+	io::Result,
+	os::unix::ffi::OsStrExt,
+	os::unix::fs::MetadataExt,
+	path::Path,
+	path::PathBuf
+};
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+	File,
+	Dir,
+	Symlink,
+	Hardlink
+}
+
+impl Kind {
+	fn of(stat: &Metadata) -> Self {
+		match stat {
+			stat if stat.is_symlink() => Self::Symlink,
+			stat if stat.is_dir() => Self::Dir,
+			_ => Self::File
+		}
+	}
+}
+
+/// A change an action would make to the filesystem. Paths are given as the user sees them.
+#[derive(Serialize, Debug)]
+#[serde(tag = "effect", rename_all = "snake_case")]
+pub enum Effect {
+	Create {
+		path: PathBuf,
+		kind: Kind
+	},
+
+	/// An existing file's contents would be replaced.
+	Overwrite {
+		path: PathBuf
+	},
+
+	Remove {
+		path: PathBuf,
+
+		#[serde(skip_serializing_if = "Option::is_none")]
+		kind: Option<Kind>
+	},
+
+	Move {
+		path: PathBuf,
+		to: PathBuf
+	},
+
+	Copy {
+		path: PathBuf,
+		to: PathBuf
+	},
+
+	SetMetadata {
+		path: PathBuf,
+
+		#[serde(flatten)]
+		attributes: Attributes
+	},
+
+	/// The action would fail here, so nothing after it would happen.
+	Conflict {
+		path: PathBuf,
+		reason: String
+	},
+}
+
+/// Works out what an action would do, without doing any of it.
+struct Planner<'a> {
+	store: &'a Store,
+	effects: Vec<Effect>,
+}
+
+impl Planner<'_> {
+	fn push(&mut self, effect: Effect) {
+		self.effects.push(effect);
+	}
+
+	fn conflict(&mut self, path: &Path, reason: impl Into<String>) {
+		let path = self.store.relative(path);
+		self.push(Effect::Conflict { path, reason: reason.into() });
+	}
+
+	/// Records a conflict unless entries can be added to and removed from `dir`.
+	fn writable(&mut self, dir: &Path) -> bool {
+		let writable = accessible(dir, libc::W_OK | libc::X_OK);
+
+		if !writable {
+			self.conflict(dir, "Permission denied");
+		}
+
+		writable
+	}
+
+	fn parent_writable(&mut self, path: &Path) -> bool {
+		self.writable(path.parent().unwrap_or(self.store.base()))
+	}
+
+	fn remove(&mut self, path: &Path) -> Result<()> {
+		let stat = path.symlink_metadata()?;
+
+		if !self.parent_writable(path) {
+			return Ok(());
+		}
+
+		if stat.is_dir() && self.writable(path) {
+			for child in path.read_dir()? {
+				self.remove(&child?.path())?;
+			}
+		}
+
+		let path = self.store.relative(path);
+		self.push(Effect::Remove { path, kind: Some(Kind::of(&stat)) });
+		Ok(())
+	}
+
+	/// Mirrors [`crate::copy`]. Nothing is checked inside directories the copy itself creates.
+	fn copy(&mut self, path: &Path, to: &Path, fresh: bool) -> Result<()> {
+		let stat = path.symlink_metadata()?;
+		let existing = if fresh { None } else { to.symlink_metadata().ok() };
+
+		if !fresh && existing.is_none() && !self.parent_writable(to) {
+			return Ok(());
+		}
+
+		match (Kind::of(&stat), existing) {
+			(Kind::File, Some(existing)) if existing.is_file() => if self.writable_file(path, to) {
+				let to = self.store.relative(to);
+				self.push(Effect::Overwrite { path: to });
+			},
+			(_, Some(_)) => self.conflict(to, "Destination already exists"),
+
+			(Kind::Dir, None) if accessible(path, libc::R_OK | libc::X_OK) => {
+				let relative = self.store.relative(to);
+				self.push(Effect::Create { path: relative, kind: Kind::Dir });
+
+				for child in path.read_dir()? {
+					let child = child?;
+					self.copy(&child.path(), &to.join(child.file_name()), true)?;
+				}
+			},
+			(Kind::File, None) if accessible(path, libc::R_OK) => {
+				let to = self.store.relative(to);
+				self.push(Effect::Create { path: to, kind: Kind::File });
+			},
+			(Kind::Symlink, None) => {
+				let to = self.store.relative(to);
+				self.push(Effect::Create { path: to, kind: Kind::Symlink });
+			},
+
+			_ => self.conflict(path, "Permission denied")
+		}
+
+		Ok(())
+	}
+
+	fn writable_file(&mut self, from: &Path, to: &Path) -> bool {
+		match (accessible(from, libc::R_OK), accessible(to, libc::W_OK)) {
+			(true, true) => true,
+			(false, _) => { self.conflict(from, "Permission denied"); false },
+			(_, false) => { self.conflict(to, "Permission denied"); false }
+		}
+	}
+
+	/// Mirrors [`crate::transfer::relocate`], which falls back to copying across filesystems, or with `replace`,
+	/// [`crate::transfer::replace`].
+	fn rename(&mut self, path: &Path, to: &Path, replace: bool) -> Result<()> {
+		let stat = path.symlink_metadata()?;
+
+		if !self.parent_writable(path) || !self.parent_writable(to) {
+			return Ok(());
+		}
+
+		match to.symlink_metadata() {
+			Ok(_) if replace => self.remove(to)?,
+			Ok(existing) if existing.is_dir() != stat.is_dir() => {
+				self.conflict(to, "Destination already exists");
+				return Ok(());
+			},
+			Ok(existing) if existing.is_dir() && to.read_dir()?.next().is_some() => {
+				self.conflict(to, "Destination is not empty");
+				return Ok(());
+			},
+			Ok(existing) => {
+				let relative = self.store.relative(to);
+				self.push(Effect::Remove { path: relative, kind: Some(Kind::of(&existing)) });
+			},
+			Err(_) => ()
+		}
+
+		let device = to.parent()
+			.and_then(|dir| dir.metadata().ok())
+			.map(|dir| dir.dev());
+
+		if device.is_some_and(|device| device != stat.dev()) {
+			self.copy(path, to, true)?;
+			self.remove(path)
+		} else {
+			let (path, to) = (self.store.relative(path), self.store.relative(to));
+			self.push(Effect::Move { path, to });
+			Ok(())
+		}
+	}
+
+	fn mkdir(&mut self, path: &Path) -> Result<()> {
+		let missing = path.ancestors()
+			.take_while(|dir| dir.symlink_metadata().is_err())
+			.collect::<Vec<_>>();
+
+		match missing.last() {
+			None if path.is_dir() => (),
+			None => self.conflict(path, "Not a directory"),
+			Some(outermost) => if self.parent_writable(outermost) {
+				for dir in missing.into_iter().rev() {
+					let path = self.store.relative(dir);
+					self.push(Effect::Create { path, kind: Kind::Dir });
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Creates an entry of the given kind, which must not already exist.
+	fn create(&mut self, path: &Path, kind: Kind) {
+		if path.symlink_metadata().is_ok() {
+			self.conflict(path, "Destination already exists");
+		} else if self.parent_writable(path) {
+			let path = self.store.relative(path);
+			self.push(Effect::Create { path, kind });
+		}
+	}
+
+	fn set_metadata(&mut self, path: &Path, attributes: Attributes) -> Result<()> {
+		// Only the owner may change a file's permissions or set its timestamps to arbitrary values.
+		let euid = unsafe { libc::geteuid() };
+
+		if euid != 0 && path.metadata()?.uid() != euid {
+			self.conflict(path, "Operation not permitted");
+		} else {
+			let path = self.store.relative(path);
+			self.push(Effect::SetMetadata { path, attributes });
+		}
+
+		Ok(())
+	}
+
+	/// Each operation of a batch which passed validation is described as a whole, as later operations may depend on
+	/// entries earlier ones create.
+	fn operation(&mut self, op: Operation) {
+		let relative = |path: PathBuf| self.store.relative(path);
+
+		let effect = match op {
+			Operation::Mkdir { path } => Effect::Create { path: relative(path), kind: Kind::Dir },
+			Operation::Move { path, to } => Effect::Move { path: relative(path), to: relative(to) },
+			Operation::Copy { path, to } => Effect::Copy { path: relative(path), to: relative(to) },
+			Operation::Rm { path } => Effect::Remove { kind: path.symlink_metadata().ok().as_ref().map(Kind::of), path: relative(path) },
+			Operation::WriteMetadata { path, attributes } => Effect::SetMetadata { path: relative(path), attributes }
+		};
+
+		self.push(effect);
+	}
+}
+
+/// Whether the agent's effective user may access `path` in the given `mode`.
+fn accessible(path: &Path, mode: libc::c_int) -> bool {
+	let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+		return false;
+	};
+
+	unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode, libc::AT_EACCESS) == 0 }
+}
+
+/// Prints the effects `action` would have as NDJSON, stopping short of changing anything. Returns whether the action
+/// would succeed. Batches which fail validation print the outcome of each operation instead.
+pub fn dry_run(store: &Store, action: Action, allow_absolute_links: bool, format: Format) -> Result<bool> {
+	let mut planner = Planner { store, effects: Vec::new() };
+
+	if let Err(err) = action.check_names(&store.names) {
+		let path = action.paths().last().map(|path| path.to_path_buf()).unwrap_or_default();
+		planner.conflict(&path, err.to_string());
+	}
+
+	match action {
+		Action::FileWrite { path, create } => match path.symlink_metadata() {
+			Ok(stat) if stat.is_file() => if accessible(&path, libc::W_OK) {
+				let path = store.relative(&path);
+				planner.push(Effect::Overwrite { path });
+			} else {
+				planner.conflict(&path, "Permission denied");
+			},
+			Ok(_) => planner.conflict(&path, "Not a file"),
+			Err(_) if create.unwrap_or(false) => planner.create(&path, Kind::File),
+			Err(err) => return Err(err)
+		},
+
+		Action::Mkdir { path, replace } => match path.symlink_metadata() {
+			Ok(_) if replace => {
+				planner.remove(&path)?;
+				planner.push(Effect::Create { path: store.relative(&path), kind: Kind::Dir });
+			},
+			_ => planner.mkdir(&path)?
+		},
+		Action::Remove { path } => planner.remove(&path)?,
+		Action::Move { path, to, rollback: false, replace } => planner.rename(&path, &to, replace)?,
+		Action::Copy { path, to, rollback: false, replace } => match to.symlink_metadata() {
+			Ok(_) if replace => {
+				planner.remove(&to)?;
+				planner.copy(&path, &to, true)?
+			},
+			_ => planner.copy(&path, &to, false)?
+		},
+
+		Action::Symlink { path, target } => {
+			link_target(store.base(), &path, target, allow_absolute_links)?;
+			planner.create(&path, Kind::Symlink);
+		},
+
+		Action::Hardlink { path, target } => match target.symlink_metadata()? {
+			stat if stat.is_file() => planner.create(&path, Kind::Hardlink),
+			_ => Err(Error::new(ErrorKind::InvalidInput, "Hard links can only point to files"))?
+		},
+
+		Action::WriteMeta { path } => planner.set_metadata(&path, serde_json::from_reader(io::stdin())?)?,
+
+		Action::Batch => match batch::check(store.base(), &store.names, serde_json::from_reader(io::stdin())?) {
+			Ok(ops) => ops.into_iter().for_each(|op| planner.operation(op)),
+			Err(outcomes) => {
+				let mut output = Output::new(format)?;
+
+				for outcome in outcomes {
+					output.write(&outcome)?;
+				}
+
+				return Ok(false);
+			}
+		},
+
+		_ => Err(Error::new(ErrorKind::Unsupported, "This action can't be previewed"))?
+	}
+
+	let mut output = Output::new(format)?;
+
+	for effect in planner.effects.iter() {
+		output.write(effect)?;
+	}
+
+	Ok(!planner.effects.iter().any(|effect| matches!(effect, Effect::Conflict { .. })))
+}
@@ -0,0 +1,461 @@
+use crate::{
+	create_meta,
+	crypt,
+	format,
+	meta,
+	tags,
+	temp
+};
+use serde::{
+	Deserialize,
+	Serialize
+};
+use std::{
+	collections::BTreeMap,
+	collections::HashMap,
+	fs,
+	fs::File,
+	fs::Metadata,
+	fs::OpenOptions,
+	io::Error,
+	io::ErrorKind,
+	io::Result,
+	os::unix::fs::MetadataExt,
+	os::unix::fs::PermissionsExt,
+	path::Component,
+	path::Path,
+	path::PathBuf,
+	time::SystemTime
+};
+
+/// Directory below the meta directory holding one directory per snapshot.
+pub const SNAPSHOTS: &str = "snapshots";
+
+/// Permission bits taken away from everything in a snapshot's tree.
+const WRITE: u32 = 0o222;
+
+/// Describes a snapshot. Saved next to its tree as `snapshot.json`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+	pub id: String,
+
+	#[serde(with = "format::timestamp")]
+	pub created: SystemTime,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub label: Option<String>,
+
+	/// Files in the snapshot, and their total size on disk.
+	pub files: u64,
+	pub bytes: u64,
+
+	/// Files unchanged since the previous snapshot, which share their contents with it rather than taking up space.
+	pub shared: u64,
+}
+
+/// The modes of the entries in a snapshot's tree, which is kept read-only. Saved next to the tree as `modes.json`.
+/// Entries which were writable by their owner alone aren't listed, as their mode can be told from the tree.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Modes(BTreeMap<PathBuf, u32>);
+
+impl Modes {
+	/// The modes of the snapshot in `dir`, or `None` for snapshots taken before their trees were read-only.
+	fn load(dir: &Path) -> Result<Option<Self>> {
+		match fs::read(dir.join("modes.json")) {
+			Ok(modes) => Ok(Some(serde_json::from_slice(&modes)?)),
+			Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+			Err(err) => Err(err)
+		}
+	}
+
+	/// Records the mode of `path`, relative to the tree, and returns the mode to give it there.
+	fn take(&mut self, path: &Path, mode: u32) -> u32 {
+		if mode & WRITE != 0o200 {
+			self.0.insert(path.to_path_buf(), mode);
+		}
+
+		mode & !WRITE
+	}
+
+	/// The mode `path`, relative to the tree, had before its snapshot was taken.
+	fn original(&self, path: &Path, mode: u32) -> u32 {
+		self.0.get(path).copied().unwrap_or(mode | 0o200)
+	}
+}
+
+/// Which way [`capture`] copies, which decides the modes it gives what it copies.
+enum Direction<'a> {
+	/// Into the snapshot with the tree `tree`, recording modes in `modes`. Files are linked to the previous snapshot
+	/// only if it kept `previous` modes too, as linked files share them.
+	Take {
+		tree: &'a Path,
+		modes: Modes,
+		previous: Option<Modes>,
+	},
+
+	/// Out of the snapshot with the tree `tree`, giving back the modes it recorded. Files from snapshots which recorded
+	/// none keep the modes they have.
+	Restore {
+		tree: &'a Path,
+		modes: Option<Modes>,
+	},
+}
+
+impl Direction<'_> {
+	/// The mode to give a copy of `from`, which has `mode`, at `to`.
+	fn mode(&mut self, from: &Path, to: &Path, mode: u32) -> u32 {
+		let mode = mode & 0o7777;
+
+		match self {
+			Self::Take { tree, modes, .. } => modes.take(to.strip_prefix(tree).unwrap_or(to), mode),
+			Self::Restore { tree, modes: Some(modes) } => modes.original(from.strip_prefix(tree).unwrap_or(from), mode),
+			Self::Restore { modes: None, .. } => mode
+		}
+	}
+
+	/// The mode the previous snapshot's copy of what goes to `to` had before it was taken, given its `mode` there.
+	/// `None` if it can't be linked to.
+	fn previous(&self, to: &Path, mode: u32) -> Option<u32> {
+		match self {
+			Self::Take { tree, previous: Some(modes), .. } => Some(modes.original(to.strip_prefix(tree).ok()?, mode & 0o7777)),
+			_ => None
+		}
+	}
+}
+
+/// Counts what went into a snapshot as it is built.
+#[derive(Default)]
+struct Tally {
+	files: u64,
+	bytes: u64,
+	shared: u64,
+}
+
+fn root(base: &Path) -> PathBuf {
+	meta(base).join(SNAPSHOTS)
+}
+
+/// The directory of the snapshot `id`. IDs are single path components, so they can't point outside the snapshots.
+fn dir(base: &Path, id: &str) -> Result<PathBuf> {
+	match Path::new(id).components().collect::<Vec<_>>().as_slice() {
+		[Component::Normal(name)] if !name.to_string_lossy().ends_with(".partial") => Ok(root(base).join(id)),
+		_ => Err(Error::new(ErrorKind::InvalidInput, format!("`{}` isn't a snapshot", id)))
+	}
+}
+
+/// Locks the snapshots, exclusively to create or delete one, or shared to restore from one, so that snapshots being
+/// built aren't mistaken for ones which were interrupted. The lock is released when the returned file is dropped.
+fn lock(base: &Path, exclusive: bool) -> Result<File> {
+	create_meta(root(base))?;
+
+	let file = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(false)
+		.open(root(base).join(".lock"))?;
+
+	match exclusive {
+		true => file.lock()?,
+		false => file.lock_shared()?
+	}
+
+	Ok(file)
+}
+
+/// Gives directories their owner write permission back, so that their contents can be removed.
+fn unlock(path: &Path) -> Result<()> {
+	let stat = path.symlink_metadata()?;
+
+	if stat.is_dir() {
+		fs::set_permissions(path, fs::Permissions::from_mode(stat.mode() | 0o700))?;
+
+		for child in path.read_dir()? {
+			unlock(&child?.path())?;
+		}
+	}
+
+	Ok(())
+}
+
+fn remove(path: &Path) -> Result<()> {
+	unlock(path)?;
+	fs::remove_dir_all(path)
+}
+
+/// Whether the file in the previous snapshot can stand in for `from`, which is going to `to`. Like `rsync --link-dest`,
+/// files are compared by size and modification time. Permissions and tags have to match too, since linked files share
+/// them.
+fn unchanged(base: &Path, from: &Path, stat: &Metadata, to: &Path, previous: &Path, direction: &Direction) -> bool {
+	previous.symlink_metadata().is_ok_and(|previous| previous.is_file()
+		&& previous.size() == stat.size()
+		&& previous.mtime() == stat.mtime()
+		&& previous.mtime_nsec() == stat.mtime_nsec()
+		&& direction.previous(to, previous.mode()) == Some(stat.mode() & 0o7777))
+		&& tags::get(base, from).ok() == tags::get(base, previous).ok()
+}
+
+/// Copies `from` to `to`, keeping modification times, and permissions as the `direction` has it. Files which haven't
+/// changed since the previous snapshot are linked to it instead.
+fn capture(base: &Path, from: &Path, to: &Path, previous: Option<&Path>, direction: &mut Direction, tally: &mut Tally) -> Result<()> {
+	let stat = from.symlink_metadata()?;
+
+	if stat.is_dir() {
+		// Filled in before its permissions are applied, in case it is read-only.
+		fs::create_dir(to)?;
+
+		for child in from.read_dir()? {
+			let child = child?;
+
+			if temp::is_temp(&child.file_name()) {
+				continue;
+			}
+
+			capture(base, &child.path(), &to.join(child.file_name()), previous.map(|previous| previous.join(child.file_name())).as_deref(), direction, tally)?;
+		}
+
+		tags::copy(base, from, to)?;
+		fs::set_permissions(to, fs::Permissions::from_mode(direction.mode(from, to, stat.mode())))?;
+		File::open(to)?.set_modified(stat.modified()?)?;
+	} else if stat.is_symlink() {
+		std::os::unix::fs::symlink(from.read_link()?, to)?;
+	} else if stat.is_file() {
+		tally.files += 1;
+		tally.bytes += stat.size();
+
+		if let Some(previous) = previous.filter(|previous| unchanged(base, from, &stat, to, previous, direction)) {
+			// Files can only have so many links. Past that, they are copied instead.
+			if fs::hard_link(previous, to).is_ok() {
+				direction.mode(from, to, stat.mode());
+				tally.shared += 1;
+				return Ok(());
+			}
+		}
+
+		fs::copy(from, to)?;
+		tags::copy(base, from, to)?;
+		File::open(to)?.set_modified(stat.modified()?)?;
+		fs::set_permissions(to, fs::Permissions::from_mode(direction.mode(from, to, stat.mode())))?;
+	}
+
+	Ok(())
+}
+
+impl Snapshot {
+	/// The complete snapshots, oldest first.
+	pub fn all(base: &Path) -> Result<Vec<Self>> {
+		let mut snapshots = Vec::new();
+
+		let entries = match root(base).read_dir() {
+			Ok(entries) => entries,
+			Err(err) if err.kind() == ErrorKind::NotFound => return Ok(snapshots),
+			Err(err) => return Err(err)
+		};
+
+		for entry in entries {
+			let entry = entry?;
+
+			if entry.file_name().to_string_lossy().ends_with(".partial") {
+				continue;
+			}
+
+			if let Ok(snapshot) = fs::read(entry.path().join("snapshot.json")) {
+				snapshots.push(serde_json::from_slice::<Self>(&snapshot)?);
+			}
+		}
+
+		snapshots.sort_by_key(|snapshot| (snapshot.created, snapshot.id.clone()));
+
+		Ok(snapshots)
+	}
+
+	pub fn load(base: &Path, id: &str) -> Result<Self> {
+		match fs::read(dir(base, id)?.join("snapshot.json")) {
+			Ok(snapshot) => Ok(serde_json::from_slice(&snapshot)?),
+			Err(err) if err.kind() == ErrorKind::NotFound => Err(Error::new(ErrorKind::NotFound, format!("There is no snapshot `{}`", id))),
+			Err(err) => Err(err)
+		}
+	}
+
+	/// Snapshots everything below `base`. Files unchanged since the latest snapshot are hard links to it, so only what
+	/// changed takes up space. A snapshot becomes visible only once it is complete.
+	pub fn create(base: &Path, label: Option<String>) -> Result<Self> {
+		let _lock = lock(base, true)?;
+
+		let created = SystemTime::now();
+		let stamp = humantime::format_rfc3339_seconds(created).to_string().replace(['-', ':'], "");
+		let id = (1..)
+			.map(|n| if n == 1 { stamp.clone() } else { format!("{}-{}", stamp, n) })
+			.find(|id| root(base).join(id).symlink_metadata().is_err())
+			.expect("some ID is free");
+
+		// Left behind by snapshots which were interrupted.
+		for entry in root(base).read_dir()? {
+			let entry = entry?;
+
+			if entry.file_name().to_string_lossy().ends_with(".partial") {
+				remove(&entry.path())?;
+				tags::forget(base, &entry.path())?;
+			}
+		}
+
+		let previous = Self::all(base)?.pop();
+		let partial = root(base).join(format!("{}.partial", id));
+
+		fs::create_dir(&partial)?;
+
+		let mut tally = Tally::default();
+		let tree = partial.join("tree");
+		let built = (|| {
+			let modes = match &previous {
+				Some(previous) => Modes::load(&dir(base, &previous.id)?)?,
+				None => None
+			};
+
+			let mut direction = Direction::Take { tree: &tree, modes: Modes::default(), previous: modes };
+			fs::create_dir(&tree)?;
+
+			for child in base.read_dir()? {
+				let child = child?;
+
+				if child.path() == meta(base) || temp::is_temp(&child.file_name()) {
+					continue;
+				}
+
+				let previous = previous.as_ref()
+					.map(|previous| previous.tree(base).join(child.file_name()));
+
+				capture(base, &child.path(), &tree.join(child.file_name()), previous.as_deref(), &mut direction, &mut tally)?;
+			}
+
+			if let Direction::Take { modes, .. } = direction {
+				fs::write(partial.join("modes.json"), serde_json::to_string(&modes)?)?;
+			}
+
+			let snapshot = Self { id: id.clone(), created, label, files: tally.files, bytes: tally.bytes, shared: tally.shared };
+			fs::write(partial.join("snapshot.json"), serde_json::to_string_pretty(&snapshot)?)?;
+
+			// Nothing is added to the tree once it is complete.
+			fs::set_permissions(&tree, fs::Permissions::from_mode(0o500))?;
+			fs::rename(&partial, root(base).join(&id))?;
+			tags::rename(base, &partial, &root(base).join(&id))?;
+
+			Ok(snapshot)
+		})();
+
+		if built.is_err() && remove(&partial).is_ok() {
+			let _ = tags::forget(base, &partial);
+		}
+
+		built
+	}
+
+	/// The directory holding the snapshotted files, laid out as they were below the base.
+	pub fn tree(&self, base: &Path) -> PathBuf {
+		root(base).join(&self.id).join("tree")
+	}
+
+	pub fn delete(self, base: &Path) -> Result<()> {
+		let _lock = lock(base, true)?;
+		let dir = dir(base, &self.id)?;
+
+		remove(&dir)?;
+		tags::forget(base, &dir)
+	}
+
+	/// Runs `f` while no snapshot is created or deleted, with the IDs of the snapshots holding a file encrypted with a
+	/// key other than `active`. Snapshots hold copies of the files they were taken of, linked only to the same files in
+	/// other snapshots, so re-encrypting the storage leaves them as they were.
+	pub fn with_retired<T>(base: &Path, active: u32, f: impl FnOnce(Vec<String>) -> Result<T>) -> Result<T> {
+		let _lock = lock(base, true)?;
+		let mut seen = HashMap::new();
+		let mut retired = Vec::new();
+
+		for snapshot in Self::all(base)? {
+			if holds_retired(&snapshot.tree(base), active, &mut seen)? {
+				retired.push(snapshot.id);
+			}
+		}
+
+		f(retired)
+	}
+
+	/// Copies `path` in the snapshot back to `to`, replacing whatever is there. The restored files are copies with the
+	/// modes they had before the snapshot was taken, so changing them leaves the snapshot as it is.
+	pub fn restore(&self, base: &Path, path: &Path, to: &Path) -> Result<()> {
+		let _lock = lock(base, false)?;
+		let tree = self.tree(base);
+		let from = tree.join(path.strip_prefix(base).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?);
+
+		from.symlink_metadata()
+			.map_err(|_| Error::new(ErrorKind::NotFound, format!("`{}` isn't in snapshot `{}`", path.display(), self.id)))?;
+
+		let mut direction = Direction::Restore { tree: &tree, modes: Modes::load(&dir(base, &self.id)?)? };
+
+		// The base itself can't be swapped out, so each entry below it is restored in turn instead.
+		if to == base {
+			for child in from.read_dir()? {
+				let child = child?;
+				put(base, &child.path(), &to.join(child.file_name()), &mut direction)?;
+			}
+
+			return Ok(());
+		}
+
+		put(base, &from, to, &mut direction)
+	}
+}
+
+/// Copies `from` in a snapshot to `to`, replacing whatever is there. It is copied next to `to` first, so that `to` is
+/// only replaced by a complete copy.
+fn put(base: &Path, from: &Path, to: &Path, direction: &mut Direction) -> Result<()> {
+	let staged = temp::name(to, "restore");
+	let replaced = temp::name(to, "replaced");
+
+	if let Err(err) = capture(base, from, &staged, None, direction, &mut Tally::default()) {
+		let _ = remove_any(&staged);
+		return Err(err);
+	}
+
+	if to.symlink_metadata().is_ok() {
+		fs::rename(to, &replaced)?;
+		fs::rename(&staged, to)?;
+		tags::rename(base, &staged, to)?;
+		remove_any(&replaced)
+	} else {
+		fs::rename(&staged, to)?;
+		tags::rename(base, &staged, to)
+	}
+}
+
+/// Whether any file below `path` is encrypted with a key other than `active`. Files are linked between snapshots, so
+/// each one is only read the first time it is `seen`.
+fn holds_retired(path: &Path, active: u32, seen: &mut HashMap<(u64, u64), bool>) -> Result<bool> {
+	let stat = path.symlink_metadata()?;
+
+	if stat.is_dir() {
+		for child in path.read_dir()? {
+			if holds_retired(&child?.path(), active, seen)? {
+				return Ok(true);
+			}
+		}
+	} else if stat.is_file() {
+		if let Some(retired) = seen.get(&(stat.dev(), stat.ino())) {
+			return Ok(*retired);
+		}
+
+		let retired = crypt::Header::read(File::open(path)?)?.is_some_and(|header| header.key != active);
+		seen.insert((stat.dev(), stat.ino()), retired);
+
+		return Ok(retired);
+	}
+
+	Ok(false)
+}
+
+fn remove_any(path: &Path) -> Result<()> {
+	match path.symlink_metadata()?.is_dir() {
+		true => remove(path),
+		false => fs::remove_file(path)
+	}
+}
@@ -0,0 +1,203 @@
+use serde::Serialize;
+use std::{
+	ffi::CString,
+	ffi::OsString,
+	fs,
+	io::Error,
+	io::ErrorKind,
+	io::Result,
+	os::fd::AsRawFd,
+	os::unix::ffi::OsStrExt,
+	os::unix::ffi::OsStringExt,
+	path::Path,
+	path::PathBuf
+};
+
+/// Size of the blocks quota limits are given in, regardless of the filesystem's own block size.
+const QUOTA_BLOCK: u64 = 1024;
+
+/// The filesystem holding a user's base, and how much of it they may still use.
+#[derive(Serialize, Debug, Clone)]
+pub struct Statfs {
+	/// The filesystem type, as the kernel names it in `/proc/self/mountinfo`
+	#[serde(rename = "type")]
+	pub fs_type: String,
+
+	pub read_only: bool,
+	pub noexec: bool,
+
+	pub bytes: Counts,
+	pub inodes: Counts,
+
+	/// The user's disk quota, if quotas are enabled on the filesystem
+	pub quota: Option<Quota>,
+
+	/// What the user can still create, the lesser of what the filesystem has available and what their quota allows
+	pub left: Left,
+}
+
+/// `available` is what unprivileged users can use, which excludes any space reserved for root.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Counts {
+	pub total: u64,
+	pub free: u64,
+	pub available: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Quota {
+	pub bytes: Limits,
+	pub inodes: Limits,
+}
+
+/// Limits are `None` where the quota doesn't set one. Writes past the soft limit succeed until its grace period runs
+/// out; the hard limit is never exceeded.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Limits {
+	pub used: u64,
+	pub soft: Option<u64>,
+	pub hard: Option<u64>,
+}
+
+/// `inodes` is `None` on filesystems which allocate inodes as needed, unless the quota limits them.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Left {
+	pub bytes: u64,
+	pub inodes: Option<u64>,
+}
+
+impl Limits {
+	fn left(&self) -> Option<u64> {
+		self.hard.map(|hard| hard.saturating_sub(self.used))
+	}
+}
+
+/// Undoes the octal escapes `/proc/self/mountinfo` uses for whitespace and backslashes in paths.
+fn unescape(field: &str) -> PathBuf {
+	let bytes = field.as_bytes();
+	let mut path = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+
+	while i < bytes.len() {
+		let octal = bytes.get(i + 1..i + 4)
+			.filter(|_| bytes[i] == b'\\')
+			.and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+
+		match octal {
+			Some(byte) => {
+				path.push(byte);
+				i += 4;
+			},
+			None => {
+				path.push(bytes[i]);
+				i += 1;
+			}
+		}
+	}
+
+	PathBuf::from(OsString::from_vec(path))
+}
+
+/// The type of the filesystem mounted closest above `path`. Later mounts hide earlier ones at the same point.
+fn fs_type(path: &Path) -> Result<String> {
+	let path = path.canonicalize()?;
+	let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+
+	mountinfo.lines()
+		.filter_map(|line| {
+			let fields = line.split(' ').collect::<Vec<_>>();
+			let separator = fields.iter().position(|field| *field == "-")?;
+
+			Some((unescape(fields.get(4)?), fields.get(separator + 1)?.to_string()))
+		})
+		.filter(|(mount, _)| path.starts_with(mount))
+		.fold(None, |closest: Option<(PathBuf, String)>, (mount, fs_type)| match closest {
+			Some(closest) if closest.0.as_os_str().len() > mount.as_os_str().len() => Some(closest),
+			_ => Some((mount, fs_type))
+		})
+		.map(|(_, fs_type)| fs_type)
+		.ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No filesystem is mounted at `{}`", path.display())))
+}
+
+/// The disk quota of `uid` on the filesystem holding `path`, or `None` if it has none.
+fn quota(path: &Path, uid: u32) -> Result<Option<Quota>> {
+	let dir = fs::File::open(path)?;
+	let mut quota = unsafe { std::mem::zeroed::<libc::dqblk>() };
+
+	let result = unsafe {
+		libc::syscall(libc::SYS_quotactl_fd, dir.as_raw_fd(), libc::QCMD(libc::Q_GETQUOTA, libc::USRQUOTA) as libc::c_uint, uid, &mut quota as *mut libc::dqblk)
+	};
+
+	if result < 0 {
+		return match Error::last_os_error() {
+			// Quotas are off or unsupported, whether by the filesystem or the kernel, or the call is filtered out. The
+			// kernel also refuses to look quotas up on read-only mounts, where nothing can be written regardless.
+			err if matches!(err.raw_os_error(), Some(libc::ESRCH | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL | libc::EPERM | libc::EROFS)) => Ok(None),
+			err => Err(err)
+		};
+	}
+
+	let limit = |limit: u64, scale: u64| (limit > 0).then_some(limit.saturating_mul(scale));
+
+	Ok(Some(Quota {
+		bytes: Limits {
+			used: quota.dqb_curspace,
+			soft: limit(quota.dqb_bsoftlimit, QUOTA_BLOCK),
+			hard: limit(quota.dqb_bhardlimit, QUOTA_BLOCK),
+		},
+		inodes: Limits {
+			used: quota.dqb_curinodes,
+			soft: limit(quota.dqb_isoftlimit, 1),
+			hard: limit(quota.dqb_ihardlimit, 1),
+		},
+	}))
+}
+
+/// Describes the filesystem holding `base`, as seen by `uid`.
+pub fn statfs(base: &Path, uid: u32) -> Result<Statfs> {
+	let path = CString::new(base.as_os_str().as_bytes()).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+	let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+
+	if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+		return Err(Error::last_os_error());
+	}
+
+	let block = stat.f_frsize as u64;
+	let bytes = Counts {
+		total: stat.f_blocks as u64 * block,
+		free: stat.f_bfree as u64 * block,
+		available: stat.f_bavail as u64 * block,
+	};
+
+	let inodes = Counts {
+		total: stat.f_files as u64,
+		free: stat.f_ffree as u64,
+		available: stat.f_favail as u64,
+	};
+
+	let read_only = stat.f_flag & libc::ST_RDONLY != 0;
+	let quota = quota(base, uid)?;
+
+	let left = match read_only {
+		true => Left { bytes: 0, inodes: Some(0) },
+		false => Left {
+			bytes: quota.and_then(|quota| quota.bytes.left()).map_or(bytes.available, |left| left.min(bytes.available)),
+
+			// Filesystems which allocate inodes as needed report none at all.
+			inodes: [(inodes.total > 0).then_some(inodes.available), quota.and_then(|quota| quota.inodes.left())]
+				.into_iter()
+				.flatten()
+				.min(),
+		}
+	};
+
+	Ok(Statfs {
+		fs_type: fs_type(base)?,
+		read_only,
+		noexec: stat.f_flag & libc::ST_NOEXEC != 0,
+		bytes,
+		inodes,
+		quota,
+		left,
+	})
+}
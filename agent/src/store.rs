@@ -0,0 +1,146 @@
+use crate::{
+	compress,
+	compress::Policy,
+	compress::SeekableWriter,
+	crypt::Vault,
+	meta,
+	names,
+	pipe,
+	tags,
+	temp,
+	Finish,
+	ReadSeek
+};
+use std::{
+	fs,
+	fs::File,
+	fs::Metadata,
+	fs::OpenOptions,
+	io::BufRead,
+	io::BufReader,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	io::Seek,
+	io::SeekFrom,
+	os::unix::fs::MetadataExt,
+	path::Path,
+	path::PathBuf
+};
+
+/// Translates between the logical contents of a user's files and how they are kept on disk. Files are compressed
+/// first, then encrypted, so reading peels the layers off in the opposite order.
+pub struct Store {
+	base: PathBuf,
+	pub vault: Vault,
+	pub policy: Policy,
+	pub names: names::Policy,
+}
+
+impl Store {
+	pub fn new(base: impl AsRef<Path>, vault: Vault) -> Result<Self> {
+		Ok(Self {
+			policy: Policy::load(meta(&base))?,
+			names: names::Policy::load(meta(&base))?,
+			base: base.as_ref().to_path_buf(),
+			vault,
+		})
+	}
+
+	pub fn base(&self) -> &Path {
+		&self.base
+	}
+
+	/// The path as the user sees it, rooted at their base.
+	pub fn relative(&self, path: impl AsRef<Path>) -> PathBuf {
+		Path::new("/").join(path.as_ref().strip_prefix(&self.base).unwrap_or(path.as_ref()))
+	}
+
+	pub fn open(&self, path: impl AsRef<Path>) -> Result<Box<dyn ReadSeek>> {
+		compress::open(self.vault.open(path)?)
+	}
+
+	/// The logical size and first `len` bytes of a file. Falls back to the size on disk and no content if the file can't
+	/// be opened, such as when its keyring is locked.
+	pub fn head(&self, path: impl AsRef<Path>, stat: &Metadata, len: usize) -> (u64, Vec<u8>) {
+		let mut head = Vec::with_capacity(len);
+
+		self.open(path)
+			.and_then(|mut file| {
+				let size = file.seek(SeekFrom::End(0))?;
+				file.seek(SeekFrom::Start(0))?;
+				file.take(len as u64).read_to_end(&mut head)?;
+				Ok((size, head))
+			})
+			.unwrap_or((stat.size(), Vec::new()))
+	}
+
+	/// Whether a file's contents are stored compressed.
+	pub fn compressed(&self, path: impl AsRef<Path>) -> Result<bool> {
+		compress::is_compressed(self.vault.open(path)?)
+	}
+
+	/// Writes `from` to the file at `path`, which must exist unless `create` is set. The file is written beside its
+	/// destination and only moved over it once `from` has ended, so a write which fails or is killed part way leaves the
+	/// file that was there untouched. Symbolic links are written through, as they would be if the file were opened.
+	pub fn write(&self, path: impl AsRef<Path>, from: impl Read, create: bool) -> Result<()> {
+		let path = fs::canonicalize(&path).unwrap_or_else(|_| path.as_ref().to_path_buf());
+
+		let replaced = match path.metadata() {
+			Ok(stat) => Some(stat),
+			Err(err) if create && err.kind() == ErrorKind::NotFound => None,
+			Err(err) => return Err(err)
+		};
+
+		let mut from = BufReader::with_capacity(compress::FRAME_SIZE, from);
+		let compress = self.policy.applies(self.relative(&path)) && compress::compressible(&path, from.fill_buf()?);
+
+		let (tmp, file) = temp::file(&path, "write")?;
+
+		if let Some(stat) = replaced {
+			file.set_permissions(stat.permissions())?;
+		}
+
+		if let Err(err) = self.encode(from, file, compress).and_then(|_| tags::carry(&path, &tmp)).and_then(|_| fs::rename(&tmp, &path)) {
+			let _ = fs::remove_file(&tmp);
+			return Err(err);
+		}
+
+		Ok(())
+	}
+
+	/// Writes `from` into `to` as the current encryption settings dictate, compressing it if `compress` is set.
+	pub fn encode(&self, from: impl Read, to: File, compress: bool) -> Result<()> {
+		let writer = self.vault.writer(Box::new(to))?;
+		let mut writer: Box<dyn Finish> = if compress {
+			Box::new(SeekableWriter::new(writer)?)
+		} else {
+			compress::plain(writer)
+		};
+
+		pipe(from, &mut writer)?;
+		writer.finish()
+	}
+
+	/// Atomically replaces a file with whatever `write` produces, preserving its permissions and modification time.
+	/// An interrupted rewrite leaves the original untouched.
+	pub fn replace(&self, path: impl AsRef<Path>, write: impl FnOnce(File) -> Result<()>) -> Result<()> {
+		let path = path.as_ref();
+		let stat = path.symlink_metadata()?;
+		let (tmp, file) = temp::file(path, "rewrite")?;
+
+		file.set_permissions(stat.permissions())?;
+
+		if let Err(err) = write(file).and_then(|_| tags::carry(path, &tmp)) {
+			let _ = fs::remove_file(&tmp);
+			return Err(err);
+		}
+
+		OpenOptions::new()
+			.write(true)
+			.open(&tmp)?
+			.set_modified(stat.modified()?)?;
+
+		fs::rename(&tmp, path)
+	}
+}
@@ -0,0 +1,266 @@
+use crate::{
+	create_meta,
+	meta
+};
+use std::{
+	cell::OnceCell,
+	collections::BTreeMap,
+	collections::BTreeSet,
+	ffi::CString,
+	fs,
+	io::Error,
+	io::ErrorKind,
+	io::Result,
+	os::unix::ffi::OsStrExt,
+	os::unix::fs::MetadataExt,
+	os::unix::fs::PermissionsExt,
+	path::Path,
+	path::PathBuf
+};
+
+/// Extended attribute holding a file's tags as a JSON array.
+pub const XATTR: &str = "user.cloud.tags";
+
+/// Tags of files on filesystems without extended attributes, by path.
+pub const SIDECAR: &str = "tags.json";
+
+/// Longest tag accepted, in bytes.
+const MAX_LEN: usize = 64;
+
+fn c_path(path: &Path) -> Result<CString> {
+	CString::new(path.as_os_str().as_bytes()).map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+}
+
+fn c_name() -> CString {
+	CString::new(XATTR).expect("no interior NUL")
+}
+
+/// The raw attribute, `None` if it isn't set. Fails with `Unsupported` if the filesystem has no extended attributes.
+fn get_xattr(path: &Path) -> Result<Option<Vec<u8>>> {
+	let (path, name) = (c_path(path)?, c_name());
+
+	loop {
+		let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+
+		if len < 0 {
+			return match Error::last_os_error() {
+				err if err.raw_os_error() == Some(libc::ENODATA) => Ok(None),
+				err => Err(err)
+			};
+		}
+
+		let mut value = vec![0u8; len as usize];
+		let read = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+
+		if read >= 0 {
+			value.truncate(read as usize);
+			return Ok(Some(value));
+		}
+
+		match Error::last_os_error() {
+			// Grew in between; try again.
+			err if err.raw_os_error() == Some(libc::ERANGE) => continue,
+			err => return Err(err)
+		}
+	}
+}
+
+fn set_xattr(path: &Path, value: Option<&[u8]>) -> Result<()> {
+	let (path, name) = (c_path(path)?, c_name());
+
+	let result = match value {
+		Some(value) => unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) },
+		None => unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) }
+	};
+
+	match result {
+		0 => Ok(()),
+		_ => match Error::last_os_error() {
+			err if value.is_none() && err.raw_os_error() == Some(libc::ENODATA) => Ok(()),
+			err => Err(err)
+		}
+	}
+}
+
+type Sidecar = BTreeMap<PathBuf, BTreeSet<String>>;
+
+fn load_sidecar(base: &Path) -> Result<Sidecar> {
+	match fs::read(meta(base).join(SIDECAR)) {
+		Ok(sidecar) => Ok(serde_json::from_slice(&sidecar)?),
+		Err(err) if err.kind() == ErrorKind::NotFound => Ok(Sidecar::new()),
+		Err(err) => Err(err)
+	}
+}
+
+fn save_sidecar(base: &Path, sidecar: &Sidecar) -> Result<()> {
+	create_meta(meta(base))?;
+
+	let path = meta(base).join(SIDECAR);
+	let tmp = path.with_extension("tmp");
+
+	fs::write(&tmp, serde_json::to_string_pretty(sidecar)?)?;
+	fs::rename(tmp, path)
+}
+
+fn relative(base: &Path, path: &Path) -> PathBuf {
+	Path::new("/").join(path.strip_prefix(base).unwrap_or(path))
+}
+
+/// Checks a tag and trims surrounding whitespace from it.
+pub fn validate(tag: &str) -> Result<String> {
+	let tag = tag.trim();
+
+	if tag.is_empty() || tag.len() > MAX_LEN || tag.contains(|c: char| c.is_control() || c == ',') {
+		return Err(Error::new(ErrorKind::InvalidInput, format!("Tags must be 1 to {} bytes long, without commas or control characters: {:?}", MAX_LEN, tag)));
+	}
+
+	Ok(tag.to_owned())
+}
+
+/// Looks up the tags of many paths, such as those of a listing, reading the sidecar at most once.
+pub struct Reader<'a> {
+	base: &'a Path,
+	sidecar: OnceCell<Sidecar>,
+}
+
+impl<'a> Reader<'a> {
+	pub fn new(base: &'a Path) -> Self {
+		Self { base, sidecar: OnceCell::new() }
+	}
+
+	/// The tags of a file or directory, sorted.
+	pub fn get(&self, path: &Path) -> Result<BTreeSet<String>> {
+		match get_xattr(path) {
+			Ok(Some(tags)) => Ok(serde_json::from_slice(&tags)?),
+			Ok(None) => Ok(BTreeSet::new()),
+			Err(err) if err.kind() == ErrorKind::Unsupported => {
+				let sidecar = match self.sidecar.get() {
+					Some(sidecar) => sidecar,
+					None => {
+						let sidecar = load_sidecar(self.base)?;
+						self.sidecar.get_or_init(|| sidecar)
+					}
+				};
+
+				Ok(sidecar.get(&relative(self.base, path)).cloned().unwrap_or_default())
+			},
+			Err(err) => Err(err)
+		}
+	}
+}
+
+/// The tags of a file or directory, sorted.
+pub fn get(base: &Path, path: &Path) -> Result<BTreeSet<String>> {
+	Reader::new(base).get(path)
+}
+
+/// Replaces the tags of a file or directory. Only files and directories can be tagged.
+pub fn set(base: &Path, path: &Path, tags: &BTreeSet<String>) -> Result<()> {
+	let stat = path.symlink_metadata()?;
+
+	if !stat.is_file() && !stat.is_dir() {
+		return Err(Error::new(ErrorKind::InvalidInput, "Only files and directories can be tagged"));
+	}
+
+	let value = serde_json::to_vec(tags)?;
+	let value = (!tags.is_empty()).then_some(value.as_slice());
+
+	match set_writable(path, value) {
+		Err(err) if err.kind() == ErrorKind::Unsupported => {
+			let mut sidecar = load_sidecar(base)?;
+
+			match tags.is_empty() {
+				true => sidecar.remove(&relative(base, path)),
+				false => sidecar.insert(relative(base, path), tags.clone())
+			};
+
+			save_sidecar(base, &sidecar)
+		},
+		result => result
+	}
+}
+
+/// Sets the attribute, even on read-only files.
+fn set_writable(path: &Path, value: Option<&[u8]>) -> Result<()> {
+	match set_xattr(path, value) {
+		// Extended attributes can only be changed on files which can be written to, even by their owner. Read-only
+		// files are made writable just long enough to tag them.
+		Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+			let stat = path.symlink_metadata()?;
+
+			if stat.mode() & 0o200 != 0 {
+				return Err(err);
+			}
+
+			fs::set_permissions(path, fs::Permissions::from_mode(stat.mode() | 0o200))?;
+			let result = set_xattr(path, value);
+			fs::set_permissions(path, stat.permissions())?;
+			result
+		},
+		result => result
+	}
+}
+
+/// Gives `tmp` the tags of `path`, which it is about to be renamed over, so that rewriting a file keeps them. Tags
+/// in the sidecar belong to the path rather than the file, so stay put by themselves.
+pub fn carry(path: &Path, tmp: &Path) -> Result<()> {
+	match get_xattr(path) {
+		Ok(Some(tags)) => set_writable(tmp, Some(&tags)),
+		Err(err) if err.kind() != ErrorKind::Unsupported && err.kind() != ErrorKind::NotFound => Err(err),
+		_ => Ok(())
+	}
+}
+
+/// Changes the sidecar, if there is one, with `f`, saving it only if `f` changed anything.
+fn update_sidecar(base: &Path, f: impl FnOnce(&mut Sidecar)) -> Result<()> {
+	let mut sidecar = load_sidecar(base)?;
+
+	if sidecar.is_empty() {
+		return Ok(());
+	}
+
+	let before = sidecar.clone();
+	f(&mut sidecar);
+
+	match sidecar == before {
+		true => Ok(()),
+		false => save_sidecar(base, &sidecar)
+	}
+}
+
+/// Moves the sidecar's tags of `from` and everything below it to `to`, once it has been renamed there, replacing
+/// those of whatever was at `to`. Extended attributes move with the files.
+pub fn rename(base: &Path, from: &Path, to: &Path) -> Result<()> {
+	let (from, to) = (relative(base, from), relative(base, to));
+
+	update_sidecar(base, |sidecar| {
+		sidecar.retain(|path, _| !path.starts_with(&to));
+
+		let moved = sidecar.keys().filter(|path| path.starts_with(&from)).cloned().collect::<Vec<_>>();
+
+		for path in moved {
+			if let Some(tags) = sidecar.remove(&path) {
+				let path = match path.strip_prefix(&from) {
+					Ok(rest) if !rest.as_os_str().is_empty() => to.join(rest),
+					_ => to.clone()
+				};
+
+				sidecar.insert(path, tags);
+			}
+		}
+	})
+}
+
+/// Drops the sidecar's tags of `path` and everything below it, once it has been removed.
+pub fn forget(base: &Path, path: &Path) -> Result<()> {
+	let path = relative(base, path);
+	update_sidecar(base, |sidecar| sidecar.retain(|tagged, _| !tagged.starts_with(&path)))
+}
+
+/// Gives `to` the tags of `from`, if it has any, so that copies keep them.
+pub fn copy(base: &Path, from: &Path, to: &Path) -> Result<()> {
+	match get(base, from)? {
+		tags if tags.is_empty() => Ok(()),
+		tags => set(base, to, &tags)
+	}
+}
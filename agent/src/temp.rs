@@ -0,0 +1,118 @@
+use chacha20poly1305::aead::{
+	OsRng,
+	rand_core::RngCore
+};
+use crate::names;
+use std::{
+	ffi::OsStr,
+	fs,
+	fs::File,
+	fs::OpenOptions,
+	fs::TryLockError,
+	io::ErrorKind,
+	io::Result,
+	ops::Deref,
+	path::Path,
+	path::PathBuf
+};
+
+/// What the agent stages things beside their destination for. Names ending in one of these followed by an id are the
+/// agent's own, and are left out of listings.
+const VERBS: &[&str] = &["write", "rewrite", "patch", "upload", "restore", "replacing", "replaced", "transfer", "render"];
+
+const ID_LEN: usize = 16;
+
+/// Longest name most filesystems allow, in bytes.
+const NAME_MAX: usize = 255;
+
+/// A name beside `path` to stage something in, of the form `.<name>.<verb>.<id>`. The id is random, so that concurrent
+/// writers to the same path never share one, and a user's own file is never mistaken for it.
+pub fn name(path: &Path, verb: &str) -> PathBuf {
+	let mut id = [0u8; ID_LEN / 2];
+	OsRng.fill_bytes(&mut id);
+
+	let id = id.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+	let name = path.file_name().unwrap_or_default().to_string_lossy();
+	let name = names::truncate(&name, NAME_MAX - verb.len() - ID_LEN - 3);
+
+	path.with_file_name(format!(".{}.{}.{}", name, verb, id))
+}
+
+/// A file being staged. It stays locked until this is dropped, which tells it apart from one left behind by an agent
+/// which was killed part way.
+pub struct Staged {
+	path: PathBuf,
+	_lock: File,
+}
+
+impl Deref for Staged {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl AsRef<Path> for Staged {
+	fn as_ref(&self) -> &Path {
+		&self.path
+	}
+}
+
+/// Creates a file beside `path` to stage its new contents in. It is created afresh, so it is never shared with another
+/// writer. The lock is held by the returned [`Staged`] rather than the file, which may be closed before it is renamed.
+pub fn file(path: &Path, verb: &str) -> Result<(Staged, File)> {
+	loop {
+		let tmp = name(path, verb);
+		let file = match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+			Ok(file) => file,
+			Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+			Err(err) => return Err(err)
+		};
+
+		file.lock()?;
+		return Ok((Staged { path: tmp, _lock: file.try_clone()? }, file));
+	}
+}
+
+/// Whether `name` is one the agent stages things under.
+pub fn is_temp(name: &OsStr) -> bool {
+	let name = name.to_string_lossy();
+
+	let Some((rest, id)) = name.strip_prefix('.').and_then(|name| name.rsplit_once('.')) else {
+		return false;
+	};
+
+	id.len() == ID_LEN
+		&& id.bytes().all(|c| c.is_ascii_hexdigit())
+		&& rest.rsplit_once('.').is_some_and(|(_, verb)| VERBS.contains(&verb))
+}
+
+/// Removes the files staged beside `path` which no agent is writing any longer, as are left behind by agents which were
+/// killed part way. Staged directories are kept, as the transfers which make them can be resumed.
+pub fn sweep(path: &Path) -> Result<()> {
+	for entry in path.parent().unwrap_or(path).read_dir()? {
+		let entry = entry?;
+
+		if !is_temp(&entry.file_name()) || !entry.file_type()?.is_file() {
+			continue;
+		}
+
+		let file = match File::open(entry.path()) {
+			Ok(file) => file,
+			Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied) => continue,
+			Err(err) => return Err(err)
+		};
+
+		match file.try_lock() {
+			Ok(()) => match fs::remove_file(entry.path()) {
+				Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+				_ => ()
+			},
+			Err(TryLockError::WouldBlock) => (),
+			Err(TryLockError::Error(err)) => return Err(err)
+		}
+	}
+
+	Ok(())
+}
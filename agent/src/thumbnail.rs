@@ -0,0 +1,231 @@
+use crate::{
+	create_meta,
+	meta,
+	mime,
+	store::Store,
+	temp,
+	ReadSeek
+};
+use clap::ValueEnum;
+use image::{
+	codecs::jpeg::JpegEncoder,
+	codecs::webp::WebPEncoder,
+	DynamicImage,
+	ImageDecoder,
+	ImageReader
+};
+use sha2::{
+	Digest,
+	Sha256
+};
+use std::{
+	fs,
+	io::BufReader,
+	io::BufWriter,
+	io::Error,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	io::Seek,
+	io::SeekFrom,
+	os::unix::ffi::OsStrExt,
+	path::Path,
+	path::PathBuf,
+	time::UNIX_EPOCH
+};
+
+pub const CACHE: &str = "thumbnails";
+
+const EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp", "gif"];
+
+/// Types of image thumbnails are rendered from, as told by their first bytes.
+const MIME_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp", "image/gif"];
+
+/// Bytes of thumbnails kept per user. Past this, the oldest are removed, including those of images since removed.
+const CACHE_LIMIT: u64 = 256 * 1024 * 1024;
+
+const QUALITY: u8 = 80;
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum Size {
+	#[value(name = "128")]
+	Small,
+
+	#[value(name = "256")]
+	Medium,
+
+	#[value(name = "1024")]
+	Large
+}
+
+impl Size {
+	pub fn pixels(self) -> u32 {
+		match self {
+			Self::Small => 128,
+			Self::Medium => 256,
+			Self::Large => 1024
+		}
+	}
+}
+
+/// Whether a thumbnail can be produced for a file starting with `head`. Files whose contents couldn't be read, such as
+/// those under a locked keyring, are judged by their extension alone, so this is only a hint for those.
+pub fn supported(path: impl AsRef<Path>, head: &[u8]) -> bool {
+	match infer::get(head) {
+		Some(kind) => MIME_TYPES.contains(&kind.mime_type()),
+		None if head.is_empty() => path.as_ref()
+			.extension()
+			.is_some_and(|ext| EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())),
+		None => false
+	}
+}
+
+fn hex(digest: &[u8]) -> String {
+	digest.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+/// Returns the thumbnail for an image, rendering it into the cache first if needed. Thumbnails are named after the
+/// image's path and the thumbnail size, followed by the image's modification time and size, so a changed file never
+/// serves a stale thumbnail, and the one it replaces can be found and removed.
+pub fn thumbnail(store: &Store, path: impl AsRef<Path>, size: Size) -> Result<Box<dyn ReadSeek>> {
+	let path = path.as_ref();
+	let stat = path.metadata()?;
+
+	if !stat.is_file() {
+		return Err(Error::new(ErrorKind::Unsupported, "No thumbnail can be generated for this file"));
+	}
+
+	let modified = stat.modified()?
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_nanos();
+
+	let version = Sha256::new()
+		.chain_update(modified.to_be_bytes())
+		.chain_update(stat.len().to_be_bytes())
+		.finalize();
+
+	let stale = format!("{}.{}.", hex(&Sha256::digest(store.relative(path).as_os_str().as_bytes())[..16]), size.pixels());
+	let key = format!("{}{}", stale, hex(&version[..16]));
+	let cache = meta(store.base()).join(CACHE);
+
+	for ext in ["jpg", "webp"] {
+		match store.vault.open(cache.join(format!("{}.{}", key, ext))) {
+			Err(err) if err.kind() == ErrorKind::NotFound => continue,
+			thumbnail => return thumbnail
+		}
+	}
+
+	let mut file = store.open(path)?;
+	let mut head = Vec::new();
+	file.by_ref().take(mime::SNIFF_LEN as u64).read_to_end(&mut head)?;
+	file.seek(SeekFrom::Start(0))?;
+
+	if head.is_empty() || !supported(path, &head) {
+		return Err(Error::new(ErrorKind::Unsupported, "No thumbnail can be generated for this file"));
+	}
+
+	let mut decoder = ImageReader::new(BufReader::new(file))
+		.with_guessed_format()?
+		.into_decoder()
+		.map_err(invalid)?;
+	let orientation = decoder.orientation().map_err(invalid)?;
+
+	let mut image = DynamicImage::from_decoder(decoder).map_err(invalid)?;
+	image.apply_orientation(orientation);
+
+	let image = image.thumbnail(size.pixels(), size.pixels());
+	let target = cache.join(format!("{}.{}", key, if image.color().has_alpha() { "webp" } else { "jpg" }));
+
+	create_meta(&cache)?;
+	render(store, &image, &target)?;
+
+	// The thumbnail is there either way, and whatever is left over goes the next time one is rendered.
+	let _ = prune(&cache, &target, &stale);
+
+	store.vault.open(target)
+}
+
+/// Removes the thumbnails of earlier versions of the image `keep` was rendered from, which start with `stale`. Then
+/// removes the oldest thumbnails until the cache fits in [`CACHE_LIMIT`], which clears out those of removed images.
+fn prune(cache: &Path, keep: &Path, stale: &str) -> Result<()> {
+	let mut entries = Vec::new();
+	let mut total = keep.metadata()?.len();
+
+	for entry in cache.read_dir()? {
+		let entry = entry?;
+		let name = entry.file_name();
+
+		if entry.path() == keep || temp::is_temp(&name) {
+			continue;
+		}
+
+		if name.to_string_lossy().starts_with(stale) {
+			remove(&entry.path())?;
+			continue;
+		}
+
+		let stat = match entry.metadata() {
+			Ok(stat) => stat,
+			Err(err) if err.kind() == ErrorKind::NotFound => continue,
+			Err(err) => return Err(err)
+		};
+
+		total += stat.len();
+		entries.push((stat.modified()?, stat.len(), entry.path()));
+	}
+
+	entries.sort();
+
+	for (_, len, path) in entries {
+		if total <= CACHE_LIMIT {
+			break;
+		}
+
+		remove(&path)?;
+		total -= len;
+	}
+
+	Ok(())
+}
+
+/// Removes a thumbnail, unless another agent got to it first.
+fn remove(path: &Path) -> Result<()> {
+	match fs::remove_file(path) {
+		Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+		_ => Ok(())
+	}
+}
+
+/// Encodes a thumbnail as lossless WebP if it has transparency, and as JPEG otherwise. Thumbnails of encrypted
+/// images are encrypted too.
+fn render(store: &Store, image: &DynamicImage, target: &PathBuf) -> Result<()> {
+	let (tmp, file) = temp::file(target, "render")?;
+
+	let rendered = (|| {
+		let mut writer = BufWriter::new(store.vault.writer(Box::new(file))?);
+
+		if image.color().has_alpha() {
+			image.to_rgba8().write_with_encoder(WebPEncoder::new_lossless(&mut writer))
+		} else {
+			image.to_rgb8().write_with_encoder(JpegEncoder::new_with_quality(&mut writer, QUALITY))
+		}.map_err(invalid)?;
+
+		writer.into_inner()
+			.map_err(|err| err.into_error())?
+			.finish()
+	})();
+
+	if let Err(err) = rendered.and_then(|_| fs::rename(&tmp, target)) {
+		let _ = fs::remove_file(&tmp);
+		return Err(err);
+	}
+
+	Ok(())
+}
+
+fn invalid(err: image::ImageError) -> Error {
+	Error::new(ErrorKind::InvalidData, err)
+}
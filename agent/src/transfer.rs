@@ -0,0 +1,474 @@
+use crate::{
+	copy,
+	create_meta,
+	meta,
+	rm,
+	store::Store,
+	tags,
+	temp
+};
+use serde::{
+	Deserialize,
+	Serialize
+};
+use sha2::{
+	Digest,
+	Sha256
+};
+use std::{
+	fs,
+	fs::File,
+	fs::OpenOptions,
+	io::Error,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	io::Write,
+	os::unix::ffi::OsStrExt,
+	os::unix::fs::MetadataExt,
+	path::Path,
+	path::PathBuf,
+	time::Duration,
+	time::Instant
+};
+
+pub const JOURNALS: &str = "transfers";
+
+/// How often progress is written to the journal while files are being copied.
+const CHECKPOINT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+	Move,
+	Copy
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+	/// Entries are being copied into the staging path. The destination is as it was.
+	Copying,
+
+	/// The staging path has been renamed to the destination. All that remains of a move is removing the source.
+	Committed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+	File,
+	Dir,
+	Symlink
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+	/// Relative to the root of the transfer. Empty for the root itself.
+	path: PathBuf,
+	kind: Kind,
+	size: u64,
+
+	/// SHA-256 of the file's contents, once the copy has been verified against it.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	verified: Option<String>,
+}
+
+/// Records the progress of a transfer between filesystems, so that it can be resumed or rolled back if interrupted.
+/// Everything is copied into a staging path next to the destination first, which is only renamed into place once every
+/// file has been verified. The source of a move is removed after that.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Journal {
+	mode: Mode,
+	from: PathBuf,
+	to: PathBuf,
+	staging: PathBuf,
+
+	/// Whether the staging path is swapped with whatever is at the destination, rather than simply renamed there.
+	#[serde(default)]
+	replace: bool,
+
+	phase: Phase,
+	entries: Vec<Entry>,
+
+	#[serde(skip)]
+	location: PathBuf,
+}
+
+impl Journal {
+	/// Journals are named after what they transfer, so repeating a command finds the journal it left behind.
+	fn location(base: &Path, mode: Mode, from: &Path, to: &Path) -> PathBuf {
+		let id = Sha256::new()
+			.chain_update(serde_json::to_vec(&mode).unwrap_or_default())
+			.chain_update(from.as_os_str().as_bytes())
+			.chain_update([0])
+			.chain_update(to.as_os_str().as_bytes())
+			.finalize()
+			.iter()
+			.map(|byte| format!("{:02x}", byte))
+			.collect::<String>();
+
+		meta(base).join(JOURNALS).join(id).with_extension("json")
+	}
+
+	fn find(base: &Path, mode: Mode, from: &Path, to: &Path) -> Result<Option<Self>> {
+		let location = Self::location(base, mode, from, to);
+
+		match File::open(&location) {
+			Ok(file) => Ok(Some(Self { location, ..serde_json::from_reader(file)? })),
+			Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+			Err(err) => Err(err)
+		}
+	}
+
+	/// Every journal left behind by interrupted transfers.
+	pub fn all(base: &Path) -> Result<Vec<Self>> {
+		let dir = meta(base).join(JOURNALS);
+
+		if !dir.exists() {
+			return Ok(Vec::new());
+		}
+
+		let mut journals = Vec::new();
+
+		for entry in dir.read_dir()? {
+			let location = entry?.path();
+
+			if location.extension().is_some_and(|ext| ext == "json") {
+				let journal: Self = serde_json::from_reader(File::open(&location)?)?;
+				journals.push(Self { location, ..journal });
+			}
+		}
+
+		Ok(journals)
+	}
+
+	/// Lists the source tree to start a new transfer.
+	fn create(base: &Path, mode: Mode, from: &Path, to: &Path, replace: bool) -> Result<Self> {
+		fn list(root: &Path, path: &Path, entries: &mut Vec<Entry>) -> Result<()> {
+			let stat = path.symlink_metadata()?;
+			let relative = path.strip_prefix(root).unwrap_or(Path::new("")).to_path_buf();
+
+			if stat.is_symlink() {
+				entries.push(Entry { path: relative, kind: Kind::Symlink, size: 0, verified: None });
+			} else if stat.is_dir() {
+				entries.push(Entry { path: relative, kind: Kind::Dir, size: 0, verified: None });
+
+				for child in path.read_dir()? {
+					list(root, &child?.path(), entries)?;
+				}
+			} else if stat.is_file() {
+				entries.push(Entry { path: relative, kind: Kind::File, size: stat.size(), verified: None });
+			} else {
+				Err(Error::new(ErrorKind::InvalidInput, format!("{:?} can't be transferred", path)))?;
+			}
+
+			Ok(())
+		}
+
+		let mut entries = Vec::new();
+		list(from, from, &mut entries)?;
+
+		let journal = Self {
+			mode,
+			from: from.to_path_buf(),
+			to: to.to_path_buf(),
+			staging: temp::name(to, "transfer"),
+			replace,
+			phase: Phase::Copying,
+			entries,
+			location: Self::location(base, mode, from, to),
+		};
+
+		create_meta(journal.location.parent().unwrap_or(base))?;
+		journal.save()?;
+		Ok(journal)
+	}
+
+	fn save(&self) -> Result<()> {
+		let tmp = self.location.with_extension("tmp");
+		let mut file = OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(true)
+			.open(&tmp)?;
+
+		file.write_all(&serde_json::to_vec(self)?)?;
+		file.sync_all()?;
+		fs::rename(tmp, &self.location)
+	}
+
+	fn target(root: &Path, entry: &Entry) -> PathBuf {
+		match entry.path.as_os_str().is_empty() {
+			true => root.to_path_buf(),
+			false => root.join(&entry.path)
+		}
+	}
+
+	/// Copies whatever hasn't been verified yet, then moves the copy into place and removes the source of a move.
+	fn run(mut self, base: &Path) -> Result<()> {
+		// Interrupted after moving the copy into place, but before noting as much. Every file is recorded as verified
+		// before that happens. Something was at the destination all along if the copy replaces it, in which case the copy
+		// is simply made again.
+		let copied = self.entries.iter().all(|entry| entry.kind != Kind::File || entry.verified.is_some());
+
+		if self.phase == Phase::Copying && !self.replace && copied && self.staging.symlink_metadata().is_err() && self.to.symlink_metadata().is_ok() {
+			self.phase = Phase::Committed;
+		}
+
+		if self.phase == Phase::Copying {
+			let mut checkpoint = Instant::now();
+
+			for index in 0..self.entries.len() {
+				let entry = &self.entries[index];
+				let (from, to) = (Self::target(&self.from, entry), Self::target(&self.staging, entry));
+
+				match entry.kind {
+					Kind::Dir => if !to.is_dir() {
+						fs::create_dir(&to)?;
+						fs::set_permissions(&to, from.metadata()?.permissions())?;
+						tags::copy(base, &from, &to)?;
+					},
+					Kind::Symlink => if to.symlink_metadata().is_err() {
+						std::os::unix::fs::symlink(from.read_link()?, &to)?;
+					},
+					Kind::File if entry.verified.is_some() && to.metadata().is_ok_and(|stat| stat.len() == entry.size) => (),
+					Kind::File => {
+						let hash = transfer(&from, &to)?;
+						tags::copy(base, &from, &to)?;
+						self.entries[index].verified = Some(hash);
+
+						if checkpoint.elapsed() >= CHECKPOINT {
+							self.save()?;
+							checkpoint = Instant::now();
+						}
+					}
+				}
+			}
+
+			self.save()?;
+
+			if self.replace {
+				swap(base, &self.staging, &self.to)?;
+			} else {
+				fs::rename(&self.staging, &self.to)?;
+				tags::rename(base, &self.staging, &self.to)?;
+			}
+
+			self.phase = Phase::Committed;
+			self.save()?;
+		}
+
+		if self.mode == Mode::Move {
+			match rm(&self.from) {
+				Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+				_ => tags::forget(base, &self.from)?
+			}
+		}
+
+		fs::remove_file(&self.location)
+	}
+
+	/// Discards the partial copy, leaving the source as it was.
+	fn rollback(self, base: &Path) -> Result<()> {
+		if self.phase == Phase::Committed {
+			return Err(Error::new(ErrorKind::InvalidInput, "The transfer has already been committed, so can only be resumed"));
+		}
+
+		match rm(&self.staging) {
+			Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+			_ => tags::forget(base, &self.staging)?
+		}
+
+		fs::remove_file(&self.location)
+	}
+
+	/// Summarises the journal for `file::transfers`.
+	pub fn progress(&self, store: &Store) -> Progress {
+		let files = self.entries.iter().filter(|entry| entry.kind == Kind::File);
+
+		Progress {
+			mode: self.mode,
+			from: store.relative(&self.from),
+			to: store.relative(&self.to),
+			phase: self.phase,
+			files: files.clone().count(),
+			verified: files.clone().filter(|entry| entry.verified.is_some()).count(),
+			bytes: files.clone().map(|entry| entry.size).sum(),
+			bytes_verified: files.filter(|entry| entry.verified.is_some()).map(|entry| entry.size).sum(),
+		}
+	}
+}
+
+#[derive(Serialize, Debug)]
+pub struct Progress {
+	mode: Mode,
+	from: PathBuf,
+	to: PathBuf,
+	phase: Phase,
+	files: usize,
+	verified: usize,
+	bytes: u64,
+	bytes_verified: u64,
+}
+
+/// Copies a file, then reads the copy back to check that it matches the source in size and hash. Returns the hash.
+fn transfer(from: &Path, to: &Path) -> Result<String> {
+	let stat = from.metadata()?;
+	let mut source = Hashing::new(File::open(from)?);
+	let mut target = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(to)?;
+
+	std::io::copy(&mut source, &mut target)?;
+	target.set_permissions(stat.permissions())?;
+	target.set_modified(stat.modified()?)?;
+	target.sync_all()?;
+
+	let (expected, copied) = source.finish();
+	let mut copy = Hashing::new(File::open(to)?);
+	let verified = std::io::copy(&mut copy, &mut std::io::sink())?;
+	let (actual, _) = copy.finish();
+
+	if copied != stat.size() || verified != copied || actual != expected {
+		Err(Error::new(ErrorKind::InvalidData, format!("{:?} changed or was corrupted while being copied", from)))?;
+	}
+
+	Ok(actual)
+}
+
+/// Hashes what is read through it with SHA-256.
+pub struct Hashing<R> {
+	inner: R,
+	hasher: Sha256,
+	len: u64,
+}
+
+impl<R> Hashing<R> {
+	pub fn new(inner: R) -> Self {
+		Self { inner, hasher: Sha256::new(), len: 0 }
+	}
+
+	/// The hex encoded hash and number of bytes read.
+	pub fn finish(self) -> (String, u64) {
+		let hash = self.hasher.finalize()
+			.iter()
+			.map(|byte| format!("{:02x}", byte))
+			.collect();
+
+		(hash, self.len)
+	}
+}
+
+impl<R: Read> Read for Hashing<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		let len = self.inner.read(buf)?;
+		self.hasher.update(&buf[..len]);
+		self.len += len as u64;
+		Ok(len)
+	}
+}
+
+/// Whether `from` and `to` would end up on different filesystems.
+fn crosses_devices(from: &Path, to: &Path) -> Result<bool> {
+	let device = from.symlink_metadata()?.dev();
+	let parent = to.parent().unwrap_or(to).metadata()?.dev();
+
+	Ok(device != parent)
+}
+
+/// Renames `from` to `to`. Between filesystems, the move is journaled, resuming any earlier attempt.
+pub fn relocate(base: &Path, from: &Path, to: &Path) -> Result<()> {
+	if let Some(journal) = Journal::find(base, Mode::Move, from, to)? {
+		return journal.run(base);
+	}
+
+	match fs::rename(from, to) {
+		Err(err) if err.kind() == ErrorKind::CrossesDevices => Journal::create(base, Mode::Move, from, to, false)?.run(base),
+		Err(err) => Err(err),
+		Ok(()) => tags::rename(base, from, to)
+	}
+}
+
+/// Copies `from` to `to`. Between filesystems, the copy is journaled, resuming any earlier attempt.
+pub fn duplicate(base: &Path, from: &Path, to: &Path) -> Result<()> {
+	if let Some(journal) = Journal::find(base, Mode::Copy, from, to)? {
+		return journal.run(base);
+	}
+
+	if crosses_devices(from, to)? {
+		Journal::create(base, Mode::Copy, from, to, false)?.run(base)
+	} else {
+		copy(base, from, to)
+	}
+}
+
+/// Moves or copies `from` to `to`, replacing whatever is there, which is left as it was should the transfer fail. A move
+/// within a filesystem is a single rename. Otherwise the transfer is made to a staging path beside `to`, which is then
+/// swapped into place: at once if neither is a directory, and otherwise by moving what is at `to` aside first.
+pub fn replace(base: &Path, mode: Mode, from: &Path, to: &Path) -> Result<()> {
+	if let Some(journal) = Journal::find(base, mode, from, to)? {
+		return journal.run(base);
+	}
+
+	if crosses_devices(from, to)? {
+		return Journal::create(base, mode, from, to, true)?.run(base);
+	}
+
+	if mode == Mode::Move {
+		return swap(base, from, to);
+	}
+
+	let staged = temp::name(to, "replacing");
+
+	if let Err(err) = copy(base, from, &staged).and_then(|_| swap(base, &staged, to)) {
+		if staged.symlink_metadata().is_ok() {
+			let _ = rm(&staged).and_then(|_| tags::forget(base, &staged));
+		}
+
+		return Err(err);
+	}
+
+	Ok(())
+}
+
+/// Moves `staged` to `to`, replacing whatever is there. A directory at either is swapped by moving what is at `to`
+/// aside first, which is put back should the move fail.
+pub fn swap(base: &Path, staged: &Path, to: &Path) -> Result<()> {
+	let aside = temp::name(to, "replaced");
+
+	let set_aside = match to.symlink_metadata() {
+		Ok(stat) if stat.is_dir() || staged.symlink_metadata()?.is_dir() => {
+			fs::rename(to, &aside)?;
+			true
+		},
+		Ok(_) => false,
+		Err(err) if err.kind() == ErrorKind::NotFound => false,
+		Err(err) => return Err(err)
+	};
+
+	if let Err(err) = fs::rename(staged, to) {
+		if set_aside {
+			let _ = fs::rename(&aside, to);
+		}
+
+		return Err(err);
+	}
+
+	tags::rename(base, staged, to)?;
+
+	match set_aside {
+		true => rm(&aside),
+		false => Ok(())
+	}
+}
+
+/// Abandons an interrupted move or copy from `from` to `to`.
+pub fn rollback(base: &Path, mode: Mode, from: &Path, to: &Path) -> Result<()> {
+	match Journal::find(base, mode, from, to)? {
+		Some(journal) => journal.rollback(base),
+		None => Err(Error::new(ErrorKind::NotFound, "No interrupted transfer between these paths"))
+	}
+}
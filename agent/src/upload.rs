@@ -0,0 +1,192 @@
+use crate::{
+	compress,
+	create_meta,
+	crypt,
+	format,
+	meta,
+	store::Store,
+	tags,
+	temp,
+	ReadSeek
+};
+use serde::Serialize;
+use std::{
+	fs,
+	fs::File,
+	fs::OpenOptions,
+	io::BufRead,
+	io::BufReader,
+	io::Error,
+	io::ErrorKind,
+	io::Read,
+	io::Result,
+	path::Path,
+	path::PathBuf,
+	time::SystemTime
+};
+
+/// Directory below the meta directory holding one directory per staged upload, with a file per part.
+pub const UPLOADS: &str = "uploads";
+
+/// A part of a staged upload, as it is listed.
+#[derive(Serialize, Debug, Clone)]
+pub struct Part {
+	pub number: u32,
+
+	/// The part's logical size, before it was encrypted.
+	pub size: u64,
+
+	#[serde(with = "format::timestamp")]
+	pub modified: SystemTime,
+}
+
+/// The directory of the upload `id`. IDs are chosen by the server and may only contain ASCII letters, digits, `-` and
+/// `_`, so they can't point outside the uploads.
+fn dir(base: &Path, id: &str) -> Result<PathBuf> {
+	match id {
+		"" => Err(Error::new(ErrorKind::InvalidInput, "Upload IDs can't be empty")),
+		id if id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => Ok(meta(base).join(UPLOADS).join(id)),
+		id => Err(Error::new(ErrorKind::InvalidInput, format!("`{}` isn't an upload", id)))
+	}
+}
+
+/// Locks the upload in `dir`, exclusively to add, join or discard parts, or shared to list them, so that a listing waits
+/// for parts still arriving rather than missing them. The lock is released when the returned file is dropped.
+fn lock(dir: &Path, exclusive: bool) -> Result<File> {
+	let file = OpenOptions::new()
+		.read(true)
+		.write(exclusive)
+		.create(exclusive)
+		.truncate(false)
+		.open(dir.join(".lock"))?;
+
+	match exclusive {
+		true => file.lock()?,
+		false => file.lock_shared()?
+	}
+
+	Ok(file)
+}
+
+/// Stores `from` as part `number` of the upload `id`, starting the upload if it is the first part. A part is only put in
+/// place once all of it has arrived, replacing any part already stored under its number.
+pub fn part(store: &Store, id: &str, number: u32, from: impl Read) -> Result<()> {
+	let dir = dir(store.base(), id)?;
+	create_meta(&dir)?;
+	let _lock = lock(&dir, true)?;
+
+	let tmp = dir.join(format!(".{}.partial", number));
+	let file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(&tmp)?;
+
+	// Parts are short-lived, so they are encrypted but never compressed.
+	match store.encode(from, file, false) {
+		Ok(()) => fs::rename(&tmp, dir.join(number.to_string())),
+		Err(err) => {
+			let _ = fs::remove_file(&tmp);
+			Err(err)
+		}
+	}
+}
+
+/// The parts of the upload `id` which have arrived in full, by number.
+pub fn parts(store: &Store, id: &str) -> Result<Vec<Part>> {
+	let dir = dir(store.base(), id)?;
+	let _lock = lock(&dir, false)?;
+	let mut parts = Vec::new();
+
+	for entry in dir.read_dir()? {
+		let entry = entry?;
+
+		let Some(number) = entry.file_name().to_str().and_then(|name| name.parse().ok()) else {
+			continue;
+		};
+
+		let stat = entry.metadata()?;
+		let (size, _) = store.head(entry.path(), &stat, 0);
+
+		parts.push(Part { number, size, modified: stat.modified()? });
+	}
+
+	parts.sort_by_key(|part| part.number);
+	Ok(parts)
+}
+
+/// Joins `numbers` parts of the upload `id`, in the order given, into `path`, then discards the upload. The file is
+/// written next to `path` and renamed into place, so an interrupted commit leaves whatever was there untouched.
+pub fn commit(store: &Store, id: &str, path: &Path, numbers: &[u32]) -> Result<()> {
+	let dir = dir(store.base(), id)?;
+
+	if numbers.is_empty() {
+		return Err(Error::new(ErrorKind::InvalidInput, "An upload needs at least one part"));
+	}
+
+	let _lock = lock(&dir, true)?;
+
+	let parts = numbers.iter()
+		.map(|number| store.open(dir.join(number.to_string())))
+		.collect::<Result<Vec<Box<dyn ReadSeek>>>>()?;
+
+	let mut from = BufReader::with_capacity(compress::FRAME_SIZE, parts.into_iter()
+		.fold(Box::new(std::io::empty()) as Box<dyn Read>, |joined, part| Box::new(joined.chain(part))));
+
+	let compress = store.policy.applies(store.relative(path)) && compress::compressible(path, from.fill_buf()?);
+
+	let (tmp, file) = temp::file(path, "upload")?;
+
+	if let Ok(stat) = path.metadata() {
+		file.set_permissions(stat.permissions())?;
+	}
+
+	if let Err(err) = store.encode(from, file, compress).and_then(|_| tags::carry(path, &tmp)).and_then(|_| fs::rename(&tmp, path)) {
+		let _ = fs::remove_file(&tmp);
+		return Err(err);
+	}
+
+	fs::remove_dir_all(dir)
+}
+
+/// Re-encrypts the parts of every staged upload which were encrypted with a key other than `active`, so that they can
+/// still be joined once the other keys are dropped.
+pub fn reencrypt(store: &Store, active: u32) -> Result<()> {
+	let uploads = match meta(store.base()).join(UPLOADS).read_dir() {
+		Ok(uploads) => uploads,
+		Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err)
+	};
+
+	for upload in uploads {
+		let dir = upload?.path();
+		let _lock = lock(&dir, true)?;
+
+		for entry in dir.read_dir()? {
+			let part = entry?.path();
+
+			if part.file_name().and_then(|name| name.to_str()).is_none_or(|name| name.parse::<u32>().is_err()) {
+				continue;
+			}
+
+			if crypt::Header::read(File::open(&part)?)?.is_some_and(|header| header.key != active) {
+				store.replace(&part, |file| store.vault.write(store.vault.open(&part)?, file))?;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Discards the upload `id` and every part of it. Uploads which no part has arrived for have nothing to discard.
+pub fn abort(base: &Path, id: &str) -> Result<()> {
+	let dir = dir(base, id)?;
+
+	let _lock = match lock(&dir, true) {
+		Ok(lock) => lock,
+		Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err)
+	};
+
+	fs::remove_dir_all(dir)
+}
@@ -21,29 +21,67 @@ export function getGithubLoginUrl(): string {
 
 export { default as config } from '../config.json';
 
+/**
+ * Version of the agent's output this client understands. Every response of records starts with a header naming the
+ * version it was written in.
+ */
+export const SCHEMA = 3;
+
+export type Header = { schema: number, format: "json" | "ron" | "cbor" };
+
+/**
+ * Checks the header a response of records starts with, failing if the records which follow can't be understood.
+ */
+function checkHeader(header: Header): Header {
+	if (header?.schema !== SCHEMA)
+		throw new Error(`Unsupported agent output schema ${header?.schema}; expected ${SCHEMA}`);
+
+	return header;
+}
+
 export type FileEntry = {
 	file: string,
 	size: number,
+	mime: string,
 	modified: Date,
-	created: Date
+	created: Date,
+	thumbnail: boolean,
+	links: number,
+	tags: string[]
 };
 export type DirEntry = {
-	dir: string
+	dir: string,
+	tags: string[]
+};
+export type LinkEntry = {
+	link: string,
+	target: string
 };
 
-export type DirContents = FileEntry | DirEntry;
+export type DirContents = FileEntry | DirEntry | LinkEntry;
+
+/**
+ * The URL of a file or directory under `/api/files`. Each segment is escaped, so names may contain anything.
+ */
+export function fileUrl(path: string): URL {
+	const segments = path.split('/')
+		.filter(segment => segment.length > 0)
+		.map(segment => encodeURIComponent(segment));
+
+	return new URL(`${config.apiLocation}/files/${segments.join('/')}`);
+}
 
 export async function* readDir(dir: string, depth: number = 100): AsyncGenerator<DirContents> {
-	const url = new URL(config.apiLocation + "/system");
-	url.searchParams.set("command", "file::lsdir");
-	url.searchParams.set("args", [dir, "--depth", depth].join(';'));
+	const url = fileUrl(dir);
+	url.searchParams.set("list", "");
+	url.searchParams.set("depth", depth.toString());
 
 	const token = await Promise.resolve(window.localStorage.getItem("token"))
 		.then(res => !res ? Promise.reject("No token") : Promise.resolve(res))
 		.then(token => JSON.parse(token) as string);
 
 	const reader = await fetch(url, {
-		method: "POST",
+		method: "GET",
 		headers: {
 			Authorization: `Bearer ${token}`
 		}
@@ -73,16 +111,108 @@ export async function* readDir(dir: string, depth: number = 100): AsyncGenerator
 				yield JSON.parse(chunk);
 	}
 
+	let header: Header | undefined;
+
 	for await (const dirent of read(reader))
-		if ("File" in dirent)
+		if (!header)
+			header = checkHeader(dirent);
+		else if ("File" in dirent)
 			yield {
 				file: dirent.File.path,
-				created: new Date(dirent.File.created.secs_since_epoch * 1000),
-				modified: new Date(dirent.File.modified.secs_since_epoch * 1000),
-				size: dirent.File.size
+				created: new Date(dirent.File.created),
+				modified: new Date(dirent.File.modified),
+				size: dirent.File.size,
+				mime: dirent.File.mime,
+				thumbnail: dirent.File.thumbnail,
+				links: dirent.File.links,
+				tags: dirent.File.tags ?? []
 			};
 		else if ("Dir" in dirent)
-			yield { dir: dirent.Dir };
+			yield { dir: dirent.Dir.path, tags: dirent.Dir.tags ?? [] };
+		else if ("Link" in dirent)
+			yield { link: dirent.Link.path, target: dirent.Link.target };
+}
+
+export type Effect =
+	| { effect: "create", path: string, kind: "file" | "dir" | "symlink" | "hardlink" }
+	| { effect: "overwrite", path: string }
+	| { effect: "remove", path: string, kind?: "file" | "dir" | "symlink" }
+	| { effect: "move" | "copy", path: string, to: string }
+	| { effect: "set_metadata", path: string, modified?: string, accessed?: string, mode?: number }
+	| { effect: "conflict", path: string, reason: string };
+
+/**
+ * Lists the changes a command would make without making them, so that they can be confirmed first. Input the command
+ * reads, such as the operations of a `file::batch`, is passed as `body`.
+ */
+export async function preview(command: string, args: string[], body?: BodyInit): Promise<Effect[]> {
+	const url = new URL(config.apiLocation + "/system");
+	url.searchParams.set("command", command);
+	url.searchParams.set("args", args.join(';'));
+	url.searchParams.set("dry_run", "true");
+
+	const token = await Promise.resolve(window.localStorage.getItem("token"))
+		.then(res => !res ? Promise.reject("No token") : Promise.resolve(res))
+		.then(token => JSON.parse(token) as string);
+
+	return await fetch(url, { method: "POST", body, headers: { Authorization: `Bearer ${token}` } })
+		.then(res => res.text())
+		.then(text => text.split("\n")
+			.filter(line => line.trim().length > 0)
+			.map(line => JSON.parse(line)))
+		.then(([header, ...effects]) => (checkHeader(header), effects as Effect[]));
+}
+
+export type Limits = { used: number, soft: number | null, hard: number | null };
+export type Statfs = {
+	type: string,
+	read_only: boolean,
+	noexec: boolean,
+	bytes: { total: number, free: number, available: number },
+	inodes: { total: number, free: number, available: number },
+	quota: { bytes: Limits, inodes: Limits } | null,
+	left: { bytes: number, inodes: number | null }
+};
+
+/**
+ * Describes the filesystem holding the user's files. `left` is what can still be uploaded once the user's quota is
+ * taken into account; uploads larger than that are refused with `507 Insufficient Storage`.
+ */
+export async function statfs(): Promise<Statfs> {
+	const url = new URL(config.apiLocation + "/system");
+	url.searchParams.set("command", "file::statfs");
+
+	const token = await Promise.resolve(window.localStorage.getItem("token"))
+		.then(res => !res ? Promise.reject("No token") : Promise.resolve(res))
+		.then(token => JSON.parse(token) as string);
+
+	return await fetch(url, { method: "POST", headers: { Authorization: `Bearer ${token}` } })
+		.then(res => res.ok ? res.text() : Promise.reject("Failed to read storage information"))
+		.then(text => text.split("\n")
+			.filter(line => line.trim().length > 0)
+			.map(line => JSON.parse(line)))
+		.then(([header, statfs]) => (checkHeader(header), statfs as Statfs));
+}
+
+export type ThumbnailSize = 128 | 256 | 1024;
+
+/**
+ * Resolves to an object URL for the file's thumbnail. The modification time is part of the URL, so the browser can
+ * cache thumbnails for as long as the file remains unchanged.
+ */
+export async function thumbnail(file: FileEntry, size: ThumbnailSize = 256): Promise<string> {
+	const url = new URL(config.apiLocation + "/thumbnail");
+	url.searchParams.set("path", file.file);
+	url.searchParams.set("size", size.toString());
+	url.searchParams.set("v", file.modified.getTime().toString());
+
+	const token = await Promise.resolve(window.localStorage.getItem("token"))
+		.then(res => !res ? Promise.reject("No token") : Promise.resolve(res))
+		.then(token => JSON.parse(token) as string);
+
+	return await fetch(url, { headers: { Authorization: `Bearer ${token}` } })
+		.then(res => res.ok ? res.blob() : Promise.reject("No thumbnail available"))
+		.then(blob => URL.createObjectURL(blob));
 }
 
 export async function loadUser(): Promise<LoginResult | null> {
@@ -24,7 +24,9 @@ export default function Files(props: Props) {
 		return <>
 			{path}
 			<ul>
-				{index.map(i => 'file' in i ? <li key={i.file}>{i.file}</li> : <li key={i.dir}>{i.dir}</li>)}
+				{index.map(i => 'file' in i ? <li key={i.file}>{i.file}</li>
+					: 'link' in i ? <li key={i.link}>{i.link} &rarr; {i.target}</li>
+					: <li key={i.dir}>{i.dir}</li>)}
 			</ul>
 		</>;
 	else return <Loading />;
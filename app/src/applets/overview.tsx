@@ -3,11 +3,30 @@ import * as router from 'react-router';
 
 import { user } from '../main.js';
 import $ from "../localisation.js";
+import * as api from "../api.js";
+
+/**
+ * Formats a byte count in binary units, such as `1.5 GiB`.
+ */
+function bytes(count: number): string {
+	const units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+	const exponent = Math.min(Math.floor(Math.log2(Math.max(count, 1)) / 10), units.length - 1);
+
+	return `${(count / 1024 ** exponent).toFixed(exponent > 0 ? 1 : 0)} ${units[exponent]}`;
+}
 
 export default function Overview() {
 	const login = React.useContext(user)!;
 
+	const [statfs, setStatfs] = React.useState<api.Statfs | null>(null);
+
+	React.useEffect(() => void api.statfs()
+		.then(statfs => setStatfs(statfs)), []);
+
 	return <>
 		{$`Wilkommen ${login.user.displayName}!`}
+		{statfs && <p>
+			{$`${bytes(statfs.left.bytes)} of ${bytes(statfs.quota?.bytes.hard ?? statfs.bytes.total)} left`}
+		</p>}
 	</>;
-}
\ No newline at end of file
+}
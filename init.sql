@@ -0,0 +1,90 @@
+-- Every call made to the agent through `/api/system`
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    "user" INTEGER NOT NULL,
+    command TEXT NOT NULL,
+    args TEXT[] NOT NULL DEFAULT '{}',
+    paths TEXT[] NOT NULL DEFAULT '{}',
+    bytes_in BIGINT NOT NULL DEFAULT 0,
+    bytes_out BIGINT NOT NULL DEFAULT 0,
+    exit_code INTEGER,
+    error TEXT,
+    duration_ms BIGINT NOT NULL,
+    client_ip TEXT,
+    request_id TEXT NOT NULL,
+    dry_run BOOLEAN NOT NULL DEFAULT FALSE,
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user ON audit_log ("user", id DESC);
+CREATE INDEX IF NOT EXISTS audit_log_paths ON audit_log USING GIN (paths);
+
+-- Passwords for clients which can only sign in with a username and password, such as WebDAV mounts. Only their
+-- SHA-256 is kept.
+CREATE TABLE IF NOT EXISTS app_passwords (
+    id BIGSERIAL PRIMARY KEY,
+    "user" INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    hash TEXT NOT NULL UNIQUE,
+    created TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS app_passwords_user ON app_passwords ("user");
+
+-- Access keys for the S3-compatible API. SigV4 signs requests with the secret itself, so unlike app passwords it has
+-- to be kept as it is.
+CREATE TABLE IF NOT EXISTS s3_keys (
+    id BIGSERIAL PRIMARY KEY,
+    "user" INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    access_key TEXT NOT NULL UNIQUE,
+    secret TEXT NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS s3_keys_user ON s3_keys ("user");
+
+-- Multipart uploads started through the S3-compatible API. Their parts are staged by the agent in the user's storage.
+CREATE TABLE IF NOT EXISTS s3_uploads (
+    id TEXT PRIMARY KEY,
+    "user" INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS s3_uploads_user ON s3_uploads ("user", key);
+
+-- Resumable uploads through the tus protocol. Their data is staged by the agent in the user's storage, one part per
+-- `PATCH`, until `length` bytes have arrived and it is moved to `path`. Uploads are discarded once `expires` passes.
+CREATE TABLE IF NOT EXISTS tus_uploads (
+    id TEXT PRIMARY KEY,
+    "user" INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    length BIGINT NOT NULL,
+
+    -- `Upload-Metadata` as the client sent it, to be handed back.
+    metadata TEXT,
+    created TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires TIMESTAMPTZ NOT NULL,
+    completed TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS tus_uploads_expires ON tus_uploads (expires);
+
+-- Links through which anyone may download `path`, or in `drop` mode, upload files into it. Passwords are kept as argon2
+-- hashes, since unlike app passwords, people choose them.
+CREATE TABLE IF NOT EXISTS shares (
+    id TEXT PRIMARY KEY,
+    "user" INTEGER NOT NULL,
+    path TEXT NOT NULL,
+    mode TEXT NOT NULL CHECK (mode IN ('read', 'drop')),
+    password TEXT,
+    expires TIMESTAMPTZ,
+    max_downloads INTEGER,
+    downloads INTEGER NOT NULL DEFAULT 0,
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS shares_user ON shares ("user");
@@ -0,0 +1,389 @@
+use crate::Args;
+use actix_web::{
+    web::Payload,
+    HttpRequest
+};
+use futures_util::StreamExt;
+use serde::{
+    de::DeserializeOwned,
+    Deserialize,
+    Serialize
+};
+use sha2::{
+    Digest,
+    Sha256
+};
+use sqlx::{
+    FromRow,
+    PgPool
+};
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    process::ExitStatus,
+    process::Stdio,
+    sync::Arc,
+    sync::Mutex,
+    time::Duration,
+    time::SystemTime
+};
+use tokio::io::AsyncWriteExt;
+use tokio::process::{
+    Child,
+    ChildStdin,
+    Command
+};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct StorageProps {
+    pub display: String,
+    pub email: String,
+
+    #[sqlx(rename = "unix_uid")]
+    pub uid: i32,
+
+    #[sqlx(rename = "data")]
+    pub base: String,
+
+    #[sqlx(rename = "uid")]
+    pub pk: i32
+}
+
+/// Looks up where a user's files live, and which UNIX user the agent should act as.
+pub async fn storage(pool: &PgPool, email: &str) -> sqlx::Result<StorageProps> {
+    let row = sqlx::query(r#"SELECT * FROM users LEFT JOIN storage ON users.uid = storage.uid WHERE email = $1"#)
+        .bind(email)
+        .fetch_one(pool)
+        .await?;
+
+    StorageProps::from_row(&row)
+}
+
+/// Prepares an agent invocation on behalf of the user. The caller appends the command and its arguments. The agent
+/// runs under the configured resource limits, and is killed if its handle is dropped.
+pub fn command(args: &Args, storage: &StorageProps, req: &HttpRequest) -> Command {
+    let mut command = unattended(args, storage);
+
+    // Users whose keys are wrapped by a passphrase unlock them per request. The environment keeps it out of `ps`.
+    if let Some(passphrase) = req.headers().get("X-Storage-Passphrase").and_then(|i| i.to_str().ok()) {
+        command.env("CLOUD_PASSPHRASE", passphrase);
+    }
+
+    command
+}
+
+/// Prepares an agent invocation the server makes by itself, such as to discard expired uploads, which no request can
+/// unlock the user's keys for.
+pub fn unattended(args: &Args, storage: &StorageProps) -> Command {
+    let mut command = Command::new("agent");
+    command.args(["--base", &storage.base]);
+    command.kill_on_drop(true);
+
+    let limits = [
+        (libc::RLIMIT_CPU, args.agent_cpu),
+        (libc::RLIMIT_NOFILE, Some(args.agent_files)),
+        (libc::RLIMIT_AS, args.agent_memory),
+    ];
+
+    // Limits survive `exec`, so they are set in the forked child just before it becomes the agent. Only
+    // async-signal-safe calls are allowed here.
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in limits {
+                if let Some(limit) = limit {
+                    let limit = libc::rlimit { rlim_cur: limit as libc::rlim_t, rlim_max: limit as libc::rlim_t };
+
+                    if libc::setrlimit(resource, &limit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+
+            Ok(())
+        });
+    }
+
+    if let Some(ref key) = args.master_key {
+        command.arg("--master-key").arg(key);
+    }
+
+    if args.allow_absolute_links {
+        command.arg("--allow-absolute-links");
+    }
+
+    command.arg(storage.uid.to_string());
+    command
+}
+
+/// Version of the agent's output this server understands.
+pub const SCHEMA: u32 = 3;
+
+/// The record the agent prints ahead of any others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub schema: u32,
+}
+
+/// An entry of a listing, or the output of `file::metadata`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirEntry {
+    Dir {
+        path: PathBuf,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    File {
+        path: PathBuf,
+        size: u64,
+        mime: String,
+        #[serde(with = "humantime_serde")]
+        modified: SystemTime,
+        #[serde(with = "humantime_serde")]
+        created: SystemTime,
+        #[serde(default)]
+        thumbnail: bool,
+        #[serde(default)]
+        links: u64,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    Link {
+        path: PathBuf,
+        target: PathBuf,
+    },
+}
+
+/// A part of an upload staged by the agent, as `upload::list` prints it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Part {
+    pub number: u32,
+    pub size: u64,
+    #[serde(with = "humantime_serde")]
+    pub modified: SystemTime,
+}
+
+/// The free space and quota figures the server acts on, out of those `file::statfs` reports.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Statfs {
+    pub left: Left,
+}
+
+/// What the user can still create. `inodes` is `None` if neither the filesystem nor the user's quota limits them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Left {
+    pub bytes: u64,
+    pub inodes: Option<u64>,
+}
+
+/// Runs an agent command which prints records, and reads them. `None` if the command fails or its output can't be
+/// understood.
+async fn records<T: DeserializeOwned>(args: &Args, storage: &StorageProps, req: &HttpRequest, arguments: &[&str]) -> Option<Vec<T>> {
+    let output = command(args, storage, req)
+        .args(arguments)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .output();
+
+    let output = tokio::time::timeout(timeout(args, arguments.first()?), output)
+        .await
+        .ok()?
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let mut records = serde_json::Deserializer::from_slice(&output.stdout).into_iter::<serde_json::Value>();
+    let header: Header = serde_json::from_value(records.next()?.ok()?).ok()?;
+
+    if header.schema != SCHEMA {
+        log::error!("The agent prints schema {}, but only schema {} is understood", header.schema, SCHEMA);
+        return None;
+    }
+
+    records
+        .map(|record| serde_json::from_value(record.ok()?).ok())
+        .collect()
+}
+
+/// Runs an agent command which prints a single record, and reads that record.
+async fn query<T: DeserializeOwned>(args: &Args, storage: &StorageProps, req: &HttpRequest, arguments: &[&str]) -> Option<T> {
+    records(args, storage, req, arguments).await?.into_iter().next()
+}
+
+/// Describes a single path, following symbolic links. `None` if it doesn't exist or can't be accessed.
+pub async fn metadata(args: &Args, storage: &StorageProps, req: &HttpRequest, path: &str) -> Option<DirEntry> {
+    query(args, storage, req, &["file::metadata", "--follow", "--", path]).await
+}
+
+/// Describes a single path, or the symbolic link at it. `None` if it doesn't exist or can't be accessed.
+pub async fn lstat(args: &Args, storage: &StorageProps, req: &HttpRequest, path: &str) -> Option<DirEntry> {
+    query(args, storage, req, &["file::metadata", "--", path]).await
+}
+
+/// Lists what is below a directory, `depth` levels deep, or all the way down if `None`. `None` if it isn't a directory.
+pub async fn list(args: &Args, storage: &StorageProps, req: &HttpRequest, path: &str, depth: Option<u32>) -> Option<Vec<DirEntry>> {
+    let depth = depth.map(|depth| format!("--depth={}", depth));
+    let arguments = ["file::lsdir"].into_iter()
+        .chain(depth.as_deref())
+        .chain(["--", path])
+        .collect::<Vec<_>>();
+
+    records(args, storage, req, &arguments).await
+}
+
+/// The parts of a staged upload which have arrived in full, by number. `None` if there is no such upload.
+pub async fn parts(args: &Args, storage: &StorageProps, req: &HttpRequest, id: &str) -> Option<Vec<Part>> {
+    records(args, storage, req, &["upload::list", "--", id]).await
+}
+
+/// Describes the filesystem holding the user's files, and how much of it their quota leaves them.
+pub async fn statfs(args: &Args, storage: &StorageProps, req: &HttpRequest) -> Option<Statfs> {
+    query(args, storage, req, &["file::statfs"]).await
+}
+
+/// How long an invocation of `command` may run before it is killed.
+pub fn timeout(args: &Args, command: &str) -> Duration {
+    let seconds = args.agent_timeout_for
+        .iter()
+        .rev()
+        .find_map(|(name, seconds)| (name == command).then_some(*seconds))
+        .unwrap_or(args.agent_timeout);
+
+    Duration::from_secs(seconds)
+}
+
+/// Parses a `command=seconds` pair for `--agent-timeout-for`.
+pub fn parse_timeout(value: &str) -> Result<(String, u64), String> {
+    let (command, seconds) = value.split_once('=')
+        .ok_or_else(|| format!("expected `command=seconds`, found `{}`", value))?;
+
+    Ok((command.to_owned(), seconds.parse().map_err(|err| format!("invalid timeout `{}`: {}", seconds, err))?))
+}
+
+/// How a supervised agent came to an end.
+#[derive(Debug)]
+pub enum Ending {
+    Exited(ExitStatus),
+    TimedOut,
+
+    /// Whoever was waiting on its output went away.
+    Abandoned,
+}
+
+/// Waits for the agent to exit, killing it if it outlives `timeout` or `abandoned` is cancelled.
+pub async fn supervise(agent: &mut Child, timeout: Duration, abandoned: CancellationToken) -> io::Result<Ending> {
+    let ending = tokio::select! {
+        status = agent.wait() => return status.map(Ending::Exited),
+        _ = tokio::time::sleep(timeout) => Ending::TimedOut,
+        _ = abandoned.cancelled() => Ending::Abandoned,
+    };
+
+    agent.kill().await?;
+    Ok(ending)
+}
+
+/// What the client sent the agent on stdin.
+#[derive(Debug, Clone, Default)]
+pub struct Received {
+    pub bytes: u64,
+
+    /// The SHA-256 of what was received, in hex.
+    pub sha256: String,
+
+    /// Why the input was cut off rather than closed, in which case the agent was killed.
+    pub error: Option<String>,
+}
+
+/// Feeds the request body to the agent as it arrives, no faster than the agent reads it. The agent only sees the end
+/// of its input once the body is complete and matches `expected`, if given. Otherwise the agent is killed while its
+/// stdin is still open, so that it never takes part of an upload for all of it. Uploads are written beside the file
+/// they replace and only moved over it at the end of their input, so the file is left as it was. `exited` is cancelled
+/// once the agent has exited.
+pub async fn feed(mut stdin: ChildStdin, mut body: Payload, expected: Option<Vec<u8>>, abandoned: CancellationToken, exited: CancellationToken) -> Received {
+    let mut hasher = Sha256::new();
+    let mut received = Received::default();
+
+    let mut error = loop {
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                // The agent closes its end once it has no use for more input, typically because it failed. Its exit
+                // status tells why, so the rest of the body is simply left unread.
+                if let Err(err) = stdin.write_all(&chunk).await {
+                    log::debug!("{:?}", err);
+                    break None;
+                }
+
+                hasher.update(&chunk);
+                received.bytes += chunk.len() as u64;
+            },
+            Some(Err(err)) => break Some(format!("The upload was cut off: {}", err)),
+            None => break None
+        }
+    };
+
+    let sha256 = hasher.finalize();
+    received.sha256 = sha256.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    if error.is_none() && expected.is_some_and(|expected| expected != sha256.as_slice()) {
+        error = Some("The upload doesn't match its Content-Digest.".to_owned());
+    }
+
+    if let Some(error) = error {
+        received.error = Some(error);
+        abandoned.cancel();
+        exited.cancelled().await;
+    }
+
+    // Closing stdin is what tells the agent that its input is complete.
+    drop(stdin);
+    received
+}
+
+/// Caps how many agents each user may have running at once.
+#[derive(Debug, Clone, Default)]
+pub struct Limiter {
+    running: Arc<Mutex<HashMap<i32, usize>>>,
+    limit: usize,
+}
+
+impl Limiter {
+    pub fn new(limit: usize) -> Self {
+        Self { running: Arc::default(), limit }
+    }
+
+    /// Reserves a slot for the user, or `None` if all of theirs are taken. The slot is released with the permit.
+    pub fn acquire(&self, user: i32) -> Option<Permit> {
+        let mut running = self.running.lock().unwrap_or_else(|err| err.into_inner());
+        let count = running.entry(user).or_default();
+
+        if *count >= self.limit {
+            return None;
+        }
+
+        *count += 1;
+        Some(Permit { running: self.running.clone(), user })
+    }
+}
+
+#[derive(Debug)]
+pub struct Permit {
+    running: Arc<Mutex<HashMap<i32, usize>>>,
+    user: i32,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap_or_else(|err| err.into_inner());
+
+        if let Some(count) = running.get_mut(&self.user) {
+            *count -= 1;
+
+            if *count == 0 {
+                running.remove(&self.user);
+            }
+        }
+    }
+}
@@ -1,4 +1,9 @@
 use crate::{
+    agent,
+    agent::DirEntry,
+    agent::Ending,
+    agent::StorageProps,
+    audit,
     Args,
     HTTPClient
 };
@@ -19,6 +24,21 @@ use actix_web::{
     error::JsonPayloadError,
     error::PayloadError,
     get,
+    http::header,
+    http::Method,
+    http::header::Charset,
+    http::header::ContentDisposition,
+    http::header::DispositionParam,
+    http::header::DispositionType,
+    http::header::EntityTag,
+    http::header::ETag,
+    http::header::ExtendedValue,
+    http::header::HttpDate,
+    http::header::IfMatch,
+    http::header::IfModifiedSince,
+    http::header::IfNoneMatch,
+    http::header::IfUnmodifiedSince,
+    http::header::LastModified,
     middleware::from_fn,
     middleware::Next,
     post,
@@ -64,11 +84,17 @@ use std::{
     cell::Cell,
     cell::LazyCell,
     cell::RefCell,
-    process::Stdio
+    os::unix::process::ExitStatusExt,
+    process::Stdio,
+    time::Duration,
+    time::Instant,
+    time::SystemTime,
+    time::UNIX_EPOCH
 };
 use std::path::PathBuf;
-use tokio::io::AsyncWriteExt;
+use tokio::io::AsyncReadExt;
 use futures_util::StreamExt as _;
+use tokio_util::sync::CancellationToken;
 
 thread_local! {
     pub static RNG: RefCell<rand::rngs::ThreadRng> = RefCell::new(rand::rng());
@@ -117,7 +143,7 @@ struct OAuthResponse {
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
-    email: String,
+    pub email: String,
     #[serde(rename = "displayName")]
     display: String,
 }
@@ -272,56 +298,69 @@ pub async fn get_user(req: HttpRequest) -> Result<impl Responder> {
 pub struct SystemQueryParameterMap {
     command: Option<String>,
     args: Option<String>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
-pub struct StorageProps {
-    display: String,
-    email: String,
 
-    #[sqlx(rename = "unix_uid")]
-    uid: i32,
+    /// Have the agent print the changes the command would make instead of making them.
+    #[serde(default)]
+    dry_run: bool,
 
-    #[sqlx(rename = "data")]
-    base: String,
-
-    #[sqlx(rename = "uid")]
-    pk: i32
+    /// Format of the records the agent prints: `json` (the default), `ron` or `cbor`.
+    format: Option<String>,
 }
 
-#[post("/system")]
-pub async fn system(mut req: HttpRequest, pool: Data<PgPool>, query: Query<SystemQueryParameterMap>, mut body: Payload) -> Result<impl Responder> {
+/// Media types of the formats the agent can print records in.
+const FORMATS: &[(&str, &str)] = &[
+    ("json", "application/x-ndjson"),
+    ("ron", "application/ron"),
+    ("cbor", "application/cbor-seq"),
+];
+
+/// Resolves the storage of the signed-in user, or the response explaining why that isn't possible.
+pub async fn storage(req: &HttpRequest, pool: &PgPool) -> std::result::Result<StorageProps, HttpResponse> {
     let Some(user) = req.extensions().get::<User>().cloned() else {
-        return Ok(HttpResponse::Unauthorized().json(json! {{
+        return Err(HttpResponse::Unauthorized().json(json! {{
             "success": false,
             "msg": "Not signed in"
         }}));
     };
 
-    let user: StorageProps = match sqlx::query(r#"SELECT * FROM users LEFT JOIN storage ON users.uid = storage.uid WHERE email = $1"#)
-        .bind(&user.email)
-        .fetch_one(pool.get_ref())
+    agent::storage(pool, &user.email)
         .await
-    {
-        Ok(row) => match FromRow::from_row(&row) {
-            Ok(props) => props,
-            Err(err) => {
-                error!("{:?}", err);
-                return Ok(HttpResponse::InternalServerError().json(json! {{
-                    "success": false,
-                    "msg": "Internal server error.",
-                    "err": err.to_string()
-            }}));
-            }
-        },
-        Err(err) => {
+        .map_err(|err| {
             error!("{:?}", err);
-            return Ok(HttpResponse::InternalServerError().json(json! {{
+            HttpResponse::InternalServerError().json(json! {{
                 "success": false,
                 "msg": "Internal server error.",
                 "err": err.to_string()
-            }}));
-        }
+            }})
+        })
+}
+
+/// An agent command to run on behalf of the signed-in user, however the request spelled it.
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub command: String,
+    pub args: Vec<String>,
+
+    /// The path a `file::read` downloads, a `file::write` or `file::patch` uploads to, a `file::rm` deletes, an
+    /// `upload::commit` puts in place or a `file::lsdir` lists, so that it can be described or checked first.
+    pub path: Option<String>,
+
+    pub dry_run: bool,
+
+    /// The name and media type of the format records are printed in, if not the agent's default.
+    pub format: Option<(&'static str, &'static str)>,
+}
+
+/// Looks up one of [`FORMATS`] by name.
+pub fn format(name: &str) -> Option<(&'static str, &'static str)> {
+    FORMATS.iter().find(|(format, _)| *format == name).copied()
+}
+
+#[post("/system")]
+pub async fn system(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, query: Query<SystemQueryParameterMap>, body: Payload) -> Result<impl Responder> {
+    let user = match storage(&req, &pool).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res)
     };
 
     let Some(ref cmd) = query.command else {
@@ -331,45 +370,629 @@ pub async fn system(mut req: HttpRequest, pool: Data<PgPool>, query: Query<Syste
         }}));
     };
 
-    let args = query
+    let format = match query.format.as_deref() {
+        None => None,
+        Some(name) => match format(name) {
+            Some(format) => Some(format),
+            None => return Ok(HttpResponse::BadRequest().json(json! {{
+                "success": false,
+                "msg": "`format` must be one of `json`, `ron` or `cbor`"
+            }}))
+        }
+    };
+
+    let agent_args = query
         .args
         .as_ref()
-        .map(|i| i.split(';').collect::<Vec<&str>>())
+        .map(|i| i.split(';').map(str::to_owned).collect::<Vec<String>>())
         .unwrap_or(vec![]);
 
-    log::debug!("{:?}", &args);
+    log::debug!("{:?}", &agent_args);
+
+    let call = Call {
+        path: matches!(cmd.as_str(), "file::read" | "file::write" | "file::patch" | "file::rm").then(|| agent_args.first().cloned()).flatten(),
+        command: cmd.clone(),
+        args: agent_args,
+        dry_run: query.dry_run,
+        format,
+    };
+
+    run(&req, &pool, &args, &limiter, user, call, Some(body)).await
+}
+
+/// Runs an agent command, feeding it the request body, if any, and streaming its output back as the response. The call
+/// is recorded in the audit log once the agent has exited.
+pub async fn run(req: &HttpRequest, pool: &PgPool, args: &Args, limiter: &agent::Limiter, user: StorageProps, call: Call, body: Option<Payload>) -> Result<HttpResponse> {
+    let expected = match content_digest(req) {
+        Ok(expected) => expected,
+        Err(msg) => return Ok(HttpResponse::BadRequest().json(json! {{
+            "success": false,
+            "msg": msg
+        }}))
+    };
+
+
+    let started = Instant::now();
+    let request_id = request_id(req);
+    let mut record = audit::Record {
+        user: user.pk,
+        command: call.command.clone(),
+        args: call.args.clone(),
+        paths: Vec::new(),
+        bytes_in: 0,
+        bytes_out: 0,
+        exit_code: None,
+        error: None,
+        duration: Duration::ZERO,
+        client_ip: req.connection_info().realip_remote_addr().map(str::to_owned),
+        request_id: request_id.clone(),
+        dry_run: call.dry_run,
+    };
+
+    // Held until the agent exits, including the metadata lookup for downloads.
+    let Some(permit) = limiter.acquire(user.pk) else {
+        record.error = Some("Too many agents running.".to_owned());
+        audit::spawn(pool.clone(), record);
+
+        return Ok(too_many(request_id));
+    };
+
+    // Changes conditional on what they replace are refused if it has changed since the client last saw it.
+    if let Some(path) = &call.path
+        && CHANGES.contains(&call.command.as_str())
+        && CONDITIONS.iter().any(|condition| req.headers().contains_key(condition))
+        && let Precondition::Failed = precondition(req, agent::metadata(args, &user, req, path).await.as_ref(), false)
+    {
+        record.error = Some("Precondition failed.".to_owned());
+        record.duration = started.elapsed();
+        audit::spawn(pool.clone(), record);
+
+        return Ok(precondition_failed(request_id));
+    }
+
+    // Downloads are described up front so that browsers know what they are receiving.
+    let download = match (call.command.as_str(), &call.path) {
+        ("file::read", Some(path)) => match agent::metadata(args, &user, req, path).await {
+            Some(entry @ DirEntry::File { .. }) => match precondition(req, Some(&entry), true) {
+                Precondition::Holds => Some(entry),
+                Precondition::Failed => {
+                    record.error = Some("Precondition failed.".to_owned());
+                    record.duration = started.elapsed();
+                    audit::spawn(pool.clone(), record);
+
+                    return Ok(precondition_failed(request_id));
+                },
+                Precondition::NotModified => {
+                    record.duration = started.elapsed();
+                    audit::spawn(pool.clone(), record);
+
+                    let mut res = HttpResponse::NotModified();
+                    res.insert_header(("X-Request-Id", request_id));
+                    validate(&mut res, &entry);
+
+                    return Ok(res.finish());
+                }
+            },
+            _ => {
+                record.error = Some("No such file.".to_owned());
+                record.duration = started.elapsed();
+                audit::spawn(pool.clone(), record);
+
+                return Ok(HttpResponse::NotFound()
+                    .insert_header(("X-Request-Id", request_id))
+                    .json(json! {{
+                        "success": false,
+                        "msg": "No such file."
+                    }}));
+            }
+        },
+        _ => None
+    };
+
+    // Describing a download is all a `HEAD` request asks for, so the agent needn't read the file.
+    if let Some(ref entry) = download
+        && req.method() == Method::HEAD
+    {
+        record.duration = started.elapsed();
+        audit::spawn(pool.clone(), record);
+
+        let mut res = HttpResponse::Ok();
+        res.insert_header(("X-Request-Id", request_id));
+        serve(&mut res, entry, &call.args);
+
+        // An empty body would have its length replace the file's.
+        return Ok(res.streaming(futures_util::stream::empty::<Result<web::Bytes>>()));
+    }
+
+    // Listing something which isn't a directory prints nothing, which would look like an empty directory.
+    if let ("file::lsdir", Some(path)) = (call.command.as_str(), &call.path)
+        && !matches!(agent::metadata(args, &user, req, path).await, Some(DirEntry::Dir { .. }))
+    {
+        record.error = Some("No such directory.".to_owned());
+        record.duration = started.elapsed();
+        audit::spawn(pool.clone(), record);
 
-    let mut agent = match tokio::process::Command::new("agent")
-        .args(&["--base", &user.base, &user.uid.to_string(), cmd])
-        .args(&args)
-        .stdin(Stdio::piped())
+        return Ok(HttpResponse::NotFound()
+            .insert_header(("X-Request-Id", request_id))
+            .json(json! {{
+                "success": false,
+                "msg": "No such directory."
+            }}));
+    }
+
+    // Uploads which won't fit are turned away before any of them is written. A file being replaced is only removed once
+    // the upload has taken its place, so both take up space until then. A form's length is that of all the files in it,
+    // which are checked one by one as they arrive instead.
+    if STAGED.contains(&call.command.as_str())
+        && req.content_type() != "multipart/form-data"
+        && let Some(length) = req.headers().get(header::CONTENT_LENGTH).and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+        && let Some(statfs) = agent::statfs(args, &user, req).await
+        && length > statfs.left.bytes
+    {
+        record.error = Some("Not enough space left.".to_owned());
+        record.duration = started.elapsed();
+        audit::spawn(pool.clone(), record);
+
+        return Ok(HttpResponse::InsufficientStorage()
+            .insert_header(("X-Request-Id", request_id))
+            .json(json! {{
+                "success": false,
+                "msg": "Not enough space left.",
+                "left": statfs.left.bytes
+            }}));
+    }
+
+    let mut agent = match agent::command(args, &user, req)
+        .args(call.dry_run.then_some("--dry-run"))
+        .args(call.format.into_iter().flat_map(|(name, _)| ["--format", name]))
+        .arg(&call.command)
+        .args(&call.args)
+        .stdin(if body.is_some() { Stdio::piped() } else { Stdio::null() })
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
     {
         Ok(agent) => agent,
         Err(err) => {
             log::error!("{:?}", err);
+
+            record.error = Some(err.to_string());
+            record.duration = started.elapsed();
+            audit::spawn(pool.clone(), record);
+
             return Ok(HttpResponse::InternalServerError().json(json! {{
                 "success": false,
                 "msg": "Failed to spawn agent.",
                 "err": err.to_string(),
-                "args": &query.0
+                "command": &call.command,
+                "args": &call.args
             }}));
         }
     };
 
-    if let Some(mut stdin) = agent.stdin {
-        // Here we can fully write the incoming side before receiving the outgoing because the agent has no commands (yet) that require both streams at once.
-        // However, in future I plan on implementing encrypted files in terms of the agent. For this I would need to use both streams
+    let abandoned = CancellationToken::new();
+    let exited = CancellationToken::new();
+    let consumed = agent.stdin.take().zip(body).map(|(stdin, body)| {
+        // The agent encrypts and decrypts as it streams, so it may produce output before all input has been consumed.
+        // Feeding stdin from its own task means neither side can stall waiting on the other.
+        actix_web::rt::spawn(agent::feed(stdin, body, expected, abandoned.clone(), exited.clone()))
+    });
+
+    let (stream, produced) = audit::Counted::new(tokio_util::io::ReaderStream::new(agent.stdout.take().expect("stdout is piped")), abandoned.clone());
+    let mut stderr = agent.stderr.take().expect("stderr is piped");
+    let timeout = agent::timeout(args, &call.command);
+    let pool = pool.clone();
+
+    // The agent is killed if it runs for too long or the client stops listening. Either way, the call is recorded once
+    // it has exited and its output has been sent on or abandoned.
+    let finished = actix_web::rt::spawn(async move {
+        let mut diagnostics = String::new();
+        let (read, ending) = tokio::join!(
+            stderr.read_to_string(&mut diagnostics),
+            agent::supervise(&mut agent, timeout, abandoned));
+
+        exited.cancel();
+
+        if let Err(err) = read {
+            log::error!("{:?}", err);
+        }
+
+        record.diagnostics(&diagnostics);
+
+        match ending {
+            Ok(Ending::Exited(status)) => {
+                record.exit_code = status.code();
 
-        while let Some(Ok(chunk)) = body.next().await {
-            stdin.write(chunk.as_ref()).await?;
+                if let Some(signal) = status.signal() {
+                    record.error = Some(format!("Killed by signal {}.", signal));
+                }
+            },
+            Ok(Ending::TimedOut) => record.error = Some(format!("Killed after {} seconds.", timeout.as_secs())),
+            Ok(Ending::Abandoned) => record.error = Some("Killed as the client went away.".to_owned()),
+            Err(err) => record.error = Some(err.to_string())
         }
+
+        drop(permit);
+        record.duration = started.elapsed();
+        record.bytes_out = produced.await.unwrap_or(0);
+
+        let received = match consumed {
+            Some(consumed) => consumed.await.unwrap_or_default(),
+            None => agent::Received::default()
+        };
+
+        record.bytes_in = received.bytes;
+
+        // An upload which was cut off is the reason the agent was killed.
+        if let Some(ref error) = received.error {
+            record.error = Some(error.clone());
+        }
+
+        audit::record(&pool, &record)
+            .await
+            .unwrap_or_else(|err| log::error!("Failed to write audit record {}: {:?}", record.request_id, err));
+
+        (record, received)
+    });
+
+    // Commands which print nothing are answered once the agent is done, so that the client learns whether they
+    // succeeded, and for uploads, how much arrived. Their output is still read so that the agent can't stall.
+    if QUIET.contains(&call.command.as_str()) && !call.dry_run {
+        stream.for_each(|_| async {}).await;
+
+        let Ok((record, received)) = finished.await else {
+            return Ok(HttpResponse::InternalServerError()
+                .insert_header(("X-Request-Id", request_id))
+                .json(json! {{
+                    "success": false,
+                    "msg": "Internal server error."
+                }}));
+        };
+
+        // The agent exits with an error if it refuses the command, such as for a path which doesn't exist. It only
+        // lacks an exit code if it had to be killed.
+        let mut res = match (&received.error, record.exit_code) {
+            (Some(_), _) => HttpResponse::BadRequest(),
+            (None, Some(0)) => HttpResponse::Ok(),
+            (None, Some(_)) => HttpResponse::UnprocessableEntity(),
+            (None, None) => HttpResponse::InternalServerError()
+        };
+
+        let mut outcome = json! {{
+            "success": received.error.is_none() && record.exit_code == Some(0),
+            "msg": received.error.or(record.error)
+        }};
+
+        if UPLOADS.contains(&call.command.as_str()) {
+            outcome["bytes"] = received.bytes.into();
+            outcome["sha256"] = received.sha256.into();
+
+            // The file's new validators, for the client's next conditional change.
+            if let Some(path) = &call.path
+                && outcome["success"] == true
+                && let Some(entry) = agent::metadata(args, &user, req, path).await
+            {
+                validate(&mut res, &entry);
+            }
+        }
+
+        return Ok(res
+            .insert_header(("X-Request-Id", request_id))
+            .json(outcome));
+    }
+
+    let mut res = HttpResponse::Ok();
+    res.insert_header(("X-Request-Id", request_id));
+
+    if let Some(ref entry) = download {
+        serve(&mut res, entry, &call.args);
+    } else if let Some((_, mime)) = call.format {
+        res.content_type(mime);
     }
 
-    if let Some(stdout) = agent.stdout {
-        Ok(HttpResponse::Ok().streaming(tokio_util::io::ReaderStream::new(stdout)))
-    } else {
-        Ok(HttpResponse::Ok().into())
+    Ok(res.streaming(stream))
+}
+
+/// Commands which store what they read from the request body.
+const UPLOADS: &[&str] = &["file::write", "file::patch", "upload::part"];
+
+/// Uploads whose size is known up front from `Content-Length`, and which can be checked against the space left.
+const STAGED: &[&str] = &["file::write", "upload::part"];
+
+/// Commands which print nothing unless previewed.
+const QUIET: &[&str] = &["file::write", "file::patch", "file::mkdir", "file::rm", "file::sweep", "file::rename", "file::copy",
+    "upload::part", "upload::commit", "upload::abort"];
+
+/// Commands which change or delete the file at their path, and so may be made conditional on its current version.
+const CHANGES: &[&str] = &["file::write", "file::patch", "file::rm", "upload::commit"];
+
+/// Headers which make a request conditional (RFC 9110, section 13.1).
+const CONDITIONS: &[header::HeaderName] = &[header::IF_MATCH, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE, header::IF_UNMODIFIED_SINCE];
+
+/// How a conditional request turned out.
+enum Precondition {
+    Holds,
+
+    /// The request must be refused with `412 Precondition Failed`.
+    Failed,
+
+    /// The client's copy of a file it reads is current, so it is answered with `304 Not Modified`.
+    NotModified
+}
+
+/// Identifies a version of a file by its size and modification time, both of which change with any write through the
+/// agent. Modification times are kept to the nanosecond, so edits in quick succession still differ.
+pub fn etag(size: u64, modified: SystemTime) -> EntityTag {
+    let nanos = modified.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+    EntityTag::new_strong(format!("{:x}-{:x}", size, nanos))
+}
+
+/// HTTP dates only go down to the second.
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// Evaluates a request's preconditions against `entry`, the current version of the file it reads or changes, in the
+/// order RFC 9110 (section 13.2.2) lays down. Only files have validators, so a directory only matches `If-Match: *`.
+fn precondition(req: &HttpRequest, entry: Option<&DirEntry>, read: bool) -> Precondition {
+    let current = match entry {
+        Some(DirEntry::File { size, modified, .. }) => Some((etag(*size, *modified), *modified)),
+        _ => None
+    };
+
+    let failed = match req.get_header::<IfMatch>() {
+        Some(IfMatch::Any) => entry.is_none(),
+        Some(IfMatch::Items(tags)) => !current.as_ref().is_some_and(|(current, _)| tags.iter().any(|tag| tag.strong_eq(current))),
+        None => req.get_header::<IfUnmodifiedSince>()
+            .zip(current.as_ref())
+            .is_some_and(|(IfUnmodifiedSince(since), (_, modified))| seconds(*modified) > seconds(since.into()))
+    };
+
+    if failed {
+        return Precondition::Failed;
+    }
+
+    let matched = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => entry.is_some(),
+        Some(IfNoneMatch::Items(tags)) => current.as_ref().is_some_and(|(current, _)| tags.iter().any(|tag| tag.weak_eq(current))),
+        None => read && req.get_header::<IfModifiedSince>()
+            .zip(current.as_ref())
+            .is_some_and(|(IfModifiedSince(since), (_, modified))| seconds(*modified) <= seconds(since.into()))
+    };
+
+    match (matched, read) {
+        (false, _) => Precondition::Holds,
+        (true, true) => Precondition::NotModified,
+        (true, false) => Precondition::Failed
+    }
+}
+
+/// Sets the validators of a file's current version. Browsers are asked to check back before reusing a copy, since
+/// files can change at any time.
+fn validate(res: &mut HttpResponseBuilder, entry: &DirEntry) {
+    if let DirEntry::File { size, modified, .. } = entry {
+        res.insert_header(ETag(etag(*size, *modified)))
+            .insert_header(LastModified(HttpDate::from(*modified)))
+            .insert_header((header::CACHE_CONTROL, "private, no-cache"));
+    }
+}
+
+fn precondition_failed(request_id: String) -> HttpResponse {
+    HttpResponse::PreconditionFailed()
+        .insert_header(("X-Request-Id", request_id))
+        .json(json! {{
+            "success": false,
+            "msg": "The file has changed since the request's conditions were written."
+        }})
+}
+
+/// The SHA-256 a `Content-Digest` header (RFC 9530) gives for the request body. `Ok(None)` if there is no such header,
+/// and an error if it is malformed or names no SHA-256 digest, which is the only algorithm checked.
+fn content_digest(req: &HttpRequest) -> std::result::Result<Option<Vec<u8>>, &'static str> {
+    let Some(header) = req.headers().get("Content-Digest") else {
+        return Ok(None);
+    };
+
+    header.to_str()
+        .map_err(|_| "`Content-Digest` must be ASCII")?
+        .split(',')
+        .filter_map(|digest| digest.trim().split_once('='))
+        .find(|(algorithm, _)| algorithm.eq_ignore_ascii_case("sha-256"))
+        .ok_or("`Content-Digest` must include a `sha-256` digest")?
+        .1
+        .strip_prefix(':')
+        .and_then(|digest| digest.strip_suffix(':'))
+        .and_then(|digest| BASE64_STANDARD.decode(digest).ok())
+        .filter(|digest| digest.len() == 32)
+        .map(Some)
+        .ok_or("`Content-Digest` must give the `sha-256` digest as a base64 byte sequence")
+}
+
+fn too_many(request_id: String) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, "1"))
+        .insert_header(("X-Request-Id", request_id))
+        .json(json! {{
+            "success": false,
+            "msg": "Too many requests are already running. Try again shortly."
+        }})
+}
+
+/// The ID the client or a proxy in front of the server assigned to the request, or a new one.
+fn request_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get("X-Request-Id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_owned)
+        .unwrap_or_else(|| RNG.with_borrow_mut(|rng| format!("{:016x}{:016x}", rng.next_u64(), rng.next_u64())))
+}
+
+/// Types which browsers would render as a document able to run scripts, and which are therefore only ever downloaded.
+const ACTIVE_CONTENT: &[&str] = &["text/html", "application/xhtml+xml", "image/svg+xml", "text/xml", "application/xml"];
+
+/// Sets the headers describing a file's content, so that it can be previewed or saved under its own name.
+fn describe(res: &mut HttpResponseBuilder, path: &std::path::Path, mime: &str) {
+    let name = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut parameters = vec![DispositionParam::Filename(name.clone())];
+
+    if !name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_owned()),
+            language_tag: None,
+            value: name.into_bytes(),
+        }));
+    }
+
+    res.content_type(mime)
+        .insert_header(ContentDisposition {
+            disposition: if ACTIVE_CONTENT.contains(&mime) { DispositionType::Attachment } else { DispositionType::Inline },
+            parameters,
+        })
+        .insert_header((header::CONTENT_SECURITY_POLICY, "sandbox"));
+}
+
+/// Sets the headers of a download of the file `entry` describes, with the range `args` ask for.
+fn serve(res: &mut HttpResponseBuilder, entry: &DirEntry, args: &[String]) {
+    if let DirEntry::File { path, size, mime, .. } = entry {
+        let offset = flag(args, "--offset").unwrap_or(0).min(*size);
+        let len = flag(args, "--length").unwrap_or(u64::MAX).min(size - offset);
+
+        describe(res, path, mime);
+        validate(res, entry);
+        res.no_chunking(len);
+    }
+}
+
+/// Reads a numeric agent flag given either as `--flag value` or `--flag=value`.
+fn flag(args: &[String], name: &str) -> Option<u64> {
+    args.iter()
+        .enumerate()
+        .find_map(|(i, arg)| match arg.strip_prefix(name) {
+            Some("") => args.get(i + 1).map(String::as_str),
+            Some(value) => value.strip_prefix('='),
+            None => None
+        })
+        .and_then(|value| value.parse().ok())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThumbnailQuery {
+    path: String,
+    size: Option<u32>,
+}
+
+/// Thumbnails are addressed by the file's modification time (passed along by the client), so they can be cached
+/// indefinitely.
+#[get("/thumbnail")]
+pub async fn thumbnail(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, query: Query<ThumbnailQuery>) -> Result<impl Responder> {
+    let user = match storage(&req, &pool).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res)
+    };
+
+    let Some(_permit) = limiter.acquire(user.pk) else {
+        return Ok(too_many(request_id(&req)));
+    };
+
+    let output = agent::command(&args, &user, &req)
+        .args(["file::thumbnail", "--size", &query.size.unwrap_or(256).to_string(), "--", &query.path])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .output();
+
+    let output = match tokio::time::timeout(agent::timeout(&args, "file::thumbnail"), output).await {
+        Ok(Ok(output)) => output,
+        Err(_) => return Ok(HttpResponse::GatewayTimeout().json(json! {{
+            "success": false,
+            "msg": "Timed out generating thumbnail."
+        }})),
+        Ok(Err(err)) => {
+            log::error!("{:?}", err);
+            return Ok(HttpResponse::InternalServerError().json(json! {{
+                "success": false,
+                "msg": "Failed to spawn agent.",
+                "err": err.to_string()
+            }}));
+        }
+    };
+
+    if !output.status.success() {
+        return Ok(HttpResponse::NotFound().json(json! {{
+            "success": false,
+            "msg": "No thumbnail available."
+        }}));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(if output.stdout.starts_with(b"RIFF") { "image/webp" } else { "image/jpeg" })
+        .insert_header((header::CACHE_CONTROL, "private, max-age=31536000, immutable"))
+        .body(output.stdout))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditQuery {
+    command: Option<String>,
+    path: Option<String>,
+    since: Option<f64>,
+    until: Option<f64>,
+    before: Option<i64>,
+    limit: Option<i64>,
+
+    /// Someone else's history. Administrators only.
+    user: Option<String>,
+
+    /// Everyone's history. Administrators only.
+    #[serde(default)]
+    all: bool,
+}
+
+/// The signed-in user's history of `/api/system` calls, newest first. Administrators may look at other users' too.
+#[get("/audit")]
+pub async fn get_audit(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, query: Query<AuditQuery>) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(HttpResponse::Unauthorized().json(json! {{
+            "success": false,
+            "msg": "Not signed in"
+        }}));
+    };
+
+    let query = query.into_inner();
+    let email = match (query.all, query.user) {
+        (false, None) => Some(user.email),
+        _ if !args.admin.contains(&user.email) => return Ok(HttpResponse::Forbidden().json(json! {{
+            "success": false,
+            "msg": "Only administrators may view other users' history."
+        }})),
+        (_, Some(email)) => Some(email),
+        (true, None) => None
+    };
+
+    let filter = audit::Filter {
+        email,
+        command: query.command,
+        path: query.path,
+        since: query.since,
+        until: query.until,
+        before: query.before,
+        limit: query.limit,
+    };
+
+    match audit::query(&pool, &filter).await {
+        Ok(entries) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true,
+            "entries": entries
+        }})),
+        Err(err) => {
+            error!("{:?}", err);
+            Ok(HttpResponse::InternalServerError().json(json! {{
+                "success": false,
+                "msg": "Internal server error.",
+                "err": err.to_string()
+            }}))
+        }
     }
 }
@@ -0,0 +1,191 @@
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use serde::{
+    Deserialize,
+    Serialize
+};
+use sqlx::PgPool;
+use std::{
+    pin::Pin,
+    task::Context,
+    task::Poll,
+    time::Duration
+};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+/// Entries returned by a single query unless fewer are asked for.
+pub const PAGE_SIZE: i64 = 100;
+
+/// One `/api/system` call, as it is written to the `audit_log` table.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub user: i32,
+    pub command: String,
+    pub args: Vec<String>,
+    pub paths: Vec<String>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration: Duration,
+    pub client_ip: Option<String>,
+    pub request_id: String,
+    pub dry_run: bool,
+}
+
+impl Record {
+    /// Picks out what the agent reported on stderr: the line of JSON naming the paths it resolved, and the error it
+    /// exited with, if any.
+    pub fn diagnostics(&mut self, stderr: &str) {
+        #[derive(Deserialize)]
+        struct Invocation {
+            paths: Vec<String>,
+        }
+
+        for line in stderr.lines() {
+            if let Ok(invocation) = serde_json::from_str::<Invocation>(line) {
+                self.paths = invocation.paths;
+            } else if let Some(err) = line.strip_prefix("Error: ") {
+                self.error = Some(err.to_owned());
+            } else {
+                log::debug!("agent: {}", line);
+            }
+        }
+    }
+}
+
+pub async fn record(pool: &PgPool, record: &Record) -> sqlx::Result<()> {
+    sqlx::query(r#"INSERT INTO audit_log ("user", command, args, paths, bytes_in, bytes_out, exit_code, error, duration_ms, client_ip, request_id, dry_run)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)"#)
+        .bind(record.user)
+        .bind(&record.command)
+        .bind(&record.args)
+        .bind(&record.paths)
+        .bind(record.bytes_in as i64)
+        .bind(record.bytes_out as i64)
+        .bind(record.exit_code)
+        .bind(&record.error)
+        .bind(record.duration.as_millis() as i64)
+        .bind(&record.client_ip)
+        .bind(&record.request_id)
+        .bind(record.dry_run)
+        .execute(pool)
+        .await
+        .map(|_| ())
+}
+
+/// Writes the record in the background. The response doesn't wait on it, so failures are only logged.
+pub fn spawn(pool: PgPool, record: Record) {
+    actix_web::rt::spawn(async move {
+        if let Err(err) = self::record(&pool, &record).await {
+            log::error!("Failed to write audit record {}: {:?}", record.request_id, err);
+        }
+    });
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub id: i64,
+    pub email: String,
+    pub command: String,
+    pub args: Vec<String>,
+    pub paths: Vec<String>,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    pub client_ip: Option<String>,
+    pub request_id: String,
+    pub dry_run: bool,
+
+    /// Seconds since the UNIX epoch.
+    pub created: f64,
+}
+
+/// Narrows down a query of the audit log. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub email: Option<String>,
+    pub command: Option<String>,
+
+    /// Matches entries touching this path or anything below it.
+    pub path: Option<String>,
+    pub since: Option<f64>,
+    pub until: Option<f64>,
+
+    /// Only entries older than this one, for paging back through history.
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// The matching entries, newest first.
+pub async fn query(pool: &PgPool, filter: &Filter) -> sqlx::Result<Vec<Entry>> {
+    sqlx::query_as(r#"SELECT id, users.email, command, args, paths, bytes_in, bytes_out, exit_code, error, duration_ms, client_ip, request_id, dry_run,
+       extract(epoch FROM audit_log.created)::float8 AS created
+FROM audit_log JOIN users ON users.uid = audit_log."user"
+WHERE ($1::text IS NULL OR users.email = $1)
+  AND ($2::text IS NULL OR command = $2)
+  AND ($3::text IS NULL OR EXISTS (SELECT 1 FROM unnest(paths) AS path WHERE path = $3 OR starts_with(path, rtrim($3, '/') || '/')))
+  AND ($4::float8 IS NULL OR audit_log.created >= to_timestamp($4))
+  AND ($5::float8 IS NULL OR audit_log.created < to_timestamp($5))
+  AND ($6::bigint IS NULL OR id < $6)
+ORDER BY id DESC
+LIMIT $7"#)
+        .bind(&filter.email)
+        .bind(&filter.command)
+        .bind(&filter.path)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(filter.before)
+        .bind(filter.limit.unwrap_or(PAGE_SIZE).clamp(1, 10 * PAGE_SIZE))
+        .fetch_all(pool)
+        .await
+}
+
+/// Counts the bytes passing through a response body, reporting the total once the body is dropped, whether it was
+/// sent in full or the client went away. In the latter case, `abandoned` is cancelled too.
+pub struct Counted<S> {
+    inner: S,
+    bytes: u64,
+    finished: bool,
+    done: Option<oneshot::Sender<u64>>,
+    abandoned: CancellationToken,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, abandoned: CancellationToken) -> (Self, oneshot::Receiver<u64>) {
+        let (done, total) = oneshot::channel();
+        (Self { inner, bytes: 0, finished: false, done: Some(done), abandoned }, total)
+    }
+}
+
+impl<S: Stream<Item = std::io::Result<Bytes>> + Unpin> Stream for Counted<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = Pin::new(&mut self.inner).poll_next(cx);
+
+        match next {
+            Poll::Ready(Some(Ok(ref chunk))) => self.bytes += chunk.len() as u64,
+            Poll::Ready(None) => self.finished = true,
+            _ => ()
+        }
+
+        next
+    }
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.abandoned.cancel();
+        }
+
+        if let Some(done) = self.done.take() {
+            let _ = done.send(self.bytes);
+        }
+    }
+}
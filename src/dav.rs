@@ -0,0 +1,988 @@
+use crate::{
+    agent,
+    agent::DirEntry,
+    agent::StorageProps,
+    api,
+    api::Call,
+    api::User,
+    api::RNG,
+    passwords,
+    xml,
+    xml::Element,
+    Args
+};
+use actix_web::{
+    body::MessageBody,
+    dev::ServiceRequest,
+    dev::ServiceResponse,
+    http::header,
+    http::header::HttpDate,
+    http::StatusCode,
+    middleware::Next,
+    web::Data,
+    web::Payload,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+    Result
+};
+use base64::{
+    prelude::BASE64_STANDARD,
+    Engine
+};
+use futures_util::StreamExt as _;
+use humantime_serde::re::humantime;
+use log::error;
+use percent_encoding::{
+    percent_decode_str,
+    utf8_percent_encode,
+    AsciiSet,
+    CONTROLS
+};
+use rand::RngCore;
+use reqwest::Url;
+use sqlx::{
+    FromRow,
+    PgPool
+};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    sync::Mutex,
+    time::Duration,
+    time::Instant
+};
+
+/// Where the service is mounted. Request paths and `Destination` headers are taken relative to it.
+const PREFIX: &str = "/dav";
+
+const DAV: &str = "DAV:";
+const XML: &str = "application/xml; charset=utf-8";
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+/// Largest `PROPFIND` or `LOCK` body read.
+const BODY_LIMIT: usize = 64 * 1024;
+
+/// How long a lock lasts if the client doesn't ask, and the shortest and longest it may last between refreshes.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+const MIN_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Most locks held in one storage at once, as they are kept in memory.
+const MAX_LOCKS: usize = 1000;
+
+/// Characters escaped in the path segments of `href`s.
+const SEGMENT: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'/').add(b'<').add(b'>').add(b'?')
+    .add(b'[').add(b'\\').add(b']').add(b'^').add(b'`').add(b'{').add(b'|').add(b'}');
+
+const SUPPORTED_LOCK: &str = "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+    <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>";
+
+fn status(status: StatusCode) -> HttpResponse {
+    HttpResponse::build(status).finish()
+}
+
+fn challenge() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="jcake-cloud", charset="UTF-8""#))
+        .finish()
+}
+
+/// Signs clients in with HTTP Basic credentials: the user's email address, and either one of their app passwords or
+/// an API token. WebDAV clients can't follow the OAuth flow the app signs in with.
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>> {
+    let credentials = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, credentials)| BASE64_STANDARD.decode(credentials.trim()).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok());
+
+    let Some((email, password)) = credentials.as_deref().and_then(|credentials| credentials.split_once(':')) else {
+        return Ok(req.into_response(challenge()));
+    };
+
+    let Some(pool) = req.app_data::<Data<PgPool>>() else {
+        return Ok(req.into_response(status(StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+
+    let user = sqlx::query(r#"WITH used AS (
+    UPDATE app_passwords SET last_used = now()
+    FROM users WHERE users.uid = app_passwords."user" AND users.email = $1 AND hash = $2
+    RETURNING app_passwords."user"
+)
+SELECT users.* FROM users
+WHERE email = $1 AND (uid IN (SELECT "user" FROM used) OR EXISTS (SELECT 1 FROM oauth_keys WHERE "user" = users.uid AND token = $3))"#)
+        .bind(email)
+        .bind(passwords::hash(password))
+        .bind(password)
+        .fetch_optional(pool.get_ref())
+        .await;
+
+    match user.and_then(|row| row.map(|row| User::from_row(&row)).transpose()) {
+        Ok(Some(user)) => {
+            req.extensions_mut().insert(user);
+        },
+        Ok(None) => return Ok(req.into_response(challenge())),
+        Err(err) => {
+            error!("{:?}", err);
+            return Ok(req.into_response(status(StatusCode::INTERNAL_SERVER_ERROR)));
+        }
+    }
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+#[derive(Debug, Clone)]
+struct Lock {
+    /// The storage the lock is in. Users who share storage share its locks.
+    base: String,
+    path: String,
+    infinite: bool,
+    exclusive: bool,
+
+    /// The `owner` the client gave, written back out as it was.
+    owner: Option<String>,
+    expires: Instant,
+}
+
+impl Lock {
+    /// Whether the lock applies to `path`, directly or through one of its parents.
+    fn covers(&self, path: &str) -> bool {
+        self.path == path || (self.infinite && within(path, &self.path))
+    }
+
+    /// Whether the lock stands in the way of taking out `other`.
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.base == other.base
+            && (self.covers(&other.path) || (other.infinite && within(&self.path, &other.path)))
+            && (self.exclusive || other.exclusive)
+    }
+
+    fn activelock(&self, token: &str) -> String {
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{scope}/></D:lockscope><D:depth>{depth}</D:depth>{owner}\
+                <D:timeout>Second-{timeout}</D:timeout><D:locktoken><D:href>{token}</D:href></D:locktoken>\
+                <D:lockroot><D:href>{root}</D:href></D:lockroot></D:activelock>",
+            scope = if self.exclusive { "exclusive" } else { "shared" },
+            depth = if self.infinite { "infinity" } else { "0" },
+            owner = self.owner.as_deref().unwrap_or_default(),
+            timeout = self.expires.saturating_duration_since(Instant::now()).as_secs(),
+            token = xml::escape(token),
+            root = xml::escape(&href(&self.path, false)))
+    }
+}
+
+/// WebDAV locks by token. They are only kept in memory: they are short-lived and advisory, and clients take them out
+/// again once they find them gone.
+#[derive(Debug, Clone, Default)]
+pub struct Locks(Arc<Mutex<HashMap<String, Lock>>>);
+
+impl Locks {
+    /// The unexpired locks in `base`, along with their tokens.
+    fn held(&self, base: &str) -> Vec<(String, Lock)> {
+        let mut locks = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+
+        locks.retain(|_, lock| lock.expires > now);
+        locks.iter()
+            .filter(|(_, lock)| lock.base == base)
+            .map(|(token, lock)| (token.clone(), lock.clone()))
+            .collect()
+    }
+
+    /// Takes out `lock` unless one already held conflicts with it, or its storage holds [`MAX_LOCKS`] already. Fails
+    /// with the status to respond with.
+    fn try_insert(&self, token: String, lock: Lock) -> std::result::Result<(), StatusCode> {
+        let mut locks = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+
+        locks.retain(|_, lock| lock.expires > now);
+
+        if locks.values().any(|held| held.conflicts(&lock)) {
+            return Err(StatusCode::LOCKED);
+        }
+
+        if locks.values().filter(|held| held.base == lock.base).count() >= MAX_LOCKS {
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        locks.insert(token, lock);
+        Ok(())
+    }
+
+    fn remove(&self, token: &str) {
+        self.0.lock().unwrap_or_else(|err| err.into_inner()).remove(token);
+    }
+
+    /// Drops the locks on `path` and everything below it, once it is gone.
+    fn release(&self, base: &str, path: &str) {
+        self.0.lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .retain(|_, lock| lock.base != base || !within(&lock.path, path));
+    }
+
+    /// Extends the lock on `path` whose token was submitted, returning it along with its token.
+    fn refresh(&self, base: &str, path: &str, tokens: &[String], timeout: Duration) -> Option<(String, Lock)> {
+        let mut locks = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+
+        locks.iter_mut()
+            .find(|(token, lock)| lock.base == base && lock.expires > now && lock.covers(path) && tokens.contains(token))
+            .map(|(token, lock)| {
+                lock.expires = now + timeout;
+                (token.clone(), lock.clone())
+            })
+    }
+}
+
+/// A new lock token, as a random UUID URN.
+fn token() -> String {
+    let mut bytes = [0u8; 16];
+    RNG.with_borrow_mut(|rng| rng.fill_bytes(&mut bytes));
+
+    // Marks the UUID as version 4, which is to say random.
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+
+    let hex = bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    format!("urn:uuid:{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Whether `path` is `root` or below it.
+fn within(path: &str, root: &str) -> bool {
+    root == "/" || path == root || path.strip_prefix(root).is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn parent(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/",
+        Some((parent, _)) => parent
+    }
+}
+
+/// The path below the user's base a request path addresses, decoded and without a trailing slash. `None` if it isn't
+/// below [`PREFIX`], isn't UTF-8, or climbs out with `..`.
+fn resolve(path: &str) -> Option<String> {
+    let rest = path.strip_prefix(PREFIX)?;
+
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+
+    for segment in rest.split('/') {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+
+        match segment.as_ref() {
+            "" | "." => (),
+            ".." => return None,
+            name if name.contains(['/', '\0']) => return None,
+            name => segments.push(name.to_owned())
+        }
+    }
+
+    Some(format!("/{}", segments.join("/")))
+}
+
+/// The path a `Destination` header or a tagged `If` list addresses, given either as an absolute URL or an absolute path.
+fn target(url: &str) -> Option<String> {
+    match Url::parse(url) {
+        Ok(url) => resolve(url.path()),
+        Err(_) if url.starts_with('/') => resolve(url),
+        Err(_) => None
+    }
+}
+
+/// The URL path of a path below the user's base. Collections end in a slash.
+fn href(path: &str, collection: bool) -> String {
+    let mut href = PREFIX.to_owned();
+
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        href.push('/');
+        href.extend(utf8_percent_encode(segment, SEGMENT));
+    }
+
+    if collection || href == PREFIX {
+        href.push('/');
+    }
+
+    href
+}
+
+/// The `Depth` header: `Some(None)` for infinity, which is also the default, and `None` if it is malformed.
+fn depth(req: &HttpRequest) -> Option<Option<u32>> {
+    match req.headers().get("Depth").map(|depth| depth.to_str().map(str::trim)) {
+        None => Some(None),
+        Some(Ok(depth)) if depth.eq_ignore_ascii_case("infinity") => Some(None),
+        Some(Ok("0")) => Some(Some(0)),
+        Some(Ok("1")) => Some(Some(1)),
+        _ => None
+    }
+}
+
+/// The first lock timeout a `Timeout` header asks for which is understood, kept between [`MIN_LOCK_TIMEOUT`] and
+/// [`MAX_LOCK_TIMEOUT`].
+fn timeout(req: &HttpRequest) -> Duration {
+    req.headers()
+        .get("Timeout")
+        .and_then(|timeout| timeout.to_str().ok())
+        .and_then(|timeout| timeout.split(',').map(str::trim).find_map(|timeout| match timeout {
+            infinite if infinite.eq_ignore_ascii_case("infinite") => Some(MAX_LOCK_TIMEOUT),
+            seconds => seconds.strip_prefix("Second-")?.parse().ok().map(Duration::from_secs)
+        }))
+        .unwrap_or(LOCK_TIMEOUT)
+        .clamp(MIN_LOCK_TIMEOUT, MAX_LOCK_TIMEOUT)
+}
+
+/// Reads a request body of at most [`BODY_LIMIT`] bytes.
+async fn read(body: &mut Payload) -> std::result::Result<String, HttpResponse> {
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|_| status(StatusCode::BAD_REQUEST))?;
+
+        if bytes.len() + chunk.len() > BODY_LIMIT {
+            return Err(status(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    String::from_utf8(bytes).map_err(|_| status(StatusCode::BAD_REQUEST))
+}
+
+/// Answers a change made through the API the way WebDAV clients expect: `201` if it created the resource, and an empty
+/// `204` otherwise. A command the agent refuses is down to the state of the storage, such as a missing parent, hence
+/// `409`. Other refusals, such as `412` or `507`, keep their status.
+fn changed(res: HttpResponse, created: bool) -> HttpResponse {
+    let status = match res.status() {
+        StatusCode::OK if created => StatusCode::CREATED,
+        StatusCode::OK => StatusCode::NO_CONTENT,
+        StatusCode::UNPROCESSABLE_ENTITY => StatusCode::CONFLICT,
+        _ => return res
+    };
+
+    let mut answer = HttpResponse::build(status);
+
+    for name in [header::ETAG, header::LAST_MODIFIED, header::HeaderName::from_static("x-request-id")] {
+        if let Some(value) = res.headers().get(&name) {
+            answer.insert_header((name, value.clone()));
+        }
+    }
+
+    answer.finish()
+}
+
+/// A condition of an `If` header (RFC 4918, section 10.4).
+#[derive(Debug, Clone)]
+enum Condition {
+    Token(String),
+    ETag(String),
+}
+
+/// Conditions which must all hold, tagged with the resource they are about if it isn't the request's. Each condition
+/// may be negated.
+#[derive(Debug, Clone)]
+struct List {
+    resource: Option<String>,
+    conditions: Vec<(bool, Condition)>,
+}
+
+/// Parses an `If` header. `None` if it is malformed.
+fn lists(header: &str) -> Option<Vec<List>> {
+    let mut lists = Vec::new();
+    let mut resource = None;
+    let mut rest = header.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(tagged) = rest.strip_prefix('<') {
+            let (tag, after) = tagged.split_once('>')?;
+            resource = Some(tag.to_owned());
+            rest = after;
+        } else if let Some(mut list) = rest.strip_prefix('(') {
+            let mut conditions = Vec::new();
+
+            rest = loop {
+                list = list.trim_start();
+
+                let negated = list.get(..3).is_some_and(|not| not.eq_ignore_ascii_case("not"));
+
+                if negated {
+                    list = list[3..].trim_start();
+                }
+
+                if let Some(token) = list.strip_prefix('<') {
+                    let (token, after) = token.split_once('>')?;
+                    conditions.push((negated, Condition::Token(token.to_owned())));
+                    list = after;
+                } else if let Some(etag) = list.strip_prefix('[') {
+                    let (etag, after) = etag.split_once(']')?;
+                    conditions.push((negated, Condition::ETag(etag.to_owned())));
+                    list = after;
+                } else if let Some(after) = list.strip_prefix(')').filter(|_| !negated && !conditions.is_empty()) {
+                    break after;
+                } else {
+                    return None;
+                }
+            };
+
+            lists.push(List { resource: resource.clone(), conditions });
+        } else {
+            return None;
+        }
+
+        rest = rest.trim_start();
+    }
+
+    (!lists.is_empty()).then_some(lists)
+}
+
+/// What a `PROPFIND` asks for.
+#[derive(Debug, Clone)]
+enum Find {
+    All,
+    Names,
+    Props(Vec<Element>),
+}
+
+/// The live properties of an entry, named in the `DAV:` namespace.
+fn properties(entry: &DirEntry, locks: &[(String, Lock)]) -> Vec<(&'static str, String)> {
+    let (path, collection) = match entry {
+        DirEntry::Dir { path, .. } => (path, true),
+        DirEntry::File { path, .. } | DirEntry::Link { path, .. } => (path, false)
+    };
+
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let path = path.to_string_lossy();
+
+    let mut properties = vec![
+        ("displayname", xml::escape(&name)),
+        ("resourcetype", if collection { "<D:collection/>".to_owned() } else { String::new() }),
+    ];
+
+    if let DirEntry::File { size, mime, modified, created, .. } = entry {
+        properties.extend([
+            ("getcontentlength", size.to_string()),
+            ("getcontenttype", xml::escape(mime)),
+            ("getlastmodified", HttpDate::from(*modified).to_string()),
+            ("creationdate", humantime::format_rfc3339_seconds(*created).to_string()),
+            ("getetag", xml::escape(&api::etag(*size, *modified).to_string())),
+        ]);
+    }
+
+    properties.push(("supportedlock", SUPPORTED_LOCK.to_owned()));
+    properties.push(("lockdiscovery", locks.iter()
+        .filter(|(_, lock)| lock.covers(&path))
+        .map(|(token, lock)| lock.activelock(token))
+        .collect()));
+
+    properties
+}
+
+fn property(name: &str, value: &str) -> String {
+    match value {
+        "" => format!("<D:{}/>", name),
+        value => format!("<D:{name}>{value}</D:{name}>")
+    }
+}
+
+/// The `response` element describing an entry in a multistatus.
+fn response(entry: &DirEntry, find: &Find, locks: &[(String, Lock)]) -> String {
+    let (path, collection) = match entry {
+        DirEntry::Dir { path, .. } => (path, true),
+        DirEntry::File { path, .. } | DirEntry::Link { path, .. } => (path, false)
+    };
+
+    let properties = properties(entry, locks);
+
+    let mut found = String::new();
+    let mut missing = String::new();
+
+    match find {
+        Find::All => found.extend(properties.iter().map(|(name, value)| property(name, value))),
+        Find::Names => found.extend(properties.iter().map(|(name, _)| property(name, ""))),
+        Find::Props(requested) => for requested in requested {
+            match properties.iter().find(|(name, _)| requested.is(DAV, name)) {
+                Some((name, value)) => found.push_str(&property(name, value)),
+                None => missing.push_str(&format!("<{} xmlns=\"{}\"/>", requested.name, xml::escape(&requested.namespace)))
+            }
+        }
+    }
+
+    let mut response = format!("<D:response><D:href>{}</D:href>", xml::escape(&href(&path.to_string_lossy(), collection)));
+
+    // A response needs at least one `propstat`, even if nothing was asked for.
+    if !found.is_empty() || missing.is_empty() {
+        response.push_str(&format!("<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>", found));
+    }
+
+    if !missing.is_empty() {
+        response.push_str(&format!("<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>", missing));
+    }
+
+    response.push_str("</D:response>");
+    response
+}
+
+/// What every method needs to run agent commands for the request.
+struct Dav<'a> {
+    req: &'a HttpRequest,
+    pool: &'a PgPool,
+    args: &'a Args,
+    limiter: &'a agent::Limiter,
+    locks: &'a Locks,
+    user: StorageProps,
+}
+
+impl Dav<'_> {
+    async fn metadata(&self, path: &str) -> Option<DirEntry> {
+        agent::metadata(self.args, &self.user, self.req, path).await
+    }
+
+    async fn is_dir(&self, path: &str) -> bool {
+        matches!(self.metadata(path).await, Some(DirEntry::Dir { .. }))
+    }
+
+    /// Runs an agent command through the API, so that it is limited and audited like any other.
+    async fn run(&self, command: &str, arguments: &[&str], path: Option<&str>, body: Option<Payload>) -> Result<HttpResponse> {
+        let call = Call {
+            command: command.to_owned(),
+            args: arguments.iter().map(|argument| argument.to_string()).collect(),
+            path: path.map(str::to_owned),
+            dry_run: false,
+            format: None,
+        };
+
+        api::run(self.req, self.pool, self.args, self.limiter, self.user.clone(), call, body).await
+    }
+
+    /// Evaluates the `If` header, if any, against the locks and entity tags it names: it holds if any of its lists
+    /// does, and a list holds if all of its conditions do. Returns the lock tokens the header submits, or the response
+    /// refusing the request if the header is malformed or doesn't hold.
+    async fn conditions(&self, path: &str) -> std::result::Result<Vec<String>, HttpResponse> {
+        let Some(header) = self.req.headers().get("If") else {
+            return Ok(Vec::new());
+        };
+
+        let Some(lists) = header.to_str().ok().and_then(lists) else {
+            return Err(status(StatusCode::BAD_REQUEST));
+        };
+
+        let locks = self.locks.held(&self.user.base);
+        let mut etags = HashMap::new();
+        let mut holds = false;
+
+        for list in &lists {
+            let Some(resource) = list.resource.as_deref().map_or(Some(path.to_owned()), target) else {
+                continue;
+            };
+
+            let mut all = true;
+
+            for (negated, condition) in &list.conditions {
+                let matched = match condition {
+                    Condition::Token(token) => locks.iter().any(|(held, lock)| held == token && lock.covers(&resource)),
+                    Condition::ETag(etag) => {
+                        if !etags.contains_key(&resource) {
+                            let current = match self.metadata(&resource).await {
+                                Some(DirEntry::File { size, modified, .. }) => Some(api::etag(size, modified).to_string()),
+                                _ => None
+                            };
+
+                            etags.insert(resource.clone(), current);
+                        }
+
+                        etags[&resource].as_deref() == Some(etag.as_str())
+                    }
+                };
+
+                if matched == *negated {
+                    all = false;
+                    break;
+                }
+            }
+
+            if all {
+                holds = true;
+                break;
+            }
+        }
+
+        if !holds {
+            return Err(status(StatusCode::PRECONDITION_FAILED));
+        }
+
+        Ok(lists.into_iter()
+            .flat_map(|list| list.conditions)
+            .filter_map(|condition| match condition {
+                (false, Condition::Token(token)) => Some(token),
+                _ => None
+            })
+            .collect())
+    }
+
+    /// Whether `path`, and with `recursive`, everything below it, may be changed under the locks held on it. Each lock
+    /// in the way must have its token submitted, though one token is enough for several shared locks on one path.
+    fn unlocked(&self, path: &str, recursive: bool, tokens: &[String]) -> bool {
+        let locks = self.locks.held(&self.user.base)
+            .into_iter()
+            .filter(|(_, lock)| lock.covers(path) || (recursive && within(&lock.path, path)))
+            .collect::<Vec<_>>();
+
+        locks.iter().all(|(token, lock)| tokens.contains(token) || (!lock.exclusive && locks.iter()
+            .any(|(other, shared)| !shared.exclusive && shared.path == lock.path && tokens.contains(other))))
+    }
+
+    /// Checks the `If` header and the locks on `path` ahead of a change.
+    async fn may_change(&self, path: &str, recursive: bool) -> std::result::Result<Vec<String>, HttpResponse> {
+        let tokens = self.conditions(path).await?;
+
+        match self.unlocked(path, recursive, &tokens) {
+            true => Ok(tokens),
+            false => Err(status(StatusCode::LOCKED))
+        }
+    }
+
+    /// Downloads a file. Collections have no content of their own.
+    async fn get(&self, path: &str) -> Result<HttpResponse> {
+        let res = self.run("file::read", &["--", path], Some(path), None).await?;
+
+        if res.status() == StatusCode::NOT_FOUND && self.is_dir(path).await {
+            return Ok(HttpResponse::MethodNotAllowed().insert_header((header::ALLOW, ALLOW)).finish());
+        }
+
+        Ok(res)
+    }
+
+    async fn put(&self, path: &str, body: Payload) -> Result<HttpResponse> {
+        if let Err(res) = self.may_change(path, false).await {
+            return Ok(res);
+        }
+
+        let existing = self.metadata(path).await;
+
+        if let Some(DirEntry::Dir { .. }) = existing {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+
+        let res = self.run("file::write", &["--", path, "true"], Some(path), Some(body)).await?;
+        Ok(changed(res, existing.is_none()))
+    }
+
+    async fn delete(&self, path: &str) -> Result<HttpResponse> {
+        if path == "/" {
+            return Ok(status(StatusCode::FORBIDDEN));
+        }
+
+        if let Err(res) = self.may_change(path, true).await {
+            return Ok(res);
+        }
+
+        if self.metadata(path).await.is_none() {
+            return Ok(status(StatusCode::NOT_FOUND));
+        }
+
+        let res = self.run("file::rm", &["--", path], Some(path), None).await?;
+
+        if res.status().is_success() {
+            self.locks.release(&self.user.base, path);
+        }
+
+        Ok(changed(res, false))
+    }
+
+    async fn mkcol(&self, path: &str, mut body: Payload) -> Result<HttpResponse> {
+        match read(&mut body).await {
+            Ok(body) if body.is_empty() => (),
+            Ok(_) => return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE)),
+            Err(res) => return Ok(res)
+        }
+
+        if let Err(res) = self.may_change(path, false).await {
+            return Ok(res);
+        }
+
+        if self.metadata(path).await.is_some() {
+            return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+        }
+
+        // The agent would create missing parents along the way.
+        if !self.is_dir(parent(path)).await {
+            return Ok(status(StatusCode::CONFLICT));
+        }
+
+        let res = self.run("file::mkdir", &["--", path], None, None).await?;
+        Ok(changed(res, true))
+    }
+
+    /// Copies or moves `path` to the `Destination` header, replacing whatever is there unless `Overwrite` is `F`.
+    async fn transfer(&self, path: &str, moving: bool) -> Result<HttpResponse> {
+        let Some(destination) = self.req.headers().get("Destination").and_then(|destination| destination.to_str().ok()) else {
+            return Ok(status(StatusCode::BAD_REQUEST));
+        };
+
+        // Destinations elsewhere would be on another server as far as the client can tell.
+        let Some(to) = target(destination) else {
+            return Ok(status(StatusCode::BAD_GATEWAY));
+        };
+
+        let overwrite = !self.req.headers().get("Overwrite").is_some_and(|overwrite| overwrite.as_bytes().eq_ignore_ascii_case(b"F"));
+
+        // Collections are only ever moved whole, but may be copied without their members.
+        let recursive = match depth(self.req) {
+            Some(None) => true,
+            Some(Some(0)) if !moving => false,
+            _ => return Ok(status(StatusCode::BAD_REQUEST))
+        };
+
+        if path == "/" || within(&to, path) {
+            return Ok(status(StatusCode::FORBIDDEN));
+        }
+
+        let tokens = match self.conditions(path).await {
+            Ok(tokens) => tokens,
+            Err(res) => return Ok(res)
+        };
+
+        if (moving && !self.unlocked(path, true, &tokens)) || !self.unlocked(&to, true, &tokens) {
+            return Ok(status(StatusCode::LOCKED));
+        }
+
+        let Some(source) = self.metadata(path).await else {
+            return Ok(status(StatusCode::NOT_FOUND));
+        };
+
+        if !self.is_dir(parent(&to)).await {
+            return Ok(status(StatusCode::CONFLICT));
+        }
+
+        let existing = self.metadata(&to).await;
+
+        if existing.is_some() && !overwrite {
+            return Ok(status(StatusCode::PRECONDITION_FAILED));
+        }
+
+        // What is overwritten is only replaced once the copy or move is complete, and is left as it was should it fail.
+        let options = if existing.is_some() { &["--replace", "--"][..] } else { &["--"] };
+
+        let res = match (source, recursive) {
+            (DirEntry::Dir { .. }, false) => self.run("file::mkdir", &[options, &[&to]].concat(), None, None).await?,
+            _ => self.run(if moving { "file::rename" } else { "file::copy" }, &[options, &[path, &to]].concat(), None, None).await?
+        };
+
+        // Locks stay with the path they were taken out on, so those of what was moved or overwritten are gone.
+        if res.status().is_success() {
+            if existing.is_some() {
+                self.locks.release(&self.user.base, &to);
+            }
+
+            if moving {
+                self.locks.release(&self.user.base, path);
+            }
+        }
+
+        Ok(changed(res, existing.is_none()))
+    }
+
+    async fn propfind(&self, path: &str, mut body: Payload) -> Result<HttpResponse> {
+        let find = match read(&mut body).await {
+            Ok(body) if body.trim().is_empty() => Find::All,
+            Ok(body) => match xml::parse(&body) {
+                Ok(root) if root.is(DAV, "propfind") => match root.children.iter().find(|child| child.namespace == DAV) {
+                    Some(child) if child.name == "allprop" => Find::All,
+                    Some(child) if child.name == "propname" => Find::Names,
+                    Some(child) if child.name == "prop" => Find::Props(child.children.clone()),
+                    _ => return Ok(status(StatusCode::BAD_REQUEST))
+                },
+                _ => return Ok(status(StatusCode::BAD_REQUEST))
+            },
+            Err(res) => return Ok(res)
+        };
+
+        let Some(depth) = depth(self.req) else {
+            return Ok(status(StatusCode::BAD_REQUEST));
+        };
+
+        let Some(_permit) = self.limiter.acquire(self.user.pk) else {
+            return Ok(HttpResponse::TooManyRequests().insert_header((header::RETRY_AFTER, "1")).finish());
+        };
+
+        let Some(entry) = self.metadata(path).await else {
+            return Ok(status(StatusCode::NOT_FOUND));
+        };
+
+        let below = match (&entry, depth) {
+            (DirEntry::Dir { .. }, None | Some(1)) => match agent::list(self.args, &self.user, self.req, path, depth).await {
+                Some(below) => below,
+                None => return Ok(status(StatusCode::INTERNAL_SERVER_ERROR))
+            },
+            _ => Vec::new()
+        };
+
+        let locks = self.locks.held(&self.user.base);
+        let responses = [entry].iter()
+            .chain(&below)
+            .map(|entry| response(entry, &find, &locks))
+            .collect::<String>();
+
+        Ok(HttpResponse::MultiStatus()
+            .content_type(XML)
+            .body(format!(r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">{}</D:multistatus>"#, responses)))
+    }
+
+    /// Takes out a write lock on `path`, creating an empty file there if there is nothing yet. Without a body, refreshes
+    /// the lock whose token is submitted instead.
+    async fn lock(&self, path: &str, mut body: Payload) -> Result<HttpResponse> {
+        let info = match read(&mut body).await {
+            Ok(info) => info,
+            Err(res) => return Ok(res)
+        };
+
+        let tokens = match self.conditions(path).await {
+            Ok(tokens) => tokens,
+            Err(res) => return Ok(res)
+        };
+
+        let discovery = |token: &str, lock: &Lock| format!(
+            r#"<?xml version="1.0" encoding="utf-8"?><D:prop xmlns:D="DAV:"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>"#,
+            lock.activelock(token));
+
+        if info.trim().is_empty() {
+            return Ok(match self.locks.refresh(&self.user.base, path, &tokens, timeout(self.req)) {
+                Some((token, lock)) => HttpResponse::Ok().content_type(XML).body(discovery(&token, &lock)),
+                None => status(StatusCode::PRECONDITION_FAILED)
+            });
+        }
+
+        let Ok(info) = xml::parse(&info).map_err(|err| log::debug!("{}", err)) else {
+            return Ok(status(StatusCode::BAD_REQUEST));
+        };
+
+        let exclusive = match info.child(DAV, "lockscope").and_then(|scope| scope.children.first()) {
+            Some(scope) if scope.is(DAV, "exclusive") => true,
+            Some(scope) if scope.is(DAV, "shared") => false,
+            _ => return Ok(status(StatusCode::BAD_REQUEST))
+        };
+
+        // Write locks are the only kind there is.
+        let write = info.child(DAV, "locktype").is_some_and(|locktype| locktype.child(DAV, "write").is_some());
+
+        if !info.is(DAV, "lockinfo") || !write {
+            return Ok(status(StatusCode::BAD_REQUEST));
+        }
+
+        let infinite = match depth(self.req) {
+            Some(None) => true,
+            Some(Some(0)) => false,
+            _ => return Ok(status(StatusCode::BAD_REQUEST))
+        };
+
+        let token = token();
+        let lock = Lock {
+            base: self.user.base.clone(),
+            path: path.to_owned(),
+            infinite,
+            exclusive,
+            owner: info.child(DAV, "owner").map(Element::write),
+            expires: Instant::now() + timeout(self.req),
+        };
+
+        let held = self.locks.held(&self.user.base);
+
+        if held.iter().any(|(_, held)| held.conflicts(&lock)) {
+            return Ok(status(StatusCode::LOCKED));
+        }
+
+        if held.len() >= MAX_LOCKS {
+            return Ok(status(StatusCode::SERVICE_UNAVAILABLE));
+        }
+
+        let created = match self.metadata(path).await {
+            Some(_) => false,
+            None if !self.is_dir(parent(path)).await => return Ok(status(StatusCode::CONFLICT)),
+            None => {
+                // The body has been read in full, so the file is written empty.
+                let res = self.run("file::write", &["--", path, "true"], Some(path), Some(body)).await?;
+
+                if !res.status().is_success() {
+                    return Ok(changed(res, true));
+                }
+
+                true
+            }
+        };
+
+        // Another lock may have been taken out while the file was being created, so the check is made again.
+        if let Err(code) = self.locks.try_insert(token.clone(), lock.clone()) {
+            return Ok(status(code));
+        }
+
+        Ok(HttpResponse::build(if created { StatusCode::CREATED } else { StatusCode::OK })
+            .insert_header(("Lock-Token", format!("<{}>", token)))
+            .content_type(XML)
+            .body(discovery(&token, &lock)))
+    }
+
+    async fn unlock(&self, path: &str) -> Result<HttpResponse> {
+        let Some(token) = self.req.headers()
+            .get("Lock-Token")
+            .and_then(|token| token.to_str().ok())
+            .and_then(|token| token.trim().strip_prefix('<')?.strip_suffix('>'))
+        else {
+            return Ok(status(StatusCode::BAD_REQUEST));
+        };
+
+        let held = self.locks.held(&self.user.base)
+            .iter()
+            .any(|(held, lock)| held == token && lock.covers(path));
+
+        if !held {
+            return Ok(status(StatusCode::CONFLICT));
+        }
+
+        self.locks.remove(token);
+        Ok(status(StatusCode::NO_CONTENT))
+    }
+}
+
+/// Serves WebDAV (RFC 4918) below [`PREFIX`], translating each method into agent commands run as the signed-in user.
+pub async fn handle(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, locks: Data<Locks>, body: Payload) -> Result<HttpResponse> {
+    let user = match api::storage(&req, &pool).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res)
+    };
+
+    let Some(path) = resolve(req.path()) else {
+        return Ok(status(StatusCode::NOT_FOUND));
+    };
+
+    let dav = Dav {
+        req: &req,
+        pool: &pool,
+        args: &args,
+        limiter: &limiter,
+        locks: &locks,
+        user,
+    };
+
+    match req.method().as_str() {
+        "OPTIONS" => Ok(HttpResponse::Ok()
+            .insert_header(("DAV", "1, 2"))
+            .insert_header((header::ALLOW, ALLOW))
+            .insert_header(("MS-Author-Via", "DAV"))
+            .finish()),
+        "GET" | "HEAD" => dav.get(&path).await,
+        "PUT" => dav.put(&path, body).await,
+        "DELETE" => dav.delete(&path).await,
+        "MKCOL" => dav.mkcol(&path, body).await,
+        "COPY" => dav.transfer(&path, false).await,
+        "MOVE" => dav.transfer(&path, true).await,
+        "PROPFIND" => dav.propfind(&path, body).await,
+        "LOCK" => dav.lock(&path, body).await,
+        "UNLOCK" => dav.unlock(&path).await,
+        _ => Ok(HttpResponse::MethodNotAllowed().insert_header((header::ALLOW, ALLOW)).finish())
+    }
+}
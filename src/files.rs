@@ -0,0 +1,527 @@
+use crate::{
+    agent,
+    agent::DirEntry,
+    agent::StorageProps,
+    api,
+    api::Call,
+    Args
+};
+use actix_multipart::{
+    Field,
+    Multipart
+};
+use actix_web::{
+    body,
+    delete,
+    dev,
+    error::InternalError,
+    error::PayloadError,
+    get,
+    post,
+    put,
+    web::Bytes,
+    web::Data,
+    web::Path,
+    web::Payload,
+    web::Query,
+    FromRequest,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+    Responder,
+    Result
+};
+use futures_util::{
+    Stream,
+    StreamExt as _
+};
+use serde::Deserialize;
+use serde_json::{
+    json,
+    Value
+};
+use sqlx::PgPool;
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    io,
+    pin::Pin,
+    rc::Rc
+};
+
+/// Depth of listings which don't ask for one.
+const DEPTH: u32 = 1;
+
+/// Most files a single form may upload, which keeps its response to a reasonable size.
+const FORM_FILES: usize = 10000;
+
+/// Most names tried for a file which mustn't replace another.
+const FREE_NAMES: u32 = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReadQuery {
+    /// List the directory rather than reading a file. Takes no value.
+    list: Option<String>,
+
+    /// How far below the directory to list.
+    depth: Option<u32>,
+
+    /// Format of the records in a listing: `json` (the default), `ron` or `cbor`.
+    format: Option<String>,
+
+    /// The range of a file to read.
+    offset: Option<u64>,
+    length: Option<u64>,
+}
+
+/// Query parameters of the routes which change files.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChangeQuery {
+    /// Describe the changes instead of making them.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Body of `:move` and `:copy`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transfer {
+    to: String,
+
+    /// Abandon an interrupted transfer between filesystems instead of resuming it.
+    #[serde(default)]
+    rollback: bool,
+}
+
+fn bad_request(msg: impl ToString) -> HttpResponse {
+    HttpResponse::BadRequest().json(json! {{
+        "success": false,
+        "msg": msg.to_string()
+    }})
+}
+
+/// Answers query strings which don't fit the route's parameters the same way as the routes' own errors.
+pub fn invalid_query(err: actix_web::error::QueryPayloadError, _: &HttpRequest) -> actix_web::Error {
+    InternalError::from_response(err.to_string(), bad_request(err)).into()
+}
+
+/// The path below the user's base a route addresses. Paths are taken as they are, so they may contain anything a
+/// filename can, `;` included; the agent keeps them inside the base.
+fn resolve(path: &str) -> String {
+    format!("/{}", path)
+}
+
+/// Runs `call` as the signed-in user, like `/api/system` would.
+async fn run(req: &HttpRequest, pool: &PgPool, args: &Args, limiter: &agent::Limiter, call: Call, body: Option<Payload>) -> Result<HttpResponse> {
+    match api::storage(req, pool).await {
+        Ok(user) => api::run(req, pool, args, limiter, user, call, body).await,
+        Err(res) => Ok(res)
+    }
+}
+
+/// Reads a file, or with `?list`, lists a directory.
+#[get("/files/{path:.*}")]
+pub async fn read(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, path: Path<String>, query: Query<ReadQuery>) -> Result<impl Responder> {
+    let path = resolve(&path);
+    let query = query.into_inner();
+
+    let call = if query.list.is_some() {
+        if query.offset.is_some() || query.length.is_some() {
+            return Ok(bad_request("`offset` and `length` only apply to files"));
+        }
+
+        let Some(format) = api::format(query.format.as_deref().unwrap_or("json")) else {
+            return Ok(bad_request("`format` must be one of `json`, `ron` or `cbor`"));
+        };
+
+        Call {
+            command: "file::lsdir".to_owned(),
+            args: vec![format!("--depth={}", query.depth.unwrap_or(DEPTH)), "--".to_owned(), path.clone()],
+            path: Some(path),
+            dry_run: false,
+            format: Some(format),
+        }
+    } else {
+        if query.depth.is_some() || query.format.is_some() {
+            return Ok(bad_request("`depth` and `format` only apply to listings"));
+        }
+
+        let range = query.offset.map(|offset| format!("--offset={}", offset))
+            .into_iter()
+            .chain(query.length.map(|length| format!("--length={}", length)));
+
+        Call {
+            command: "file::read".to_owned(),
+            args: range.chain(["--".to_owned(), path.clone()]).collect(),
+            path: Some(path),
+            dry_run: false,
+            format: None,
+        }
+    };
+
+    run(&req, &pool, &args, &limiter, call, None).await
+}
+
+/// Creates or replaces a file with the request body.
+#[put("/files/{path:.*}")]
+pub async fn write(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, path: Path<String>, query: Query<ChangeQuery>, body: Payload) -> Result<impl Responder> {
+    let path = resolve(&path);
+
+    let call = Call {
+        command: "file::write".to_owned(),
+        args: vec!["--".to_owned(), path.clone(), "true".to_owned()],
+        path: Some(path),
+        dry_run: query.dry_run,
+        format: None,
+    };
+
+    run(&req, &pool, &args, &limiter, call, Some(body)).await
+}
+
+/// Deletes a file, or a directory along with everything in it.
+#[delete("/files/{path:.*}")]
+pub async fn remove(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, path: Path<String>, query: Query<ChangeQuery>) -> Result<impl Responder> {
+    let path = resolve(&path);
+
+    let call = Call {
+        command: "file::rm".to_owned(),
+        args: vec!["--".to_owned(), path.clone()],
+        path: Some(path),
+        dry_run: query.dry_run,
+        format: None,
+    };
+
+    run(&req, &pool, &args, &limiter, call, None).await
+}
+
+/// Runs the action named after the last `:` of the path: `:mkdir` creates the directory, along with its parents, and
+/// `:move` and `:copy` transfer the file or directory to the path given as `to` in a JSON body.
+#[post("/files/{path:.*}")]
+pub async fn act(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, path: Path<String>, query: Query<ChangeQuery>, body: Bytes) -> Result<impl Responder> {
+    let Some((path, action)) = path.rsplit_once(':') else {
+        return Ok(bad_request("Name an action after the path: `:mkdir`, `:move` or `:copy`"));
+    };
+
+    let path = resolve(path);
+
+    let (command, arguments) = match action {
+        "mkdir" => ("file::mkdir", vec!["--".to_owned(), path]),
+        "move" | "copy" => {
+            let transfer = match serde_json::from_slice::<Transfer>(&body) {
+                Ok(transfer) => transfer,
+                Err(err) => return Ok(bad_request(format!("Expected a body like `{{\"to\": \"/path\"}}`: {}", err)))
+            };
+
+            let command = if action == "move" { "file::rename" } else { "file::copy" };
+            let rollback = transfer.rollback.then(|| "--rollback".to_owned());
+
+            (command, rollback.into_iter().chain(["--".to_owned(), path, transfer.to]).collect())
+        },
+        _ => return Ok(bad_request(format!("Unknown action `:{}`; expected `:mkdir`, `:move` or `:copy`", action)))
+    };
+
+    let call = Call {
+        command: command.to_owned(),
+        args: arguments,
+        path: None,
+        dry_run: query.dry_run,
+        format: None,
+    };
+
+    run(&req, &pool, &args, &limiter, call, None).await
+}
+
+/// A file of a form upload, as it is fed to the agent. A file larger than `left` bytes ends in an error once it goes
+/// past them, so that the agent is killed before it takes the file for complete.
+struct FormFile {
+    field: Field,
+    left: u64,
+    exceeded: Rc<Cell<bool>>,
+    done: bool,
+}
+
+impl FormFile {
+    async fn next(&mut self) -> Option<std::result::Result<Bytes, PayloadError>> {
+        if self.done {
+            return None;
+        }
+
+        let next = match self.field.next().await {
+            Some(Ok(data)) if data.len() as u64 > self.left => {
+                self.exceeded.set(true);
+                Some(Err(PayloadError::Io(io::Error::new(io::ErrorKind::FileTooLarge, "The file is too large"))))
+            },
+            Some(Ok(data)) => {
+                self.left -= data.len() as u64;
+                Some(Ok(data))
+            },
+            Some(Err(err)) => Some(Err(PayloadError::Io(io::Error::other(err.to_string())))),
+            None => None
+        };
+
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+/// The path of a file of a form relative to the directory it is uploaded into, from the filename it was sent with.
+/// Browsers give files of folders their path within the folder, separated by `/`. `None` for names which don't make a
+/// relative path.
+pub fn relative(filename: &str) -> Option<String> {
+    let segments = filename.split('/').collect::<Vec<_>>();
+
+    if segments.iter().any(|segment| matches!(*segment, "" | "." | "..") || segment.contains('\0')) {
+        return None;
+    }
+
+    Some(segments.join("/"))
+}
+
+/// Uploads the files of a `multipart/form-data` body into the directory, such as a folder dragged into the browser.
+/// Each file's filename is taken as its path below the directory, whose missing directories are created. Files are
+/// streamed to the agent one at a time, each as large as `--form-file-limit` and the space left allow, and the response
+/// tells how each of them went.
+#[post("/files/{path:.*}:upload")]
+pub async fn upload(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, path: Path<String>, body: Payload) -> Result<impl Responder> {
+    let user = match api::storage(&req, &pool).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res)
+    };
+
+    let to = Destination {
+        dir: resolve(&path).trim_end_matches('/').to_owned(),
+        replace: true,
+    };
+
+    receive(&req, &pool, &args, &limiter, user, to, body).await
+}
+
+/// Where the files of a form are uploaded to.
+#[derive(Debug, Clone)]
+pub struct Destination {
+    /// The directory the files' names are relative to.
+    pub dir: String,
+
+    /// Replace files which are already there. Otherwise, each file is given the first name like `notes (1).txt` which
+    /// isn't taken, and files are refused if their directories are reached through symbolic links, which could lead out
+    /// of `dir`.
+    pub replace: bool,
+}
+
+/// Uploads the files of a `multipart/form-data` body into `to` as `user`, answering with how each of them went.
+pub async fn receive(req: &HttpRequest, pool: &PgPool, args: &Args, limiter: &agent::Limiter, user: StorageProps, to: Destination, body: Payload) -> Result<HttpResponse> {
+    if req.content_type() != "multipart/form-data" {
+        return Ok(bad_request("Expected a `multipart/form-data` body"));
+    }
+
+    let dir = to.dir.as_str();
+    let mut form = Multipart::new(req.headers(), body);
+    let mut created = HashSet::new();
+    let mut files = Vec::new();
+
+    // Space is checked once, then counted down by what each file takes up.
+    let Some(mut left) = agent::statfs(args, &user, req).await.map(|statfs| statfs.left.bytes) else {
+        log::error!("Couldn't tell how much space {} has left, so their form upload was refused", user.email);
+
+        return Ok(HttpResponse::InternalServerError().json(json! {{
+            "success": false,
+            "msg": "Couldn't tell how much space is left."
+        }}));
+    };
+
+    while let Some(field) = form.next().await {
+        let field = match field {
+            Ok(field) => field,
+            Err(err) => {
+                files.push(json! {{
+                    "success": false,
+                    "msg": format!("The form is malformed: {}", err)
+                }});
+
+                break;
+            }
+        };
+
+        // Fields which aren't files are skipped.
+        let Some(filename) = field.content_disposition().and_then(|disposition| disposition.get_filename()).map(str::to_owned) else {
+            continue;
+        };
+
+        if files.len() >= FORM_FILES {
+            files.push(json! {{
+                "success": false,
+                "msg": format!("A form may upload at most {} files", FORM_FILES)
+            }});
+
+            break;
+        }
+
+        let fail = |msg: &str| json! {{
+            "path": &filename,
+            "success": false,
+            "msg": msg
+        }};
+
+        let Some(relative) = relative(&filename) else {
+            files.push(fail("The filename isn't a relative path"));
+            continue;
+        };
+
+        let path = format!("{}/{}", dir, relative);
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent).to_owned();
+
+        if !to.replace
+            && let Some((subdir, _)) = relative.rsplit_once('/')
+            && !reachable(args, &user, req, dir, subdir).await
+        {
+            files.push(fail("The file's directory is reached through a symbolic link"));
+            continue;
+        }
+
+        if !parent.is_empty() && !created.contains(&parent) {
+            let call = Call {
+                command: "file::mkdir".to_owned(),
+                args: vec!["--".to_owned(), parent.clone()],
+                path: None,
+                dry_run: false,
+                format: None,
+            };
+
+            let res = api::run(req, pool, args, limiter, user.clone(), call, None).await?;
+
+            if !res.status().is_success() {
+                files.push(fail("Failed to create the file's directory"));
+                continue;
+            }
+
+            created.insert(parent);
+        }
+
+        let (path, relative) = if to.replace {
+            (path, relative)
+        } else {
+            match free(args, &user, req, dir, &relative).await {
+                Some(relative) => (format!("{}/{}", dir, relative), relative),
+                None => {
+                    files.push(fail("Too many files already have the name"));
+                    continue;
+                }
+            }
+        };
+
+        let replaced = match agent::metadata(args, &user, req, &path).await {
+            Some(DirEntry::Dir { .. }) => {
+                files.push(fail("A directory is in the way"));
+                continue;
+            },
+            Some(DirEntry::File { size, .. }) => Some(size),
+            _ => None
+        };
+
+        let exceeded = Rc::new(Cell::new(false));
+        let file = FormFile {
+            field,
+            left: args.form_file_limit.map_or(left, |limit| limit.min(left)),
+            exceeded: exceeded.clone(),
+            done: false,
+        };
+
+        let stream: Pin<Box<dyn Stream<Item = std::result::Result<Bytes, PayloadError>>>> = Box::pin(futures_util::stream::unfold(file, |mut file| async move {
+            file.next().await.map(|next| (next, file))
+        }));
+
+        let call = Call {
+            command: "file::write".to_owned(),
+            args: vec!["--".to_owned(), path.clone(), "true".to_owned()],
+            path: Some(path.clone()),
+            dry_run: false,
+            format: None,
+        };
+
+        let body = Payload::from_request(req, &mut dev::Payload::from(stream)).await?;
+        let res = api::run(req, pool, args, limiter, user.clone(), call, Some(body)).await?;
+        let status = res.status();
+
+        let mut outcome = body::to_bytes(res.into_body())
+            .await
+            .ok()
+            .and_then(|body| serde_json::from_slice::<Value>(&body).ok())
+            .filter(Value::is_object)
+            .unwrap_or_else(|| json! {{ "success": status.is_success() }});
+
+        outcome["path"] = relative.into();
+
+        if exceeded.get() {
+            outcome["msg"] = match args.form_file_limit {
+                Some(limit) if limit < left => format!("Files may be at most {} bytes", limit),
+                _ => "Not enough space left.".to_owned()
+            }.into();
+        }
+
+        if outcome["success"] == true {
+            // The file it replaced is gone now that it has taken its place.
+            left = left.saturating_sub(outcome["bytes"].as_u64().unwrap_or(0)).saturating_add(replaced.unwrap_or(0));
+        } else {
+            // A file which was cut off never takes the place of the one it replaces, but the agent is killed before it
+            // can discard what it staged, which would otherwise go on taking up space.
+            let call = Call {
+                command: "file::sweep".to_owned(),
+                args: vec!["--".to_owned(), path.clone()],
+                path: None,
+                dry_run: false,
+                format: None,
+            };
+
+            api::run(req, pool, args, limiter, user.clone(), call, None).await?;
+        }
+
+        files.push(outcome);
+    }
+
+    Ok(HttpResponse::Ok().json(json! {{
+        "success": files.iter().all(|file| file["success"] == true),
+        "files": files
+    }}))
+}
+
+/// Whether `relative` is reached from `root` without following symbolic links, so that it can't lead anywhere else.
+/// Paths which don't exist yet are reached once they are created.
+pub async fn reachable(args: &Args, user: &StorageProps, req: &HttpRequest, root: &str, relative: &str) -> bool {
+    let mut path = root.to_owned();
+
+    for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+        path = format!("{}/{}", path, segment);
+
+        match agent::lstat(args, user, req, &path).await {
+            Some(DirEntry::Link { .. }) => return false,
+            Some(_) => (),
+            None => return true
+        }
+    }
+
+    true
+}
+
+/// The first of `relative`, `relative (1)`, `relative (2)` and so on which isn't taken below `dir`, numbered ahead of
+/// the extension. `None` once [`FREE_NAMES`] are taken.
+async fn free(args: &Args, user: &StorageProps, req: &HttpRequest, dir: &str, relative: &str) -> Option<String> {
+    let (stem, extension) = match relative.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && !stem.ends_with('/') && !extension.contains('/') => (stem, format!(".{}", extension)),
+        _ => (relative, String::new())
+    };
+
+    for n in 0..FREE_NAMES {
+        let name = match n {
+            0 => relative.to_owned(),
+            n => format!("{} ({}){}", stem, n, extension)
+        };
+
+        if agent::lstat(args, user, req, &format!("{}/{}", dir, name)).await.is_none() {
+            return Some(name);
+        }
+    }
+
+    None
+}
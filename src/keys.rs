@@ -0,0 +1,145 @@
+use crate::{
+    api::User,
+    api::RNG,
+    passwords::internal,
+    passwords::not_signed_in
+};
+use actix_web::{
+    delete,
+    get,
+    post,
+    web::Bytes,
+    web::Data,
+    web::Path,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+    Responder,
+    Result
+};
+use base64::{
+    prelude::BASE64_URL_SAFE_NO_PAD,
+    Engine
+};
+use rand::RngCore;
+use serde::{
+    Deserialize,
+    Serialize
+};
+use serde_json::json;
+use sqlx::PgPool;
+
+/// Longest name an access key may be given.
+const NAME_LENGTH: usize = 100;
+
+/// An S3 access key as it is listed. The secret is only ever shown when the key is created.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessKey {
+    pub id: i64,
+    pub name: String,
+    pub access_key: String,
+
+    /// Seconds since the UNIX epoch.
+    pub created: f64,
+    pub last_used: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewKey {
+    /// Tells the user which tool the key is for, so they know which to revoke.
+    name: String,
+}
+
+/// Creates an access key for the S3-compatible API. The response is the only time the secret is shown.
+#[post("/s3-keys")]
+pub async fn create(req: HttpRequest, pool: Data<PgPool>, body: Bytes) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    let name = match serde_json::from_slice::<NewKey>(&body) {
+        Ok(NewKey { name }) if !name.trim().is_empty() && name.chars().count() <= NAME_LENGTH => name.trim().to_owned(),
+        _ => return Ok(HttpResponse::BadRequest().json(json! {{
+            "success": false,
+            "msg": format!("Expected a body like `{{\"name\": \"Backups\"}}`, with a name of at most {} characters", NAME_LENGTH)
+        }}))
+    };
+
+    // Shaped like AWS's own: 20 upper-case characters for the ID and 40 for the secret.
+    let (access_key, secret) = RNG.with_borrow_mut(|rng| {
+        let mut id = [0u8; 9];
+        let mut secret = [0u8; 30];
+        rng.fill_bytes(&mut id);
+        rng.fill_bytes(&mut secret);
+
+        (format!("JC{}", id.iter().map(|byte| format!("{:02X}", byte)).collect::<String>()), BASE64_URL_SAFE_NO_PAD.encode(secret))
+    });
+
+    match sqlx::query_as::<_, AccessKey>(r#"INSERT INTO s3_keys ("user", name, access_key, secret)
+SELECT uid, $2, $3, $4 FROM users WHERE email = $1
+RETURNING id, name, access_key, extract(epoch FROM created)::float8 AS created, extract(epoch FROM last_used)::float8 AS last_used"#)
+        .bind(&user.email)
+        .bind(&name)
+        .bind(&access_key)
+        .bind(&secret)
+        .fetch_one(pool.get_ref())
+        .await
+    {
+        Ok(created) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true,
+            "accessKeyId": access_key,
+            "secretAccessKey": secret,
+            "key": created
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
+
+/// The signed-in user's access keys, newest first.
+#[get("/s3-keys")]
+pub async fn list(req: HttpRequest, pool: Data<PgPool>) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    match sqlx::query_as::<_, AccessKey>(r#"SELECT id, name, access_key, extract(epoch FROM s3_keys.created)::float8 AS created, extract(epoch FROM last_used)::float8 AS last_used
+FROM s3_keys JOIN users ON users.uid = s3_keys."user"
+WHERE users.email = $1
+ORDER BY id DESC"#)
+        .bind(&user.email)
+        .fetch_all(pool.get_ref())
+        .await
+    {
+        Ok(keys) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true,
+            "keys": keys
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
+
+/// Revokes an access key. Requests signed with it are refused from then on.
+#[delete("/s3-keys/{id}")]
+pub async fn revoke(req: HttpRequest, pool: Data<PgPool>, id: Path<i64>) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    match sqlx::query(r#"DELETE FROM s3_keys USING users WHERE users.uid = s3_keys."user" AND users.email = $1 AND s3_keys.id = $2"#)
+        .bind(&user.email)
+        .bind(*id)
+        .execute(pool.get_ref())
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => Ok(HttpResponse::NotFound().json(json! {{
+            "success": false,
+            "msg": "No such access key."
+        }})),
+        Ok(_) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
@@ -1,5 +1,15 @@
 mod sql;
+mod agent;
+mod audit;
 mod api;
+mod files;
+mod dav;
+mod passwords;
+mod keys;
+mod s3;
+mod uploads;
+mod shares;
+mod xml;
 mod app;
 
 use crate::{
@@ -115,7 +125,55 @@ struct Args  {
     r#static: PathBuf,
 
     #[clap(long, default_value = "index.html")]
-    index: PathBuf
+    index: PathBuf,
+
+    /// Key used by the agent to wrap users' data keys when encryption is enabled
+    #[clap(long)]
+    master_key: Option<PathBuf>,
+
+    /// Let users create symbolic links with absolute targets (still confined to their storage)
+    #[clap(long)]
+    allow_absolute_links: bool,
+
+    /// Email address of a user who may view everyone's audit history. May be repeated.
+    #[clap(long)]
+    admin: Vec<String>,
+
+    /// Seconds an agent may run before it is killed
+    #[clap(long, default_value_t = 600)]
+    agent_timeout: u64,
+
+    /// Overrides `--agent-timeout` for one command, given as `command=seconds`. May be repeated.
+    #[clap(long, value_parser = agent::parse_timeout)]
+    agent_timeout_for: Vec<(String, u64)>,
+
+    /// Seconds of CPU time each agent may use
+    #[clap(long)]
+    agent_cpu: Option<u64>,
+
+    /// Files each agent may have open at once
+    #[clap(long, default_value_t = 1024)]
+    agent_files: u64,
+
+    /// Bytes of address space each agent may use
+    #[clap(long)]
+    agent_memory: Option<u64>,
+
+    /// Agents a single user may have running at once. Requests beyond this are refused with `429`.
+    #[clap(long, default_value_t = 4)]
+    agent_concurrency: usize,
+
+    /// Serve an S3-compatible API under `/s3`, with each user's storage as a bucket of this name
+    #[clap(long)]
+    s3_bucket: Option<String>,
+
+    /// Seconds an unfinished resumable upload is kept after data last arrived for it
+    #[clap(long, default_value_t = 24 * 60 * 60)]
+    upload_expiry: u64,
+
+    /// Largest file, in bytes, a `multipart/form-data` upload may contain
+    #[clap(long)]
+    form_file_limit: Option<u64>
 }
 
 #[actix_web::main]
@@ -137,6 +195,11 @@ async fn main() -> actix_web::Result<()> {
     let sql_map = SqlMap::new(args.sql.clone())?;
 
     let client = HTTPClient { client: reqwest::Client::new() };
+    let limiter = agent::Limiter::new(args.agent_concurrency);
+    let locks = dav::Locks::default();
+    let attempts = shares::Attempts::default();
+
+    actix_web::rt::spawn(uploads::expire(pool.clone(), args.clone()));
 
     let oauth_config: OAuthConfig = serde_json::from_reader(fs::OpenOptions::new()
         .read(true)
@@ -149,6 +212,9 @@ async fn main() -> actix_web::Result<()> {
             .app_data(web::Data::new(args.clone()))
             .app_data(web::Data::new(client.clone()))
             .app_data(web::Data::new(oauth_config.clone()))
+            .app_data(web::Data::new(limiter.clone()))
+            .app_data(web::Data::new(locks.clone()))
+            .app_data(web::Data::new(attempts.clone()))
             .service(Files::new("/static", &args.r#static).prefer_utf8(true))
             .route("/app", web::to(async |args: Data<Args>| NamedFile::open(&args.index)))
             .route("/app/{suburl:.*}", web::to(async |args: Data<Args>| NamedFile::open(&args.index)))
@@ -159,8 +225,43 @@ async fn main() -> actix_web::Result<()> {
             .service(api::login)
             .service(web::scope("/api")
                 .wrap(from_fn(api::authenticate))
+                .app_data(web::QueryConfig::default().error_handler(files::invalid_query))
                 .service(api::get_user)
-                .service(api::system))
+                .service(api::system)
+                .service(api::thumbnail)
+                .service(api::get_audit)
+                .service(files::read)
+                .service(files::write)
+                .service(files::remove)
+                .service(files::upload)
+                .service(files::act)
+                .service(passwords::create)
+                .service(passwords::list)
+                .service(passwords::revoke)
+                .service(keys::create)
+                .service(keys::list)
+                .service(keys::revoke)
+                .service(shares::create)
+                .service(shares::list)
+                .service(shares::revoke)
+                .service(web::scope("/uploads")
+                    .wrap(middleware::DefaultHeaders::new().add(("Tus-Resumable", uploads::VERSION)))
+                    .service(uploads::options)
+                    .service(uploads::create)
+                    .service(uploads::head)
+                    .service(uploads::append)
+                    .service(uploads::terminate)))
+            .service(shares::root)
+            .service(shares::read)
+            .service(shares::upload)
+            .service(web::scope("/dav")
+                .wrap(from_fn(dav::authenticate))
+                .default_service(web::to(dav::handle)))
+            .configure(|config| if args.s3_bucket.is_some() {
+                config.service(web::scope("/s3")
+                    .wrap(from_fn(s3::authenticate))
+                    .default_service(web::to(s3::handle)));
+            })
     })
         // .workers(std::thread::available_parallelism().expect("Failed to get CPUs").get())
         .bind(addr)?
@@ -0,0 +1,165 @@
+use crate::api::{
+    User,
+    RNG
+};
+use actix_web::{
+    delete,
+    get,
+    post,
+    web::Bytes,
+    web::Data,
+    web::Path,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+    Responder,
+    Result
+};
+use base64::{
+    prelude::BASE64_URL_SAFE_NO_PAD,
+    Engine
+};
+use log::error;
+use rand::RngCore;
+use serde::{
+    Deserialize,
+    Serialize
+};
+use serde_json::json;
+use sha2::{
+    Digest,
+    Sha256
+};
+use sqlx::PgPool;
+
+/// Longest name an app password may be given.
+const NAME_LENGTH: usize = 100;
+
+/// An app password as it is listed. The password itself is only ever shown when it is created.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AppPassword {
+    pub id: i64,
+    pub name: String,
+
+    /// Seconds since the UNIX epoch.
+    pub created: f64,
+    pub last_used: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewPassword {
+    /// Tells the user which client the password is for, so they know which to revoke.
+    name: String,
+}
+
+/// App passwords are random, so a plain SHA-256 is enough to keep them from being read back out of the database.
+pub fn hash(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn not_signed_in() -> HttpResponse {
+    HttpResponse::Unauthorized().json(json! {{
+        "success": false,
+        "msg": "Not signed in"
+    }})
+}
+
+pub fn internal(err: sqlx::Error) -> HttpResponse {
+    error!("{:?}", err);
+    HttpResponse::InternalServerError().json(json! {{
+        "success": false,
+        "msg": "Internal server error.",
+        "err": err.to_string()
+    }})
+}
+
+/// Creates an app password, for clients such as WebDAV mounts which can only sign in with a username and password.
+/// The response is the only time the password is shown.
+#[post("/app-passwords")]
+pub async fn create(req: HttpRequest, pool: Data<PgPool>, body: Bytes) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    let name = match serde_json::from_slice::<NewPassword>(&body) {
+        Ok(NewPassword { name }) if !name.trim().is_empty() && name.chars().count() <= NAME_LENGTH => name.trim().to_owned(),
+        _ => return Ok(HttpResponse::BadRequest().json(json! {{
+            "success": false,
+            "msg": format!("Expected a body like `{{\"name\": \"Laptop\"}}`, with a name of at most {} characters", NAME_LENGTH)
+        }}))
+    };
+
+    let password = RNG.with_borrow_mut(|rng| {
+        let mut bytes = [0u8; 24];
+        rng.fill_bytes(&mut bytes);
+        BASE64_URL_SAFE_NO_PAD.encode(bytes)
+    });
+
+    match sqlx::query_as::<_, AppPassword>(r#"INSERT INTO app_passwords ("user", name, hash)
+SELECT uid, $2, $3 FROM users WHERE email = $1
+RETURNING id, name, extract(epoch FROM created)::float8 AS created, extract(epoch FROM last_used)::float8 AS last_used"#)
+        .bind(&user.email)
+        .bind(&name)
+        .bind(hash(&password))
+        .fetch_one(pool.get_ref())
+        .await
+    {
+        Ok(created) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true,
+            "password": password,
+            "appPassword": created
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
+
+/// The signed-in user's app passwords, newest first.
+#[get("/app-passwords")]
+pub async fn list(req: HttpRequest, pool: Data<PgPool>) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    match sqlx::query_as::<_, AppPassword>(r#"SELECT id, name, extract(epoch FROM app_passwords.created)::float8 AS created, extract(epoch FROM last_used)::float8 AS last_used
+FROM app_passwords JOIN users ON users.uid = app_passwords."user"
+WHERE users.email = $1
+ORDER BY id DESC"#)
+        .bind(&user.email)
+        .fetch_all(pool.get_ref())
+        .await
+    {
+        Ok(passwords) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true,
+            "appPasswords": passwords
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
+
+/// Revokes an app password. Clients signed in with it are turned away from their next request on.
+#[delete("/app-passwords/{id}")]
+pub async fn revoke(req: HttpRequest, pool: Data<PgPool>, id: Path<i64>) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    match sqlx::query(r#"DELETE FROM app_passwords USING users WHERE users.uid = app_passwords."user" AND users.email = $1 AND app_passwords.id = $2"#)
+        .bind(&user.email)
+        .bind(*id)
+        .execute(pool.get_ref())
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => Ok(HttpResponse::NotFound().json(json! {{
+            "success": false,
+            "msg": "No such app password."
+        }})),
+        Ok(_) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
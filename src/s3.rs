@@ -0,0 +1,1557 @@
+use crate::{
+    agent,
+    agent::DirEntry,
+    agent::StorageProps,
+    api,
+    api::Call,
+    api::User,
+    api::RNG,
+    xml,
+    Args
+};
+use actix_web::{
+    body,
+    body::MessageBody,
+    dev,
+    dev::ServiceRequest,
+    dev::ServiceResponse,
+    error::PayloadError,
+    http::header,
+    http::header::HeaderValue,
+    http::StatusCode,
+    middleware::Next,
+    web::Bytes,
+    web::BytesMut,
+    web::Data,
+    web::Payload,
+    FromRequest,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+    Result
+};
+use base64::{
+    prelude::BASE64_STANDARD,
+    prelude::BASE64_URL_SAFE_NO_PAD,
+    Engine
+};
+use futures_util::{
+    Stream,
+    StreamExt as _
+};
+use hmac::{
+    Hmac,
+    Mac
+};
+use humantime_serde::re::humantime;
+use log::error;
+use percent_encoding::{
+    percent_decode_str,
+    utf8_percent_encode,
+    AsciiSet,
+    NON_ALPHANUMERIC
+};
+use rand::RngCore;
+use sha2::{
+    Digest,
+    Sha256
+};
+use sqlx::{
+    FromRow,
+    PgPool,
+    Row
+};
+use std::{
+    collections::BTreeMap,
+    io,
+    ops::Bound,
+    pin::Pin,
+    time::Duration,
+    time::SystemTime,
+    time::UNIX_EPOCH
+};
+
+/// Where the service is mounted. Requests address `/s3/{bucket}/{key}`.
+const PREFIX: &str = "/s3";
+
+const NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+const XML: &str = "application/xml";
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// The SHA-256 of nothing, which is what SigV4 hashes for chunks without data.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// How far the time a request was signed may be from the server's clock.
+const SKEW: Duration = Duration::from_secs(15 * 60);
+
+/// Longest a presigned URL may stay valid, in seconds.
+const MAX_EXPIRY: u64 = 7 * 24 * 60 * 60;
+
+/// Largest XML body read, enough for a batch delete of [`MAX_KEYS`] long keys.
+const BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Longest line of an `aws-chunked` body: a chunk's size and signature, or a trailing header.
+const LINE_LIMIT: usize = 4096;
+
+/// Most entries a listing returns, and most keys a batch delete takes.
+const MAX_KEYS: usize = 1000;
+
+/// Highest part number of a multipart upload.
+const MAX_PARTS: u32 = 10000;
+
+/// Characters SigV4 leaves as they are when it canonicalises a request: letters, digits and `-._~`.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// The same, with `/` left alone too, for keys in listings which ask for them URL-encoded.
+const KEY: &AsciiSet = &UNRESERVED.remove(b'/');
+
+/// Subresources of buckets and objects which aren't supported, and so are refused rather than mistaken for plain
+/// reads and writes.
+const UNSUPPORTED: &[&str] = &["accelerate", "acl", "analytics", "attributes", "cors", "encryption", "intelligent-tiering",
+    "inventory", "legal-hold", "lifecycle", "logging", "metrics", "notification", "object-lock", "ownershipControls", "policy",
+    "publicAccessBlock", "replication", "requestPayment", "restore", "retention", "select", "tagging", "torrent",
+    "versioning", "versions", "website"];
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Compares signatures without giving away, through timing, how much of them matched.
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Decodes a component of a URL. `+` is taken as it is, as AWS's clients encode spaces as `%20`.
+fn decode(component: &str) -> Option<String> {
+    percent_decode_str(component).decode_utf8().ok().map(|decoded| decoded.into_owned())
+}
+
+/// A time as S3 formats it in response bodies.
+fn time(time: SystemTime) -> String {
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+/// An error as S3 reports it.
+#[derive(Debug, Clone)]
+struct Failure {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: StatusCode, code: &'static str, message: impl ToString) -> Self {
+        Self { status, code, message: message.to_string() }
+    }
+
+    fn internal() -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", "We encountered an internal error. Please try again.")
+    }
+
+    fn no_such_key() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "NoSuchKey", "The specified key does not exist.")
+    }
+
+    fn no_such_upload() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "NoSuchUpload", "The specified multipart upload does not exist.")
+    }
+
+    fn invalid(message: impl ToString) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidRequest", message)
+    }
+
+    fn respond(&self) -> HttpResponse {
+        HttpResponse::build(self.status)
+            .content_type(XML)
+            .body(format!(r#"<?xml version="1.0" encoding="UTF-8"?><Error><Code>{}</Code><Message>{}</Message></Error>"#,
+                self.code, xml::escape(&self.message)))
+    }
+}
+
+type Answer = std::result::Result<HttpResponse, Failure>;
+
+fn document(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(XML)
+        .body(format!(r#"<?xml version="1.0" encoding="UTF-8"?>{}"#, body))
+}
+
+/// What a request was signed with, kept for checking the signatures of the chunks of an `aws-chunked` body, each of
+/// which chains on the one before.
+#[derive(Debug, Clone)]
+struct Signing {
+    key: Vec<u8>,
+
+    /// When the request was signed, as `x-amz-date` gives it.
+    timestamp: String,
+    scope: String,
+    signature: String,
+}
+
+impl Signing {
+    /// Signs the next link of the chain, which is made of `hashes` and the signature before, and moves on to it.
+    fn chain(&mut self, algorithm: &str, hashes: &str) -> String {
+        let string = format!("{}\n{}\n{}\n{}\n{}", algorithm, self.timestamp, self.scope, self.signature, hashes);
+        self.signature = hex(&hmac(&self.key, &string));
+        self.signature.clone()
+    }
+}
+
+/// A SigV4 signature, from the `Authorization` header or the query of a presigned URL.
+#[derive(Debug, Clone)]
+struct Credentials {
+    access_key: String,
+
+    /// The date, region, service and terminator the signing key is derived from, joined by `/`.
+    scope: String,
+    timestamp: String,
+    signed_headers: Vec<String>,
+    signature: String,
+    presigned: bool,
+}
+
+/// Parses a timestamp in SigV4's basic ISO 8601 format, such as `20240131T120000Z`.
+fn timestamp(timestamp: &str) -> Option<SystemTime> {
+    let bytes = timestamp.as_bytes();
+
+    if bytes.len() != 16 || bytes[8] != b'T' || bytes[15] != b'Z' || !timestamp.is_ascii() {
+        return None;
+    }
+
+    humantime::parse_rfc3339(&format!("{}-{}-{}T{}:{}:{}Z", &timestamp[..4], &timestamp[4..6], &timestamp[6..8],
+        &timestamp[9..11], &timestamp[11..13], &timestamp[13..15])).ok()
+}
+
+fn access_denied(message: impl ToString) -> Failure {
+    Failure::new(StatusCode::FORBIDDEN, "AccessDenied", message)
+}
+
+/// Reads the signature a request carries, checking that it was made recently enough, or for a presigned URL, that it
+/// hasn't expired.
+fn credentials(req: &HttpRequest) -> std::result::Result<Credentials, Failure> {
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let query = req.query_string()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(name, value)| Some((decode(name)?, decode(value)?)))
+        .collect::<BTreeMap<_, _>>();
+
+    let (credential, signed_headers, signature, timestamp_given, expires) = if let Some(authorization) = header("Authorization") {
+        let Some(fields) = authorization.strip_prefix(ALGORITHM).filter(|fields| fields.starts_with(' ')) else {
+            return Err(Failure::invalid("Only AWS4-HMAC-SHA256 signatures are supported."));
+        };
+
+        let fields = fields.split(',')
+            .filter_map(|field| field.trim().split_once('='))
+            .collect::<BTreeMap<_, _>>();
+
+        let (Some(credential), Some(signed), Some(signature)) = (fields.get("Credential"), fields.get("SignedHeaders"), fields.get("Signature")) else {
+            return Err(Failure::invalid("The authorization header is malformed."));
+        };
+
+        (credential.to_string(), signed.to_string(), signature.to_string(), header("x-amz-date").map(str::to_owned), None)
+    } else if let Some(algorithm) = query.get("X-Amz-Algorithm") {
+        if algorithm != ALGORITHM {
+            return Err(Failure::invalid("Only AWS4-HMAC-SHA256 signatures are supported."));
+        }
+
+        let (Some(credential), Some(signed), Some(signature), Some(expires)) = (query.get("X-Amz-Credential"), query.get("X-Amz-SignedHeaders"),
+            query.get("X-Amz-Signature"), query.get("X-Amz-Expires").and_then(|expires| expires.parse::<u64>().ok()))
+        else {
+            return Err(Failure::invalid("The query parameters of the presigned URL are malformed."));
+        };
+
+        if expires > MAX_EXPIRY {
+            return Err(access_denied(format!("Presigned URLs may be valid for at most {} seconds.", MAX_EXPIRY)));
+        }
+
+        (credential.clone(), signed.clone(), signature.clone(), query.get("X-Amz-Date").cloned(), Some(expires))
+    } else {
+        return Err(access_denied("Anonymous requests aren't accepted."));
+    };
+
+    let Some((access_key, scope)) = credential.split_once('/') else {
+        return Err(Failure::invalid("The credential is malformed."));
+    };
+
+    let Some(signed_at) = timestamp_given.as_deref().and_then(timestamp) else {
+        return Err(access_denied("The request must carry x-amz-date."));
+    };
+
+    let timestamp_given = timestamp_given.unwrap_or_default();
+
+    match scope.split('/').collect::<Vec<_>>().as_slice() {
+        [date, _, "s3", "aws4_request"] if timestamp_given.starts_with(date) => (),
+        _ => return Err(Failure::invalid("The credential's scope is malformed."))
+    }
+
+    let now = SystemTime::now();
+    let early = signed_at.duration_since(now).is_ok_and(|ahead| ahead > SKEW);
+    let late = now.duration_since(signed_at).is_ok_and(|behind| behind > expires.map_or(SKEW, Duration::from_secs));
+
+    if early || late {
+        return Err(match expires {
+            Some(_) => access_denied("Request has expired"),
+            None => Failure::new(StatusCode::FORBIDDEN, "RequestTimeTooSkewed", "The difference between the request time and the current time is too large.")
+        });
+    }
+
+    let signed_headers = signed_headers.split(';').map(str::to_owned).collect::<Vec<_>>();
+
+    if !signed_headers.iter().any(|name| name == "host") {
+        return Err(Failure::invalid("The host header must be signed."));
+    }
+
+    Ok(Credentials {
+        access_key: access_key.to_owned(),
+        scope: scope.to_owned(),
+        timestamp: timestamp_given,
+        signed_headers,
+        signature,
+        presigned: expires.is_some(),
+    })
+}
+
+impl Credentials {
+    /// The request as SigV4 canonicalises it, which is what the client signed.
+    fn canonical_request(&self, req: &HttpRequest) -> Option<String> {
+        let encode = |component: &str| Some(utf8_percent_encode(&decode(component)?, UNRESERVED).to_string());
+
+        let path = req.path()
+            .split('/')
+            .map(encode)
+            .collect::<Option<Vec<_>>>()?
+            .join("/");
+
+        let mut query = req.query_string()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Some((encode(name)?, encode(value)?))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        query.retain(|(name, _)| name != "X-Amz-Signature");
+        query.sort();
+
+        let query = query.iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let headers = self.signed_headers
+            .iter()
+            .map(|name| {
+                let values = req.headers()
+                    .get_all(name.as_str())
+                    .map(|value| value.to_str().ok().map(|value| value.split_whitespace().collect::<Vec<_>>().join(" ")))
+                    .collect::<Option<Vec<_>>>()?;
+
+                Some(format!("{}:{}\n", name, values.join(",")))
+            })
+            .collect::<Option<String>>()?;
+
+        let payload = match req.headers().get("x-amz-content-sha256").and_then(|value| value.to_str().ok()) {
+            Some(payload) => payload,
+            None if self.presigned => "UNSIGNED-PAYLOAD",
+            None => return None
+        };
+
+        Some(format!("{}\n{}\n{}\n{}\n{}\n{}", req.method(), path, query, headers, self.signed_headers.join(";"), payload))
+    }
+
+    /// Checks the signature against the secret of its access key, returning what it was signed with if it matches.
+    fn verify(&self, req: &HttpRequest, secret: &str) -> Option<Signing> {
+        let canonical = self.canonical_request(req)?;
+        let string = format!("{}\n{}\n{}\n{}", ALGORITHM, self.timestamp, self.scope, hex(&Sha256::digest(canonical.as_bytes())));
+
+        let key = self.scope
+            .split('/')
+            .fold(format!("AWS4{}", secret).into_bytes(), |key, part| hmac(&key, part));
+
+        same(&hex(&hmac(&key, &string)), &self.signature).then(|| Signing {
+            key,
+            timestamp: self.timestamp.clone(),
+            scope: self.scope.clone(),
+            signature: self.signature.clone(),
+        })
+    }
+}
+
+/// Signs clients in with SigV4, using an access key created through `/api/s3-keys`.
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>> {
+    let credentials = match credentials(req.request()) {
+        Ok(credentials) => credentials,
+        Err(failure) => return Ok(req.into_response(failure.respond()))
+    };
+
+    let Some(pool) = req.app_data::<Data<PgPool>>().cloned() else {
+        return Ok(req.into_response(Failure::internal().respond()));
+    };
+
+    let row = sqlx::query(r#"SELECT users.*, s3_keys.id AS key, s3_keys.secret FROM s3_keys JOIN users ON users.uid = s3_keys."user" WHERE access_key = $1"#)
+        .bind(&credentials.access_key)
+        .fetch_optional(pool.get_ref())
+        .await;
+
+    let (user, key, secret) = match row.and_then(|row| row.map(|row| Ok((User::from_row(&row)?, row.try_get::<i64, _>("key")?, row.try_get::<String, _>("secret")?))).transpose()) {
+        Ok(Some(found)) => found,
+        Ok(None) => return Ok(req.into_response(Failure::new(StatusCode::FORBIDDEN, "InvalidAccessKeyId",
+            "The AWS access key ID you provided does not exist in our records.").respond())),
+        Err(err) => {
+            error!("{:?}", err);
+            return Ok(req.into_response(Failure::internal().respond()));
+        }
+    };
+
+    let Some(signing) = credentials.verify(req.request(), &secret) else {
+        return Ok(req.into_response(Failure::new(StatusCode::FORBIDDEN, "SignatureDoesNotMatch",
+            "The request signature we calculated does not match the signature you provided.").respond()));
+    };
+
+    if let Err(err) = sqlx::query("UPDATE s3_keys SET last_used = now() WHERE id = $1").bind(key).execute(pool.get_ref()).await {
+        error!("{:?}", err);
+    }
+
+    req.extensions_mut().insert(user);
+    req.extensions_mut().insert(signing);
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+/// A checksum a client sent along with an upload, in `x-amz-checksum-*`. Only CRC32 and SHA-256 are checked.
+enum Checksum {
+    Crc32(crc32fast::Hasher),
+    Sha256(Sha256),
+}
+
+impl Checksum {
+    fn new(algorithm: &str) -> Option<Self> {
+        match algorithm {
+            "crc32" => Some(Self::Crc32(crc32fast::Hasher::new())),
+            "sha256" => Some(Self::Sha256(Sha256::new())),
+            _ => None
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Crc32(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data)
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Self::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec()
+        }
+    }
+}
+
+fn invalid_body(message: impl ToString) -> PayloadError {
+    PayloadError::Io(io::Error::new(io::ErrorKind::InvalidData, message.to_string()))
+}
+
+/// Checks a request body against the hashes and checksums the client sent, and decodes `aws-chunked` bodies, as it
+/// streams to the agent. Anything amiss ends the stream with an error, so that the agent is killed before it can take
+/// the upload for complete.
+struct Body {
+    payload: Payload,
+    buffer: BytesMut,
+
+    /// Whether the body is `aws-chunked`, and whether trailing headers follow its last chunk.
+    chunked: bool,
+    trailer: bool,
+
+    /// For bodies whose chunks are signed, what the signature of the next chunk chains on.
+    signing: Option<Signing>,
+
+    /// Bytes of the current chunk still to be passed on, whether it has begun, and its signature.
+    left: u64,
+    in_chunk: bool,
+    chunk_signature: Option<String>,
+    chunk: Sha256,
+
+    /// The SHA-256 the whole body must have, from `x-amz-content-sha256`.
+    sha256: Option<(Sha256, Vec<u8>)>,
+
+    /// The checksum header, the checksum so far and what it must come to. That isn't known until the trailing headers
+    /// have been read if it is sent in them.
+    checksum: Option<(String, Checksum, Option<Vec<u8>>)>,
+
+    length: u64,
+
+    /// The length of a chunked body once decoded, from `x-amz-decoded-content-length`.
+    decoded_length: Option<u64>,
+
+    done: bool,
+}
+
+impl Body {
+    /// Reads more of the request into the buffer. `false` at its end.
+    async fn fill(&mut self) -> std::result::Result<bool, PayloadError> {
+        match self.payload.next().await {
+            Some(chunk) => {
+                self.buffer.extend_from_slice(&chunk?);
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// Reads a line of a chunked body, without its CRLF. `None` at the end of the body.
+    async fn line(&mut self) -> std::result::Result<Option<String>, PayloadError> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let line = self.buffer.split_to(end + 2);
+                return String::from_utf8(line[..end].to_vec()).map(Some).map_err(|_| invalid_body("A chunk header isn't UTF-8"));
+            }
+
+            if self.buffer.len() > LINE_LIMIT {
+                return Err(invalid_body("A chunk header is too long"));
+            }
+
+            if !self.fill().await? {
+                return match self.buffer.is_empty() {
+                    true => Ok(None),
+                    false => Err(invalid_body("The body ends partway through a chunk header"))
+                };
+            }
+        }
+    }
+
+    fn pass(&mut self, data: &[u8]) {
+        self.length += data.len() as u64;
+        self.chunk.update(data);
+
+        if let Some((hasher, _)) = &mut self.sha256 {
+            hasher.update(data);
+        }
+
+        if let Some((_, checksum, _)) = &mut self.checksum {
+            checksum.update(data);
+        }
+    }
+
+    /// Checks the signature of the chunk which just ended, if chunks are signed.
+    fn check_chunk(&mut self) -> std::result::Result<(), PayloadError> {
+        let hash = hex(&std::mem::take(&mut self.chunk).finalize());
+        let signature = self.chunk_signature.take();
+
+        let Some(signing) = &mut self.signing else {
+            return Ok(());
+        };
+
+        let expected = signing.chain("AWS4-HMAC-SHA256-PAYLOAD", &format!("{}\n{}", EMPTY_SHA256, hash));
+
+        match signature {
+            Some(signature) if same(&signature, &expected) => Ok(()),
+            _ => Err(invalid_body("A chunk's signature doesn't match"))
+        }
+    }
+
+    /// Reads the headers which follow the last chunk, up to the empty line ending them.
+    async fn trailers(&mut self) -> std::result::Result<(), PayloadError> {
+        let mut canonical = String::new();
+        let mut signature = None;
+
+        while let Some(line) = self.line().await? {
+            if line.is_empty() {
+                break;
+            }
+
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(invalid_body("A trailing header is malformed"));
+            };
+
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+
+            if name == "x-amz-trailer-signature" {
+                signature = Some(value.to_owned());
+                continue;
+            }
+
+            canonical.push_str(&format!("{}:{}\n", name, value));
+
+            if let Some((header, _, expected)) = &mut self.checksum
+                && *header == name
+            {
+                *expected = Some(BASE64_STANDARD.decode(value).unwrap_or_default());
+            }
+        }
+
+        if self.trailer
+            && let Some(signing) = &mut self.signing
+        {
+            let expected = signing.chain("AWS4-HMAC-SHA256-TRAILER", &hex(&Sha256::digest(canonical.as_bytes())));
+
+            if !signature.is_some_and(|signature| same(&signature, &expected)) {
+                return Err(invalid_body("The trailing headers' signature doesn't match"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the body as a whole once all of it has been passed on.
+    fn finish(&mut self) -> std::result::Result<(), PayloadError> {
+        if let Some(expected) = self.decoded_length
+            && expected != self.length
+        {
+            return Err(invalid_body("The body's length doesn't match x-amz-decoded-content-length"));
+        }
+
+        if let Some((hasher, expected)) = self.sha256.take()
+            && hasher.finalize().as_slice() != expected
+        {
+            return Err(invalid_body("The body doesn't match x-amz-content-sha256"));
+        }
+
+        if let Some((header, checksum, expected)) = self.checksum.take()
+            && expected.is_none_or(|expected| expected != checksum.finish())
+        {
+            return Err(invalid_body(format!("The body doesn't match {}", header)));
+        }
+
+        Ok(())
+    }
+
+    /// The next piece of the decoded body. `None` once all of it has been checked.
+    async fn advance(&mut self) -> std::result::Result<Option<Bytes>, PayloadError> {
+        if !self.chunked {
+            return match self.payload.next().await {
+                Some(data) => {
+                    let data = data?;
+                    self.pass(&data);
+                    Ok(Some(data))
+                },
+                None => self.finish().map(|_| None)
+            };
+        }
+
+        loop {
+            if self.left > 0 {
+                if self.buffer.is_empty() && !self.fill().await? {
+                    return Err(invalid_body("The body ends partway through a chunk"));
+                }
+
+                let data = self.buffer.split_to(self.left.min(self.buffer.len() as u64) as usize).freeze();
+                self.left -= data.len() as u64;
+                self.pass(&data);
+
+                return Ok(Some(data));
+            }
+
+            // Each chunk's data is followed by CRLF.
+            if self.in_chunk {
+                if self.line().await?.as_deref() != Some("") {
+                    return Err(invalid_body("A chunk is longer than its header says"));
+                }
+
+                self.in_chunk = false;
+                self.check_chunk()?;
+                continue;
+            }
+
+            let Some(line) = self.line().await? else {
+                return Err(invalid_body("The body ends before its last chunk"));
+            };
+
+            let (size, extensions) = line.split_once(';').unwrap_or((&line, ""));
+            let size = u64::from_str_radix(size.trim(), 16).map_err(|_| invalid_body("A chunk's size is malformed"))?;
+
+            self.chunk_signature = extensions.split(';')
+                .find_map(|extension| extension.trim().strip_prefix("chunk-signature="))
+                .map(str::to_owned);
+
+            if size == 0 {
+                self.check_chunk()?;
+                self.trailers().await?;
+                return self.finish().map(|_| None);
+            }
+
+            self.left = size;
+            self.in_chunk = true;
+        }
+    }
+
+    async fn next(&mut self) -> Option<std::result::Result<Bytes, PayloadError>> {
+        if self.done {
+            return None;
+        }
+
+        let next = self.advance().await.transpose();
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+/// Wraps a request body in a [`Body`] as its `x-amz-content-sha256` and checksum headers call for.
+async fn body(req: &HttpRequest, payload: Payload) -> std::result::Result<Payload, Failure> {
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let signing = req.extensions().get::<Signing>().cloned();
+
+    let (chunked, signed, trailer, sha256) = match header("x-amz-content-sha256").unwrap_or("UNSIGNED-PAYLOAD") {
+        "UNSIGNED-PAYLOAD" => (false, false, false, None),
+        "STREAMING-UNSIGNED-PAYLOAD-TRAILER" => (true, false, true, None),
+        "STREAMING-AWS4-HMAC-SHA256-PAYLOAD" => (true, true, false, None),
+        "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER" => (true, true, true, None),
+        hash => match unhex(hash).filter(|hash| hash.len() == 32) {
+            Some(hash) => (false, false, false, Some((Sha256::new(), hash))),
+            None => return Err(Failure::new(StatusCode::BAD_REQUEST, "InvalidArgument", "x-amz-content-sha256 isn't supported."))
+        }
+    };
+
+    let checksum = if trailer {
+        header("x-amz-trailer")
+            .map(|name| name.trim().to_ascii_lowercase())
+            .and_then(|name| Some((Checksum::new(name.strip_prefix("x-amz-checksum-")?)?, name)))
+            .map(|(checksum, name)| (name, checksum, None))
+    } else {
+        ["crc32", "sha256"].into_iter().find_map(|algorithm| {
+            let name = format!("x-amz-checksum-{}", algorithm);
+            let expected = BASE64_STANDARD.decode(header(&name)?).unwrap_or_default();
+            Some((name, Checksum::new(algorithm)?, Some(expected)))
+        })
+    };
+
+    let body = Body {
+        payload,
+        buffer: BytesMut::new(),
+        chunked,
+        trailer,
+        signing: signing.filter(|_| signed),
+        left: 0,
+        in_chunk: false,
+        chunk_signature: None,
+        chunk: Sha256::new(),
+        sha256,
+        checksum,
+        length: 0,
+        decoded_length: header("x-amz-decoded-content-length").and_then(|length| length.parse().ok()).filter(|_| chunked),
+        done: false,
+    };
+
+    let stream: Pin<Box<dyn Stream<Item = std::result::Result<Bytes, PayloadError>>>> = Box::pin(futures_util::stream::unfold(body, |mut body| async move {
+        body.next().await.map(|next| (next, body))
+    }));
+
+    Payload::from_request(req, &mut dev::Payload::from(stream)).await.map_err(|_| Failure::internal())
+}
+
+/// Reads an XML request body of at most [`BODY_LIMIT`] bytes.
+async fn read(req: &HttpRequest, payload: Payload) -> std::result::Result<xml::Element, Failure> {
+    let mut body = body(req, payload).await?;
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|err| Failure::new(StatusCode::BAD_REQUEST, "IncompleteBody", err))?;
+
+        if bytes.len() + chunk.len() > BODY_LIMIT {
+            return Err(Failure::new(StatusCode::BAD_REQUEST, "MaxMessageLengthExceeded", "Your request was too big."));
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    String::from_utf8(bytes)
+        .map_err(|_| ())
+        .and_then(|body| xml::parse(&body).map_err(|err| log::debug!("{}", err)))
+        .map_err(|_| Failure::new(StatusCode::BAD_REQUEST, "MalformedXML", "The XML you provided was not well-formed."))
+}
+
+/// Translates a refusal by the API into the error an S3 client expects.
+async fn failed(res: HttpResponse) -> Failure {
+    let status = res.status();
+    let message = body::to_bytes(res.into_body())
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok())
+        .and_then(|body| body["msg"].as_str().map(str::to_owned))
+        .unwrap_or_else(|| "The request was refused.".to_owned());
+
+    match status {
+        StatusCode::NOT_FOUND => Failure::no_such_key(),
+        StatusCode::PRECONDITION_FAILED => Failure::new(status, "PreconditionFailed", "At least one of the preconditions you specified did not hold."),
+        StatusCode::TOO_MANY_REQUESTS => Failure::new(StatusCode::SERVICE_UNAVAILABLE, "SlowDown", "Please reduce your request rate."),
+        StatusCode::INSUFFICIENT_STORAGE => Failure::new(StatusCode::BAD_REQUEST, "EntityTooLarge", message),
+        StatusCode::BAD_REQUEST => Failure::new(status, "IncompleteBody", message),
+        StatusCode::UNPROCESSABLE_ENTITY => Failure::invalid(message),
+        _ => Failure::internal()
+    }
+}
+
+/// Answers a successful write, passing on the new `ETag` of what was written.
+fn stored(res: &HttpResponse) -> HttpResponse {
+    let mut answer = HttpResponse::Ok();
+
+    for name in [header::ETAG, header::HeaderName::from_static("x-request-id")] {
+        if let Some(value) = res.headers().get(&name) {
+            answer.insert_header((name, value.clone()));
+        }
+    }
+
+    answer.finish()
+}
+
+/// The path below the user's base an object key addresses. `None` for keys which don't make a path, such as those with
+/// empty or `..` segments. A trailing slash, which marks a directory, is dropped.
+fn path(key: &str) -> Option<String> {
+    let segments = key.strip_suffix('/').unwrap_or(key).split('/').collect::<Vec<_>>();
+
+    if segments.iter().any(|segment| matches!(*segment, "" | "." | "..") || segment.contains('\0')) {
+        return None;
+    }
+
+    Some(format!("/{}", segments.join("/")))
+}
+
+fn parent(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/",
+        Some((parent, _)) => parent
+    }
+}
+
+/// The key of a listed entry.
+fn key(path: &std::path::Path) -> String {
+    path.to_string_lossy().trim_start_matches('/').to_owned()
+}
+
+/// The range of a file of `size` bytes a `Range` header asks for, as an offset and a length. Only single ranges are
+/// supported. `None` if the range can't be satisfied.
+fn byte_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = header.trim().strip_prefix("bytes=")?.split_once('-')?;
+
+    match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let length = suffix.parse::<u64>().ok()?.min(size);
+            (length > 0).then(|| (size - length, length))
+        },
+        (start, "") => {
+            let start = start.parse::<u64>().ok()?;
+            (start < size).then(|| (start, size - start))
+        },
+        (start, end) => {
+            let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+            (start <= end && start < size).then(|| (start, end.min(size - 1) - start + 1))
+        }
+    }
+}
+
+/// The query parameters of a request, decoded.
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn parse(query: &str) -> Option<Self> {
+        query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Some((decode(name)?, decode(value)?))
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Self)
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    fn unsupported(&self) -> bool {
+        self.0.iter().any(|(name, _)| UNSUPPORTED.contains(&name.as_str()))
+    }
+}
+
+/// What every operation needs to run agent commands for the request.
+struct S3<'a> {
+    req: &'a HttpRequest,
+    pool: &'a PgPool,
+    args: &'a Args,
+    limiter: &'a agent::Limiter,
+    user: StorageProps,
+    bucket: &'a str,
+    params: Params,
+}
+
+impl S3<'_> {
+    async fn metadata(&self, path: &str) -> Option<DirEntry> {
+        agent::metadata(self.args, &self.user, self.req, path).await
+    }
+
+    /// Runs an agent command through the API, so that it is limited and audited like any other.
+    async fn run(&self, command: &str, arguments: &[&str], path: Option<&str>, body: Option<Payload>) -> Answer {
+        let call = Call {
+            command: command.to_owned(),
+            args: arguments.iter().map(|argument| argument.to_string()).collect(),
+            path: path.map(str::to_owned),
+            dry_run: false,
+            format: None,
+        };
+
+        api::run(self.req, self.pool, self.args, self.limiter, self.user.clone(), call, body)
+            .await
+            .map_err(|err| {
+                error!("{:?}", err);
+                Failure::internal()
+            })
+    }
+
+    /// Runs an agent command which changes something, translating its refusal if it does.
+    async fn change(&self, command: &str, arguments: &[&str], path: Option<&str>, body: Option<Payload>) -> Answer {
+        let res = self.run(command, arguments, path, body).await?;
+
+        match res.status().is_success() {
+            true => Ok(res),
+            false => Err(failed(res).await)
+        }
+    }
+
+    /// Creates the directories leading up to `path`. Keys imply their prefixes, so writes never fail for want of them.
+    async fn parents(&self, path: &str) -> std::result::Result<(), Failure> {
+        let parent = parent(path);
+
+        if parent != "/" && !matches!(self.metadata(parent).await, Some(DirEntry::Dir { .. })) {
+            self.change("file::mkdir", &["--", parent], None, None).await?;
+        }
+
+        Ok(())
+    }
+
+    fn max(&self, name: &str) -> std::result::Result<usize, Failure> {
+        match self.params.get(name) {
+            None => Ok(MAX_KEYS),
+            Some(max) => max.parse::<usize>()
+                .map(|max| max.min(MAX_KEYS))
+                .map_err(|_| Failure::new(StatusCode::BAD_REQUEST, "InvalidArgument", format!("{} must be a number.", name)))
+        }
+    }
+
+    fn buckets(&self) -> Answer {
+        Ok(document(format!(
+            r#"<ListAllMyBucketsResult xmlns="{}"><Owner><ID>{}</ID><DisplayName>{}</DisplayName></Owner><Buckets><Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket></Buckets></ListAllMyBucketsResult>"#,
+            NAMESPACE, xml::escape(&self.user.email), xml::escape(&self.user.display), xml::escape(self.bucket), time(UNIX_EPOCH))))
+    }
+
+    /// The objects whose keys begin with `prefix`, and with a delimiter, the prefixes they share up to it, sorted by
+    /// key. Prefixes map to `None`.
+    async fn entries(&self, prefix: &str, delimiter: &str) -> std::result::Result<BTreeMap<String, Option<(u64, SystemTime)>>, Failure> {
+        let mut entries = BTreeMap::new();
+
+        // Only the directory holding the prefix needs walking, and with `/` as the delimiter, only its first level.
+        let dir = match prefix.rsplit_once('/') {
+            Some((dir, _)) => match path(dir) {
+                Some(dir) => dir,
+                None => return Ok(entries)
+            },
+            None => "/".to_owned()
+        };
+
+        if !matches!(self.metadata(&dir).await, Some(DirEntry::Dir { .. })) {
+            return Ok(entries);
+        }
+
+        let shallow = delimiter == "/";
+        let listed = agent::list(self.args, &self.user, self.req, &dir, shallow.then_some(1))
+            .await
+            .ok_or_else(Failure::internal)?;
+
+        for entry in listed {
+            let (key, object) = match entry {
+                DirEntry::File { path, size, modified, .. } => (key(&path), Some((size, modified))),
+                DirEntry::Dir { path, .. } if shallow => (format!("{}/", key(&path)), None),
+                _ => continue
+            };
+
+            let Some(rest) = key.strip_prefix(prefix) else {
+                continue;
+            };
+
+            match rest.find(delimiter).filter(|_| !delimiter.is_empty()) {
+                Some(end) => {
+                    entries.insert(format!("{}{}", prefix, &rest[..end + delimiter.len()]), None);
+                },
+                None if object.is_some() => {
+                    entries.insert(key, object);
+                },
+                None => ()
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// ListObjectsV2, or without `list-type=2`, the original ListObjects.
+    async fn list(&self) -> Answer {
+        let v2 = self.params.get("list-type") == Some("2");
+        let prefix = self.params.get("prefix").unwrap_or_default();
+        let delimiter = self.params.get("delimiter").unwrap_or_default();
+        let max = self.max("max-keys")?;
+
+        let token = match self.params.get("continuation-token").filter(|_| v2) {
+            Some(token) => match BASE64_URL_SAFE_NO_PAD.decode(token).ok().and_then(|token| String::from_utf8(token).ok()) {
+                Some(token) => Some(token),
+                None => return Err(Failure::new(StatusCode::BAD_REQUEST, "InvalidArgument", "The continuation token provided is incorrect."))
+            },
+            None => None
+        };
+
+        let marker = match v2 {
+            true => token.clone().or(self.params.get("start-after").map(str::to_owned)),
+            false => self.params.get("marker").map(str::to_owned)
+        };
+
+        let url = self.params.get("encoding-type") == Some("url");
+        let encode = |text: &str| match url {
+            true => utf8_percent_encode(text, KEY).to_string(),
+            false => xml::escape(text)
+        };
+
+        let entries = self.entries(prefix, delimiter).await?;
+        let after = marker.clone().map_or(Bound::Unbounded, Bound::Excluded);
+        let page = entries.range::<String, _>((after, Bound::Unbounded)).take(max + 1).collect::<Vec<_>>();
+        let truncated = page.len() > max;
+        let page = &page[..page.len().min(max)];
+
+        let mut body = format!(r#"<ListBucketResult xmlns="{}"><Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>"#,
+            NAMESPACE, xml::escape(self.bucket), encode(prefix), max, truncated);
+
+        if !delimiter.is_empty() {
+            body.push_str(&format!("<Delimiter>{}</Delimiter>", encode(delimiter)));
+        }
+
+        if url {
+            body.push_str("<EncodingType>url</EncodingType>");
+        }
+
+        let last = page.last().map(|(key, _)| key.as_str());
+
+        if v2 {
+            body.push_str(&format!("<KeyCount>{}</KeyCount>", page.len()));
+
+            if let Some(token) = self.params.get("continuation-token") {
+                body.push_str(&format!("<ContinuationToken>{}</ContinuationToken>", xml::escape(token)));
+            }
+
+            if let Some(start) = self.params.get("start-after") {
+                body.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start)));
+            }
+
+            if let Some(last) = last.filter(|_| truncated) {
+                body.push_str(&format!("<NextContinuationToken>{}</NextContinuationToken>", BASE64_URL_SAFE_NO_PAD.encode(last)));
+            }
+        } else {
+            body.push_str(&format!("<Marker>{}</Marker>", encode(marker.as_deref().unwrap_or_default())));
+
+            if let Some(last) = last.filter(|_| truncated) {
+                body.push_str(&format!("<NextMarker>{}</NextMarker>", encode(last)));
+            }
+        }
+
+        for (key, object) in page {
+            match object {
+                Some((size, modified)) => body.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    encode(key), time(*modified), xml::escape(&api::etag(*size, *modified).to_string()), size)),
+                None => body.push_str(&format!("<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", encode(key)))
+            }
+        }
+
+        body.push_str("</ListBucketResult>");
+        Ok(document(body))
+    }
+
+    /// Downloads an object, or with `HEAD`, describes it. A single range may be asked for.
+    async fn get(&self, path: &str) -> Answer {
+        let range = match self.req.headers().get(header::RANGE).and_then(|range| range.to_str().ok()) {
+            Some(range) if !range.contains(',') => {
+                let Some(DirEntry::File { size, .. }) = self.metadata(path).await else {
+                    return Err(Failure::no_such_key());
+                };
+
+                match byte_range(range, size) {
+                    Some((offset, length)) => Some((offset, length, size)),
+                    None => return Err(Failure::new(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange", "The requested range is not satisfiable"))
+                }
+            },
+            _ => None
+        };
+
+        let bounds = range.iter()
+            .flat_map(|(offset, length, _)| [format!("--offset={}", offset), format!("--length={}", length)])
+            .collect::<Vec<_>>();
+
+        let arguments = bounds.iter()
+            .map(String::as_str)
+            .chain(["--", path])
+            .collect::<Vec<_>>();
+
+        let mut res = self.run("file::read", &arguments, Some(path), None).await?;
+
+        if !matches!(res.status(), StatusCode::OK | StatusCode::NOT_MODIFIED) {
+            return Err(failed(res).await);
+        }
+
+        res.headers_mut().insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+        if let Some((offset, length, size)) = range
+            && res.status() == StatusCode::OK
+        {
+            *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", offset, offset + length - 1, size)) {
+                res.headers_mut().insert(header::CONTENT_RANGE, value);
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Stores an object. Keys ending in `/` stand for directories, which are created instead.
+    async fn put(&self, key: &str, path: &str, payload: Payload) -> Answer {
+        let existing = self.metadata(path).await;
+
+        if key.ends_with('/') {
+            let length = ["x-amz-decoded-content-length", "Content-Length"].iter()
+                .find_map(|name| self.req.headers().get(*name))
+                .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+                .unwrap_or(0);
+
+            if length > 0 {
+                return Err(Failure::invalid("Keys ending in `/` stand for directories, which can't have content."));
+            }
+
+            if !matches!(existing, Some(DirEntry::Dir { .. })) {
+                self.change("file::mkdir", &["--", path], None, None).await?;
+            }
+
+            return Ok(HttpResponse::Ok().finish());
+        }
+
+        if let Some(DirEntry::Dir { .. }) = existing {
+            return Err(Failure::invalid("A directory is stored under this key."));
+        }
+
+        self.parents(path).await?;
+
+        let res = self.change("file::write", &["--", path, "true"], Some(path), Some(body(self.req, payload).await?)).await?;
+        Ok(stored(&res))
+    }
+
+    /// Removes the object stored under `key`, if there is one. Keys ending in `/` only remove empty directories, since
+    /// the objects below them are stored separately.
+    async fn remove(&self, key: &str, path: &str) -> std::result::Result<(), Failure> {
+        let removable = match self.metadata(path).await {
+            Some(DirEntry::Dir { .. }) if key.ends_with('/') => agent::list(self.args, &self.user, self.req, path, Some(1))
+                .await
+                .is_some_and(|below| below.is_empty()),
+            Some(DirEntry::File { .. } | DirEntry::Link { .. }) => !key.ends_with('/'),
+            _ => false
+        };
+
+        if removable {
+            self.change("file::rm", &["--", path], Some(path), None).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str, path: &str) -> Answer {
+        self.remove(key, path).await?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// DeleteObjects: removes up to [`MAX_KEYS`] objects, reporting on each.
+    async fn delete_objects(&self, payload: Payload) -> Answer {
+        let request = read(self.req, payload).await?;
+
+        if request.name != "Delete" {
+            return Err(Failure::new(StatusCode::BAD_REQUEST, "MalformedXML", "The XML you provided was not well-formed."));
+        }
+
+        let quiet = request.children.iter().any(|child| child.name == "Quiet" && child.text.trim() == "true");
+        let keys = request.children.iter()
+            .filter(|child| child.name == "Object")
+            .filter_map(|object| object.children.iter().find(|child| child.name == "Key"))
+            .map(|key| key.text.clone())
+            .collect::<Vec<_>>();
+
+        if keys.is_empty() || keys.len() > MAX_KEYS {
+            return Err(Failure::new(StatusCode::BAD_REQUEST, "MalformedXML", format!("Between 1 and {} keys may be deleted at once.", MAX_KEYS)));
+        }
+
+        let mut body = format!(r#"<DeleteResult xmlns="{}">"#, NAMESPACE);
+
+        for key in keys {
+            let outcome = match path(&key) {
+                Some(path) => self.remove(&key, &path).await,
+                None => Err(Failure::new(StatusCode::BAD_REQUEST, "InvalidArgument", "The key isn't a valid path."))
+            };
+
+            match outcome {
+                Ok(()) if quiet => (),
+                Ok(()) => body.push_str(&format!("<Deleted><Key>{}</Key></Deleted>", xml::escape(&key))),
+                Err(failure) => body.push_str(&format!("<Error><Key>{}</Key><Code>{}</Code><Message>{}</Message></Error>",
+                    xml::escape(&key), failure.code, xml::escape(&failure.message)))
+            }
+        }
+
+        body.push_str("</DeleteResult>");
+        Ok(document(body))
+    }
+
+    /// CopyObject, from the object `x-amz-copy-source` names in the same bucket.
+    async fn copy(&self, key: &str, path: &str, source: &str) -> Answer {
+        let source = source.split('?').next().and_then(decode).unwrap_or_default();
+        let (bucket, from) = source.trim_start_matches('/').split_once('/').unwrap_or_default();
+
+        if bucket != self.bucket {
+            return Err(Failure::new(StatusCode::NOT_FOUND, "NoSuchBucket", "The specified bucket does not exist."));
+        }
+
+        let Some(from) = self::path(from).filter(|_| !from.ends_with('/') && !key.ends_with('/')) else {
+            return Err(Failure::invalid("Only objects can be copied."));
+        };
+
+        let Some(DirEntry::File { .. }) = self.metadata(&from).await else {
+            return Err(Failure::no_such_key());
+        };
+
+        if from == path {
+            let replace = self.req.headers().get("x-amz-metadata-directive").is_some_and(|directive| directive == "REPLACE");
+
+            if !replace {
+                return Err(Failure::invalid("This copy request is illegal because it is trying to copy an object to itself without changing the object's metadata."));
+            }
+        } else {
+            match self.metadata(path).await {
+                Some(DirEntry::Dir { .. }) => return Err(Failure::invalid("A directory is stored under this key.")),
+                Some(_) => (),
+                None => self.parents(path).await?
+            }
+
+            // The object is only replaced once the copy is complete, so a copy which fails leaves it as it was.
+            self.change("file::copy", &["--replace", "--", &from, path], None, None).await?;
+        }
+
+        let Some(DirEntry::File { size, modified, .. }) = self.metadata(path).await else {
+            return Err(Failure::internal());
+        };
+
+        Ok(document(format!(r#"<CopyObjectResult xmlns="{}"><LastModified>{}</LastModified><ETag>{}</ETag></CopyObjectResult>"#,
+            NAMESPACE, time(modified), xml::escape(&api::etag(size, modified).to_string()))))
+    }
+
+    /// Checks that `id` is a multipart upload of the user's to `key`.
+    async fn upload(&self, id: &str, key: &str) -> std::result::Result<(), Failure> {
+        let found = sqlx::query(r#"SELECT 1 FROM s3_uploads WHERE id = $1 AND "user" = $2 AND key = $3"#)
+            .bind(id)
+            .bind(self.user.pk)
+            .bind(key)
+            .fetch_optional(self.pool)
+            .await
+            .map_err(|err| {
+                error!("{:?}", err);
+                Failure::internal()
+            })?;
+
+        found.map(|_| ()).ok_or_else(Failure::no_such_upload)
+    }
+
+    async fn create_upload(&self, key: &str) -> Answer {
+        if key.ends_with('/') {
+            return Err(Failure::invalid("Keys ending in `/` stand for directories, which can't have content."));
+        }
+
+        let id = RNG.with_borrow_mut(|rng| {
+            let mut bytes = [0u8; 16];
+            rng.fill_bytes(&mut bytes);
+            hex(&bytes)
+        });
+
+        sqlx::query(r#"INSERT INTO s3_uploads (id, "user", key) VALUES ($1, $2, $3)"#)
+            .bind(&id)
+            .bind(self.user.pk)
+            .bind(key)
+            .execute(self.pool)
+            .await
+            .map_err(|err| {
+                error!("{:?}", err);
+                Failure::internal()
+            })?;
+
+        Ok(document(format!(r#"<InitiateMultipartUploadResult xmlns="{}"><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>"#,
+            NAMESPACE, xml::escape(self.bucket), xml::escape(key), id)))
+    }
+
+    /// Stores a part of a multipart upload, answering with the `ETag` its completion must name it by.
+    async fn upload_part(&self, key: &str, id: &str, payload: Payload) -> Answer {
+        let Some(number) = self.params.get("partNumber").and_then(|number| number.parse::<u32>().ok()).filter(|number| (1..=MAX_PARTS).contains(number)) else {
+            return Err(Failure::new(StatusCode::BAD_REQUEST, "InvalidArgument", format!("Part number must be an integer between 1 and {}, inclusive.", MAX_PARTS)));
+        };
+
+        if self.req.headers().contains_key("x-amz-copy-source") {
+            return Err(Failure::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "Parts can't be copied from other objects."));
+        }
+
+        self.upload(id, key).await?;
+        self.change("upload::part", &["--", id, &number.to_string()], None, Some(body(self.req, payload).await?)).await?;
+
+        let Some(part) = agent::parts(self.args, &self.user, self.req, id).await.and_then(|parts| parts.into_iter().find(|part| part.number == number)) else {
+            return Err(Failure::internal());
+        };
+
+        Ok(HttpResponse::Ok()
+            .insert_header(header::ETag(api::etag(part.size, part.modified)))
+            .finish())
+    }
+
+    /// Joins the parts the request lists into the object, replacing whatever was stored under its key at once.
+    async fn complete_upload(&self, key: &str, path: &str, id: &str, payload: Payload) -> Answer {
+        self.upload(id, key).await?;
+
+        let request = read(self.req, payload).await?;
+        let invalid_part = || Failure::new(StatusCode::BAD_REQUEST, "InvalidPart",
+            "One or more of the specified parts could not be found. The part might not have been uploaded, or the specified entity tag might not have matched the part's entity tag.");
+
+        let requested = request.children.iter()
+            .filter(|child| child.name == "Part")
+            .map(|part| {
+                let number = part.children.iter().find(|child| child.name == "PartNumber")?.text.trim().parse::<u32>().ok()?;
+                let etag = part.children.iter().find(|child| child.name == "ETag")?.text.trim().trim_matches('"').to_owned();
+                Some((number, etag))
+            })
+            .collect::<Option<Vec<_>>>();
+
+        let Some(requested) = requested.filter(|requested| request.name == "CompleteMultipartUpload" && !requested.is_empty()) else {
+            return Err(Failure::new(StatusCode::BAD_REQUEST, "MalformedXML", "The XML you provided was not well-formed."));
+        };
+
+        if requested.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(Failure::new(StatusCode::BAD_REQUEST, "InvalidPartOrder", "The list of parts was not in ascending order."));
+        }
+
+        let stored = agent::parts(self.args, &self.user, self.req, id).await.unwrap_or_default();
+
+        for (number, etag) in &requested {
+            let found = stored.iter()
+                .find(|part| part.number == *number)
+                .is_some_and(|part| api::etag(part.size, part.modified).tag() == etag);
+
+            if !found {
+                return Err(invalid_part());
+            }
+        }
+
+        if let Some(DirEntry::Dir { .. }) = self.metadata(path).await {
+            return Err(Failure::invalid("A directory is stored under this key."));
+        }
+
+        self.parents(path).await?;
+
+        let numbers = requested.iter().map(|(number, _)| number.to_string()).collect::<Vec<_>>();
+        let arguments = ["--", id, path].into_iter()
+            .chain(numbers.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+
+        self.change("upload::commit", &arguments, Some(path), None).await?;
+        self.forget(id).await;
+
+        let Some(DirEntry::File { size, modified, .. }) = self.metadata(path).await else {
+            return Err(Failure::internal());
+        };
+
+        Ok(document(format!(r#"<CompleteMultipartUploadResult xmlns="{}"><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>"#,
+            NAMESPACE, xml::escape(self.bucket), xml::escape(key), xml::escape(&api::etag(size, modified).to_string()))))
+    }
+
+    /// Drops the record of an upload which has been completed or aborted.
+    async fn forget(&self, id: &str) {
+        if let Err(err) = sqlx::query("DELETE FROM s3_uploads WHERE id = $1").bind(id).execute(self.pool).await {
+            error!("{:?}", err);
+        }
+    }
+
+    async fn abort_upload(&self, key: &str, id: &str) -> Answer {
+        self.upload(id, key).await?;
+        self.change("upload::abort", &["--", id], None, None).await?;
+        self.forget(id).await;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    async fn list_parts(&self, key: &str, id: &str) -> Answer {
+        self.upload(id, key).await?;
+
+        let marker = self.params.get("part-number-marker").and_then(|marker| marker.parse::<u32>().ok()).unwrap_or(0);
+        let max = self.max("max-parts")?;
+        let parts = agent::parts(self.args, &self.user, self.req, id).await.unwrap_or_default();
+        let parts = parts.iter().filter(|part| part.number > marker).collect::<Vec<_>>();
+        let truncated = parts.len() > max;
+        let parts = &parts[..parts.len().min(max)];
+
+        let mut body = format!(r#"<ListPartsResult xmlns="{}"><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId><PartNumberMarker>{}</PartNumberMarker><MaxParts>{}</MaxParts><IsTruncated>{}</IsTruncated><StorageClass>STANDARD</StorageClass>"#,
+            NAMESPACE, xml::escape(self.bucket), xml::escape(key), xml::escape(id), marker, max, truncated);
+
+        if let Some(last) = parts.last() {
+            body.push_str(&format!("<NextPartNumberMarker>{}</NextPartNumberMarker>", last.number));
+        }
+
+        for part in parts {
+            body.push_str(&format!("<Part><PartNumber>{}</PartNumber><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size></Part>",
+                part.number, time(part.modified), xml::escape(&api::etag(part.size, part.modified).to_string()), part.size));
+        }
+
+        body.push_str("</ListPartsResult>");
+        Ok(document(body))
+    }
+
+    /// ListMultipartUploads: the user's uploads which are neither completed nor aborted, oldest first for each key.
+    async fn list_uploads(&self) -> Answer {
+        let prefix = self.params.get("prefix").unwrap_or_default();
+        let max = self.max("max-uploads")?;
+
+        let uploads = sqlx::query(r#"SELECT id, key, extract(epoch FROM created)::float8 AS created FROM s3_uploads
+WHERE "user" = $1 AND left(key, length($2)) = $2
+ORDER BY key, created
+LIMIT $3"#)
+            .bind(self.user.pk)
+            .bind(prefix)
+            .bind(max as i64 + 1)
+            .fetch_all(self.pool)
+            .await
+            .map_err(|err| {
+                error!("{:?}", err);
+                Failure::internal()
+            })?;
+
+        let truncated = uploads.len() > max;
+
+        let mut body = format!(r#"<ListMultipartUploadsResult xmlns="{}"><Bucket>{}</Bucket><Prefix>{}</Prefix><MaxUploads>{}</MaxUploads><IsTruncated>{}</IsTruncated>"#,
+            NAMESPACE, xml::escape(self.bucket), xml::escape(prefix), max, truncated);
+
+        for upload in uploads.iter().take(max) {
+            let created = UNIX_EPOCH + Duration::from_secs_f64(upload.try_get::<f64, _>("created").unwrap_or_default().max(0.0));
+
+            body.push_str(&format!("<Upload><Key>{}</Key><UploadId>{}</UploadId><Initiated>{}</Initiated><StorageClass>STANDARD</StorageClass></Upload>",
+                xml::escape(&upload.try_get::<String, _>("key").unwrap_or_default()), upload.try_get::<String, _>("id").unwrap_or_default(), time(created)));
+        }
+
+        body.push_str("</ListMultipartUploadsResult>");
+        Ok(document(body))
+    }
+
+    /// Operations on the bucket as a whole.
+    async fn bucket(&self, payload: Payload) -> Answer {
+        let params = &self.params;
+
+        match self.req.method().as_str() {
+            _ if params.unsupported() => Err(Failure::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "This subresource isn't supported.")),
+            "HEAD" => Ok(HttpResponse::Ok().finish()),
+            "GET" if params.has("location") => Ok(document(format!(r#"<LocationConstraint xmlns="{}"/>"#, NAMESPACE))),
+            "GET" if params.has("uploads") => self.list_uploads().await,
+            "GET" => self.list().await,
+            "POST" if params.has("delete") => self.delete_objects(payload).await,
+            "PUT" => Err(Failure::new(StatusCode::CONFLICT, "BucketAlreadyOwnedByYou", "Your previous request to create the named bucket succeeded and you already own it.")),
+            "DELETE" => Err(access_denied("The bucket is the user's storage, and can't be deleted.")),
+            _ => Err(Failure::new(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", "The specified method is not allowed against this resource."))
+        }
+    }
+
+    /// Operations on a single object.
+    async fn object(&self, key: &str, payload: Payload) -> Answer {
+        let Some(path) = path(key) else {
+            return Err(Failure::new(StatusCode::BAD_REQUEST, "InvalidArgument", "Keys must be paths, without empty, `.` or `..` segments."));
+        };
+
+        let params = &self.params;
+        let upload = params.get("uploadId");
+
+        match (self.req.method().as_str(), upload) {
+            _ if params.unsupported() => Err(Failure::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "This subresource isn't supported.")),
+            ("GET", Some(id)) => self.list_parts(key, id).await,
+            ("GET" | "HEAD", None) => self.get(&path).await,
+            ("PUT", Some(id)) => self.upload_part(key, id, payload).await,
+            ("PUT", None) => match self.req.headers().get("x-amz-copy-source").and_then(|source| source.to_str().ok()) {
+                Some(source) => self.copy(key, &path, source).await,
+                None => self.put(key, &path, payload).await
+            },
+            ("POST", None) if params.has("uploads") => self.create_upload(key).await,
+            ("POST", Some(id)) => self.complete_upload(key, &path, id, payload).await,
+            ("DELETE", Some(id)) => self.abort_upload(key, id).await,
+            ("DELETE", None) => self.delete(key, &path).await,
+            _ => Err(Failure::new(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", "The specified method is not allowed against this resource."))
+        }
+    }
+}
+
+/// Serves a subset of the S3 API below [`PREFIX`], with the signed-in user's storage as the one bucket. Requests
+/// address it path-style, as `/s3/{bucket}/{key}`, and each operation runs agent commands as the user.
+pub async fn handle(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, payload: Payload) -> Result<HttpResponse> {
+    let Some(bucket) = args.s3_bucket.as_deref() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let Ok(user) = api::storage(&req, &pool).await else {
+        return Ok(Failure::internal().respond());
+    };
+
+    let Some((name, key)) = req.path()
+        .strip_prefix(PREFIX)
+        .map(|rest| rest.strip_prefix('/').unwrap_or(rest))
+        .and_then(|rest| {
+            let (name, key) = rest.split_once('/').unwrap_or((rest, ""));
+            Some((decode(name)?, decode(key)?))
+        })
+    else {
+        return Ok(Failure::new(StatusCode::BAD_REQUEST, "InvalidURI", "Couldn't parse the specified URI.").respond());
+    };
+
+    let Some(params) = Params::parse(req.query_string()) else {
+        return Ok(Failure::new(StatusCode::BAD_REQUEST, "InvalidURI", "Couldn't parse the specified URI.").respond());
+    };
+
+    let s3 = S3 {
+        req: &req,
+        pool: &pool,
+        args: &args,
+        limiter: &limiter,
+        user,
+        bucket,
+        params,
+    };
+
+    let answer = match (name.as_str(), key.as_str()) {
+        ("", _) if req.method() == "GET" => s3.buckets(),
+        ("", _) => Err(Failure::new(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", "The specified method is not allowed against this resource.")),
+        (name, _) if name != bucket => Err(Failure::new(StatusCode::NOT_FOUND, "NoSuchBucket", "The specified bucket does not exist.")),
+        (_, "") => s3.bucket(payload).await,
+        (_, key) => s3.object(key, payload).await
+    };
+
+    Ok(answer.unwrap_or_else(|failure| failure.respond()))
+}
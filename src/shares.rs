@@ -0,0 +1,649 @@
+use crate::{
+    agent,
+    agent::DirEntry,
+    api,
+    api::Call,
+    api::User,
+    api::RNG,
+    files,
+    files::Destination,
+    passwords::internal,
+    passwords::not_signed_in,
+    Args
+};
+use actix_web::{
+    delete,
+    get,
+    http::header,
+    http::header::Charset,
+    http::header::ContentDisposition,
+    http::header::DispositionParam,
+    http::header::DispositionType,
+    http::header::ExtendedValue,
+    http::header::HeaderValue,
+    http::Method,
+    http::StatusCode,
+    post,
+    route,
+    web,
+    web::Bytes,
+    web::Data,
+    web::Path,
+    web::Payload,
+    web::Query,
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+    Responder,
+    Result
+};
+use argon2::{
+    password_hash::PasswordHash,
+    password_hash::PasswordHasher,
+    password_hash::PasswordVerifier,
+    password_hash::SaltString,
+    Argon2
+};
+use base64::{
+    prelude::BASE64_STANDARD,
+    prelude::BASE64_URL_SAFE_NO_PAD,
+    Engine
+};
+use rand::RngCore;
+use serde::{
+    Deserialize,
+    Serialize
+};
+use serde_json::json;
+use sqlx::{
+    FromRow,
+    PgPool
+};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    sync::Mutex,
+    time::Duration,
+    time::Instant,
+    time::SystemTime,
+    time::UNIX_EPOCH
+};
+
+/// Longest password a link may be given.
+const PASSWORD_LENGTH: usize = 1024;
+
+/// Wrong passwords a link may be given within [`ATTEMPT_WINDOW`] before it stops checking them.
+const MAX_ATTEMPTS: u32 = 10;
+const ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Directories deep a path below a link may go. Each one on the way is checked for symbolic links by an agent of its
+/// own.
+const MAX_DEPTH: usize = 32;
+
+/// Whether a link lets people download what it points to, or upload files into it without seeing what is there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Read,
+
+    /// A "file drop": files are uploaded into the directory, and never replace what is already there.
+    Drop,
+}
+
+impl Mode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Read => "read",
+            Mode::Drop => "drop"
+        }
+    }
+}
+
+/// A share link as it is listed. Its password is never shown, only whether it has one.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct Share {
+    pub id: String,
+    pub path: String,
+    pub mode: String,
+    pub protected: bool,
+
+    /// Seconds since the UNIX epoch.
+    pub expires: Option<f64>,
+    pub max_downloads: Option<i32>,
+    pub downloads: i32,
+    pub created: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct NewShare {
+    path: String,
+
+    #[serde(default)]
+    mode: Mode,
+    password: Option<String>,
+
+    /// Seconds since the UNIX epoch. Links without one last until they are revoked.
+    expires: Option<f64>,
+
+    /// Downloads the link allows, counting each file or archive served but not listings.
+    max_downloads: Option<i32>,
+}
+
+/// A link as it is looked up to be used.
+#[derive(Debug, Clone, FromRow)]
+struct Link {
+    path: String,
+    mode: String,
+    password: Option<String>,
+    owner: i32,
+    email: String,
+    expired: bool,
+    used_up: bool,
+}
+
+/// Wrong passwords recently given to each link, and when the first of them was, so that a link's password can't be
+/// guessed at as fast as the server can hash. Like WebDAV locks, they are only kept in memory.
+#[derive(Debug, Clone, Default)]
+pub struct Attempts(Arc<Mutex<HashMap<String, (u32, Instant)>>>);
+
+impl Attempts {
+    /// Whether the link may be given another password.
+    fn allowed(&self, id: &str) -> bool {
+        let mut attempts = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+
+        attempts.retain(|_, (_, first)| now.duration_since(*first) < ATTEMPT_WINDOW);
+        attempts.get(id).is_none_or(|(failed, _)| *failed < MAX_ATTEMPTS)
+    }
+
+    fn failed(&self, id: &str) {
+        let mut attempts = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        attempts.entry(id.to_owned()).or_insert((0, Instant::now())).0 += 1;
+    }
+}
+
+/// Query parameters of public links.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkQuery {
+    /// Download a directory, or a file, as a zip archive. Takes no value.
+    zip: Option<String>,
+}
+
+fn refuse(status: StatusCode, msg: impl ToString) -> HttpResponse {
+    HttpResponse::build(status).json(json! {{
+        "success": false,
+        "msg": msg.to_string()
+    }})
+}
+
+fn no_such_link() -> HttpResponse {
+    refuse(StatusCode::NOT_FOUND, "No such link.")
+}
+
+fn busy(retry: Duration, msg: &str) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry.as_secs().to_string()))
+        .json(json! {{
+            "success": false,
+            "msg": msg
+        }})
+}
+
+fn challenge() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="Shared link", charset="UTF-8""#))
+        .json(json! {{
+            "success": false,
+            "msg": "The link needs a password."
+        }})
+}
+
+/// Hashes a link's password with a salt of its own. People choose these, so unlike app passwords they need a hash
+/// which is slow to guess against.
+fn hash(password: &str) -> argon2::password_hash::Result<String> {
+    let salt = RNG.with_borrow_mut(|rng| {
+        let mut salt = [0u8; 16];
+        rng.fill_bytes(&mut salt);
+        salt
+    });
+
+    Ok(Argon2::default().hash_password(password.as_bytes(), &SaltString::encode_b64(&salt)?)?.to_string())
+}
+
+/// The password given as HTTP Basic credentials, whatever the username.
+fn password(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, credentials)| BASE64_STANDARD.decode(credentials.trim()).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| credentials.split_once(':').map(|(_, password)| password.to_owned()))
+}
+
+/// Checks a password against the link's hash. Hashing takes a while, so it is done off the server's threads.
+async fn unlocked(password: String, hash: &str) -> bool {
+    let hash = hash.to_owned();
+
+    web::block(move || PasswordHash::new(&hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()))
+        .await
+        .unwrap_or(false)
+}
+
+/// Creates a link to a file or directory of the signed-in user's. The link's ID is the only secret it has, unless it is
+/// given a password.
+#[post("/shares")]
+pub async fn create(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, body: Bytes) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    let share = match serde_json::from_slice::<NewShare>(&body) {
+        Ok(share) => share,
+        Err(err) => return Ok(refuse(StatusCode::BAD_REQUEST, format!("Expected a body like `{{\"path\": \"/photos\", \"mode\": \"read\"}}`: {}", err)))
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |since| since.as_secs_f64());
+
+    if share.expires.is_some_and(|expires| expires <= now) {
+        return Ok(refuse(StatusCode::BAD_REQUEST, "`expires` must lie in the future"));
+    }
+
+    if share.max_downloads.is_some_and(|max| max < 1) {
+        return Ok(refuse(StatusCode::BAD_REQUEST, "`maxDownloads` must be at least 1"));
+    }
+
+    if share.password.as_ref().is_some_and(|password| password.is_empty() || password.len() > PASSWORD_LENGTH) {
+        return Ok(refuse(StatusCode::BAD_REQUEST, format!("Passwords must be between 1 and {} bytes long", PASSWORD_LENGTH)));
+    }
+
+    let storage = match api::storage(&req, &pool).await {
+        Ok(storage) => storage,
+        Err(res) => return Ok(res)
+    };
+
+    let path = format!("/{}", share.path.trim_matches('/'));
+
+    match (agent::metadata(&args, &storage, &req, &path).await, share.mode) {
+        (None, _) => return Ok(refuse(StatusCode::NOT_FOUND, "No such file or directory.")),
+        (Some(DirEntry::Dir { .. }), Mode::Drop) => (),
+        (Some(_), Mode::Drop) => return Ok(refuse(StatusCode::BAD_REQUEST, "Only directories can take uploads")),
+        (Some(_), Mode::Read) => ()
+    }
+
+    let password = match share.password {
+        Some(password) => match web::block(move || hash(&password)).await {
+            Ok(Ok(hash)) => Some(hash),
+            err => {
+                log::error!("{:?}", err);
+                return Ok(refuse(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error."));
+            }
+        },
+        None => None
+    };
+
+    let id = RNG.with_borrow_mut(|rng| {
+        let mut id = [0u8; 16];
+        rng.fill_bytes(&mut id);
+        BASE64_URL_SAFE_NO_PAD.encode(id)
+    });
+
+    match sqlx::query_as::<_, Share>(r#"INSERT INTO shares (id, "user", path, mode, password, expires, max_downloads)
+SELECT $2, uid, $3, $4, $5, to_timestamp($6), $7 FROM users WHERE email = $1
+RETURNING id, path, mode, password IS NOT NULL AS protected, extract(epoch FROM expires)::float8 AS expires, max_downloads, downloads,
+    extract(epoch FROM created)::float8 AS created"#)
+        .bind(&user.email)
+        .bind(&id)
+        .bind(&path)
+        .bind(share.mode.as_str())
+        .bind(&password)
+        .bind(share.expires)
+        .bind(share.max_downloads)
+        .fetch_one(pool.get_ref())
+        .await
+    {
+        Ok(created) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true,
+            "url": format!("/s/{}", id),
+            "share": created
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
+
+/// The signed-in user's links, newest first, including those which have expired or been used up.
+#[get("/shares")]
+pub async fn list(req: HttpRequest, pool: Data<PgPool>) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    match sqlx::query_as::<_, Share>(r#"SELECT id, path, mode, password IS NOT NULL AS protected, extract(epoch FROM expires)::float8 AS expires, max_downloads,
+    downloads, extract(epoch FROM shares.created)::float8 AS created
+FROM shares JOIN users ON users.uid = shares."user"
+WHERE users.email = $1
+ORDER BY shares.created DESC"#)
+        .bind(&user.email)
+        .fetch_all(pool.get_ref())
+        .await
+    {
+        Ok(shares) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true,
+            "shares": shares
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
+
+/// Revokes a link. It leads nowhere from then on.
+#[delete("/shares/{id}")]
+pub async fn revoke(req: HttpRequest, pool: Data<PgPool>, id: Path<String>) -> Result<impl Responder> {
+    let Some(user) = req.extensions().get::<User>().cloned() else {
+        return Ok(not_signed_in());
+    };
+
+    match sqlx::query(r#"DELETE FROM shares USING users WHERE users.uid = shares."user" AND users.email = $1 AND shares.id = $2"#)
+        .bind(&user.email)
+        .bind(id.as_str())
+        .execute(pool.get_ref())
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => Ok(no_such_link()),
+        Ok(_) => Ok(HttpResponse::Ok().json(json! {{
+            "success": true
+        }})),
+        Err(err) => Ok(internal(err))
+    }
+}
+
+/// Finds the link `id` and the storage of the user it belongs to, or the response explaining why it can't be used.
+/// Passwords are checked in one of the owner's agent slots, since hashing one costs about as much as running an agent.
+async fn open(req: &HttpRequest, pool: &PgPool, limiter: &agent::Limiter, id: &str) -> std::result::Result<(Link, agent::StorageProps), HttpResponse> {
+    let link = sqlx::query_as::<_, Link>(r#"SELECT shares.path, mode, password, users.uid AS owner, users.email,
+    coalesce(expires <= now(), false) AS expired, coalesce(downloads >= max_downloads, false) AS used_up
+FROM shares JOIN users ON users.uid = shares."user"
+WHERE id = $1"#)
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal)?
+        .ok_or_else(no_such_link)?;
+
+    if link.expired {
+        return Err(refuse(StatusCode::GONE, "The link has expired."));
+    }
+
+    if let Some(ref hash) = link.password {
+        let Some(password) = password(req) else {
+            return Err(challenge());
+        };
+
+        let Some(attempts) = req.app_data::<Data<Attempts>>() else {
+            return Err(refuse(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error."));
+        };
+
+        if !attempts.allowed(id) {
+            return Err(busy(ATTEMPT_WINDOW, "Too many wrong passwords were given. Try again later."));
+        }
+
+        let Some(_permit) = limiter.acquire(link.owner) else {
+            return Err(busy(Duration::from_secs(1), "Too many requests are already running. Try again shortly."));
+        };
+
+        if !unlocked(password, hash).await {
+            attempts.failed(id);
+            return Err(challenge());
+        }
+    }
+
+    let storage = agent::storage(pool, &link.email).await.map_err(internal)?;
+    Ok((link, storage))
+}
+
+/// Counts a download against the link's limit.
+async fn count(pool: &PgPool, id: &str) -> sqlx::Result<()> {
+    sqlx::query(r#"UPDATE shares SET downloads = downloads + 1 WHERE id = $1"#)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// The first and last byte of a file of `size` bytes a download asks for with `Range`, so that it can be resumed.
+/// `None` for the whole file: when there is no range, or several, or `If-Range` shows the file has changed since the
+/// client last saw the tag `etag`. Ranges starting past the end are `Err`.
+fn range(req: &HttpRequest, size: u64, etag: &str) -> std::result::Result<Option<(u64, u64)>, ()> {
+    let Some(range) = req.headers().get(header::RANGE).and_then(|range| range.to_str().ok()) else {
+        return Ok(None);
+    };
+
+    if req.headers().get(header::IF_RANGE).is_some_and(|tag| tag.as_bytes() != etag.as_bytes()) {
+        return Ok(None);
+    }
+
+    let Some((first, last)) = range.trim().strip_prefix("bytes=").and_then(|range| range.split_once('-')) else {
+        return Ok(None);
+    };
+
+    let (first, last) = match (first.trim(), last.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) | Err(_) => return Ok(None),
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        },
+        (first, last) => match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Ok(first), Ok(last)) if first <= last => (first, last.min(size.saturating_sub(1))),
+            (Ok(first), Err(_)) if last.is_empty() => (first, size.saturating_sub(1)),
+            _ => return Ok(None)
+        }
+    };
+
+    match first < size {
+        true => Ok(Some((first, last))),
+        false => Err(())
+    }
+}
+
+/// Names an archive after what it holds, for browsers to save it as.
+fn attachment(name: &str) -> ContentDisposition {
+    let name = format!("{}.zip", name);
+    let mut parameters = vec![DispositionParam::Filename(name.clone())];
+
+    if !name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_owned()),
+            language_tag: None,
+            value: name.into_bytes(),
+        }));
+    }
+
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Serves what a link points to.
+#[route("/s/{id}", method = "GET", method = "HEAD")]
+pub async fn root(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, id: Path<String>, query: Query<LinkQuery>) -> Result<impl Responder> {
+    serve(&req, &pool, &args, &limiter, &id, "", query.into_inner()).await
+}
+
+/// Serves a file or directory below the directory a link points to.
+#[route("/s/{id}/{path:.*}", method = "GET", method = "HEAD")]
+pub async fn read(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, path: Path<(String, String)>, query: Query<LinkQuery>) -> Result<impl Responder> {
+    let (id, path) = path.into_inner();
+    serve(&req, &pool, &args, &limiter, &id, &path, query.into_inner()).await
+}
+
+/// Serves `path` below what the link `id` points to: a file is downloaded, and a directory is listed, or with `?zip`,
+/// downloaded as an archive. Symbolic links below the link's target aren't followed, since they could lead anywhere in
+/// the owner's storage.
+async fn serve(req: &HttpRequest, pool: &PgPool, args: &Args, limiter: &agent::Limiter, id: &str, path: &str, query: LinkQuery) -> Result<HttpResponse> {
+    let (link, owner) = match open(req, pool, limiter, id).await {
+        Ok(opened) => opened,
+        Err(res) => return Ok(res)
+    };
+
+    let dir = link.path.trim_end_matches('/');
+
+    if link.mode == Mode::Drop.as_str() {
+        return Ok(match path {
+            "" => HttpResponse::Ok().json(json! {{
+                "success": true,
+                "mode": Mode::Drop,
+                "msg": "Upload files to the link as a `multipart/form-data` form."
+            }}),
+            _ => no_such_link()
+        });
+    }
+
+    if path.split('/').filter(|segment| !segment.is_empty()).count() > MAX_DEPTH {
+        return Ok(refuse(StatusCode::NOT_FOUND, "No such file or directory."));
+    }
+
+    // Finding what is served takes an agent for each directory on the way, and they count against the owner's limit
+    // like the one serving it.
+    let Some(permit) = limiter.acquire(owner.pk) else {
+        return Ok(busy(Duration::from_secs(1), "Too many requests are already running. Try again shortly."));
+    };
+
+    let target = match path.trim_end_matches('/') {
+        "" => link.path.clone(),
+        path => match files::relative(path) {
+            Some(relative) if files::reachable(args, &owner, req, dir, &relative).await => format!("{}/{}", dir, relative),
+            _ => return Ok(refuse(StatusCode::NOT_FOUND, "No such file or directory."))
+        }
+    };
+
+    let entry = match agent::metadata(args, &owner, req, &target).await {
+        Some(entry) => entry,
+        None => return Ok(refuse(StatusCode::NOT_FOUND, "No such file or directory."))
+    };
+
+    // Listings aren't downloads, so they don't count.
+    if let DirEntry::Dir { .. } = entry
+        && query.zip.is_none()
+    {
+        let Some(entries) = agent::list(args, &owner, req, &target, Some(1)).await else {
+            return Ok(refuse(StatusCode::NOT_FOUND, "No such directory."));
+        };
+
+        // Paths are shown relative to the link, and tags are kept to the owner.
+        let entries = entries.into_iter()
+            .filter_map(|mut entry| {
+                match entry {
+                    DirEntry::Dir { ref mut path, ref mut tags } | DirEntry::File { ref mut path, ref mut tags, .. } => {
+                        *path = PathBuf::from("/").join(path.strip_prefix(&link.path).ok()?);
+                        tags.clear();
+                    },
+                    DirEntry::Link { .. } => return None
+                }
+
+                Some(entry)
+            })
+            .collect::<Vec<_>>();
+
+        return Ok(HttpResponse::Ok().json(json! {{
+            "success": true,
+            "files": entries
+        }}));
+    }
+
+    if link.used_up {
+        return Ok(refuse(StatusCode::GONE, "The link has been used up."));
+    }
+
+    drop(permit);
+
+    let range = match entry {
+        DirEntry::File { size, modified, .. } if query.zip.is_none() => match range(req, size, &api::etag(size, modified).to_string()) {
+            Ok(range) => range.map(|(first, last)| (first, last, size)),
+            Err(()) => return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                .finish())
+        },
+        _ => None
+    };
+
+    let command = if query.zip.is_some() { "file::zip" } else { "file::read" };
+    let mut arguments = match range {
+        Some((first, last, _)) => vec![format!("--offset={}", first), format!("--length={}", last - first + 1)],
+        None => Vec::new()
+    };
+
+    arguments.extend(["--".to_owned(), target.clone()]);
+
+    let call = Call {
+        command: command.to_owned(),
+        args: arguments,
+        path: Some(target.clone()),
+        dry_run: false,
+        format: None,
+    };
+
+    let mut res = api::run(req, pool, args, limiter, owner, call, None).await?;
+
+    if let Some((first, last, size)) = range
+        && res.status() == StatusCode::OK
+    {
+        *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+
+        if let Ok(range) = HeaderValue::from_str(&format!("bytes {}-{}/{}", first, last, size)) {
+            res.headers_mut().insert(header::CONTENT_RANGE, range);
+        }
+    }
+
+    // Downloads count once they have started, and only from the start, so that ones which fail, are resumed, or only
+    // ask whether the file has changed don't use the link up. Several started at once may take it past its limit.
+    if req.method() != Method::HEAD
+        && res.status().is_success()
+        && range.is_none_or(|(first, _, _)| first == 0)
+        && let Err(err) = count(pool, id).await
+    {
+        log::error!("{:?}", err);
+    }
+
+    if query.zip.is_some() && res.status().is_success() {
+        let name = target.rsplit('/').find(|segment| !segment.is_empty()).unwrap_or("files");
+
+        res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+
+        if let Ok(disposition) = HeaderValue::from_str(&attachment(name).to_string()) {
+            res.headers_mut().insert(header::CONTENT_DISPOSITION, disposition);
+        }
+    }
+
+    Ok(res)
+}
+
+/// Uploads the files of a `multipart/form-data` form into the directory a "file drop" link points to. Files already
+/// there are never replaced, and nothing is said about them.
+#[post("/s/{id}")]
+pub async fn upload(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, id: Path<String>, body: Payload) -> Result<impl Responder> {
+    let (link, owner) = match open(&req, &pool, &limiter, &id).await {
+        Ok(opened) => opened,
+        Err(res) => return Ok(res)
+    };
+
+    if link.mode != Mode::Drop.as_str() {
+        return Ok(HttpResponse::MethodNotAllowed()
+            .insert_header((header::ALLOW, "GET, HEAD"))
+            .json(json! {{
+                "success": false,
+                "msg": "The link only allows downloads."
+            }}));
+    }
+
+    let to = Destination {
+        dir: link.path.trim_end_matches('/').to_owned(),
+        replace: false,
+    };
+
+    files::receive(&req, &pool, &args, &limiter, owner, to, body).await
+}
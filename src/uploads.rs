@@ -0,0 +1,677 @@
+use crate::{
+    agent,
+    agent::DirEntry,
+    agent::StorageProps,
+    api,
+    api::Call,
+    api::RNG,
+    audit,
+    Args
+};
+use actix_web::{
+    delete,
+    dev,
+    error::PayloadError,
+    http::header,
+    http::header::HttpDate,
+    http::StatusCode,
+    patch,
+    post,
+    route,
+    web::Bytes,
+    web::Data,
+    web::Path,
+    web::Payload,
+    FromRequest,
+    HttpRequest,
+    HttpResponse,
+    HttpResponseBuilder,
+    Responder,
+    Result
+};
+use base64::{
+    prelude::BASE64_STANDARD,
+    Engine
+};
+use futures_util::{
+    Stream,
+    StreamExt as _
+};
+use rand::RngCore;
+use serde_json::json;
+use sha1::Sha1;
+use sha2::{
+    Digest,
+    Sha256
+};
+use sqlx::{
+    FromRow,
+    PgPool,
+    Row
+};
+use std::{
+    cell::Cell,
+    io,
+    pin::Pin,
+    process::Stdio,
+    rc::Rc,
+    time::Duration,
+    time::Instant,
+    time::UNIX_EPOCH
+};
+
+/// The version of the tus protocol spoken, which clients must name in `Tus-Resumable`.
+pub const VERSION: &str = "1.0.0";
+
+const EXTENSIONS: &str = "creation,termination,checksum,expiration";
+
+/// Algorithms `Upload-Checksum` may use.
+const CHECKSUMS: &str = "sha1,sha256";
+
+/// Longest `Upload-Metadata` accepted.
+const METADATA_LIMIT: usize = 4096;
+
+/// How often expired uploads are looked for.
+const SWEEP: Duration = Duration::from_secs(10 * 60);
+
+/// A resumable upload as it is recorded.
+#[derive(Debug, Clone, FromRow)]
+struct Upload {
+    id: String,
+    path: String,
+    length: i64,
+    metadata: Option<String>,
+
+    /// Seconds since the UNIX epoch.
+    expires: f64,
+    completed: bool,
+}
+
+impl Upload {
+    fn expires(&self) -> String {
+        HttpDate::from(UNIX_EPOCH + Duration::from_secs_f64(self.expires.max(0.0))).to_string()
+    }
+}
+
+fn refuse(status: StatusCode, msg: impl ToString) -> HttpResponse {
+    HttpResponse::build(status).json(json! {{
+        "success": false,
+        "msg": msg.to_string()
+    }})
+}
+
+fn internal(err: impl std::fmt::Debug) -> HttpResponse {
+    log::error!("{:?}", err);
+    refuse(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error.")
+}
+
+fn no_such_upload() -> HttpResponse {
+    refuse(StatusCode::NOT_FOUND, "No such upload.")
+}
+
+/// Refuses requests for other versions of the protocol, which every request but `OPTIONS` must name.
+fn unsupported(req: &HttpRequest) -> Option<HttpResponse> {
+    match req.headers().get("Tus-Resumable") {
+        Some(version) if version == VERSION => None,
+        _ => Some(HttpResponse::PreconditionFailed()
+            .insert_header(("Tus-Version", VERSION))
+            .json(json! {{
+                "success": false,
+                "msg": format!("Only version {} of the tus protocol is supported.", VERSION)
+            }}))
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Finds the signed-in user's upload `id`, unless it has expired.
+async fn find(pool: &PgPool, user: &StorageProps, id: &str) -> sqlx::Result<Option<Upload>> {
+    sqlx::query_as::<_, Upload>(r#"SELECT id, path, length, metadata, extract(epoch FROM expires)::float8 AS expires, completed IS NOT NULL AS completed
+FROM tus_uploads
+WHERE id = $1 AND "user" = $2 AND expires > now()"#)
+        .bind(id)
+        .bind(user.pk)
+        .fetch_optional(pool)
+        .await
+}
+
+/// The parts staged for an upload, which are stored in order, and how many bytes they add up to.
+async fn staged(args: &Args, user: &StorageProps, req: &HttpRequest, id: &str) -> (Vec<agent::Part>, u64) {
+    // The staging directory only exists once the first part has arrived.
+    let parts = agent::parts(args, user, req, id).await.unwrap_or_default();
+    let offset = parts.iter().map(|part| part.size).sum();
+
+    (parts, offset)
+}
+
+/// A checksum a client sent for a chunk in `Upload-Checksum`.
+enum Checksum {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Checksum {
+    /// Parses `Upload-Checksum`, an algorithm and a Base64 digest. `None` if the algorithm isn't supported.
+    fn parse(header: &str) -> Option<(Self, Vec<u8>)> {
+        let (algorithm, digest) = header.trim().split_once(' ')?;
+        let digest = BASE64_STANDARD.decode(digest.trim()).ok()?;
+
+        match algorithm {
+            "sha1" => Some((Self::Sha1(Sha1::new()), digest)),
+            "sha256" => Some((Self::Sha256(Sha256::new()), digest)),
+            _ => None
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data)
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Self::Sha1(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec()
+        }
+    }
+}
+
+/// Why a chunk was refused partway through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Refusal {
+    /// The chunk ran past the length of the upload.
+    TooLong,
+
+    /// The chunk doesn't match its `Upload-Checksum`.
+    Mismatch,
+}
+
+/// The body of a `PATCH`, as it is fed to the agent. A chunk which runs past the end of the upload or doesn't match its
+/// checksum ends in an error, so that the agent is killed before it stores any of it. A chunk which is merely cut off
+/// ends cleanly instead, so that what did arrive is kept and the client can resume after it.
+struct Chunk {
+    payload: Payload,
+
+    /// Bytes the upload still lacks.
+    left: u64,
+    checksum: Option<(Checksum, Vec<u8>)>,
+    refused: Rc<Cell<Option<Refusal>>>,
+    done: bool,
+}
+
+impl Chunk {
+    fn refuse(&self, refusal: Refusal, msg: &str) -> Option<std::result::Result<Bytes, PayloadError>> {
+        self.refused.set(Some(refusal));
+        Some(Err(PayloadError::Io(io::Error::new(io::ErrorKind::InvalidData, msg))))
+    }
+
+    async fn next(&mut self) -> Option<std::result::Result<Bytes, PayloadError>> {
+        if self.done {
+            return None;
+        }
+
+        let next = match self.payload.next().await {
+            Some(Ok(data)) if data.len() as u64 > self.left => self.refuse(Refusal::TooLong, "The chunk runs past the end of the upload"),
+            Some(Ok(data)) => {
+                self.left -= data.len() as u64;
+
+                if let Some((checksum, _)) = &mut self.checksum {
+                    checksum.update(&data);
+                }
+
+                Some(Ok(data))
+            },
+            // A checksum can only be checked against the whole chunk.
+            Some(Err(err)) if self.checksum.is_some() => Some(Err(err)),
+            Some(Err(err)) => {
+                log::debug!("Keeping the part of an upload which arrived: {}", err);
+                None
+            },
+            None => match self.checksum.take().is_some_and(|(checksum, expected)| checksum.finish() != expected) {
+                true => self.refuse(Refusal::Mismatch, "The chunk doesn't match its Upload-Checksum"),
+                false => None
+            }
+        };
+
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+/// Turns a stream of chunks into a request body, which the agent can be fed.
+async fn payload(req: &HttpRequest, stream: impl Stream<Item = std::result::Result<Bytes, PayloadError>> + 'static) -> Result<Payload> {
+    let stream: Pin<Box<dyn Stream<Item = std::result::Result<Bytes, PayloadError>>>> = Box::pin(stream);
+    Payload::from_request(req, &mut dev::Payload::from(stream)).await
+}
+
+/// An agent command for an upload, which is run through the API so that it is limited and audited like any other.
+fn call(command: &str, arguments: Vec<String>, path: Option<String>) -> Call {
+    Call {
+        command: command.to_owned(),
+        args: arguments,
+        path,
+        dry_run: false,
+        format: None,
+    }
+}
+
+/// Moves a finished upload into place, and remembers it as complete so that its offset can still be asked for. `Err`
+/// holds the agent's refusal, in which case the parts stay staged and a later `PATCH` may try again.
+async fn complete(req: &HttpRequest, pool: &PgPool, args: &Args, limiter: &agent::Limiter, user: &StorageProps, upload: &Upload, parts: Vec<agent::Part>) -> Result<std::result::Result<(), HttpResponse>> {
+    let mut numbers = parts.iter().map(|part| part.number).collect::<Vec<_>>();
+
+    // An empty file is made of a single empty part.
+    if numbers.is_empty() {
+        let empty = payload(req, futures_util::stream::empty()).await?;
+        let res = api::run(req, pool, args, limiter, user.clone(), call("upload::part", vec!["--".to_owned(), upload.id.clone(), "1".to_owned()], None), Some(empty)).await?;
+
+        if !res.status().is_success() {
+            return Ok(Err(res));
+        }
+
+        numbers.push(1);
+    }
+
+    let arguments = ["--".to_owned(), upload.id.clone(), upload.path.clone()].into_iter()
+        .chain(numbers.iter().map(u32::to_string))
+        .collect();
+
+    let res = api::run(req, pool, args, limiter, user.clone(), call("upload::commit", arguments, Some(upload.path.clone())), None).await?;
+
+    if !res.status().is_success() {
+        return Ok(Err(res));
+    }
+
+    if let Err(err) = sqlx::query("UPDATE tus_uploads SET completed = now() WHERE id = $1").bind(&upload.id).execute(pool).await {
+        return Ok(Err(internal(err)));
+    }
+
+    Ok(Ok(()))
+}
+
+/// Parses `Upload-Metadata`: comma-separated keys, each followed by a space and its Base64 value, if it has one.
+fn metadata(header: &str) -> Option<Vec<(String, Option<String>)>> {
+    let mut pairs: Vec<(String, Option<String>)> = Vec::new();
+
+    for pair in header.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => (key, Some(String::from_utf8(BASE64_STANDARD.decode(value.trim()).ok()?).ok()?)),
+            None => (pair, None)
+        };
+
+        if pairs.iter().any(|(existing, _)| existing == key) {
+            return None;
+        }
+
+        pairs.push((key.to_owned(), value));
+    }
+
+    Some(pairs)
+}
+
+/// Describes what the server supports.
+#[route("", method = "OPTIONS")]
+pub async fn options() -> impl Responder {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Version", VERSION))
+        .insert_header(("Tus-Extension", EXTENSIONS))
+        .insert_header(("Tus-Checksum-Algorithm", CHECKSUMS))
+        .finish()
+}
+
+/// Starts an upload of `Upload-Length` bytes to the path given by the `path` key of `Upload-Metadata`, whose directory
+/// must exist. The data is then sent with `PATCH` to the URL in `Location`.
+#[post("")]
+pub async fn create(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>) -> Result<impl Responder> {
+    if let Some(res) = unsupported(&req) {
+        return Ok(res);
+    }
+
+    if req.headers().contains_key("Upload-Defer-Length") {
+        return Ok(refuse(StatusCode::BAD_REQUEST, "Uploads must give their length up front."));
+    }
+
+    let Some(length) = header(&req, "Upload-Length").and_then(|length| length.parse::<i64>().ok()).filter(|length| *length >= 0) else {
+        return Ok(refuse(StatusCode::BAD_REQUEST, "Expected an `Upload-Length`."));
+    };
+
+    let raw = header(&req, "Upload-Metadata").filter(|raw| raw.len() <= METADATA_LIMIT);
+
+    let Some(path) = raw.and_then(metadata)
+        .and_then(|pairs| pairs.into_iter().find_map(|(key, value)| (key == "path").then_some(value).flatten()))
+        .filter(|path| !path.trim_matches('/').is_empty())
+    else {
+        return Ok(refuse(StatusCode::BAD_REQUEST, "Expected `Upload-Metadata` naming where the upload goes with a `path` key."));
+    };
+
+    let path = format!("/{}", path.trim_start_matches('/'));
+
+    let user = match api::storage(&req, &pool).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res)
+    };
+
+    let parent = std::path::Path::new(&path).parent().map_or("/".into(), |parent| parent.to_string_lossy());
+
+    if !matches!(agent::metadata(&args, &user, &req, &parent).await, Some(DirEntry::Dir { .. })) {
+        return Ok(refuse(StatusCode::NOT_FOUND, "No such directory."));
+    }
+
+    // Uploads which won't fit are turned away before any of them is sent. The file being replaced counts as free.
+    let replaced = match agent::metadata(&args, &user, &req, &path).await {
+        Some(DirEntry::Dir { .. }) => return Ok(refuse(StatusCode::CONFLICT, "A directory is in the way.")),
+        Some(DirEntry::File { size, .. }) => size,
+        _ => 0
+    };
+
+    if let Some(statfs) = agent::statfs(&args, &user, &req).await
+        && length as u64 > statfs.left.bytes.saturating_add(replaced)
+    {
+        return Ok(HttpResponse::InsufficientStorage().json(json! {{
+            "success": false,
+            "msg": "Not enough space left.",
+            "left": statfs.left.bytes.saturating_add(replaced)
+        }}));
+    }
+
+    let id = RNG.with_borrow_mut(|rng| format!("{:016x}{:016x}", rng.next_u64(), rng.next_u64()));
+
+    let upload = match sqlx::query_as::<_, Upload>(r#"INSERT INTO tus_uploads (id, "user", path, length, metadata, expires)
+VALUES ($1, $2, $3, $4, $5, now() + make_interval(secs => $6))
+RETURNING id, path, length, metadata, extract(epoch FROM expires)::float8 AS expires, completed IS NOT NULL AS completed"#)
+        .bind(&id)
+        .bind(user.pk)
+        .bind(&path)
+        .bind(length)
+        .bind(raw)
+        .bind(args.upload_expiry as f64)
+        .fetch_one(pool.get_ref())
+        .await
+    {
+        Ok(upload) => upload,
+        Err(err) => return Ok(internal(err))
+    };
+
+    // Nothing will be sent for an empty file, so it is finished at once.
+    if length == 0
+        && let Err(res) = complete(&req, &pool, &args, &limiter, &user, &upload, Vec::new()).await?
+    {
+        return Ok(res);
+    }
+
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/api/uploads/{}", id)))
+        .insert_header(("Upload-Expires", upload.expires()))
+        .finish())
+}
+
+fn progress(res: &mut HttpResponseBuilder, upload: &Upload, offset: u64) {
+    res.insert_header(("Upload-Offset", offset.to_string()));
+
+    if !upload.completed {
+        res.insert_header(("Upload-Expires", upload.expires()));
+    }
+}
+
+/// Tells how much of an upload has arrived, so that the client can resume after it.
+#[route("/{id}", method = "HEAD")]
+pub async fn head(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, id: Path<String>) -> Result<impl Responder> {
+    if let Some(res) = unsupported(&req) {
+        return Ok(res);
+    }
+
+    let user = match api::storage(&req, &pool).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res)
+    };
+
+    let upload = match find(&pool, &user, &id).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return Ok(no_such_upload()),
+        Err(err) => return Ok(internal(err))
+    };
+
+    let offset = match upload.completed {
+        true => upload.length as u64,
+        false => staged(&args, &user, &req, &upload.id).await.1
+    };
+
+    let mut res = HttpResponse::Ok();
+    res.insert_header(("Upload-Length", upload.length.to_string()));
+    res.insert_header((header::CACHE_CONTROL, "no-store"));
+    progress(&mut res, &upload, offset);
+
+    if let Some(ref metadata) = upload.metadata {
+        res.insert_header(("Upload-Metadata", metadata.as_str()));
+    }
+
+    Ok(res.finish())
+}
+
+/// Appends the body to an upload at `Upload-Offset`, which must be how much of it has arrived. Each chunk is staged as
+/// the next part of the upload, and the upload is moved into place once all of it has arrived.
+#[patch("/{id}")]
+pub async fn append(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, id: Path<String>, body: Payload) -> Result<impl Responder> {
+    if let Some(res) = unsupported(&req) {
+        return Ok(res);
+    }
+
+    if header(&req, "Content-Type") != Some("application/offset+octet-stream") {
+        return Ok(refuse(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Chunks must be sent as `application/offset+octet-stream`."));
+    }
+
+    let Some(claimed) = header(&req, "Upload-Offset").and_then(|offset| offset.parse::<u64>().ok()) else {
+        return Ok(refuse(StatusCode::BAD_REQUEST, "Expected an `Upload-Offset`."));
+    };
+
+    let checksum = match header(&req, "Upload-Checksum") {
+        Some(checksum) => match Checksum::parse(checksum) {
+            Some(checksum) => Some(checksum),
+            None => return Ok(refuse(StatusCode::BAD_REQUEST, format!("`Upload-Checksum` must use one of {}.", CHECKSUMS)))
+        },
+        None => None
+    };
+
+    let user = match api::storage(&req, &pool).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res)
+    };
+
+    let mut upload = match find(&pool, &user, &id).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return Ok(no_such_upload()),
+        Err(err) => return Ok(internal(err))
+    };
+
+    let length = upload.length as u64;
+
+    let (mut parts, mut offset) = match upload.completed {
+        true => (Vec::new(), length),
+        false => staged(&args, &user, &req, &upload.id).await
+    };
+
+    if claimed != offset {
+        return Ok(HttpResponse::Conflict()
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .json(json! {{
+                "success": false,
+                "msg": format!("The upload is at offset {}.", offset)
+            }}));
+    }
+
+    if offset < length {
+        let refused = Rc::new(Cell::new(None));
+        let chunk = Chunk {
+            payload: body,
+            left: length - offset,
+            checksum,
+            refused: refused.clone(),
+            done: false,
+        };
+
+        let stream = futures_util::stream::unfold(chunk, |mut chunk| async move {
+            chunk.next().await.map(|next| (next, chunk))
+        });
+
+        // Each part is stored in full or not at all, and parts are listed in order, so they always add up to the offset.
+        let number = parts.last().map_or(1, |part| part.number + 1);
+        let res = api::run(&req, &pool, &args, &limiter, user.clone(), call("upload::part", vec!["--".to_owned(), upload.id.clone(), number.to_string()], None),
+            Some(payload(&req, stream).await?)).await?;
+
+        match refused.get() {
+            Some(Refusal::TooLong) => return Ok(refuse(StatusCode::PAYLOAD_TOO_LARGE, "The chunk runs past the end of the upload.")),
+            Some(Refusal::Mismatch) => return Ok(refuse(StatusCode::from_u16(460).unwrap_or(StatusCode::BAD_REQUEST), "The chunk doesn't match its `Upload-Checksum`.")),
+            None if !res.status().is_success() => return Ok(res),
+            None => ()
+        }
+
+        match sqlx::query("UPDATE tus_uploads SET expires = now() + make_interval(secs => $2) WHERE id = $1 RETURNING extract(epoch FROM expires)::float8 AS expires")
+            .bind(&upload.id)
+            .bind(args.upload_expiry as f64)
+            .fetch_one(pool.get_ref())
+            .await
+        {
+            Ok(row) => upload.expires = row.try_get("expires").unwrap_or(upload.expires),
+            Err(err) => log::error!("{:?}", err)
+        }
+
+        (parts, offset) = staged(&args, &user, &req, &upload.id).await;
+    }
+
+    // An upload whose move into place failed is tried again by a `PATCH` which sends nothing.
+    if offset == length && !upload.completed {
+        if let Err(res) = complete(&req, &pool, &args, &limiter, &user, &upload, parts).await? {
+            return Ok(res);
+        }
+
+        upload.completed = true;
+    }
+
+    let mut res = HttpResponse::NoContent();
+    progress(&mut res, &upload, offset);
+
+    Ok(res.finish())
+}
+
+/// Abandons an upload, discarding whatever of it has arrived.
+#[delete("/{id}")]
+pub async fn terminate(req: HttpRequest, pool: Data<PgPool>, args: Data<Args>, limiter: Data<agent::Limiter>, id: Path<String>) -> Result<impl Responder> {
+    if let Some(res) = unsupported(&req) {
+        return Ok(res);
+    }
+
+    let user = match api::storage(&req, &pool).await {
+        Ok(user) => user,
+        Err(res) => return Ok(res)
+    };
+
+    let upload = match find(&pool, &user, &id).await {
+        Ok(Some(upload)) => upload,
+        Ok(None) => return Ok(no_such_upload()),
+        Err(err) => return Ok(internal(err))
+    };
+
+    if !upload.completed {
+        let res = api::run(&req, &pool, &args, &limiter, user.clone(), call("upload::abort", vec!["--".to_owned(), upload.id.clone()], None), None).await?;
+
+        if !res.status().is_success() {
+            return Ok(res);
+        }
+    }
+
+    match sqlx::query("DELETE FROM tus_uploads WHERE id = $1").bind(&upload.id).execute(pool.get_ref()).await {
+        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+        Err(err) => Ok(internal(err))
+    }
+}
+
+/// Discards the staged parts of an expired upload, and records doing so in the audit log. `true` if nothing is left
+/// of it.
+async fn discard(pool: &PgPool, args: &Args, user: &StorageProps, id: &str) -> bool {
+    let started = Instant::now();
+    let arguments = vec!["--".to_owned(), id.to_owned()];
+
+    let mut record = audit::Record {
+        user: user.pk,
+        command: "upload::abort".to_owned(),
+        args: arguments.clone(),
+        paths: Vec::new(),
+        bytes_in: 0,
+        bytes_out: 0,
+        exit_code: None,
+        error: None,
+        duration: Duration::ZERO,
+        client_ip: None,
+        request_id: RNG.with_borrow_mut(|rng| format!("{:016x}{:016x}", rng.next_u64(), rng.next_u64())),
+        dry_run: false,
+    };
+
+    let output = agent::unattended(args, user)
+        .arg("upload::abort")
+        .args(&arguments)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output();
+
+    match tokio::time::timeout(agent::timeout(args, "upload::abort"), output).await {
+        Ok(Ok(output)) => {
+            record.diagnostics(&String::from_utf8_lossy(&output.stderr));
+            record.exit_code = output.status.code();
+        },
+        Ok(Err(err)) => record.error = Some(err.to_string()),
+        Err(_) => record.error = Some("Timed out.".to_owned())
+    }
+
+    record.duration = started.elapsed();
+    let discarded = record.exit_code == Some(0);
+    audit::spawn(pool.clone(), record);
+
+    discarded
+}
+
+/// Forgets uploads which have expired, discarding what was staged for those which were never completed. Runs for as
+/// long as the server does.
+pub async fn expire(pool: PgPool, args: Args) {
+    let mut interval = tokio::time::interval(SWEEP);
+
+    loop {
+        interval.tick().await;
+
+        let expired = match sqlx::query(r#"SELECT tus_uploads.id AS upload, tus_uploads.completed IS NOT NULL AS finished, users.*, storage.*
+FROM tus_uploads
+JOIN users ON users.uid = tus_uploads."user"
+LEFT JOIN storage ON storage.uid = users.uid
+WHERE expires <= now()"#)
+            .fetch_all(&pool)
+            .await
+        {
+            Ok(expired) => expired,
+            Err(err) => {
+                log::error!("{:?}", err);
+                continue;
+            }
+        };
+
+        for row in expired {
+            let (Ok(id), Ok(finished), Ok(user)) = (row.try_get::<String, _>("upload"), row.try_get::<bool, _>("finished"), StorageProps::from_row(&row)) else {
+                log::error!("Failed to read an expired upload");
+                continue;
+            };
+
+            // Uploads which fail to be discarded are tried again next time.
+            if !finished && !discard(&pool, &args, &user, &id).await {
+                log::error!("Failed to discard expired upload {}", id);
+                continue;
+            }
+
+            if let Err(err) = sqlx::query("DELETE FROM tus_uploads WHERE id = $1").bind(&id).execute(&pool).await {
+                log::error!("{:?}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Just enough XML to read the request bodies WebDAV clients send: namespaced elements and their text. Attributes other
+//! than namespace declarations are dropped, as are comments and processing instructions. Document type declarations
+//! are refused outright, so entities can't be used to blow up a request.
+
+use std::collections::HashMap;
+
+/// How deeply elements may nest. Request bodies are a few levels deep at most.
+const DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct Element {
+    pub namespace: String,
+    pub name: String,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    /// The first child with the given name.
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(namespace, name))
+    }
+
+    /// Writes the element back out, declaring each element's namespace as the default so that the result can be
+    /// embedded anywhere.
+    pub fn write(&self) -> String {
+        let inner = self.children.iter()
+            .map(Element::write)
+            .chain([escape(&self.text)])
+            .collect::<String>();
+
+        format!("<{name} xmlns=\"{namespace}\">{inner}</{name}>", name = self.name, namespace = escape(&self.namespace))
+    }
+}
+
+/// Escapes text for use in element content or attribute values.
+pub fn escape(text: &str) -> String {
+    text.chars()
+        .fold(String::with_capacity(text.len()), |mut escaped, c| {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&apos;"),
+                c => escaped.push(c)
+            }
+
+            escaped
+        })
+}
+
+/// Resolves the predefined entities and character references in `text`.
+fn unescape(text: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+
+        let end = rest[start..].find(';').ok_or("Unterminated entity reference")? + start;
+        let entity = &rest[start + 1..end];
+
+        let c = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => entity.strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("Unknown entity `&{};`", entity))?
+        };
+
+        unescaped.push(c);
+        rest = &rest[end + 1..];
+    }
+
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consumes everything up to and including `end`, returning what came before it.
+    fn until(&mut self, end: &str) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let length = rest.find(end).ok_or_else(|| format!("Expected `{}`", end))?;
+
+        self.position += length + end.len();
+        Ok(&rest[..length])
+    }
+
+    /// Skips comments, processing instructions and whitespace between elements.
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+
+            if self.rest().starts_with("<?") {
+                self.until("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.until("-->")?;
+            } else if self.rest().starts_with("<!") {
+                return Err("Document type declarations aren't accepted".to_owned());
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let length = rest.find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=')).unwrap_or(rest.len());
+
+        if length == 0 {
+            return Err("Expected a name".to_owned());
+        }
+
+        self.position += length;
+        Ok(&rest[..length])
+    }
+
+    fn element(&mut self, scopes: &mut Vec<HashMap<String, String>>) -> Result<Element, String> {
+        if scopes.len() > DEPTH {
+            return Err("Elements are nested too deeply".to_owned());
+        }
+
+        self.position += 1;
+        let tag = self.name()?;
+        let mut scope = HashMap::new();
+
+        let closed = loop {
+            self.skip_whitespace();
+
+            if self.rest().starts_with("/>") {
+                self.position += 2;
+                break true;
+            } else if self.rest().starts_with('>') {
+                self.position += 1;
+                break false;
+            }
+
+            let attribute = self.name()?;
+            self.skip_whitespace();
+
+            if !self.rest().starts_with('=') {
+                return Err(format!("Expected a value for `{}`", attribute));
+            }
+
+            self.position += 1;
+            self.skip_whitespace();
+
+            let quote = match self.rest().chars().next() {
+                Some(quote @ ('"' | '\'')) => quote,
+                _ => return Err(format!("Expected a quoted value for `{}`", attribute))
+            };
+
+            self.position += 1;
+            let value = unescape(self.until(&quote.to_string())?)?;
+
+            if attribute == "xmlns" {
+                scope.insert(String::new(), value);
+            } else if let Some(prefix) = attribute.strip_prefix("xmlns:") {
+                scope.insert(prefix.to_owned(), value);
+            }
+        };
+
+        scopes.push(scope);
+
+        let (prefix, name) = tag.split_once(':').unwrap_or(("", tag));
+        let namespace = scopes.iter()
+            .rev()
+            .find_map(|scope| scope.get(prefix))
+            .cloned();
+
+        let mut element = Element {
+            namespace: match namespace {
+                Some(namespace) => namespace,
+                None if prefix.is_empty() => String::new(),
+                None => return Err(format!("Undeclared namespace prefix `{}`", prefix))
+            },
+            name: name.to_owned(),
+            ..Element::default()
+        };
+
+        if !closed {
+            loop {
+                let rest = self.rest();
+
+                if rest.starts_with("</") {
+                    self.position += 2;
+
+                    if self.until(">")?.trim_end() != tag {
+                        return Err(format!("Expected `</{}>`", tag));
+                    }
+
+                    break;
+                } else if rest.starts_with("<![CDATA[") {
+                    self.position += 9;
+                    element.text.push_str(self.until("]]>")?);
+                } else if rest.starts_with("<?") || rest.starts_with("<!") {
+                    self.skip_misc()?;
+                } else if rest.starts_with('<') {
+                    element.children.push(self.element(scopes)?);
+                } else if rest.is_empty() {
+                    return Err(format!("Expected `</{}>`", tag));
+                } else {
+                    let length = rest.find('<').unwrap_or(rest.len());
+                    element.text.push_str(&unescape(&rest[..length])?);
+                    self.position += length;
+                }
+            }
+        }
+
+        // Whitespace between child elements is only there for layout.
+        if !element.children.is_empty() && element.text.trim().is_empty() {
+            element.text.clear();
+        }
+
+        scopes.pop();
+        Ok(element)
+    }
+}
+
+/// Parses a document, returning its root element.
+pub fn parse(input: &str) -> Result<Element, String> {
+    let mut parser = Parser { input: input.trim_start_matches('\u{feff}'), position: 0 };
+
+    parser.skip_misc()?;
+
+    if !parser.rest().starts_with('<') {
+        return Err("Expected an element".to_owned());
+    }
+
+    let root = parser.element(&mut Vec::new())?;
+    parser.skip_misc()?;
+
+    match parser.rest() {
+        "" => Ok(root),
+        _ => Err("Unexpected content after the root element".to_owned())
+    }
+}
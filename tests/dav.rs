@@ -0,0 +1,320 @@
+//! Checks the WebDAV server along the lines of the litmus suite: `PROPFIND` at each depth, `COPY` and `MOVE` with and
+//! without `Overwrite`, and `LOCK`/`UNLOCK` along with the requests a lock stands in the way of.
+//!
+//! The server is started against a database already set up for it, with the agent beside it, so this has to run as
+//! root and is skipped unless asked for. The user given must have storage; the checks are made in a scratch collection
+//! which is deleted afterwards.
+//!
+//! ```sh
+//! cargo build --workspace
+//! JCAKE_TEST_DATABASE=postgres://... JCAKE_TEST_EMAIL=... JCAKE_TEST_PASSWORD=... cargo test --test dav -- --ignored
+//! ```
+//!
+//! The password is one of the user's app passwords or API tokens.
+
+use reqwest::{
+    header::HeaderMap,
+    Client,
+    Method,
+    RequestBuilder,
+    StatusCode
+};
+use std::{
+    env,
+    fs,
+    net::TcpListener,
+    net::TcpStream,
+    path::PathBuf,
+    process::Child,
+    process::Command,
+    process::Stdio,
+    thread,
+    time::Duration
+};
+
+const EXCLUSIVE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype>
+<D:owner>litmus</D:owner></D:lockinfo>"#;
+
+const SHARED: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:lockinfo xmlns:D="DAV:"><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"#;
+
+/// The server, stopped and cleaned up after when dropped.
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    port: u16,
+}
+
+impl Server {
+    fn start(database: &str) -> Server {
+        let exe = PathBuf::from(env!("CARGO_BIN_EXE_jcake-cloud"));
+        let bin = exe.parent().expect("the server is in a directory").to_owned();
+
+        assert!(bin.join("agent").exists(), "the agent has to be built first, with `cargo build --workspace`");
+
+        let dir = env::temp_dir().join(format!("jcake-cloud-dav-{}", std::process::id()));
+        fs::create_dir_all(dir.join("static")).unwrap();
+        fs::create_dir_all(dir.join("sql")).unwrap();
+        fs::write(dir.join("index.html"), "").unwrap();
+        fs::write(dir.join("oauth.json"), r#"{"token_url":"http://localhost","user_url":"http://localhost","client_id":"","client_secret":""}"#).unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let path = env::join_paths([bin].into_iter().chain(env::split_paths(&env::var_os("PATH").unwrap_or_default()))).unwrap();
+
+        let child = Command::new(&exe)
+            .arg("--listen").arg(format!("127.0.0.1:{}", port))
+            .arg("--database").arg(database)
+            .arg("--sql").arg(dir.join("sql"))
+            .arg("--oauth-config").arg(dir.join("oauth.json"))
+            .arg("--static").arg(dir.join("static"))
+            .arg("--index").arg(dir.join("index.html"))
+            .current_dir(&dir)
+            .env("PATH", path)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let mut server = Server { child, dir, port };
+
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return server;
+            }
+
+            if let Some(status) = server.child.try_wait().unwrap() {
+                panic!("the server exited with {}", status);
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        panic!("the server didn't start listening");
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Dav {
+    client: Client,
+    url: String,
+    email: String,
+    password: String,
+}
+
+impl Dav {
+    fn request(&self, method: &str, path: &str) -> RequestBuilder {
+        self.client
+            .request(Method::from_bytes(method.as_bytes()).unwrap(), format!("{}{}", self.url, path))
+            .basic_auth(&self.email, Some(&self.password))
+    }
+
+    async fn status(&self, request: RequestBuilder) -> StatusCode {
+        request.send().await.unwrap().status()
+    }
+
+    async fn put(&self, path: &str, body: &'static str) -> StatusCode {
+        self.status(self.request("PUT", path).body(body)).await
+    }
+
+    async fn get(&self, path: &str) -> String {
+        let res = self.request("GET", path).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "GET {}", path);
+        res.text().await.unwrap()
+    }
+
+    /// Copies or moves `from` to `to`, with the given `Depth` and `Overwrite` headers if any.
+    async fn transfer(&self, method: &str, from: &str, to: &str, depth: Option<&str>, overwrite: Option<&str>) -> StatusCode {
+        let mut request = self.request(method, from).header("Destination", format!("{}{}", self.url, to));
+
+        if let Some(depth) = depth {
+            request = request.header("Depth", depth);
+        }
+
+        if let Some(overwrite) = overwrite {
+            request = request.header("Overwrite", overwrite);
+        }
+
+        self.status(request).await
+    }
+
+    /// The hrefs of the responses to a `PROPFIND` of `path` at `depth`.
+    async fn propfind(&self, path: &str, depth: &str) -> Vec<String> {
+        let res = self.request("PROPFIND", path).header("Depth", depth).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::MULTI_STATUS, "PROPFIND {} at depth {}", path, depth);
+
+        let body = res.text().await.unwrap();
+        let mut hrefs = body.split("<D:href>")
+            .skip(1)
+            .filter_map(|rest| rest.split_once("</D:href>"))
+            .map(|(href, _)| href.to_owned())
+            .collect::<Vec<_>>();
+
+        hrefs.sort();
+        hrefs
+    }
+
+    /// Takes out a lock, returning its token.
+    async fn lock(&self, path: &str, body: &'static str, depth: &str) -> String {
+        let res = self.request("LOCK", path).header("Depth", depth).body(body).send().await.unwrap();
+        assert!(res.status().is_success(), "LOCK {} gave {}", path, res.status());
+
+        token(res.headers())
+    }
+}
+
+fn token(headers: &HeaderMap) -> String {
+    let token = headers.get("Lock-Token").expect("a lock token").to_str().unwrap();
+    token.trim().trim_start_matches('<').trim_end_matches('>').to_owned()
+}
+
+#[tokio::test]
+#[ignore = "needs a database and root, see the top of this file"]
+async fn litmus() {
+    let var = |name: &str| env::var(name).unwrap_or_else(|_| panic!("{} has to be set", name));
+    let server = Server::start(&var("JCAKE_TEST_DATABASE"));
+
+    let dav = Dav {
+        client: Client::new(),
+        url: format!("http://127.0.0.1:{}/dav", server.port),
+        email: var("JCAKE_TEST_EMAIL"),
+        password: var("JCAKE_TEST_PASSWORD"),
+    };
+
+    let root = format!("/litmus-{}", std::process::id());
+    let at = |path: &str| format!("{}{}", root, path);
+    let href = |path: &str| format!("/dav{}", at(path));
+
+    // basic
+    let res = dav.request("OPTIONS", "/").send().await.unwrap();
+    assert_eq!(res.headers().get("DAV").and_then(|dav| dav.to_str().ok()), Some("1, 2"));
+
+    assert_eq!(dav.status(dav.request("MKCOL", &root)).await, StatusCode::CREATED);
+    assert_eq!(dav.status(dav.request("MKCOL", &root)).await, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(dav.status(dav.request("MKCOL", &at("/missing/coll"))).await, StatusCode::CONFLICT);
+    assert_eq!(dav.put(&at("/missing/file"), "").await, StatusCode::CONFLICT);
+
+    assert_eq!(dav.put(&at("/res"), "first").await, StatusCode::CREATED);
+    assert_eq!(dav.put(&at("/res"), "second").await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.get(&at("/res")).await, "second");
+
+    // props
+    assert_eq!(dav.status(dav.request("MKCOL", &at("/coll"))).await, StatusCode::CREATED);
+    assert_eq!(dav.status(dav.request("MKCOL", &at("/coll/sub"))).await, StatusCode::CREATED);
+    assert_eq!(dav.put(&at("/coll/a"), "a").await, StatusCode::CREATED);
+    assert_eq!(dav.put(&at("/coll/sub/b"), "b").await, StatusCode::CREATED);
+
+    assert_eq!(dav.propfind(&at("/coll"), "0").await, [href("/coll/")]);
+    assert_eq!(dav.propfind(&at("/coll"), "1").await, [href("/coll/"), href("/coll/a"), href("/coll/sub/")]);
+    assert_eq!(dav.propfind(&at("/coll"), "infinity").await,
+        [href("/coll/"), href("/coll/a"), href("/coll/sub/"), href("/coll/sub/b")]);
+    assert_eq!(dav.propfind(&at("/res"), "1").await, [href("/res")]);
+    assert_eq!(dav.status(dav.request("PROPFIND", &at("/coll")).header("Depth", "2")).await, StatusCode::BAD_REQUEST);
+    assert_eq!(dav.status(dav.request("PROPFIND", &at("/nothing")).header("Depth", "0")).await, StatusCode::NOT_FOUND);
+
+    // copymove: resources
+    assert_eq!(dav.transfer("COPY", &at("/res"), &at("/copy"), None, None).await, StatusCode::CREATED);
+    assert_eq!(dav.put(&at("/copy"), "changed").await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.transfer("COPY", &at("/res"), &at("/copy"), None, Some("F")).await, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(dav.get(&at("/copy")).await, "changed");
+    assert_eq!(dav.transfer("COPY", &at("/res"), &at("/copy"), None, Some("T")).await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.get(&at("/copy")).await, "second");
+    assert_eq!(dav.transfer("COPY", &at("/res"), &at("/missing/copy"), None, None).await, StatusCode::CONFLICT);
+    assert_eq!(dav.transfer("COPY", &at("/nothing"), &at("/copy"), None, None).await, StatusCode::NOT_FOUND);
+
+    assert_eq!(dav.transfer("MOVE", &at("/copy"), &at("/moved"), None, None).await, StatusCode::CREATED);
+    assert_eq!(dav.status(dav.request("GET", &at("/copy"))).await, StatusCode::NOT_FOUND);
+    assert_eq!(dav.put(&at("/other"), "other").await, StatusCode::CREATED);
+    assert_eq!(dav.transfer("MOVE", &at("/other"), &at("/moved"), None, Some("F")).await, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(dav.get(&at("/other")).await, "other");
+    assert_eq!(dav.transfer("MOVE", &at("/other"), &at("/moved"), None, Some("T")).await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.get(&at("/moved")).await, "other");
+    assert_eq!(dav.status(dav.request("GET", &at("/other"))).await, StatusCode::NOT_FOUND);
+
+    // copymove: collections
+    assert_eq!(dav.transfer("COPY", &at("/coll"), &at("/ccopy"), None, None).await, StatusCode::CREATED);
+    assert_eq!(dav.get(&at("/ccopy/sub/b")).await, "b");
+    assert_eq!(dav.transfer("COPY", &at("/coll"), &at("/shallow"), Some("0"), None).await, StatusCode::CREATED);
+    assert_eq!(dav.propfind(&at("/shallow"), "1").await, [href("/shallow/")]);
+    assert_eq!(dav.transfer("COPY", &at("/shallow"), &at("/ccopy"), Some("0"), Some("T")).await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.propfind(&at("/ccopy"), "1").await, [href("/ccopy/")]);
+    assert_eq!(dav.transfer("COPY", &at("/coll"), &at("/ccopy"), None, Some("F")).await, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(dav.transfer("COPY", &at("/coll"), &at("/ccopy"), None, Some("T")).await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.get(&at("/ccopy/a")).await, "a");
+
+    assert_eq!(dav.transfer("MOVE", &at("/coll"), &at("/coll/sub/inside"), None, None).await, StatusCode::FORBIDDEN);
+    assert_eq!(dav.transfer("MOVE", &at("/coll"), &at("/mcoll"), Some("0"), None).await, StatusCode::BAD_REQUEST);
+    assert_eq!(dav.transfer("MOVE", &at("/ccopy"), &at("/moved"), None, Some("T")).await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.get(&at("/moved/sub/b")).await, "b");
+    assert_eq!(dav.status(dav.request("PROPFIND", &at("/ccopy")).header("Depth", "0")).await, StatusCode::NOT_FOUND);
+
+    // locks: exclusive
+    let token = dav.lock(&at("/res"), EXCLUSIVE, "0").await;
+    let held = format!("(<{}>)", token);
+
+    assert_eq!(dav.status(dav.request("LOCK", &at("/res")).header("Depth", "0").body(EXCLUSIVE)).await, StatusCode::LOCKED);
+    assert_eq!(dav.status(dav.request("LOCK", &at("/res")).header("Depth", "0").body(SHARED)).await, StatusCode::LOCKED);
+    assert_eq!(dav.put(&at("/res"), "unlocked").await, StatusCode::LOCKED);
+    assert_eq!(dav.status(dav.request("PUT", &at("/res")).header("If", &held).body("locked")).await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.get(&at("/res")).await, "locked");
+    assert_eq!(dav.status(dav.request("DELETE", &at("/res"))).await, StatusCode::LOCKED);
+    assert_eq!(dav.transfer("COPY", &at("/moved"), &at("/res"), None, Some("T")).await, StatusCode::LOCKED);
+    assert_eq!(dav.transfer("MOVE", &at("/res"), &at("/elsewhere"), None, None).await, StatusCode::LOCKED);
+    assert_eq!(dav.transfer("COPY", &at("/res"), &at("/unlocked"), None, None).await, StatusCode::CREATED);
+
+    // A refresh is a lock without a body, naming the lock in `If`.
+    let res = dav.request("LOCK", &at("/res")).header("If", &held).header("Timeout", "Second-60").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.text().await.unwrap().contains(&token));
+
+    assert_eq!(dav.status(dav.request("UNLOCK", &at("/res")).header("Lock-Token", "<urn:uuid:wrong>")).await, StatusCode::CONFLICT);
+    assert_eq!(dav.status(dav.request("UNLOCK", &at("/res")).header("Lock-Token", format!("<{}>", token))).await, StatusCode::NO_CONTENT);
+    assert_eq!(dav.put(&at("/res"), "unlocked").await, StatusCode::NO_CONTENT);
+
+    // locks: shared
+    let first = dav.lock(&at("/res"), SHARED, "0").await;
+    let second = dav.lock(&at("/res"), SHARED, "0").await;
+
+    assert_ne!(first, second);
+    assert_eq!(dav.status(dav.request("LOCK", &at("/res")).header("Depth", "0").body(EXCLUSIVE)).await, StatusCode::LOCKED);
+    assert_eq!(dav.put(&at("/res"), "unlocked").await, StatusCode::LOCKED);
+    assert_eq!(dav.status(dav.request("PUT", &at("/res")).header("If", format!("(<{}>)", second)).body("shared")).await, StatusCode::NO_CONTENT);
+
+    for token in [first, second] {
+        assert_eq!(dav.status(dav.request("UNLOCK", &at("/res")).header("Lock-Token", format!("<{}>", token))).await, StatusCode::NO_CONTENT);
+    }
+
+    // locks: collections, at infinite depth
+    let token = dav.lock(&at("/moved"), EXCLUSIVE, "infinity").await;
+
+    assert_eq!(dav.put(&at("/moved/sub/b"), "unlocked").await, StatusCode::LOCKED);
+    assert_eq!(dav.put(&at("/moved/new"), "unlocked").await, StatusCode::LOCKED);
+    assert_eq!(dav.status(dav.request("LOCK", &at("/moved/a")).header("Depth", "0").body(SHARED)).await, StatusCode::LOCKED);
+    assert_eq!(dav.status(dav.request("PUT", &at("/moved/new")).header("If", format!("(<{}>)", token)).body("locked")).await, StatusCode::CREATED);
+    assert_eq!(dav.transfer("MOVE", &at("/moved"), &at("/gone"), None, None).await, StatusCode::LOCKED);
+    assert_eq!(dav.status(dav.request("UNLOCK", &at("/moved")).header("Lock-Token", format!("<{}>", token))).await, StatusCode::NO_CONTENT);
+
+    // A lock on nothing creates an empty resource.
+    let token = dav.lock(&at("/null"), EXCLUSIVE, "0").await;
+    assert_eq!(dav.get(&at("/null")).await, "");
+    assert_eq!(dav.status(dav.request("DELETE", &at("/null")).header("If", format!("(<{}>)", token))).await, StatusCode::NO_CONTENT);
+
+    // Moving over a locked resource with its token releases the lock along with what it was on.
+    let token = dav.lock(&at("/unlocked"), EXCLUSIVE, "0").await;
+    let res = dav.request("MOVE", &at("/res"))
+        .header("Destination", format!("{}{}", dav.url, at("/unlocked")))
+        .header("If", format!("<{}{}> (<{}>)", dav.url, at("/unlocked"), token))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(dav.put(&at("/unlocked"), "free").await, StatusCode::NO_CONTENT);
+
+    assert_eq!(dav.status(dav.request("DELETE", &root)).await, StatusCode::NO_CONTENT);
+}